@@ -1,5 +1,5 @@
 use candid::Principal;
-use ic_cdk::{api::msg_caller, call::Call, inspect_message};
+use ic_cdk::api::msg_caller;
 use ic_cdk_macros::{query, update};
 
 // import tests
@@ -8,50 +8,48 @@ mod test;
 
 use vault_core::{
     api::{
-        key_api::{derive_vetkey, retrieve_vetkey_per_user, storage_user_of, GhostkeysVetKdArgs}, serial_api::{_delete_vault, _global_sync, _login_data_deletes, _login_data_sync, _login_full_sync, _login_metadata_delete, _login_metadata_sync, _purge_user, _secret_notes_sync, _vault_names_sync, _vault_spreadsheet_columns_sync, _vault_spreadsheet_delete, _vault_spreadsheet_sync}
-    },
-    stable::{
-        types::GeneralState,
-        util::{_init_controllers, _inspect_message, maintain_status},
+        capacity_api::{_admit_user, _get_capacity, _set_capacity_redirect, CapacityInfo},
+        config_api::{_users_for_storage, DEFAULT_MAX_USER_STORAGE, DEFAULT_MAX_VAULTS_PER_USER, DEFAULT_MAX_VAULT_SIZE_BYTES},
+        deletion_api::PendingDeletionInfo,
+        dev_api::{_get_user_vaults, UserVaults},
+        key_api::retrieve_vetkey_per_user,
+        migration_api::{_assert_migration_caller, _authorize_migration, _cancel_migration, _complete_migration, _export_page, _migration_manifest, MigrationPage},
     },
+    service::{arm_deletion_timer, assert_controller, assert_not_frozen, audit, capacity::notify_capacity_change, policy::{TenancyMode, VaultPolicy}, vault_user, with_state},
+    stable::{types::GeneralState, util::_is_controller},
+    vault_type::{audit_log::AuditOp, migration::MigrationManifest},
 };
 
-thread_local! {
-    static GENERAL_STATE: GeneralState = GeneralState::init();
-}
-
-const MAX_VAULTS_PER_USER: u64 = 3;
-const MAX_VAULT_SIZE_BYTES: u64 = 1 * 1024 * 1024 * 1024; // 1 GB
-const STORAGE_PER_USER: u64 = MAX_VAULTS_PER_USER * MAX_VAULT_SIZE_BYTES;
+// Users that fit the default storage budget at the free tier's default limits. Controllers
+// change the limits at runtime through update_config.
+const MAX_USERS: u64 = _users_for_storage(DEFAULT_MAX_USER_STORAGE, DEFAULT_MAX_VAULTS_PER_USER * DEFAULT_MAX_VAULT_SIZE_BYTES);
 
-const MAX_USER_STORAGE: u64 = 400 * 1024 * 1024 * 1024; // 400 GB
-const MAX_USERS: u64 = MAX_USER_STORAGE / STORAGE_PER_USER;
+// Every caller acts on their own data, up to MAX_USERS users unless the install arguments say otherwise.
+struct SharedPolicy;
+impl VaultPolicy for SharedPolicy {
+    const TENANCY: TenancyMode = TenancyMode::Shared;
 
-// Helper for cost-related. TODO: move
-fn maintain_canister_status() {
-    GENERAL_STATE.with(|m| {
-        maintain_status(&m.canister_owners); // check if we need more cycles
-    });
-}
+    const DEFAULT_MAX_USERS: u64 = MAX_USERS;
 
-#[inspect_message]
-fn inspect_message() {
-    let always_accept: Vec<String> = vec![
-        "shared_canister_init".to_string(), // TODO - needs to be reworked so only the factory can call this, and only once
-        "derive_vetkd_encrypted_key".to_string(), // TODO - requires proof of work from caller to prevent canister flooding
-        "get_vetkey_for_user".to_string()
+    const ALWAYS_ACCEPT: &'static [&'static str] = &[
+        "derive_vetkd_encrypted_key", // TODO - requires proof of work from caller to prevent canister flooding
+        "get_vetkey_for_user",
     ];
-    // call common inspect
-    GENERAL_STATE.with(|m| _inspect_message(&always_accept, &m.canister_owners))
-}
 
-#[update]
-fn shared_canister_init(user: Principal, controller: Principal) {
-    GENERAL_STATE.with(|m| {
-        _init_controllers(user, controller, &m.canister_owners);
-    });
+    fn vault_user(caller: Principal, _state: &GeneralState) -> Result<Principal, String> {
+        Ok(caller)
+    }
+
+    // Registers new users until the canister closes, keeping the factory up to date on capacity.
+    fn admit_user(user: Principal, state: &GeneralState) -> Result<(), String> {
+        _admit_user(user, state)?;
+        notify_capacity_change();
+        Ok(())
+    }
 }
 
+vault_core::vault_endpoints!(SharedPolicy);
+
 #[test]
 fn test_deserialise_spreadsheet() {
     let data : Vec<u8> = vec![
@@ -78,250 +76,90 @@ fn test_deserialise_spreadsheet() {
     Key-management Specific Endpoints
 */
 
-#[update]
-async fn derive_vetkd_encrypted_key(args: GhostkeysVetKdArgs) -> Result<Vec<u8>, String> {
-    let scope_copy = args.scope.clone();
-    let owner_principal = storage_user_of(&scope_copy);
-
-    // check we haven't exceeded max users
-    GENERAL_STATE.with(|state| {
-        let current_users: u64 = state.key_management.borrow().len();
-        let mut canister_owners = state.canister_owners.borrow_mut();
-        if !canister_owners.user.contains(&owner_principal) {
-            if current_users == MAX_USERS - 1 {
-                // notify the factory canister that we are at capacity, but handle this new user.
-                let _ = Call::unbounded_wait(
-                    state.canister_owners.borrow().controller,
-                    "notify_canister_at_capacity",
-                );
-            } else if current_users >= MAX_USERS {
-                ic_cdk::trap("Canister at max user capacity");
-            } else {
-                canister_owners
-                    .user
-                    .push(owner_principal);
-            }
-        }
-    });
-
-    if let Some(existing_key) =
-        GENERAL_STATE.with(|st| st.key_management.borrow().get(&owner_principal.to_text()))
-    {
-        return Ok(existing_key);
-    }
-
-    maintain_canister_status();
-    let encrypted_key = derive_vetkey(args).await?;
-
-    GENERAL_STATE.with(|st| {
-        st.key_management
-            .borrow_mut()
-            .insert(owner_principal.to_text(), encrypted_key.clone());
-    });
-
-    Ok(encrypted_key)
-}
-
 #[query]
 fn get_vetkey_for_user(user_id: String) -> Option<Vec<u8>> {
-    GENERAL_STATE.with(|st| retrieve_vetkey_per_user(user_id, &st.key_management))
+    with_state(|st| retrieve_vetkey_per_user(user_id, &st.key_management))
 }
 
-/* 
-    New vault-specific update endpoints 
+/*
+    Capacity. Queried by the factory, and by clients looking for the canister to use instead.
 */
 
-#[update]
-fn vault_names_sync(update: Vec<u8>) {
-    let user_id = msg_caller();
-    GENERAL_STATE.with(|state| {
-        _vault_names_sync(user_id, &update, &state.vault_names_map);
-    })
-}
-
-#[update]
-fn vault_spreadsheet_columns_sync(vault_id: Principal, update: Vec<u8>) {
-    let user_id = msg_caller();
-    GENERAL_STATE.with(|state| {
-        _vault_spreadsheet_columns_sync(
-            user_id,
-            vault_id,
-            update,
-            &state.spreadsheet_columns,
-        );
-    });
-}
-
-#[update] 
-fn vault_spreadsheet_sync(vault_id: Principal, update: Vec<u8>) {
-    let user_id = msg_caller();
-    GENERAL_STATE.with(|state| {
-        _vault_spreadsheet_sync(
-            user_id,
-            vault_id,
-            update,
-            &state.spreadsheet_map,
-        );
-    });
-}
-
-#[update]
-fn vault_spreadsheet_deletes(vault_id: Principal, update: Vec<u8>) {
-    let user_id = msg_caller();
-    GENERAL_STATE.with(|state| {
-        _vault_spreadsheet_delete(user_id, vault_id, update, &state.spreadsheet_map);
-    });
-}
-
-#[update]
-fn vault_login_full_sync(vault_id: Principal, update: Vec<u8>) {
-    let user_id = msg_caller();
-    GENERAL_STATE.with(|state| {
-        _login_full_sync(
-            user_id,
-            vault_id,
-            update,
-            &state.logins_columns,
-            &state.logins_map,
-        );
-    });
-}
-
-#[update]
-fn vault_login_metadata_sync(vault_id: Principal, update: Vec<u8>) {
-    let user_id = msg_caller();
-    GENERAL_STATE.with(|state| {
-        _login_metadata_sync(user_id, vault_id, update, &state.logins_columns, &state.logins_map);
-    });
-}
-
-#[update]
-fn vault_login_metadata_delete(vault_id: Principal, update: Vec<u8>) {
-    let user_id = msg_caller();
-    GENERAL_STATE.with(|state| {
-        _login_metadata_delete(user_id, vault_id, update, &state.logins_columns, &state.logins_map);
-    });
-}
-
-#[update]
-fn vault_login_data_sync(vault_id: Principal, update: Vec<u8>) {
-    let user_id = msg_caller();
-    GENERAL_STATE.with(|state| {
-        _login_data_sync(user_id, vault_id, update, &state.logins_map);
-    });
-}
-
-#[update]
-fn vault_login_data_deletes(vault_id: Principal, update: Vec<u8>) {
-    let user_id = msg_caller();
-    GENERAL_STATE.with(|state| {
-        _login_data_deletes(user_id, vault_id, update, &state.logins_map);
-    });
+#[query]
+fn get_capacity() -> CapacityInfo {
+    with_state(_get_capacity)
 }
 
+// Lets the factory point new users elsewhere without waiting for the next notification.
 #[update]
-fn vault_secrets_sync(vault_id: Principal, update: Vec<u8>) {
-    let user_id = msg_caller();
-    GENERAL_STATE.with(|state| {
-        _secret_notes_sync(user_id, vault_id, update, &state.notes_map);
+fn set_capacity_redirect(redirect: Option<Principal>) {
+    assert_controller();
+    with_state(|state| {
+        assert_not_frozen(state);
+        _set_capacity_redirect(redirect, state)
     });
 }
 
-#[update]
-fn global_sync(vault_id: Principal, update: Vec<u8>) {
-    let user_id = msg_caller();
-    GENERAL_STATE.with(|state| {
-        _global_sync(
-            user_id,
-            vault_id,
-            update,
-            &state
-        );
-    });
-}
+/*
+    Migration to a dedicated canister. The user authorises the target, which then exports
+    their data and completes the migration once it has checked what it imported.
+*/
 
 #[update]
-fn delete_vault(vault_id: Principal) {
-    let user_id = msg_caller();
-    GENERAL_STATE.with(|state| {
-        _delete_vault(user_id, vault_id, state);
+fn authorize_migration(target: Principal) -> Result<(), String> {
+    with_state(|state| {
+        let user_id = vault_user::<SharedPolicy>(state);
+        _authorize_migration(user_id, target, ic_cdk::api::time(), &state.migration_grants)?;
+        audit(state, user_id, None, AuditOp::MigrationAuthorized, 0);
+        Ok(())
     })
 }
 
 #[update]
-fn purge_user() {
-    let user_id = msg_caller();
-    GENERAL_STATE.with(|state| {
-        _purge_user(user_id, state);
-    })
+fn cancel_migration() -> Result<(), String> {
+    with_state(|state| _cancel_migration(vault_user::<SharedPolicy>(state), state))
 }
 
-/* 
-    New vault-specific query endpoints
-*/
-
-#[query]
-fn get_vault_names() ->vault_core::api::dev_api::VaultNames {
-    let user_id = msg_caller();
-    GENERAL_STATE.with(|state| {
-        vault_core::api::dev_api::_get_vault_names(user_id, &state.vault_names_map)
-    })
-}
-
-#[query]
-fn get_vault_name(vault_id: Principal) -> Vec<u8> {
-    let user_id = msg_caller();
-    GENERAL_STATE.with(|state| {
-        vault_core::api::dev_api::_get_vault_name(user_id, vault_id, &state.vault_names_map)
-    })
-}
-
-#[query]
-fn get_spreadsheet_columns(vault_id: Principal) -> vault_core::api::dev_api::FlexGridColumns {
-    let user_id = msg_caller();
-    GENERAL_STATE.with(|state| {
-        vault_core::api::dev_api::_get_columns_info(user_id, vault_id, &state.spreadsheet_columns)
-    })
+fn assert_migration_caller(user_id: Principal, state: &GeneralState) -> Result<(), String> {
+    let caller = msg_caller();
+    let is_controller = _is_controller(caller, &state.canister_owners);
+    _assert_migration_caller(user_id, caller, is_controller, ic_cdk::api::time(), &state.migration_grants)
 }
 
 #[query]
-fn get_spreadsheet(vault_id: Principal) -> vault_core::api::dev_api::Spreadsheet {
-    let user_id = msg_caller();
-    GENERAL_STATE.with(|state| {
-        vault_core::api::dev_api::_get_spreadsheet(user_id, vault_id, &state.spreadsheet_map)
+fn get_migration_manifest(user_id: Principal) -> Result<MigrationManifest, String> {
+    with_state(|state| {
+        assert_migration_caller(user_id, state)?;
+        _migration_manifest(user_id, state)
     })
 }
 
-#[query]
-fn get_logins(vault_id: Principal) -> vault_core::api::dev_api::Logins {
-    let user_id = msg_caller();
-    GENERAL_STATE.with(|state| {
-        vault_core::api::dev_api::_get_logins(user_id, vault_id, &state.logins_map, &state.logins_columns)
-    })
-}
-
-#[query]
-fn get_secure_notes(vault_id: Principal) -> vault_core::api::dev_api::Notes {
-    let user_id = msg_caller();
-    GENERAL_STATE.with(|state| {
-        vault_core::api::dev_api::_get_notes(user_id, vault_id, &state.notes_map)
+// An update, as the export keeps its place between pages.
+#[update]
+fn export_migration_page(user_id: Principal, cursor: Option<u64>) -> Result<MigrationPage, String> {
+    with_state(|state| {
+        assert_not_frozen(state);
+        assert_migration_caller(user_id, state)?;
+        _export_page(user_id, cursor, ic_cdk::api::time(), state)
     })
 }
 
-#[query]
-fn get_user_vault(vault_id: Principal) -> vault_core::api::dev_api::VaultData {
-    let user_id = msg_caller();
-    GENERAL_STATE.with(|state| {
-        let vault_name = vault_core::api::dev_api::_get_vault_name(user_id, vault_id, &state.vault_names_map);
-        vault_core::api::dev_api::_get_vault(&vault_name, user_id, vault_id, &state)
-    })
+#[update]
+fn complete_migration(user_id: Principal, checksum: Vec<u8>) -> Result<PendingDeletionInfo, String> {
+    let pending = with_state(|state| {
+        assert_not_frozen(state);
+        assert_migration_caller(user_id, state)?;
+        let pending = _complete_migration(user_id, &checksum, ic_cdk::api::time(), state)?;
+        audit(state, user_id, None, AuditOp::MigrationCompleted, 0);
+        Ok::<PendingDeletionInfo, String>(pending)
+    })?;
+    arm_deletion_timer();
+    Ok(pending)
 }
 
 #[query]
-fn get_all_user_vaults(user_id: Principal) -> vault_core::api::dev_api::UserVaults {
-    GENERAL_STATE.with(|state| {
-        vault_core::api::dev_api::_get_user_vaults(user_id, state)
-    })
+fn get_all_user_vaults(user_id: Principal) -> UserVaults {
+    with_state(|state| _get_user_vaults(user_id, state))
 }
 
 ic_cdk::export_candid!();
@@ -2,8 +2,52 @@ use std::cell::RefCell;
 
 use candid::Principal;
 use ic_stable_structures::{memory_manager::{MemoryId, MemoryManager}, DefaultMemoryImpl, StableBTreeMap, Storable};
-use vault_core::{api::{dev_api::{_get_columns_info, _get_logins, _get_notes, _get_vault}, serial_api::{_global_sync, _login_data_sync, _login_metadata_sync, _secret_notes_sync, _vault_names_sync}}, stable::{state, types::GeneralState}, vault_type::spreadsheet::SpreadsheetKey};
-use vault_core::api::dev_api::_get_vault_names;
+use vault_core::{
+    api::{
+        access_api::{MAX_DELEGATES, _add_delegate, _assert_owner, _get_delegates, _remove_delegate, _resolve_owner},
+        attachments_api::{AttachmentUpload, _begin_attachment, _delete_attachment, _finish_attachment, _get_attachment_chunk, _link_attachment, _list_attachments, _upload_attachment_chunk},
+        audit_api::{_get_audit_log, _get_user_audit_log, _record_audit_event, _set_audit_retention},
+        capacity_api::{_admit_user, _capacity_state, _get_capacity, _needs_capacity_notification, _record_capacity_notification, _set_capacity_redirect},
+        config_api::{ConfigUpdate, _assert_vault_limit, _get_config, _update_config},
+        cycles_api::{_get_cycles_status, _needs_top_up, _record_top_up, _set_cycles_settings},
+        deletion_api::{_assert_names_writable, _assert_vault_writable, _cancel_deletion, _get_pending_deletions, _next_deletion_due, _run_due_deletions, _schedule_immediate_purge, _schedule_user_purge, _schedule_vault_deletion},
+        dev_api::{_get_columns_info, _get_custom_records, _get_expiring_records, _get_identities, _get_logins, _get_notes, _get_payment_cards, _get_record_templates, _get_sheet, _get_sheet_columns, _get_spreadsheet, _get_totp_seeds, _get_vault, _get_vault_names},
+        grid_api::{Axis, Grid, GridEdit, _edit_grid},
+        history_api::{_get_revision, _list_revisions, _record_revisions, _rollback_item, _set_history_depth},
+        machine_api::{GrantScope, MachineGrantArgs, _get_machine_grants, _get_machine_vault, _get_vaults_for_machine, _grant_machine_access, _revoke_machine_access},
+        metrics_api::{CallCounters, HttpRequest, _collect_metrics, _encode_prometheus, _http_response, _record_call},
+        migration_api::{MIGRATION_GRANT_TTL_NS, _assert_import_target_empty, _assert_migration_caller, _authorize_migration, _complete_migration, _export_page, _import_page, _migration_manifest, _verify_import},
+        organization_api::{MAX_TAG_ID_BYTES, _create_folder, _delete_folder, _delete_tag, _get_organization, _list_folder_items, _list_tagged_items, _set_item_labels, _set_tag, _update_folder},
+        registry_api::{MAX_VAULT_METADATA_BYTES, SizeChange, VaultMetadataUpdate, _assert_vault_registered, _backfill_registry, _get_vault_info, _list_vaults, _record_vault_change, _register_vaults, _registered_vault_names, _update_vault_metadata},
+        search_api::{MAX_TOKEN_BYTES, _search_items, _search_tokens_sync},
+        serial_api::{_custom_records_deletes, _custom_records_sync, _global_sync, _login_data_sync, _login_metadata_sync, _records_deletes, _records_sync, _secret_notes_sync, _sheet_columns_sync, _sheet_sync, _totp_deletes, _totp_sync, _vault_names_sync, _vault_spreadsheet_columns_sync, _vault_spreadsheet_sync},
+        sheets_api::{_create_sheet, _delete_sheet, _list_sheets, _rename_sheet, _reorder_sheets, _sheet_exists},
+        snapshot_api::{SNAPSHOT_VERSION, _assert_not_frozen, _begin_restore, _begin_snapshot, _end_snapshot, _export_snapshot_chunk, _finish_restore, _restore_snapshot_chunk},
+        templates_api::{_delete_record_template, _set_record_template},
+        trash_api::{_empty_trash, _list_trash, _move_to_trash, _restore_items},
+    },
+    stable::{
+        types::GeneralState,
+        util::{_apply_init_args, _restore_owners, _retry_backoff_ns},
+    },
+    vault_type::{
+        attachments::{ItemKind, ItemRef},
+        audit_log::{AuditEvent, AuditOp, AuditRetention},
+        canister_config::{CanisterConfig, InitArgs},
+        capacity::{CapacityRecord, CapacityState},
+        cycles::{CyclesSettings, TopUpRecord, TopUpStatus},
+        history::{HistoryItem, HistoryKind},
+        limits::{Tier, TierLimits},
+        migration::MigrationManifest,
+        pending_deletion::{DeletionKind, DeletionStatus},
+        records::{CardBrand, Expiry, IdentityDocument, RecordKind},
+        spreadsheet::{ColumnData, ColumnKind, ColumnSettings, SortOrder, SpreadsheetKey},
+        templates::{FieldType, RecordTemplate, TemplateField},
+        totp::TotpAlgorithm,
+        trash::TrashKind,
+        vault_registry::VaultStatus,
+    },
+};
 
 fn some_user_id() -> Principal {
     Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap()
@@ -68,6 +112,58 @@ fn some_notes_data() -> Vec<u8> {
     ]
 }
 
+// Seed 0 is SHA-1, 6 digits every 30s and linked to login column 0; seed 1 is SHA-256, 8 digits
+// every 60s and unlinked.
+fn some_totp_data() -> Vec<u8> {
+    let mut data = vec![0x06, 0x00, 0x0a, 0x00, 0x00, 0x06, 0x00, 0x1e, 0x01, 0x00];
+    data.extend(b"github");
+    data.extend([0xaa; 10]);
+    data.extend([0x03, 0x00, 0x04, 0x01, 0x01, 0x08, 0x00, 0x3c, 0x00, 0x00]);
+    data.extend(b"aws");
+    data.extend([0xbb; 4]);
+    data
+}
+
+// A Visa card expiring 10/2026 with a number and holder field.
+fn some_card_data() -> Vec<u8> {
+    let mut data = vec![0x00, 0x0d, 0x00, 0x00, 0x01, 0x07, 0xea, 0x0a];
+    data.extend([0x00, 0x00, 0x04]);
+    data.extend(b"1234");
+    data.extend([0x01, 0x00, 0x03]);
+    data.extend(b"Bob");
+    data
+}
+
+// A Wi-Fi network: SSID and password, then an optional renewal date and TOTP seed.
+fn some_template() -> RecordTemplate {
+    let field = |name: &str, field_type, required| TemplateField { name: name.as_bytes().to_vec(), field_type, required };
+    RecordTemplate {
+        name: b"wifi".to_vec(),
+        fields: vec![
+            field("ssid", FieldType::Text, true),
+            field("password", FieldType::Secret, true),
+            field("renewed", FieldType::Date, false),
+            field("otp", FieldType::Totp, false),
+        ],
+    }
+}
+
+fn some_custom_record(x: u8, template: u8, values: &[&[u8]]) -> Vec<u8> {
+    let body: Vec<u8> = values.iter().flat_map(|value| [(value.len() as u16).to_be_bytes().to_vec(), value.to_vec()].concat()).collect();
+    let mut data = (body.len() as u16).to_be_bytes().to_vec();
+    data.extend([x, template]);
+    data.extend(body);
+    data
+}
+
+// A passport expiring 01/2030 with a document number field.
+fn some_identity_data() -> Vec<u8> {
+    let mut data = vec![0x00, 0x05, 0x00, 0x00, 0x01, 0x07, 0xee, 0x01];
+    data.extend([0x00, 0x00, 0x02]);
+    data.extend(b"AB");
+    data
+}
+
 fn some_vault_names() -> Vec<u8> {
     let vault_1_principal: Vec<u8> = some_vault_id().to_bytes().into();
     let vault_2_principal: Vec<u8> = some_user_id().to_bytes().into();
@@ -323,9 +419,1205 @@ pub fn test_global_sync() {
 
     let state = GeneralState::init();
 
-    _global_sync(user_id, vault_id, sync_data, &state);
+    _global_sync(user_id, vault_id, sync_data, &state).unwrap();
 
     let get_all = _get_vault(&Vec::new(), user_id, vault_id, &state);
 
     assert!(get_all.logins.columns.len() > 0);
-}
\ No newline at end of file
+}
+
+fn some_machine_id() -> Principal {
+    Principal::from_text("r7inp-6aaaa-aaaaa-aaabq-cai").unwrap()
+}
+
+#[test]
+pub fn test_machine_grants() {
+    let state = GeneralState::init();
+    let user_id = some_user_id();
+    let vault_id = some_vault_id();
+    let machine = some_machine_id();
+
+    // Logins in columns 0 and 1, notes 0 and 1.
+    _vault_names_sync(user_id, &some_vault_names(), &state.vault_names_map);
+    _login_metadata_sync(user_id, vault_id, vec![0x00, 0x01, 0x00, b'a', 0x00, 0x01, 0x01, b'b'], &state.logins_columns, &state.logins_map);
+    _login_data_sync(user_id, vault_id, vec![0x00, 0x01, 0x00, 0x00, b'x', 0x00, 0x01, 0x01, 0x00, b'y'], &state.logins_map);
+    _secret_notes_sync(user_id, vault_id, some_notes_data(), &state.notes_map);
+
+    // No access before a grant exists.
+    assert!(_get_machine_vault(machine, user_id, vault_id, 10, &state).is_err());
+
+    // Grants on unknown vaults or to the owner are refused.
+    let args = MachineGrantArgs { machine, scope: GrantScope::Vault, expires_at: None };
+    assert!(_grant_machine_access(user_id, some_other_principal(), args.clone(), 10, &state).is_err());
+    let owner_args = MachineGrantArgs { machine: user_id, scope: GrantScope::Vault, expires_at: None };
+    assert!(_grant_machine_access(user_id, vault_id, owner_args, 10, &state).is_err());
+
+    // Item-scoped grant only exposes the listed login column and note.
+    let args = MachineGrantArgs {
+        machine,
+        scope: GrantScope::Items { login_columns: vec![1, 1], notes: vec![0] },
+        expires_at: Some(100),
+    };
+    _grant_machine_access(user_id, vault_id, args, 10, &state).unwrap();
+    let grants = _get_machine_grants(user_id, vault_id, &state);
+    assert_eq!(grants.len(), 1);
+    assert_eq!(grants[0].scope, GrantScope::Items { login_columns: vec![1], notes: vec![0] });
+
+    let data = _get_machine_vault(machine, user_id, vault_id, 50, &state).unwrap();
+    assert_eq!(data.logins.columns.len(), 1);
+    assert_eq!(data.logins.columns.get(&1).unwrap().rows.get(&0).unwrap(), &b"y".to_vec());
+    assert_eq!(data.notes.notes.len(), 1);
+    assert!(data.notes.notes.contains_key(&0));
+    assert!(data.spreadsheet.columns.is_empty());
+
+    let vaults = _get_vaults_for_machine(machine, 50, &state.machine_grants);
+    assert_eq!(vaults.len(), 1);
+    assert_eq!(vaults[0].owner, user_id);
+    assert_eq!(vaults[0].vault_id, vault_id);
+
+    // Expired grants give nothing.
+    assert!(_get_machine_vault(machine, user_id, vault_id, 100, &state).is_err());
+    assert!(_get_vaults_for_machine(machine, 100, &state.machine_grants).is_empty());
+
+    _revoke_machine_access(user_id, vault_id, machine, &state);
+    assert!(_get_machine_vault(machine, user_id, vault_id, 50, &state).is_err());
+}
+
+#[test]
+pub fn test_audit_log() {
+    let state = GeneralState::init();
+    let user_id = some_user_id();
+    let other_user = some_other_principal();
+    let vault_id = some_vault_id();
+
+    for i in 0..5 {
+        let event = AuditEvent::new(user_id, user_id, Some(vault_id), AuditOp::SpreadsheetSync, i, 100 + i as u64);
+        _record_audit_event(event, &state);
+    }
+    let event = AuditEvent::new(other_user, other_user, None, AuditOp::PurgeUser, 7, 200);
+    _record_audit_event(event, &state);
+
+    // Users only see their own events, newest first, in pages.
+    let page = _get_user_audit_log(user_id, None, 3, &state);
+    assert_eq!(page.entries.len(), 3);
+    assert_eq!(page.entries[0].item_count, 4);
+    assert_eq!(page.entries[0].vault_id, Some(vault_id));
+    assert_eq!(page.entries[0].op, AuditOp::SpreadsheetSync);
+    let page = _get_user_audit_log(user_id, page.next, 3, &state);
+    assert_eq!(page.entries.len(), 2);
+    assert_eq!(page.entries[1].item_count, 0);
+    assert!(page.next.is_none());
+
+    let page = _get_audit_log(None, 10, &state.audit_log);
+    assert_eq!(page.entries.len(), 6);
+    assert_eq!(page.entries[0].user, other_user);
+    assert_eq!(page.entries[0].vault_id, None);
+
+    // Tightening retention prunes the oldest events.
+    _set_audit_retention(AuditRetention { max_entries: 4, max_age_ns: 0 }, 300, &state);
+    assert_eq!(state.audit_log.borrow().len(), 4);
+    _set_audit_retention(AuditRetention { max_entries: 0, max_age_ns: 150 }, 300, &state);
+    let page = _get_audit_log(None, 10, &state.audit_log);
+    assert_eq!(page.entries.len(), 1);
+    assert_eq!(page.entries[0].op, AuditOp::PurgeUser);
+    // Pruned events leave the user index with them.
+    assert!(_get_user_audit_log(user_id, None, 10, &state).entries.is_empty());
+    assert_eq!(_get_user_audit_log(other_user, None, 10, &state).entries.len(), 1);
+    assert_eq!(state.user_audit_index.borrow().len(), 1);
+}
+
+#[test]
+pub fn test_delayed_deletion() {
+    let state = GeneralState::init();
+    let user_id = some_user_id();
+    let vault_id = some_vault_id();
+    let grace = *state.deletion_grace_period.borrow().get();
+
+    _register_vaults(user_id, &some_vault_names(), 0, &state.vault_registry);
+    _vault_names_sync(user_id, &some_vault_names(), &state.vault_names_map);
+    vault_core::api::serial_api::_vault_spreadsheet_sync(user_id, vault_id, some_spreadsheet_data(), &state.spreadsheet_map);
+    _secret_notes_sync(user_id, vault_id, some_notes_data(), &state.notes_map);
+
+    // Scheduling makes the vault read-only but keeps its data.
+    let pending = _schedule_vault_deletion(user_id, vault_id, 1_000, &state).unwrap();
+    assert_eq!(pending.execute_at, 1_000 + grace);
+    assert_eq!(pending.status, DeletionStatus::Scheduled);
+    assert!(_schedule_vault_deletion(user_id, vault_id, 1_000, &state).is_err());
+    assert!(_assert_vault_writable(user_id, vault_id, &state.pending_deletions).is_err());
+    assert!(_assert_vault_writable(user_id, some_other_principal(), &state.pending_deletions).is_ok());
+    assert!(_assert_names_writable(user_id, &some_vault_names(), &state.pending_deletions).is_err());
+    assert_eq!(_next_deletion_due(&state.pending_deletions), Some(1_000 + grace));
+    assert!(_run_due_deletions(1_000 + grace - 1, &state, &|| false).is_empty());
+    assert!(!state.notes_map.borrow().is_empty());
+
+    // Cancelling within the window restores write access.
+    _cancel_deletion(user_id, Some(vault_id), &state).unwrap();
+    assert!(_assert_vault_writable(user_id, vault_id, &state.pending_deletions).is_ok());
+    assert!(_cancel_deletion(user_id, Some(vault_id), &state).is_err());
+
+    // A purge runs in batches once due, yielding after every batch here.
+    _schedule_user_purge(user_id, 2_000, &state).unwrap();
+    assert_eq!(_get_pending_deletions(user_id, &state.pending_deletions, &state.deletion_cursors).len(), 1);
+    let mut completed = Vec::new();
+    let mut ticks = 0;
+    let mut removed = 0;
+    while completed.is_empty() {
+        completed = _run_due_deletions(2_000 + grace, &state, &|| true);
+        ticks += 1;
+        assert!(ticks < 2_000);
+        if completed.is_empty() {
+            // Progress is reported and only ever moves forward.
+            let pending = _get_pending_deletions(user_id, &state.pending_deletions, &state.deletion_cursors);
+            assert_eq!(pending[0].status, DeletionStatus::Running);
+            assert!(pending[0].stage.is_some());
+            assert!(pending[0].removed >= removed);
+            removed = pending[0].removed;
+            assert!(_cancel_deletion(user_id, None, &state).is_err());
+        }
+    }
+    assert!(state.deletion_cursors.borrow().is_empty());
+    assert_eq!(completed[0].kind, DeletionKind::User);
+    // 2 vault names with their registry entries, 3 non-empty cells and 2 notes.
+    assert_eq!(completed[0].removed, 2 * 2 + 3 + 2);
+    assert!(state.vault_registry.borrow().is_empty());
+    assert!(state.notes_map.borrow().is_empty());
+    assert!(state.spreadsheet_map.borrow().is_empty());
+    assert!(_get_vault_names(user_id, &state.vault_names_map).names.is_empty());
+    assert!(_get_pending_deletions(user_id, &state.pending_deletions, &state.deletion_cursors).is_empty());
+
+    // The remains of a failed import go on the next tick and can't be cancelled.
+    _register_vaults(user_id, &some_vault_names(), 0, &state.vault_registry);
+    _vault_names_sync(user_id, &some_vault_names(), &state.vault_names_map);
+    let pending = _schedule_immediate_purge(user_id, 3_000, &state).unwrap();
+    assert_eq!((pending.status, pending.execute_at), (DeletionStatus::Running, 3_000));
+    assert!(_cancel_deletion(user_id, None, &state).is_err());
+    assert_eq!(_run_due_deletions(3_000, &state, &|| false).len(), 1);
+    assert!(state.vault_names_map.borrow().is_empty());
+}
+
+#[test]
+pub fn test_trash() {
+    let state = GeneralState::init();
+    let user_id = some_user_id();
+    let vault_id = some_vault_id();
+    let retention = *state.trash_retention.borrow().get();
+
+    vault_core::api::serial_api::_vault_spreadsheet_sync(user_id, vault_id, some_spreadsheet_data(), &state.spreadsheet_map);
+    _secret_notes_sync(user_id, vault_id, some_notes_data(), &state.notes_map);
+    _login_metadata_sync(user_id, vault_id, some_login_metadata(), &state.logins_columns, &state.logins_map);
+    _login_data_sync(user_id, vault_id, some_login_data(), &state.logins_map);
+
+    // Delete cell (0, 2), login column 0 with its two identities, and note 0.
+    let outcome = vault_core::api::serial_api::_vault_spreadsheet_delete(user_id, vault_id, vec![0, 2], &state.spreadsheet_map);
+    assert_eq!(outcome.items, 1);
+    _move_to_trash(user_id, vault_id, outcome.removed, 1_000, &state);
+    let outcome = vault_core::api::serial_api::_login_metadata_delete(user_id, vault_id, vec![0, 0, 0], &state.logins_columns, &state.logins_map);
+    _move_to_trash(user_id, vault_id, outcome.removed, 1_000, &state);
+    let outcome = _secret_notes_sync(user_id, vault_id, vec![0, 0, 0, 0], &state.notes_map);
+    _move_to_trash(user_id, vault_id, outcome.removed, 1_000, &state);
+
+    let trash = _list_trash(user_id, vault_id, 1_000, &state);
+    assert_eq!(trash.iter().map(|item| item.id).collect::<Vec<u64>>(), vec![0, 1, 2]);
+    assert_eq!(trash[0].kind, TrashKind::SpreadsheetCell);
+    assert_eq!((trash[0].x, trash[0].y), (0, 2));
+    assert_eq!(trash[1].kind, TrashKind::LoginColumn);
+    assert_eq!(trash[1].rows.len(), 2);
+    assert_eq!(trash[2].kind, TrashKind::Note);
+    assert_eq!(trash[2].label, b"label".to_vec());
+    assert!(_list_trash(user_id, some_other_principal(), 1_000, &state).is_empty());
+
+    // A slot taken again is a conflict, everything else goes back where it was.
+    vault_core::api::serial_api::_vault_spreadsheet_sync(user_id, vault_id, some_spreadsheet_data(), &state.spreadsheet_map);
+    let result = _restore_items(user_id, vault_id, vec![0, 1, 2, 7], 2_000, &state);
+    assert_eq!(result.restored, vec![1, 2]);
+    assert_eq!(result.conflicts, vec![0]);
+    assert_eq!(state.logins_map.borrow().len(), 5);
+    assert_eq!(_get_notes(user_id, vault_id, &state.notes_map).notes.len(), 2);
+    assert_eq!(_list_trash(user_id, vault_id, 2_000, &state).len(), 1);
+
+    // Expired items are hidden, then dropped by the next move to trash.
+    assert!(_list_trash(user_id, vault_id, 1_000 + retention + 1, &state).is_empty());
+    _move_to_trash(user_id, vault_id, Vec::new(), 1_000 + retention + 1, &state);
+    assert!(state.trash.borrow().is_empty());
+
+    let outcome = vault_core::api::serial_api::_vault_spreadsheet_delete(user_id, vault_id, vec![0, 2, 11, 5], &state.spreadsheet_map);
+    _move_to_trash(user_id, vault_id, outcome.removed, 3_000, &state);
+    assert_eq!(_empty_trash(user_id, vault_id, Some(vec![0]), &state.trash), 1);
+    assert_eq!(_empty_trash(user_id, vault_id, None, &state.trash), 1);
+    assert!(_list_trash(user_id, vault_id, 3_000, &state).is_empty());
+}
+
+#[test]
+pub fn test_revision_history() {
+    let state = GeneralState::init();
+    let user_id = some_user_id();
+    let vault_id = some_vault_id();
+    let password_cell = HistoryItem { kind: HistoryKind::LoginCell, x: 0, y: 1 };
+    _set_history_depth(2, &state).unwrap();
+    _login_metadata_sync(user_id, vault_id, some_login_metadata(), &state.logins_columns, &state.logins_map);
+
+    // Overwrite login cell (0, 1) three times. Writing the same value again is not a revision.
+    for (time, password) in [(1_000, b"one"), (2_000, b"two"), (3_000, b"two"), (4_000, b"new")] {
+        let outcome = _login_data_sync(user_id, vault_id, [vec![0x00, 0x03, 0x00, 0x01], password.to_vec()].concat(), &state.logins_map);
+        _record_revisions(user_id, vault_id, outcome.replaced, time, &state);
+    }
+    let revisions = _list_revisions(user_id, vault_id, password_cell, &state.history);
+    assert_eq!(revisions.iter().map(|info| info.replaced_at).collect::<Vec<u64>>(), vec![4_000, 2_000]);
+    let oldest = _get_revision(user_id, vault_id, password_cell, revisions[1].revision, &state.history).unwrap();
+    assert_eq!(oldest.data, b"one".to_vec());
+    assert!(_list_revisions(user_id, some_other_principal(), password_cell, &state.history).is_empty());
+
+    // Rolling back keeps the current value as the newest revision.
+    _rollback_item(user_id, vault_id, password_cell, revisions[1].revision, 5_000, &state).unwrap();
+    let current = state.logins_map.borrow().get(&SpreadsheetKey::new(user_id, vault_id, 0, 1)).unwrap();
+    assert_eq!(current.data, b"one".to_vec());
+    let revisions = _list_revisions(user_id, vault_id, password_cell, &state.history);
+    assert_eq!(revisions.len(), 2);
+    assert_eq!(_get_revision(user_id, vault_id, password_cell, revisions[0].revision, &state.history).unwrap().data, b"new".to_vec());
+    assert!(_rollback_item(user_id, vault_id, password_cell, 99, 5_000, &state).is_err());
+
+    // Notes keep their label with each revision.
+    _secret_notes_sync(user_id, vault_id, some_notes_data(), &state.notes_map);
+    let outcome = _secret_notes_sync(user_id, vault_id, vec![0x01, 0x00, 0x01, 0x00, b'l', b'n'], &state.notes_map);
+    _record_revisions(user_id, vault_id, outcome.replaced, 6_000, &state);
+    let note = _get_revision(user_id, vault_id, HistoryItem { kind: HistoryKind::Note, x: 0, y: 0 }, 0, &state.history).unwrap();
+    assert_eq!(note.label, b"label".to_vec());
+    assert_eq!(note.data, b"some note data".to_vec());
+}
+
+#[test]
+pub fn test_delegates() {
+    let state = GeneralState::init();
+    let owner = some_user_id();
+    let delegate = some_machine_id();
+    assert!(_resolve_owner(owner, &state.canister_owners, &state.delegates).is_err());
+
+    state.canister_owners.borrow_mut().user.push(owner);
+    assert_eq!(_resolve_owner(owner, &state.canister_owners, &state.delegates), Ok(owner));
+    assert!(_resolve_owner(delegate, &state.canister_owners, &state.delegates).is_err());
+
+    // Delegates act on the owner's data but are not the owner.
+    _add_delegate(owner, delegate, 1_000, &state.delegates).unwrap();
+    assert_eq!(_resolve_owner(delegate, &state.canister_owners, &state.delegates), Ok(owner));
+    assert!(_assert_owner(delegate, &state.canister_owners).is_err());
+    assert_eq!(_get_delegates(&state.delegates)[0].added_at, 1_000);
+    assert!(_add_delegate(owner, owner, 1_000, &state.delegates).is_err());
+    assert!(_add_delegate(owner, Principal::anonymous(), 1_000, &state.delegates).is_err());
+
+    for i in 1..MAX_DELEGATES {
+        _add_delegate(owner, Principal::from_slice(&[0xAA, i as u8]), 1_000, &state.delegates).unwrap();
+    }
+    assert!(_add_delegate(owner, some_vault_id(), 1_000, &state.delegates).is_err());
+
+    _remove_delegate(delegate, &state.delegates).unwrap();
+    assert!(_remove_delegate(delegate, &state.delegates).is_err());
+    assert!(_resolve_owner(delegate, &state.canister_owners, &state.delegates).is_err());
+}
+
+#[test]
+pub fn test_init_args() {
+    let state = GeneralState::init();
+    let owner = some_user_id();
+    let factory = some_other_principal();
+    let args = InitArgs { owner, factory, vetkd_key_name: None, max_users: None };
+    assert!(_apply_init_args(InitArgs { owner: Principal::anonymous(), ..args.clone() }, 10, &state).is_err());
+    assert!(!state.config.borrow().get().is_initialised());
+
+    _apply_init_args(args.clone(), 10, &state).unwrap();
+    let config = state.config.borrow().get().clone();
+    assert_eq!(config.vetkd_key_name, "key_1");
+    assert_eq!(config.max_users, 10);
+    assert_eq!(CanisterConfig::from_bytes(config.to_bytes()), config);
+
+    // Users holding a vetKey come back after an upgrade, behind the owner.
+    let user = some_machine_id();
+    state.key_management.borrow_mut().insert(user.to_text(), vec![1]);
+    state.key_management.borrow_mut().insert(owner.to_text(), vec![2]);
+    _restore_owners(&state);
+    assert_eq!(state.canister_owners.borrow().user, vec![owner, user]);
+    assert_eq!(state.canister_owners.borrow().controller, factory);
+
+    _apply_init_args(InitArgs { vetkd_key_name: Some("test_key_1".into()), max_users: Some(2), ..args }, 10, &state).unwrap();
+    assert_eq!(state.config.borrow().get().vetkd_key_name, "test_key_1");
+    assert_eq!(state.config.borrow().get().max_users, 2);
+}
+
+#[test]
+pub fn test_cycles_settings() {
+    let state = GeneralState::init();
+    let settings = *state.cycles_settings.borrow().get();
+    assert_eq!(settings, CyclesSettings::default());
+    assert!(_needs_top_up(settings.min_balance - 1, &settings));
+    assert!(!_needs_top_up(settings.min_balance, &settings));
+
+    assert!(_set_cycles_settings(CyclesSettings { check_interval_ns: 1, ..settings }, &state).is_err());
+    assert!(_set_cycles_settings(CyclesSettings { top_up_amount: 0, ..settings }, &state).is_err());
+    let updated = CyclesSettings { min_balance: 5, top_up_amount: 7, check_interval_ns: 120_000_000_000 };
+    _set_cycles_settings(updated, &state).unwrap();
+    assert_eq!(CyclesSettings::from_bytes(updated.to_bytes()), updated);
+
+    // Retries back off exponentially.
+    assert_eq!(_retry_backoff_ns(2), 2 * _retry_backoff_ns(1));
+    assert_eq!(_retry_backoff_ns(3), 4 * _retry_backoff_ns(1));
+
+    assert!(_get_cycles_status(3, false, &state).last_top_up.is_none());
+    let record = TopUpRecord { status: TopUpStatus::Failed, finished_at: 1_000, balance: 3, amount: 7, attempts: 4, error: "rejected".into() };
+    _record_top_up(record.clone(), &state);
+    let status = _get_cycles_status(3, false, &state);
+    assert_eq!(status.settings, updated);
+    assert_eq!(status.last_top_up, Some(record));
+}
+
+#[test]
+pub fn test_capacity() {
+    assert_eq!(_capacity_state(0, 100), CapacityState::Open);
+    assert_eq!(_capacity_state(90, 100), CapacityState::NearFull);
+    assert_eq!(_capacity_state(1, 2), CapacityState::NearFull);
+    assert_eq!(_capacity_state(100, 100), CapacityState::Closed);
+
+    let state = GeneralState::init();
+    let args = InitArgs { owner: some_user_id(), factory: some_other_principal(), vetkd_key_name: None, max_users: Some(3) };
+    _apply_init_args(args, 10, &state).unwrap();
+    _restore_owners(&state);
+    assert!(!_needs_capacity_notification(&state));
+
+    // The user that makes the canister near full is still registered.
+    _admit_user(some_machine_id(), &state).unwrap();
+    assert_eq!(_get_capacity(&state).state, CapacityState::NearFull);
+    assert_eq!(state.canister_owners.borrow().user.len(), 2);
+    assert!(_needs_capacity_notification(&state));
+    _record_capacity_notification(CapacityState::NearFull, None, 1_000, &state);
+    assert!(!_needs_capacity_notification(&state));
+
+    _admit_user(some_vault_id(), &state).unwrap();
+    assert_eq!(_get_capacity(&state).state, CapacityState::Closed);
+    assert_eq!(_admit_user(some_vault_id(), &state), Ok(()));
+    assert_eq!(_admit_user(Principal::from_slice(&[0xAA, 1]), &state), Err("Canister at max user capacity".into()));
+
+    // New users are pointed at the canister the factory answered with.
+    let next = Principal::from_slice(&[0xAA, 2]);
+    _record_capacity_notification(CapacityState::Closed, Some(next), 2_000, &state);
+    assert_eq!(_get_capacity(&state).redirect, Some(next));
+    assert!(_admit_user(Principal::from_slice(&[0xAA, 1]), &state).unwrap_err().contains(&next.to_text()));
+    _set_capacity_redirect(None, &state);
+    let record = state.capacity.borrow().get().clone();
+    assert_eq!(record, CapacityRecord { notified: CapacityState::Closed, notified_at: 2_000, redirect: None });
+    assert_eq!(CapacityRecord::from_bytes(record.to_bytes()), record);
+}
+
+#[test]
+pub fn test_migration() {
+    let source = GeneralState::init();
+    let target = GeneralState::init();
+    let user_id = some_user_id();
+    let vault_id = some_vault_id();
+    let target_canister = some_other_principal();
+
+    _vault_names_sync(user_id, &some_vault_names(), &source.vault_names_map);
+    _register_vaults(user_id, &some_vault_names(), 0, &source.vault_registry);
+    vault_core::api::serial_api::_vault_spreadsheet_sync(user_id, vault_id, some_spreadsheet_data(), &source.spreadsheet_map);
+    vault_core::api::serial_api::_vault_spreadsheet_columns_sync(user_id, vault_id, some_columns_data(), &source.spreadsheet_columns);
+    _login_metadata_sync(user_id, vault_id, some_login_metadata(), &source.logins_columns, &source.logins_map);
+    _login_data_sync(user_id, vault_id, some_login_data(), &source.logins_map);
+    _secret_notes_sync(user_id, vault_id, some_notes_data(), &source.notes_map);
+    let args = MachineGrantArgs { machine: some_machine_id(), scope: GrantScope::Vault, expires_at: None };
+    _grant_machine_access(user_id, vault_id, args, 1_000, &source).unwrap();
+    source.key_management.borrow_mut().insert(user_id.to_text(), vec![7; 32]);
+
+    // Only the authorised target, or a controller, may export, and only while the grant lasts.
+    assert!(_assert_migration_caller(user_id, target_canister, false, 1_000, &source.migration_grants).is_err());
+    assert!(_authorize_migration(user_id, user_id, 1_000, &source.migration_grants).is_err());
+    _authorize_migration(user_id, target_canister, 1_000, &source.migration_grants).unwrap();
+    assert!(_assert_migration_caller(user_id, target_canister, false, 1_000, &source.migration_grants).is_ok());
+    assert!(_assert_migration_caller(user_id, some_machine_id(), false, 1_000, &source.migration_grants).is_err());
+    assert!(_assert_migration_caller(user_id, some_machine_id(), true, 1_000, &source.migration_grants).is_ok());
+    assert!(_assert_migration_caller(user_id, target_canister, false, 1_000 + MIGRATION_GRANT_TTL_NS, &source.migration_grants).is_err());
+
+    // The manifest is only there once the export has finished.
+    assert!(_migration_manifest(user_id, &source).is_err());
+    _assert_import_target_empty(user_id, &target).unwrap();
+    let mut imported = MigrationManifest::default();
+    let mut cursor = None;
+    loop {
+        let page = _export_page(user_id, cursor, 1_000, &source).unwrap();
+        _import_page(user_id, page.chunks, &mut imported, 0, &target).unwrap();
+        match page.next {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+    let manifest = _migration_manifest(user_id, &source).unwrap();
+    assert_eq!(manifest.vaults, 2);
+    // Names, key, columns, cells, login columns, login cells, notes and grants.
+    assert_eq!(manifest.items, 2 + 1 + 2 + 3 + 4 + 5 + 2 + 1);
+    _verify_import(&manifest, &imported).unwrap();
+    assert!(_assert_import_target_empty(user_id, &target).is_err());
+    assert_eq!(target.key_management.borrow().get(&user_id.to_text()), Some(vec![7; 32]));
+    assert_eq!(_get_machine_grants(user_id, vault_id, &target).len(), 1);
+
+    // A missing chunk is caught on the target, a change to the vaults since the export started
+    // on the source.
+    assert!(_verify_import(&manifest, &MigrationManifest { chunks: manifest.chunks - 1, ..manifest.clone() }).is_err());
+    assert!(_export_page(user_id, Some(manifest.chunks), 1_500, &source).is_err());
+    _record_vault_change(user_id, vault_id, SizeChange::default(), 1_500, &source.vault_registry);
+    assert!(_complete_migration(user_id, &manifest.checksum, 2_000, &source).is_err());
+
+    let mut cursor = None;
+    while let Some(next) = _export_page(user_id, cursor, 2_000, &source).unwrap().next {
+        cursor = Some(next);
+    }
+    let manifest = _migration_manifest(user_id, &source).unwrap();
+    let pending = _complete_migration(user_id, &manifest.checksum, 2_000, &source).unwrap();
+    assert_eq!(pending.kind, DeletionKind::User);
+    assert!(source.migration_grants.borrow().is_empty());
+}
+
+#[test]
+pub fn test_snapshot() {
+    let source = GeneralState::init();
+    let target = GeneralState::init();
+    let user_id = some_user_id();
+    let vault_id = some_vault_id();
+
+    _vault_names_sync(user_id, &some_vault_names(), &source.vault_names_map);
+    _login_metadata_sync(user_id, vault_id, some_login_metadata(), &source.logins_columns, &source.logins_map);
+    _login_data_sync(user_id, vault_id, some_login_data(), &source.logins_map);
+    _secret_notes_sync(user_id, vault_id, some_notes_data(), &source.notes_map);
+    source.key_management.borrow_mut().insert(user_id.to_text(), vec![7; 32]);
+    source.history_depth.borrow_mut().set(3);
+
+    // Chunks can only be pulled, and writes are refused, between begin and end.
+    assert!(_export_snapshot_chunk(None, &source).is_err());
+    let manifest = _begin_snapshot(1_000, &source).unwrap();
+    assert_eq!(manifest.version, SNAPSHOT_VERSION);
+    assert_eq!(manifest.sections.len(), source.snapshot_sections().len());
+    assert!(_assert_not_frozen(&source).is_err());
+    assert!(_begin_snapshot(1_000, &source).is_err());
+
+    let mut chunks = Vec::new();
+    let mut cursor = None;
+    loop {
+        let chunk = _export_snapshot_chunk(cursor, &source).unwrap();
+        cursor = chunk.next.clone();
+        chunks.push(chunk);
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert_eq!(chunks.len() as u64, manifest.chunks);
+    _end_snapshot(&source).unwrap();
+    assert!(_assert_not_frozen(&source).is_ok());
+
+    // Only an empty canister takes a restore, and only of the same version.
+    let mut old = manifest.clone();
+    old.version = SNAPSHOT_VERSION + 1;
+    assert!(_begin_restore(&old, 2_000, &target).is_err());
+    assert!(_begin_restore(&manifest, 2_000, &source).is_err());
+    assert!(_restore_snapshot_chunk(chunks[0].clone(), &target).is_err());
+    _begin_restore(&manifest, 2_000, &target).unwrap();
+
+    let mut tampered = chunks[0].clone();
+    tampered.entries.push((vec![1], vec![2]));
+    assert!(_restore_snapshot_chunk(tampered, &target).is_err());
+
+    // A missing chunk keeps the canister locked until it's sent.
+    let (keys, rest) = chunks.split_first().unwrap();
+    for chunk in rest {
+        _restore_snapshot_chunk(chunk.clone(), &target).unwrap();
+    }
+    assert!(_finish_restore(&manifest, &target).is_err());
+    _restore_snapshot_chunk(keys.clone(), &target).unwrap();
+    _finish_restore(&manifest, &target).unwrap();
+    assert!(_assert_not_frozen(&target).is_ok());
+
+    assert_eq!(target.vault_names_map.borrow().len(), 2);
+    assert_eq!(target.logins_map.borrow().len(), source.logins_map.borrow().len());
+    assert_eq!(target.notes_map.borrow().len(), source.notes_map.borrow().len());
+    assert_eq!(target.key_management.borrow().get(&user_id.to_text()), Some(vec![7; 32]));
+    assert_eq!(*target.history_depth.borrow().get(), 3);
+}
+
+#[test]
+pub fn test_metrics() {
+    let state = GeneralState::init();
+    let user_id = some_user_id();
+    let vault_id = some_vault_id();
+    _apply_init_args(InitArgs { owner: user_id, factory: some_other_principal(), vetkd_key_name: None, max_users: Some(10) }, 1, &state).unwrap();
+    _restore_owners(&state);
+    _secret_notes_sync(user_id, vault_id, some_notes_data(), &state.notes_map);
+
+    let mut counters = CallCounters { since: 500, ..Default::default() };
+    _record_call("vault_secrets_sync", 1_000, false, &mut counters);
+    _record_call("vault_secrets_sync", 3_000, false, &mut counters);
+    _record_call("rollback_item", 200, true, &mut counters);
+    counters.vetkd_derivations = 1;
+
+    let metrics = _collect_metrics(1_000, 42, 7, &counters, &state);
+    assert_eq!((metrics.users, metrics.max_users), (1, 10));
+    assert_eq!(metrics.counting_since, 500);
+    let notes = metrics.sections.iter().find(|s| s.name == "notes_map").unwrap();
+    assert_eq!((notes.memory_id, notes.entries), (6, 2));
+    assert!(notes.pages > 0);
+    let sync = metrics.endpoints.iter().find(|e| e.endpoint == "vault_secrets_sync").unwrap();
+    assert_eq!((sync.calls, sync.errors, sync.instructions_total, sync.instructions_max), (2, 0, 4_000, 3_000));
+    let rollback = metrics.endpoints.iter().find(|e| e.endpoint == "rollback_item").unwrap();
+    assert_eq!((rollback.calls, rollback.errors), (1, 1));
+
+    let text = _encode_prometheus(&metrics);
+    assert!(text.contains("vault_cycles_balance 42\n"));
+    assert!(text.contains("vault_entries{memory_id=\"6\",name=\"notes_map\"} 2\n"));
+    assert!(text.contains("vault_errors_total{endpoint=\"rollback_item\"} 1\n"));
+    assert!(text.contains("# TYPE vault_calls_total counter\n"));
+
+    let request = |url: &str| HttpRequest { method: "GET".into(), url: url.into(), headers: Vec::new(), body: Vec::new() };
+    let response = _http_response(&request("/metrics?format=text"), || metrics.clone());
+    assert_eq!(response.status_code, 200);
+    assert_eq!(response.body, text.into_bytes());
+    assert_eq!(_http_response(&request("/"), || metrics.clone()).status_code, 404);
+}
+
+#[test]
+pub fn test_runtime_config() {
+    let state = GeneralState::init();
+    let user_id = some_user_id();
+    _apply_init_args(InitArgs { owner: user_id, factory: some_other_principal(), vetkd_key_name: None, max_users: Some(100) }, 1, &state).unwrap();
+
+    let config = _get_config(Tier::Free, &state);
+    assert_eq!(config.max_users, 100);
+    assert_eq!(config.limits.vetkd_max_input_bytes, 1024);
+    assert_eq!(config.limits.free.max_vaults_per_user, 3);
+
+    // Invalid fields and users beyond the storage budget leave everything as it was.
+    assert!(_update_config(ConfigUpdate { max_users: Some(0), ..Default::default() }, Tier::Free, &state).is_err());
+    assert!(_update_config(ConfigUpdate { vetkd_max_input_bytes: Some(0), ..Default::default() }, Tier::Free, &state).is_err());
+    let no_vaults = TierLimits { max_vaults_per_user: 0, max_vault_size_bytes: 1 << 30 };
+    assert!(_update_config(ConfigUpdate { premium: Some(no_vaults), ..Default::default() }, Tier::Free, &state).is_err());
+    assert!(_update_config(ConfigUpdate { max_users: Some(200), min_cycles_balance: Some(1), ..Default::default() }, Tier::Free, &state).is_err());
+    assert_eq!(_get_config(Tier::Free, &state), config);
+
+    // Smaller vaults make room for more users.
+    let update = ConfigUpdate {
+        max_users: Some(200),
+        min_cycles_balance: Some(5_000),
+        free: Some(TierLimits { max_vaults_per_user: 2, max_vault_size_bytes: 512 << 20 }),
+        ..Default::default()
+    };
+    assert_eq!(_update_config(update, Tier::Free, &state).unwrap(), 3);
+    assert_eq!(state.config.borrow().get().max_users, 200);
+    assert_eq!(state.cycles_settings.borrow().get().min_balance, 5_000);
+    assert_eq!(_update_config(ConfigUpdate::default(), Tier::Free, &state).unwrap(), 0);
+
+    // Vaults beyond the limit are refused; renaming and removing are not.
+    _vault_names_sync(user_id, &some_vault_names(), &state.vault_names_map);
+    let new_vault = vec![1, 0, 1, 9, 0x61];
+    assert!(_assert_vault_limit(user_id, &new_vault, 2, &state.vault_names_map).is_err());
+    assert!(_assert_vault_limit(user_id, &new_vault, 3, &state.vault_names_map).is_ok());
+    assert!(_assert_vault_limit(user_id, &some_vault_names(), 1, &state.vault_names_map).is_ok());
+}
+
+#[test]
+pub fn test_vault_registry() {
+    let state = GeneralState::init();
+    let user_id = some_user_id();
+    let vault_id = some_vault_id();
+
+    // A vault exists once a names sync registers it.
+    assert!(_assert_vault_registered(user_id, vault_id, &state.vault_registry).is_err());
+    assert_eq!(_register_vaults(user_id, &some_vault_names(), 100, &state.vault_registry), 2);
+    assert_eq!(_register_vaults(user_id, &some_vault_names(), 200, &state.vault_registry), 0);
+    _vault_names_sync(user_id, &some_vault_names(), &state.vault_names_map);
+    assert!(_assert_vault_registered(user_id, vault_id, &state.vault_registry).is_ok());
+    let info = _get_vault_info(user_id, vault_id, &state.vault_registry).unwrap();
+    assert_eq!((info.owner, info.created_at, info.updated_at, info.status), (user_id, 100, 200, VaultStatus::Active));
+    assert_eq!(_list_vaults(user_id, &state.vault_registry).len(), 2);
+    assert!(_list_vaults(Principal::anonymous(), &state.vault_registry).is_empty());
+    assert_eq!(_registered_vault_names(user_id, &state.vault_names_map, &state.vault_registry).names.len(), 2);
+
+    // Counts follow what syncs write and remove.
+    let outcome = vault_core::api::serial_api::_vault_spreadsheet_sync(user_id, vault_id, some_spreadsheet_data(), &state.spreadsheet_map);
+    _record_vault_change(user_id, vault_id, outcome.size_change(), 300, &state.vault_registry);
+    let info = _get_vault_info(user_id, vault_id, &state.vault_registry).unwrap();
+    assert_eq!((info.item_count, info.size_bytes, info.updated_at), (3, 15 + 23 + 3, 300));
+    let outcome = vault_core::api::serial_api::_vault_spreadsheet_sync(user_id, vault_id, vec![0, 0, 0, 2], &state.spreadsheet_map);
+    _record_vault_change(user_id, vault_id, outcome.size_change(), 400, &state.vault_registry);
+    let info = _get_vault_info(user_id, vault_id, &state.vault_registry).unwrap();
+    assert_eq!((info.item_count, info.size_bytes), (2, 23 + 3));
+
+    // Metadata is bounded and key epochs only move forward.
+    let update = VaultMetadataUpdate { metadata: Some(vec![7; 16]), key_epoch: Some(2) };
+    let info = _update_vault_metadata(user_id, vault_id, update, 500, &state.vault_registry).unwrap();
+    assert_eq!((info.metadata, info.key_epoch), (vec![7; 16], 2));
+    let update = VaultMetadataUpdate { key_epoch: Some(1), ..Default::default() };
+    assert!(_update_vault_metadata(user_id, vault_id, update, 500, &state.vault_registry).is_err());
+    let update = VaultMetadataUpdate { metadata: Some(vec![0; MAX_VAULT_METADATA_BYTES + 1]), ..Default::default() };
+    assert!(_update_vault_metadata(user_id, vault_id, update, 500, &state.vault_registry).is_err());
+    assert!(_update_vault_metadata(user_id, some_other_principal(), VaultMetadataUpdate::default(), 500, &state.vault_registry).is_err());
+
+    // The status follows the pending deletions.
+    _schedule_vault_deletion(user_id, vault_id, 1_000, &state).unwrap();
+    assert_eq!(_get_vault_info(user_id, vault_id, &state.vault_registry).unwrap().status, VaultStatus::PendingDeletion);
+    assert_eq!(_get_vault_info(user_id, user_id, &state.vault_registry).unwrap().status, VaultStatus::Active);
+    _cancel_deletion(user_id, Some(vault_id), &state).unwrap();
+    assert_eq!(_get_vault_info(user_id, vault_id, &state.vault_registry).unwrap().status, VaultStatus::Active);
+    _schedule_user_purge(user_id, 2_000, &state).unwrap();
+    assert!(_list_vaults(user_id, &state.vault_registry).iter().all(|info| info.status == VaultStatus::PendingDeletion));
+
+    // Canisters from before the registry get their named vaults registered on upgrade.
+    let legacy = GeneralState::init();
+    legacy.canister_owners.borrow_mut().user.push(user_id);
+    _vault_names_sync(user_id, &some_vault_names(), &legacy.vault_names_map);
+    vault_core::api::serial_api::_vault_spreadsheet_sync(user_id, vault_id, some_spreadsheet_data(), &legacy.spreadsheet_map);
+    assert_eq!(_backfill_registry(5, &legacy), 2);
+    let info = _get_vault_info(user_id, vault_id, &legacy.vault_registry).unwrap();
+    assert_eq!((info.item_count, info.size_bytes, info.created_at), (3, 15 + 23 + 3, 5));
+    assert_eq!(_backfill_registry(6, &legacy), 0);
+}
+
+#[test]
+pub fn test_totp() {
+    let state = GeneralState::init();
+    let user_id = some_user_id();
+    let vault_id = some_vault_id();
+
+    // Parameters and the login link come back as sent, per vault.
+    let outcome = _totp_sync(user_id, vault_id, some_totp_data(), &state.totp_map);
+    assert_eq!(outcome.items, 2);
+    assert_eq!(outcome.size_change().items, 2);
+    assert_eq!(outcome.size_change().bytes, 6 + 10 + 3 + 4);
+    let seeds = _get_totp_seeds(user_id, vault_id, &state.totp_map).seeds;
+    assert_eq!(seeds.len(), 2);
+    assert_eq!((seeds[&0].params.algorithm, seeds[&0].params.digits, seeds[&0].params.period), (TotpAlgorithm::Sha1, 6, 30));
+    assert_eq!((seeds[&0].login_column, seeds[&0].label.clone(), seeds[&0].seed.clone()), (Some(0), b"github".to_vec(), vec![0xaa; 10]));
+    assert_eq!((seeds[&1].params.algorithm, seeds[&1].params.digits, seeds[&1].login_column), (TotpAlgorithm::Sha256, 8, None));
+    assert!(_get_totp_seeds(user_id, some_other_principal(), &state.totp_map).seeds.is_empty());
+
+    // Deletes go to the trash and can be restored.
+    let outcome = _totp_deletes(user_id, vault_id, vec![1, 9], &state.totp_map);
+    assert_eq!(outcome.size_change().items, -1);
+    _move_to_trash(user_id, vault_id, outcome.removed, 1_000, &state);
+    assert_eq!(_get_totp_seeds(user_id, vault_id, &state.totp_map).seeds.len(), 1);
+    let trash = _list_trash(user_id, vault_id, 1_000, &state);
+    assert_eq!((trash[0].kind, trash[0].x, trash[0].label.clone()), (TrashKind::Totp, 1, b"aws".to_vec()));
+    assert_eq!(_restore_items(user_id, vault_id, vec![trash[0].id], 2_000, &state).restored, vec![trash[0].id]);
+    assert_eq!(_get_totp_seeds(user_id, vault_id, &state.totp_map).seeds[&1].params.period, 60);
+
+    // A tagged global sync carries a TOTP section alongside the others.
+    let other = GeneralState::init();
+    let totp = some_totp_data();
+    let mut update = vec![1, 4];
+    update.extend(&(totp.len() as u64).to_be_bytes()[3..]);
+    update.extend(&totp);
+    update.extend([2, 0, 0, 0, 0, some_notes_data().len() as u8]);
+    update.extend(some_notes_data());
+    let outcome = _global_sync(user_id, vault_id, update, &other).unwrap();
+    assert_eq!(outcome.items, 4);
+    let vault = _get_vault(&b"vault".to_vec(), user_id, vault_id, &other);
+    assert_eq!((vault.totp.seeds.len(), vault.notes.notes.len()), (2, 2));
+    assert!(vault.spreadsheet.columns.is_empty());
+}
+
+#[test]
+pub fn test_attachments() {
+    use sha2::{Digest, Sha256};
+    let state = GeneralState::init();
+    let user_id = some_user_id();
+    let vault_id = some_vault_id();
+    let blob: Vec<u8> = (0..2_500u32).map(|i| i as u8).collect();
+    let upload = AttachmentUpload {
+        name: b"id_ed25519".to_vec(),
+        size: blob.len() as u64,
+        chunk_size: 1_000,
+        sha256: Sha256::digest(&blob).to_vec(),
+        link: Some(ItemRef { kind: ItemKind::LoginColumn, sheet: 0, x: 0, y: 0 }),
+    };
+
+    // Uploads need a registered vault and room in its quota, which they take up from the start.
+    assert!(_begin_attachment(user_id, vault_id, upload.clone(), 1_000_000, 10, &state).is_err());
+    _register_vaults(user_id, &some_vault_names(), 0, &state.vault_registry);
+    assert!(_begin_attachment(user_id, vault_id, upload.clone(), 2_000, 10, &state).is_err());
+    let id = _begin_attachment(user_id, vault_id, upload.clone(), 1_000_000, 10, &state).unwrap();
+    let info = _get_vault_info(user_id, vault_id, &state.vault_registry).unwrap();
+    assert_eq!((info.item_count, info.size_bytes), (1, 2_500));
+
+    // Chunks must have the expected size, and the blob the announced hash.
+    assert!(_upload_attachment_chunk(user_id, vault_id, id, 2, vec![0; 1_000], &state).is_err());
+    assert!(_upload_attachment_chunk(user_id, vault_id, id, 3, vec![0; 500], &state).is_err());
+    _upload_attachment_chunk(user_id, vault_id, id, 2, blob[2_000..].to_vec(), &state).unwrap();
+    _upload_attachment_chunk(user_id, vault_id, id, 0, blob[..1_000].to_vec(), &state).unwrap();
+    assert!(_finish_attachment(user_id, vault_id, id, 20, &state).is_err());
+    assert!(_get_attachment_chunk(user_id, vault_id, id, 0, &state.attachments, &state.attachment_chunks).is_err());
+    _upload_attachment_chunk(user_id, vault_id, id, 1, vec![0; 1_000], &state).unwrap();
+    assert_eq!(_finish_attachment(user_id, vault_id, id, 20, &state), Err("sha256 mismatch".into()));
+    _upload_attachment_chunk(user_id, vault_id, id, 1, blob[1_000..2_000].to_vec(), &state).unwrap();
+    let info = _finish_attachment(user_id, vault_id, id, 30, &state).unwrap();
+    assert_eq!((info.chunk_count, info.complete, info.updated_at), (3, true, 30));
+
+    // Downloads return the chunks as uploaded.
+    let downloaded: Vec<u8> = (0..info.chunk_count)
+        .flat_map(|index| _get_attachment_chunk(user_id, vault_id, id, index, &state.attachments, &state.attachment_chunks).unwrap())
+        .collect();
+    assert_eq!(downloaded, blob);
+    assert!(_list_attachments(user_id, some_other_principal(), &state.attachments).is_empty());
+
+    let info = _link_attachment(user_id, vault_id, id, None, 40, &state.attachments).unwrap();
+    assert_eq!(info.link, None);
+    assert_eq!(_list_attachments(user_id, vault_id, &state.attachments), vec![info]);
+
+    // Deleting gives the quota back and drops the chunks.
+    _delete_attachment(user_id, vault_id, id, 50, &state).unwrap();
+    assert!(state.attachment_chunks.borrow().is_empty());
+    let info = _get_vault_info(user_id, vault_id, &state.vault_registry).unwrap();
+    assert_eq!((info.item_count, info.size_bytes), (0, 0));
+    assert!(_delete_attachment(user_id, vault_id, id, 50, &state).is_err());
+}
+
+#[test]
+pub fn test_typed_records() {
+    let state = GeneralState::init();
+    let user_id = some_user_id();
+    let vault_id = some_vault_id();
+    _register_vaults(user_id, &some_vault_names(), 0, &state.vault_registry);
+
+    // Cards and identities keep their own indexes, with type and expiry in the clear.
+    let outcome = _records_sync(user_id, vault_id, RecordKind::PaymentCard, some_card_data(), &state.records);
+    assert_eq!(outcome.size_change().bytes, 4 + 3);
+    _records_sync(user_id, vault_id, RecordKind::Identity, some_identity_data(), &state.records);
+    let cards = _get_payment_cards(user_id, vault_id, &state.records).cards;
+    assert_eq!((cards[&0].brand, cards[&0].expiry), (CardBrand::Visa, Some(Expiry { year: 2026, month: 10 })));
+    assert_eq!((cards[&0].fields[&0].clone(), cards[&0].fields[&1].clone()), (b"1234".to_vec(), b"Bob".to_vec()));
+    let identities = _get_identities(user_id, vault_id, &state.records).identities;
+    assert_eq!((identities[&0].document, identities[&0].fields[&0].clone()), (IdentityDocument::Passport, b"AB".to_vec()));
+
+    // Expiring records are found across the user's vaults.
+    let this_month = Expiry { year: 2026, month: 10 };
+    let expiring = _get_expiring_records(user_id, this_month, this_month, &state);
+    assert_eq!(expiring.len(), 1);
+    assert_eq!((expiring[0].vault_id, expiring[0].kind, expiring[0].index), (vault_id, RecordKind::PaymentCard, 0));
+    assert_eq!(_get_expiring_records(user_id, this_month, Expiry { year: 2030, month: 12 }, &state).len(), 2);
+    assert!(_get_expiring_records(user_id, Expiry { year: 2024, month: 1 }, Expiry { year: 2026, month: 9 }, &state).is_empty());
+
+    // Deletes go to the trash and can be restored.
+    let outcome = _records_deletes(user_id, vault_id, RecordKind::PaymentCard, vec![0], &state.records);
+    _move_to_trash(user_id, vault_id, outcome.removed, 1_000, &state);
+    assert!(_get_payment_cards(user_id, vault_id, &state.records).cards.is_empty());
+    assert_eq!(_get_identities(user_id, vault_id, &state.records).identities.len(), 1);
+    let trash = _list_trash(user_id, vault_id, 1_000, &state);
+    assert_eq!(trash[0].kind, TrashKind::PaymentCard);
+    assert_eq!(_restore_items(user_id, vault_id, vec![trash[0].id], 2_000, &state).restored.len(), 1);
+    assert_eq!(_get_payment_cards(user_id, vault_id, &state.records).cards[&0].brand, CardBrand::Visa);
+
+    // Tagged global syncs carry both collections, and VaultData returns them.
+    let other = GeneralState::init();
+    let mut update = vec![1, 5, 0, 0, 0, 0, some_card_data().len() as u8];
+    update.extend(some_card_data());
+    update.extend([6, 0, 0, 0, 0, some_identity_data().len() as u8]);
+    update.extend(some_identity_data());
+    assert_eq!(_global_sync(user_id, vault_id, update, &other).unwrap().items, 2);
+    let vault = _get_vault(&b"vault".to_vec(), user_id, vault_id, &other);
+    assert_eq!((vault.payment_cards.cards.len(), vault.identities.identities.len()), (1, 1));
+}
+
+#[test]
+pub fn test_record_templates() {
+    let state = GeneralState::init();
+    let user_id = some_user_id();
+    let vault_id = some_vault_id();
+    let date: &[u8] = &[0x07, 0xea, 10, 19];
+
+    // Records need their template.
+    let record = some_custom_record(0, 0, &[b"home", b"secret", date, b""]);
+    assert!(_custom_records_sync(user_id, vault_id, record.clone(), &state).is_err());
+    _set_record_template(user_id, vault_id, 0, some_template(), &state).unwrap();
+    assert_eq!(_get_record_templates(user_id, vault_id, &state.record_templates).templates[&0], some_template());
+    let outcome = _custom_records_sync(user_id, vault_id, record, &state).unwrap();
+    assert_eq!(outcome.size_change().bytes, 4 + 6 + 4);
+    let records = _get_custom_records(user_id, vault_id, &state.custom_records).records;
+    assert_eq!((records[&0].template, records[&0].values[0].clone()), (0, b"home".to_vec()));
+
+    // Field counts, required fields, dates and TOTP references are checked, and a bad record
+    // leaves the whole sync unapplied.
+    for values in [
+        &[b"home".as_slice(), b"secret"][..],
+        &[b"", b"secret", b"", b""],
+        &[b"home", b"secret", &[0x07, 0xea, 13, 1], b""],
+        &[b"home", b"secret", b"", &[0]],
+    ] {
+        let mut update = some_custom_record(1, 0, &[b"cafe", b"secret", b"", b""]);
+        update.extend(some_custom_record(2, 0, values));
+        assert!(_custom_records_sync(user_id, vault_id, update, &state).is_err());
+        assert_eq!(_get_custom_records(user_id, vault_id, &state.custom_records).records.len(), 1);
+    }
+    _totp_sync(user_id, vault_id, some_totp_data(), &state.totp_map);
+    _custom_records_sync(user_id, vault_id, some_custom_record(2, 0, &[b"office", b"secret", b"", &[0]]), &state).unwrap();
+
+    // Templates can't be changed out from under their records, nor deleted while in use.
+    let mut shorter = some_template();
+    shorter.fields.pop();
+    assert!(_set_record_template(user_id, vault_id, 0, shorter, &state).is_err());
+    let mut renamed = some_template();
+    renamed.name = b"wireless".to_vec();
+    _set_record_template(user_id, vault_id, 0, renamed, &state).unwrap();
+    assert!(_delete_record_template(user_id, vault_id, 0, &state).is_err());
+
+    // Deleted records go to the trash, and only come back while they fit a template.
+    let outcome = _custom_records_deletes(user_id, vault_id, vec![0, 2], &state.custom_records);
+    _move_to_trash(user_id, vault_id, outcome.removed, 1_000, &state);
+    let trash = _list_trash(user_id, vault_id, 1_000, &state);
+    assert_eq!(trash[0].kind, TrashKind::CustomRecord);
+    _delete_record_template(user_id, vault_id, 0, &state).unwrap();
+    let result = _restore_items(user_id, vault_id, trash.iter().map(|item| item.id).collect(), 2_000, &state);
+    assert_eq!((result.restored.len(), result.conflicts.len()), (0, 2));
+    _set_record_template(user_id, vault_id, 0, some_template(), &state).unwrap();
+    assert_eq!(_restore_items(user_id, vault_id, result.conflicts, 2_000, &state).restored.len(), 2);
+
+    // Tagged global syncs carry custom records after the seeds they refer to.
+    let other = GeneralState::init();
+    _set_record_template(user_id, vault_id, 0, some_template(), &other).unwrap();
+    let record = some_custom_record(0, 0, &[b"home", b"secret", b"", &[1]]);
+    let mut update = vec![1, 4, 0, 0, 0, 0, some_totp_data().len() as u8];
+    update.extend(some_totp_data());
+    update.extend([7, 0, 0, 0, 0, record.len() as u8]);
+    update.extend(record);
+    assert_eq!(_global_sync(user_id, vault_id, update, &other).unwrap().items, 3);
+    let vault = _get_vault(&b"vault".to_vec(), user_id, vault_id, &other);
+    assert_eq!((vault.record_templates.templates.len(), vault.custom_records.records.len()), (1, 1));
+}
+
+#[test]
+pub fn test_column_kinds() {
+    let state = GeneralState::init();
+    let user_id = some_user_id();
+    let vault_id = some_vault_id();
+
+    // Typed headers carry a kind and settings after x; untyped ones still read as text.
+    let mut update = vec![0, 3, 0x81, 0, 1, 0, 120, 2, 1];
+    update.extend(b"key");
+    update.extend([0, 3, 0, 1]);
+    update.extend(b"url");
+    update.extend([0, 4, 0x80, 2, 2, 0, 0, 0, 0]);
+    update.extend(b"link");
+    assert_eq!(_vault_spreadsheet_columns_sync(user_id, vault_id, update, &state.spreadsheet_columns), 3);
+    let columns = _get_columns_info(user_id, vault_id, &state.spreadsheet_columns);
+    let settings = ColumnSettings { width: Some(120), sort: Some(SortOrder::Descending), required: true };
+    assert_eq!(columns[&0], (b"key".to_vec(), true, ColumnKind::Secret, settings));
+    assert_eq!(columns[&1], (b"url".to_vec(), false, ColumnKind::Text, ColumnSettings::default()));
+    assert_eq!((columns[&2].2, columns[&2].3), (ColumnKind::Url, ColumnSettings::default()));
+
+    // Columns stored before kinds existed read as text and are stored typed once written again.
+    let legacy = ColumnData::from_bytes(vec![1, 97, 98].into());
+    assert!(legacy.legacy && legacy.hidden && legacy.kind == ColumnKind::Text);
+    assert_eq!(legacy.name, b"ab".to_vec());
+    let typed = ColumnData::from_bytes(legacy.to_bytes());
+    assert!(!typed.legacy && typed.hidden);
+
+    // An empty, visible, typed column still deletes.
+    _vault_spreadsheet_columns_sync(user_id, vault_id, vec![0, 0, 0x80, 1, 3, 0, 0, 0, 0], &state.spreadsheet_columns);
+    assert!(!_get_columns_info(user_id, vault_id, &state.spreadsheet_columns).contains_key(&1));
+}
+
+// Cells in the spreadsheet sync format, one byte of data each.
+fn some_cells(cells: &[(u8, u8, u8)]) -> Vec<u8> {
+    cells.iter().flat_map(|(x, y, data)| [0, 1, *x, *y, *data]).collect()
+}
+
+#[test]
+pub fn test_grid_edits() {
+    let state = GeneralState::init();
+    let user_id = some_user_id();
+    let vault_id = some_vault_id();
+    let grid = |state: &GeneralState| -> Vec<(u8, u8, u8)> {
+        let mut cells: Vec<_> = _get_spreadsheet(user_id, vault_id, &state.spreadsheet_map).columns.into_iter()
+            .flat_map(|(x, column)| column.rows.into_iter().map(move |(y, data)| (x, y, data[0])))
+            .collect();
+        cells.sort();
+        cells
+    };
+    _vault_spreadsheet_sync(user_id, vault_id, some_cells(&[(0, 0, b'a'), (1, 0, b'b'), (2, 0, b'c'), (1, 1, b'd')]), &state.spreadsheet_map);
+    _vault_spreadsheet_columns_sync(user_id, vault_id, vec![0, 1, 0, 0, b'A', 0, 1, 0, 1, b'B', 0, 1, 0, 2, b'C'], &state.spreadsheet_columns);
+
+    // Moving a column shifts the ones in between, headers included.
+    let outcome = _edit_grid(user_id, vault_id, Grid::Spreadsheet, Axis::Column, GridEdit::Move { from: 0, to: 2 }, &state).unwrap();
+    assert_eq!(outcome.items, 4 + 3);
+    assert_eq!(grid(&state), vec![(0, 0, b'b'), (0, 1, b'd'), (1, 0, b'c'), (2, 0, b'a')]);
+    let headers = _get_columns_info(user_id, vault_id, &state.spreadsheet_columns);
+    assert_eq!((headers[&0].0.clone(), headers[&2].0.clone()), (b"B".to_vec(), b"A".to_vec()));
+
+    // Deleting a column takes its cells to the trash.
+    let outcome = _edit_grid(user_id, vault_id, Grid::Spreadsheet, Axis::Column, GridEdit::Delete { at: 0, count: 1 }, &state).unwrap();
+    assert_eq!(outcome.removed.len(), 2);
+    assert_eq!(grid(&state), vec![(0, 0, b'c'), (1, 0, b'a')]);
+    assert_eq!(_get_columns_info(user_id, vault_id, &state.spreadsheet_columns).len(), 2);
+
+    // Rows insert and swap; an edit that would push a cell off the grid changes nothing.
+    _edit_grid(user_id, vault_id, Grid::Spreadsheet, Axis::Row, GridEdit::Insert { at: 0, count: 2 }, &state).unwrap();
+    _edit_grid(user_id, vault_id, Grid::Spreadsheet, Axis::Row, GridEdit::Swap { a: 2, b: 254 }, &state).unwrap();
+    assert_eq!(grid(&state), vec![(0, 254, b'c'), (1, 254, b'a')]);
+    assert!(_edit_grid(user_id, vault_id, Grid::Spreadsheet, Axis::Row, GridEdit::Insert { at: 100, count: 2 }, &state).is_err());
+    assert!(_edit_grid(user_id, vault_id, Grid::Spreadsheet, Axis::Row, GridEdit::Delete { at: 0, count: 0 }, &state).is_err());
+    assert_eq!(grid(&state), vec![(0, 254, b'c'), (1, 254, b'a')]);
+
+    // Deleting a login column trashes it whole and drops the TOTP link to it; later columns and
+    // their links move back.
+    _login_metadata_sync(user_id, vault_id, vec![0, 1, 0, b'x', 0, 1, 1, b'y'], &state.logins_columns, &state.logins_map);
+    _login_data_sync(user_id, vault_id, some_cells(&[(0, 0, b'p'), (1, 0, b'q'), (1, 1, b'r')]), &state.logins_map);
+    let mut seeds = some_totp_data();
+    seeds.extend([0x01, 0x00, 0x01, 0x02, 0x00, 0x06, 0x00, 0x1e, 0x01, 0x01, b'z', 0xcc]);
+    _totp_sync(user_id, vault_id, seeds, &state.totp_map);
+    let outcome = _edit_grid(user_id, vault_id, Grid::Logins, Axis::Column, GridEdit::Delete { at: 0, count: 1 }, &state).unwrap();
+    _move_to_trash(user_id, vault_id, outcome.removed, 1_000, &state);
+    let trash = _list_trash(user_id, vault_id, 1_000, &state);
+    let column = trash.iter().find(|item| item.kind == TrashKind::LoginColumn).unwrap();
+    assert_eq!((column.label.clone(), column.rows.len()), (b"x".to_vec(), 1));
+    let logins = _get_logins(user_id, vault_id, &state.logins_map, &state.logins_columns).columns;
+    assert_eq!((logins.len(), logins[&0].label.clone(), logins[&0].rows.len()), (1, b"y".to_vec(), 2));
+    let seeds = _get_totp_seeds(user_id, vault_id, &state.totp_map).seeds;
+    assert_eq!((seeds[&0].login_column, seeds[&2].login_column), (None, Some(0)));
+}
+
+#[test]
+pub fn test_sheets() {
+    let state = GeneralState::init();
+    let user_id = some_user_id();
+    let vault_id = some_vault_id();
+    let sheet_cells = |state: &GeneralState, sheet: u8| -> Vec<(u8, u8, u8)> {
+        let mut cells: Vec<_> = _get_sheet(user_id, vault_id, sheet, &state.spreadsheet_map).columns.into_iter()
+            .flat_map(|(x, column)| column.rows.into_iter().map(move |(y, data)| (x, y, data[0])))
+            .collect();
+        cells.sort();
+        cells
+    };
+    _register_vaults(user_id, &some_vault_names(), 0, &state.vault_registry);
+    _vault_names_sync(user_id, &some_vault_names(), &state.vault_names_map);
+    _vault_spreadsheet_sync(user_id, vault_id, some_cells(&[(0, 0, b'a')]), &state.spreadsheet_map);
+
+    // Every vault starts with sheet 0, which holds the cells synced before sheets existed.
+    let sheets = _list_sheets(user_id, vault_id, &state.sheets);
+    assert_eq!(sheets.iter().map(|info| info.sheet).collect::<Vec<_>>(), vec![0]);
+
+    // New sheets take the lowest free id and go last; their cells and columns stay apart.
+    assert_eq!(_create_sheet(user_id, vault_id, b"two".to_vec(), &state.sheets), Ok(1));
+    assert_eq!(_create_sheet(user_id, vault_id, b"three".to_vec(), &state.sheets), Ok(2));
+    _sheet_sync(user_id, vault_id, 1, some_cells(&[(0, 0, b'b'), (1, 0, b'c')]), &state.spreadsheet_map);
+    _sheet_columns_sync(user_id, vault_id, 1, vec![0, 1, 0, 0, b'B', 0, 1, 0, 1, b'C'], &state.spreadsheet_columns);
+    assert_eq!(sheet_cells(&state, 0), vec![(0, 0, b'a')]);
+    assert_eq!(sheet_cells(&state, 1), vec![(0, 0, b'b'), (1, 0, b'c')]);
+    assert!(_get_columns_info(user_id, vault_id, &state.spreadsheet_columns).is_empty());
+    assert_eq!(_get_sheet_columns(user_id, vault_id, 1, &state.spreadsheet_columns)[&0].0, b"B".to_vec());
+
+    // Renaming and reordering; the order must name every sheet once.
+    _rename_sheet(user_id, vault_id, 0, b"one".to_vec(), &state.sheets).unwrap();
+    assert!(_rename_sheet(user_id, vault_id, 9, b"nine".to_vec(), &state.sheets).is_err());
+    assert!(_reorder_sheets(user_id, vault_id, vec![2, 0], &state.sheets).is_err());
+    assert!(_reorder_sheets(user_id, vault_id, vec![2, 2, 0], &state.sheets).is_err());
+    _reorder_sheets(user_id, vault_id, vec![2, 0, 1], &state.sheets).unwrap();
+    let sheets = _list_sheets(user_id, vault_id, &state.sheets);
+    assert_eq!(sheets.iter().map(|info| (info.sheet, info.position, info.name.clone())).collect::<Vec<_>>(),
+        vec![(2, 0, b"three".to_vec()), (0, 1, b"one".to_vec()), (1, 2, b"two".to_vec())]);
+    let vault = _get_vault(&b"vault".to_vec(), user_id, vault_id, &state);
+    assert_eq!(vault.sheets.len(), 3);
+    assert_eq!(vault.sheets.iter().find(|sheet| sheet.sheet == 1).unwrap().spreadsheet.columns.len(), 2);
+
+    // Grid edits reach any sheet.
+    _edit_grid(user_id, vault_id, Grid::Sheet(1), Axis::Column, GridEdit::Swap { a: 0, b: 1 }, &state).unwrap();
+    assert_eq!(sheet_cells(&state, 1), vec![(0, 0, b'c'), (1, 0, b'b')]);
+    assert_eq!(sheet_cells(&state, 0), vec![(0, 0, b'a')]);
+    assert!(_edit_grid(user_id, vault_id, Grid::Sheet(9), Axis::Row, GridEdit::Insert { at: 0, count: 1 }, &state).is_err());
+
+    // The sheets migrate with the vault.
+    let target = GeneralState::init();
+    let mut imported = MigrationManifest::default();
+    let mut cursor = None;
+    loop {
+        let page = _export_page(user_id, cursor, 1_000, &state).unwrap();
+        _import_page(user_id, page.chunks, &mut imported, 0, &target).unwrap();
+        match page.next {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+    _verify_import(&_migration_manifest(user_id, &state).unwrap(), &imported).unwrap();
+    assert_eq!(_list_sheets(user_id, vault_id, &target.sheets), _list_sheets(user_id, vault_id, &state.sheets));
+    assert_eq!(sheet_cells(&target, 1), vec![(0, 0, b'c'), (1, 0, b'b')]);
+
+    // Deleting a sheet trashes its cells, which can't be restored while the sheet is gone.
+    assert!(_delete_sheet(user_id, vault_id, 0, &state).is_err());
+    let outcome = _delete_sheet(user_id, vault_id, 1, &state).unwrap();
+    assert_eq!(outcome.items, 2 + 2);
+    _move_to_trash(user_id, vault_id, outcome.removed, 1_000, &state);
+    assert!(sheet_cells(&state, 1).is_empty());
+    assert!(_get_sheet_columns(user_id, vault_id, 1, &state.spreadsheet_columns).is_empty());
+    assert!(_sheet_exists(user_id, vault_id, 2, &state.sheets) && !_sheet_exists(user_id, vault_id, 1, &state.sheets));
+    let ids: Vec<u64> = _list_trash(user_id, vault_id, 1_000, &state).iter().map(|item| item.id).collect();
+    assert_eq!(_restore_items(user_id, vault_id, ids.clone(), 1_000, &state).conflicts.len(), 2);
+    assert_eq!(_create_sheet(user_id, vault_id, b"again".to_vec(), &state.sheets), Ok(1));
+    assert_eq!(_restore_items(user_id, vault_id, ids, 1_000, &state).restored.len(), 2);
+    assert_eq!(sheet_cells(&state, 1), vec![(0, 0, b'c'), (1, 0, b'b')]);
+
+    // Deleting the vault removes every sheet.
+    _schedule_vault_deletion(user_id, vault_id, 2_000, &state).unwrap();
+    let grace = *state.deletion_grace_period.borrow().get();
+    while _run_due_deletions(2_000 + grace, &state, &|| false).is_empty() {}
+    assert!(state.sheets.borrow().is_empty());
+    assert!(state.spreadsheet_map.borrow().is_empty());
+}
+
+#[test]
+pub fn test_folders_and_tags() {
+    let state = GeneralState::init();
+    let user_id = some_user_id();
+    let vault_id = some_vault_id();
+    let cell = |x, y| ItemRef { kind: ItemKind::SpreadsheetCell, sheet: 0, x, y };
+    let note = ItemRef { kind: ItemKind::Note, sheet: 0, x: 3, y: 0 };
+    _register_vaults(user_id, &some_vault_names(), 0, &state.vault_registry);
+    _vault_names_sync(user_id, &some_vault_names(), &state.vault_names_map);
+
+    // Folders nest but never into themselves.
+    let work = _create_folder(user_id, vault_id, None, b"work".to_vec(), &state.folders).unwrap();
+    let project = _create_folder(user_id, vault_id, Some(work), b"project".to_vec(), &state.folders).unwrap();
+    assert_eq!((work, project), (0, 1));
+    assert!(_create_folder(user_id, vault_id, Some(9), b"orphan".to_vec(), &state.folders).is_err());
+    assert!(_create_folder(user_id, vault_id, None, Vec::new(), &state.folders).is_err());
+    assert!(_update_folder(user_id, vault_id, work, Some(project), b"work".to_vec(), &state.folders).is_err());
+
+    // Items take one folder and any number of tags, which must exist.
+    _set_tag(user_id, vault_id, b"t1".to_vec(), b"urgent".to_vec(), &state.tags).unwrap();
+    _set_tag(user_id, vault_id, b"t2".to_vec(), b"shared".to_vec(), &state.tags).unwrap();
+    assert!(_set_tag(user_id, vault_id, vec![0; MAX_TAG_ID_BYTES + 1], b"long".to_vec(), &state.tags).is_err());
+    assert!(_set_item_labels(user_id, vault_id, note, None, vec![b"t3".to_vec()], &state).is_err());
+    _set_item_labels(user_id, vault_id, note, Some(project), vec![b"t1".to_vec(), b"t2".to_vec(), b"t1".to_vec()], &state).unwrap();
+    _set_item_labels(user_id, vault_id, cell(2, 5), Some(project), vec![b"t1".to_vec()], &state).unwrap();
+    assert_eq!(_list_folder_items(user_id, vault_id, project, &state.item_labels), vec![cell(2, 5), note]);
+    assert_eq!(_list_tagged_items(user_id, vault_id, b"t2".to_vec(), &state.item_labels), vec![note]);
+
+    // Only empty folders can be deleted.
+    assert!(_delete_folder(user_id, vault_id, work, &state).is_err());
+    assert!(_delete_folder(user_id, vault_id, project, &state).is_err());
+
+    // Labels follow grid edits and go with deleted rows.
+    _edit_grid(user_id, vault_id, Grid::Spreadsheet, Axis::Row, GridEdit::Insert { at: 0, count: 2 }, &state).unwrap();
+    assert_eq!(_list_tagged_items(user_id, vault_id, b"t1".to_vec(), &state.item_labels), vec![cell(2, 7), note]);
+    _edit_grid(user_id, vault_id, Grid::Spreadsheet, Axis::Row, GridEdit::Delete { at: 7, count: 1 }, &state).unwrap();
+    assert_eq!(_list_folder_items(user_id, vault_id, project, &state.item_labels), vec![note]);
+
+    // The organization migrates with the vault, folders after their parents.
+    _update_folder(user_id, vault_id, work, None, b"office".to_vec(), &state.folders).unwrap();
+    let target = GeneralState::init();
+    let mut imported = MigrationManifest::default();
+    let mut cursor = None;
+    loop {
+        let page = _export_page(user_id, cursor, 1_000, &state).unwrap();
+        _import_page(user_id, page.chunks, &mut imported, 0, &target).unwrap();
+        match page.next {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+    _verify_import(&_migration_manifest(user_id, &state).unwrap(), &imported).unwrap();
+    assert_eq!(_get_organization(user_id, vault_id, &target), _get_organization(user_id, vault_id, &state));
+
+    // Deleting a tag takes it off its items; an item left bare loses its entry.
+    _set_item_labels(user_id, vault_id, note, None, vec![b"t2".to_vec()], &state).unwrap();
+    assert_eq!(_delete_tag(user_id, vault_id, b"t2".to_vec(), &state), Ok(1));
+    assert!(state.item_labels.borrow().is_empty());
+    _delete_folder(user_id, vault_id, project, &state).unwrap();
+    let organization = _get_vault(&b"vault".to_vec(), user_id, vault_id, &state).organization;
+    assert_eq!(organization.folders.len(), 1);
+    assert_eq!(organization.tags.len(), 1);
+
+    // Global syncs write folders, tags and labels in that order; empty names remove.
+    let other = GeneralState::init();
+    let folder = [&[0, 4][..], &7u64.to_be_bytes(), &[0; 9], b"home"].concat();
+    let tag = [&[0, 3, 2][..], b"t9", b"new"].concat();
+    let labels = [&note.to_bytes()[..], &[1], &7u64.to_be_bytes(), &[0, 3, 2], b"t9"].concat();
+    let mut update = vec![1];
+    for (section, data) in [(8, folder), (9, tag), (10, labels)] {
+        update.extend([section, 0, 0, 0, 0, data.len() as u8]);
+        update.extend(data);
+    }
+    assert_eq!(_global_sync(user_id, vault_id, update, &other).unwrap().items, 3);
+    assert_eq!(_list_folder_items(user_id, vault_id, 7, &other.item_labels), vec![note]);
+    assert_eq!(_list_tagged_items(user_id, vault_id, b"t9".to_vec(), &other.item_labels), vec![note]);
+
+    // Deleting the vault removes its organization.
+    _schedule_vault_deletion(user_id, vault_id, 2_000, &state).unwrap();
+    let grace = *state.deletion_grace_period.borrow().get();
+    while _run_due_deletions(2_000 + grace, &state, &|| false).is_empty() {}
+    assert!(state.folders.borrow().is_empty());
+    assert!(state.tags.borrow().is_empty());
+}
+
+#[test]
+pub fn test_search_items() {
+    let state = GeneralState::init();
+    let user_id = some_user_id();
+    let vault_id = some_vault_id();
+    let cell = |x, y| ItemRef { kind: ItemKind::SpreadsheetCell, sheet: 0, x, y };
+    let note = ItemRef { kind: ItemKind::Note, sheet: 0, x: 1, y: 0 };
+    let entry = |item: ItemRef, tokens: &[&[u8]]| {
+        let tokens: Vec<u8> = tokens.iter().flat_map(|token| [&[token.len() as u8][..], token].concat()).collect();
+        [&item.to_bytes()[..], &(tokens.len() as u16).to_be_bytes(), &tokens].concat()
+    };
+    let hits = |state: &GeneralState, tokens: &[&[u8]]| -> Vec<ItemRef> {
+        let tokens = tokens.iter().map(|token| token.to_vec()).collect();
+        _search_items(user_id, vec![vault_id, vault_id], tokens, &state.search_index).unwrap().into_iter().map(|hit| hit.item).collect()
+    };
+    _register_vaults(user_id, &some_vault_names(), 0, &state.vault_registry);
+    _vault_names_sync(user_id, &some_vault_names(), &state.vault_names_map);
+
+    // A search returns the items carrying every token of the query.
+    let update = [entry(note, &[b"mail", b"bank"]), entry(cell(0, 2), &[b"bank"])].concat();
+    assert_eq!(_search_tokens_sync(user_id, vault_id, update, &state), Ok(2));
+    assert_eq!(hits(&state, &[b"bank"]), vec![cell(0, 2), note]);
+    assert_eq!(hits(&state, &[b"bank", b"mail"]), vec![note]);
+    assert!(hits(&state, &[b"other"]).is_empty());
+    assert!(_search_items(user_id, vec![vault_id], Vec::new(), &state.search_index).is_err());
+    assert!(_search_items(some_other_principal(), vec![vault_id], vec![b"bank".to_vec()], &state.search_index).unwrap().is_empty());
+
+    // Tokens are checked before any is written.
+    let update = [entry(cell(5, 5), &[b"ok"]), entry(note, &[&[0; MAX_TOKEN_BYTES + 1]])].concat();
+    assert!(_search_tokens_sync(user_id, vault_id, update, &state).is_err());
+    assert!(hits(&state, &[b"ok"]).is_empty());
+
+    // New tokens replace the old; none take the item out of the index.
+    _search_tokens_sync(user_id, vault_id, entry(note, &[b"mail"]), &state).unwrap();
+    assert_eq!(hits(&state, &[b"bank"]), vec![cell(0, 2)]);
+
+    // Tokens follow grid edits.
+    _edit_grid(user_id, vault_id, Grid::Spreadsheet, Axis::Row, GridEdit::Move { from: 2, to: 0 }, &state).unwrap();
+    assert_eq!(hits(&state, &[b"bank"]), vec![cell(0, 0)]);
+
+    // The index migrates with the vault.
+    let target = GeneralState::init();
+    let mut imported = MigrationManifest::default();
+    let mut cursor = None;
+    loop {
+        let page = _export_page(user_id, cursor, 1_000, &state).unwrap();
+        _import_page(user_id, page.chunks, &mut imported, 0, &target).unwrap();
+        match page.next {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+    _verify_import(&_migration_manifest(user_id, &state).unwrap(), &imported).unwrap();
+    assert_eq!(hits(&target, &[b"mail"]), vec![note]);
+    assert_eq!(hits(&target, &[b"bank"]), vec![cell(0, 0)]);
+
+    // Deleted rows leave the index.
+    _edit_grid(user_id, vault_id, Grid::Spreadsheet, Axis::Row, GridEdit::Delete { at: 0, count: 1 }, &state).unwrap();
+    assert!(hits(&state, &[b"bank"]).is_empty());
+
+    // Tagged global syncs carry tokens too.
+    let other = GeneralState::init();
+    let data = entry(note, &[b"mail"]);
+    let mut update = vec![1, 11, 0, 0, 0, 0, data.len() as u8];
+    update.extend(data);
+    assert_eq!(_global_sync(user_id, vault_id, update, &other).unwrap().items, 1);
+    assert_eq!(hits(&other, &[b"mail"]), vec![note]);
+
+    // Deleting the vault empties its index.
+    _schedule_vault_deletion(user_id, vault_id, 2_000, &state).unwrap();
+    let grace = *state.deletion_grace_period.borrow().get();
+    while _run_due_deletions(2_000 + grace, &state, &|| false).is_empty() {}
+    assert!(state.search_index.borrow().is_empty());
+    assert!(state.item_tokens.borrow().is_empty());
+}
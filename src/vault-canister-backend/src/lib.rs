@@ -1,55 +1,91 @@
 use candid::Principal;
-use ic_cdk::{inspect_message, update};
+use ic_cdk::{query, update};
 use vault_core::{
     api::{
-        key_api::{derive_vetkey, storage_user_of, GhostkeysVetKdArgs},
+        access_api::{_add_delegate, _assert_owner, _get_delegates, _is_delegate, _remove_delegate, _resolve_owner, DelegateInfo},
+        dev_api::{_get_user_vaults, UserVaults},
+        migration_api::MigrationReport,
     },
-    stable::{types::GeneralState, util::{_init_controllers, _inspect_message, maintain_status}},
+    service::{self, account_owner, audit, policy::{TenancyMode, VaultPolicy}, vault_user, with_state},
+    stable::types::GeneralState,
+    vault_type::audit_log::AuditOp,
 };
 
-thread_local! {
-    static GENERAL_STATE: GeneralState = GeneralState::init();
-}
+/*
+    A dedicated canister holds a single owner's vaults. The owner and its delegates act on those
+    vaults; everything is stored under the owner's principal whoever the caller is.
+*/
+struct DedicatedPolicy;
+impl VaultPolicy for DedicatedPolicy {
+    const TENANCY: TenancyMode = TenancyMode::Dedicated;
+
+    const DEFAULT_MAX_USERS: u64 = 1;
+
+    const ALWAYS_ACCEPT: &'static [&'static str] = &[];
 
-// Helper for cost-related. TODO: move
-fn maintain_canister_status() {
-    GENERAL_STATE.with(|m| {
-        maintain_status(&m.canister_owners);
-    });
+    fn vault_user(caller: Principal, state: &GeneralState) -> Result<Principal, String> {
+        _resolve_owner(caller, &state.canister_owners, &state.delegates)
+    }
+
+    // Delegates can't manage deletions or machine grants.
+    fn account_owner(caller: Principal, state: &GeneralState) -> Result<Principal, String> {
+        _assert_owner(caller, &state.canister_owners)
+    }
+
+    fn accepts_caller(caller: Principal, state: &GeneralState) -> bool {
+        _is_delegate(caller, &state.delegates)
+    }
 }
 
-#[inspect_message]
-fn inspect_message() {
-    let always_accept: Vec<String> = vec![
-        "canister_init".to_string(), // needs to be reworked so only the
-    ];
-    // call common inspect
-    GENERAL_STATE.with(|m| _inspect_message(&always_accept, &m.canister_owners))
+vault_core::vault_endpoints!(DedicatedPolicy);
+
+/*
+    Delegates. Managed by the owner only.
+*/
+
+#[update]
+fn add_delegate(delegate: Principal) -> Result<(), String> {
+    with_state(|state| {
+        let owner = account_owner::<DedicatedPolicy>(state);
+        _add_delegate(owner, delegate, ic_cdk::api::time(), &state.delegates)?;
+        audit(state, owner, None, AuditOp::DelegateAdded, 1);
+        Ok(())
+    })
 }
+
 #[update]
-fn canister_init(user: Principal, controller: Principal) {
-    GENERAL_STATE.with(|m| {
-        _init_controllers(user, controller, &m.canister_owners);
-    });
+fn remove_delegate(delegate: Principal) -> Result<(), String> {
+    with_state(|state| {
+        let owner = account_owner::<DedicatedPolicy>(state);
+        _remove_delegate(delegate, &state.delegates)?;
+        audit(state, owner, None, AuditOp::DelegateRemoved, 1);
+        Ok(())
+    })
 }
+
+#[query]
+fn get_delegates() -> Vec<DelegateInfo> {
+    with_state(|state| {
+        vault_user::<DedicatedPolicy>(state);
+        _get_delegates(&state.delegates)
+    })
+}
+
 /*
-    Key-management Specific Endpoints
+    Migration from a shared canister, after the owner authorised this canister there.
 */
 
 #[update]
-async fn derive_vetkd_encrypted_key(args: GhostkeysVetKdArgs) -> Result<Vec<u8>, String> {
-    maintain_canister_status();
-    let scope_copy = args.scope.clone();
-    let encrypted_key = derive_vetkey(args).await?;
-    let owner_principal = storage_user_of(&scope_copy);
-
-    GENERAL_STATE.with(|st| {
-        st.key_management
-            .borrow_mut()
-            .insert(owner_principal.to_text(), encrypted_key.clone());
-    });
-
-    Ok(encrypted_key)
+async fn import_from_shared(source: Principal) -> Result<MigrationReport, String> {
+    service::migration::import_from_shared::<DedicatedPolicy>(source).await
+}
+
+#[query]
+fn get_all_user_vaults() -> UserVaults {
+    with_state(|state| {
+        let user_id = vault_user::<DedicatedPolicy>(state);
+        _get_user_vaults(user_id, state)
+    })
 }
 
 ic_cdk::export_candid!();
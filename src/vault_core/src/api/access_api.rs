@@ -0,0 +1,70 @@
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::stable::types::{CanisterOwnersState, DelegatesMap};
+
+/*
+    Access control for dedicated canisters. A dedicated canister stores the data of a single
+    owner. The owner may add delegates, who read and write the owner's vaults but can't manage
+    delegates, machine grants or deletions.
+*/
+
+pub const MAX_DELEGATES: u64 = 16;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct DelegateInfo {
+    pub delegate: Principal,
+    pub added_at: u64,
+}
+
+// The principal whose data the canister stores: the user it was initialised with.
+pub fn _owner_of(canister_owners: &CanisterOwnersState) -> Result<Principal, String> {
+    canister_owners.borrow().user.first().copied().ok_or_else(|| "canister not initialised".into())
+}
+
+pub fn _is_delegate(caller: Principal, delegates: &DelegatesMap) -> bool {
+    delegates.borrow().contains_key(&caller)
+}
+
+// The owner when the caller is the owner itself.
+pub fn _assert_owner(caller: Principal, canister_owners: &CanisterOwnersState) -> Result<Principal, String> {
+    let owner = _owner_of(canister_owners)?;
+    if caller != owner {
+        return Err(format!("Unauthorized caller: {}", caller));
+    }
+    Ok(owner)
+}
+
+// The owner when the caller is the owner or one of its delegates.
+pub fn _resolve_owner(caller: Principal, canister_owners: &CanisterOwnersState, delegates: &DelegatesMap) -> Result<Principal, String> {
+    let owner = _owner_of(canister_owners)?;
+    if caller != owner && !_is_delegate(caller, delegates) {
+        return Err(format!("Unauthorized caller: {}", caller));
+    }
+    Ok(owner)
+}
+
+pub fn _add_delegate(owner: Principal, delegate: Principal, now: u64, delegates: &DelegatesMap) -> Result<(), String> {
+    if delegate == owner || delegate == Principal::anonymous() {
+        return Err("invalid delegate".into());
+    }
+    let mut delegates = delegates.borrow_mut();
+    if !delegates.contains_key(&delegate) && delegates.len() >= MAX_DELEGATES {
+        return Err("too many delegates".into());
+    }
+    delegates.insert(delegate, now);
+    Ok(())
+}
+
+pub fn _remove_delegate(delegate: Principal, delegates: &DelegatesMap) -> Result<(), String> {
+    match delegates.borrow_mut().remove(&delegate) {
+        Some(_) => Ok(()),
+        None => Err("not a delegate".into()),
+    }
+}
+
+pub fn _get_delegates(delegates: &DelegatesMap) -> Vec<DelegateInfo> {
+    delegates.borrow()
+        .iter()
+        .map(|entry| DelegateInfo { delegate: *entry.key(), added_at: entry.value() })
+        .collect()
+}
@@ -0,0 +1,199 @@
+use candid::{CandidType, Deserialize, Principal};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    api::registry_api::{_get_vault_info, _record_vault_change, SizeChange},
+    stable::types::{AttachmentChunksMap, AttachmentsMap, GeneralState},
+    vault_type::attachments::{AttachmentChunkKey, AttachmentKey, AttachmentManifest, ItemRef},
+};
+
+/*
+    Encrypted file attachments, for what doesn't fit in a cell or note: SSH keys, certificates,
+    recovery documents. The client encrypts the file, announces its size and hash, uploads it in
+    chunks and finishes the upload, at which point the canister checks the hash. The size counts
+    against the vault's quota from the moment the upload starts until the attachment is deleted.
+*/
+
+pub const MAX_ATTACHMENT_BYTES: u64 = 64 * 1024 * 1024; // 64 MB
+// Chunks stay well under the message size limit, and fit a snapshot or migration page alone.
+pub const MAX_ATTACHMENT_CHUNK_BYTES: u32 = 1024 * 1024; // 1 MB
+pub const MAX_ATTACHMENT_NAME_BYTES: usize = 1024;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AttachmentUpload {
+    // Encrypted file name and type.
+    pub name: Vec<u8>,
+    pub size: u64,
+    pub chunk_size: u32,
+    // SHA-256 of the encrypted blob.
+    pub sha256: Vec<u8>,
+    pub link: Option<ItemRef>,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub struct AttachmentInfo {
+    pub id: u64,
+    pub name: Vec<u8>,
+    pub size: u64,
+    pub chunk_size: u32,
+    pub chunk_count: u32,
+    pub sha256: Vec<u8>,
+    pub link: Option<ItemRef>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub complete: bool,
+}
+impl AttachmentInfo {
+    fn new(id: u64, manifest: AttachmentManifest) -> Self {
+        Self {
+            id,
+            chunk_count: manifest.chunk_count(),
+            name: manifest.name,
+            size: manifest.size,
+            chunk_size: manifest.chunk_size,
+            sha256: manifest.sha256,
+            link: manifest.link,
+            created_at: manifest.created_at,
+            updated_at: manifest.updated_at,
+            complete: manifest.complete,
+        }
+    }
+}
+
+fn _vault_range(principals: &[u8]) -> std::ops::RangeInclusive<AttachmentKey> {
+    AttachmentKey { principals: principals.to_vec(), id: 0 }..=AttachmentKey { principals: principals.to_vec(), id: u64::MAX }
+}
+
+fn _chunk_range(key: &AttachmentKey) -> std::ops::RangeInclusive<AttachmentChunkKey> {
+    key.chunk(0)..=key.chunk(u32::MAX)
+}
+
+fn _manifest(key: &AttachmentKey, attachments: &AttachmentsMap) -> Result<AttachmentManifest, String> {
+    attachments.borrow().get(key).ok_or_else(|| format!("no attachment {}", key.id))
+}
+
+// Starts an upload, reserving its size in the vault. Returns the id to upload the chunks under.
+pub fn _begin_attachment(user_id: Principal, vault_id: Principal, upload: AttachmentUpload, max_vault_bytes: u64, now: u64, state: &GeneralState) -> Result<u64, String> {
+    if upload.size == 0 || upload.size > MAX_ATTACHMENT_BYTES {
+        return Err(format!("attachment size must be between 1 and {} bytes", MAX_ATTACHMENT_BYTES));
+    }
+    if upload.chunk_size == 0 || upload.chunk_size > MAX_ATTACHMENT_CHUNK_BYTES {
+        return Err(format!("chunk size must be between 1 and {} bytes", MAX_ATTACHMENT_CHUNK_BYTES));
+    }
+    if upload.sha256.len() != 32 {
+        return Err("sha256 must be 32 bytes".into());
+    }
+    if upload.name.len() > MAX_ATTACHMENT_NAME_BYTES {
+        return Err(format!("name exceeds {} bytes", MAX_ATTACHMENT_NAME_BYTES));
+    }
+    let used = _get_vault_info(user_id, vault_id, &state.vault_registry).ok_or("vault is not registered")?.size_bytes;
+    if used.saturating_add(upload.size) > max_vault_bytes {
+        return Err(format!("vault quota of {} bytes exceeded", max_vault_bytes));
+    }
+
+    let principals = AttachmentKey::new(user_id, vault_id, 0).principals;
+    let mut attachments = state.attachments.borrow_mut();
+    let id = attachments.range(_vault_range(&principals)).next_back().map_or(0, |entry| entry.key().id + 1);
+    let manifest = AttachmentManifest {
+        size: upload.size,
+        chunk_size: upload.chunk_size,
+        sha256: upload.sha256,
+        link: upload.link,
+        created_at: now,
+        updated_at: now,
+        complete: false,
+        name: upload.name,
+    };
+    attachments.insert(AttachmentKey { principals, id }, manifest);
+    drop(attachments);
+    _record_vault_change(user_id, vault_id, SizeChange { items: 1, bytes: upload.size as i64 }, now, &state.vault_registry);
+    Ok(id)
+}
+
+// Stores a chunk of an upload in progress. Chunks can come in any order and be sent again.
+pub fn _upload_attachment_chunk(user_id: Principal, vault_id: Principal, id: u64, index: u32, data: Vec<u8>, state: &GeneralState) -> Result<(), String> {
+    let key = AttachmentKey::new(user_id, vault_id, id);
+    let manifest = _manifest(&key, &state.attachments)?;
+    if manifest.complete {
+        return Err(format!("attachment {} is already complete", id));
+    }
+    if index >= manifest.chunk_count() {
+        return Err(format!("attachment {} has {} chunks", id, manifest.chunk_count()));
+    }
+    if data.len() != manifest.chunk_len(index) {
+        return Err(format!("chunk {} must be {} bytes", index, manifest.chunk_len(index)));
+    }
+    state.attachment_chunks.borrow_mut().insert(key.chunk(index), data);
+    Ok(())
+}
+
+// Completes an upload once every chunk is in and the blob matches the announced hash. On a
+// mismatch the upload stays open so chunks can be sent again.
+pub fn _finish_attachment(user_id: Principal, vault_id: Principal, id: u64, now: u64, state: &GeneralState) -> Result<AttachmentInfo, String> {
+    let key = AttachmentKey::new(user_id, vault_id, id);
+    let mut manifest = _manifest(&key, &state.attachments)?;
+    if manifest.complete {
+        return Err(format!("attachment {} is already complete", id));
+    }
+    let mut hasher = Sha256::new();
+    let mut received = 0;
+    for entry in state.attachment_chunks.borrow().range(_chunk_range(&key)) {
+        hasher.update(entry.value());
+        received += 1;
+    }
+    if received != manifest.chunk_count() {
+        return Err(format!("{} of {} chunks received", received, manifest.chunk_count()));
+    }
+    if hasher.finalize().as_slice() != manifest.sha256.as_slice() {
+        return Err("sha256 mismatch".into());
+    }
+    manifest.complete = true;
+    manifest.updated_at = now;
+    state.attachments.borrow_mut().insert(key, manifest.clone());
+    Ok(AttachmentInfo::new(id, manifest))
+}
+
+pub fn _get_attachment_chunk(user_id: Principal, vault_id: Principal, id: u64, index: u32, attachments: &AttachmentsMap, chunks: &AttachmentChunksMap) -> Result<Vec<u8>, String> {
+    let key = AttachmentKey::new(user_id, vault_id, id);
+    if !_manifest(&key, attachments)?.complete {
+        return Err(format!("attachment {} is still uploading", id));
+    }
+    chunks.borrow().get(&key.chunk(index)).ok_or_else(|| format!("no chunk {}", index))
+}
+
+// Attachments of the vault, uploads in progress included.
+pub fn _list_attachments(user_id: Principal, vault_id: Principal, attachments: &AttachmentsMap) -> Vec<AttachmentInfo> {
+    let principals = AttachmentKey::new(user_id, vault_id, 0).principals;
+    attachments.borrow()
+        .range(_vault_range(&principals))
+        .map(|entry| {
+            let (key, manifest) = entry.into_pair();
+            AttachmentInfo::new(key.id, manifest)
+        })
+        .collect()
+}
+
+// Links the attachment to an item, or unlinks it. Links aren't checked against the vault: an
+// attachment outlives the item it belongs to until deleted itself.
+pub fn _link_attachment(user_id: Principal, vault_id: Principal, id: u64, link: Option<ItemRef>, now: u64, attachments: &AttachmentsMap) -> Result<AttachmentInfo, String> {
+    let key = AttachmentKey::new(user_id, vault_id, id);
+    let mut manifest = _manifest(&key, attachments)?;
+    manifest.link = link;
+    manifest.updated_at = now;
+    attachments.borrow_mut().insert(key, manifest.clone());
+    Ok(AttachmentInfo::new(id, manifest))
+}
+
+// Deletes an attachment, or abandons an upload, and gives its size back to the vault.
+pub fn _delete_attachment(user_id: Principal, vault_id: Principal, id: u64, now: u64, state: &GeneralState) -> Result<(), String> {
+    let key = AttachmentKey::new(user_id, vault_id, id);
+    let manifest = state.attachments.borrow_mut().remove(&key).ok_or_else(|| format!("no attachment {}", id))?;
+    let mut chunks = state.attachment_chunks.borrow_mut();
+    let keys: Vec<AttachmentChunkKey> = chunks.keys_range(_chunk_range(&key)).collect();
+    for chunk in keys.iter() {
+        chunks.remove(chunk);
+    }
+    drop(chunks);
+    _record_vault_change(user_id, vault_id, SizeChange { items: -1, bytes: -(manifest.size as i64) }, now, &state.vault_registry);
+    Ok(())
+}
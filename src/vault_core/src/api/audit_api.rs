@@ -0,0 +1,122 @@
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::{
+    stable::types::{AuditLog, AuditRetentionState, GeneralState},
+    vault_type::audit_log::{AuditEvent, AuditOp, AuditRetention, UserAuditKey},
+};
+
+/*
+    Append-only audit log of vault mutations and key derivations. Events record caller, time,
+    vault, operation kind and item counts only, never ciphertext. Each user's events are also
+    indexed by user, so their queries don't walk the whole log.
+*/
+
+// Upper bound on events pruned per append, so a retention change can't blow the instruction limit.
+const MAX_PRUNE_PER_APPEND: usize = 64;
+const MAX_PAGE_SIZE: u32 = 500;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub op: AuditOp,
+    pub item_count: u32,
+    pub caller: Principal,
+    pub user: Principal,
+    pub vault_id: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AuditPage {
+    pub entries: Vec<AuditEntry>,
+    // Pass back as `before` to fetch the next (older) page. None when there is nothing older.
+    pub next: Option<u64>,
+}
+
+fn to_entry(seq: u64, event: AuditEvent) -> AuditEntry {
+    AuditEntry {
+        seq,
+        timestamp: event.timestamp,
+        op: event.op,
+        item_count: event.item_count,
+        caller: Principal::from_slice(&event.caller),
+        user: Principal::from_slice(&event.user),
+        vault_id: if event.vault.is_empty() { None } else { Some(Principal::from_slice(&event.vault)) },
+    }
+}
+
+// Drops events that fall outside the retention window, oldest first, along with their index entries.
+fn _prune_audit_log(now: u64, retention: &AuditRetention, state: &GeneralState) {
+    let mut log = state.audit_log.borrow_mut();
+    let mut index = state.user_audit_index.borrow_mut();
+    for _ in 0..MAX_PRUNE_PER_APPEND {
+        let too_many = retention.max_entries != 0 && log.len() > retention.max_entries;
+        let too_old = match log.first_key_value() {
+            Some((_, oldest)) => retention.max_age_ns != 0 && now.saturating_sub(oldest.timestamp) > retention.max_age_ns,
+            None => false,
+        };
+        if !too_many && !too_old {
+            break;
+        }
+        if let Some((seq, event)) = log.pop_first() {
+            index.remove(&UserAuditKey::new(&event.user, seq));
+        }
+    }
+}
+
+pub fn _record_audit_event(event: AuditEvent, state: &GeneralState) {
+    let now = event.timestamp;
+    {
+        let mut log = state.audit_log.borrow_mut();
+        let seq = log.last_key_value().map_or(0, |(seq, _)| seq + 1);
+        state.user_audit_index.borrow_mut().insert(UserAuditKey::new(&event.user, seq), ());
+        log.insert(seq, event);
+    }
+    let retention = *state.audit_retention.borrow().get();
+    _prune_audit_log(now, &retention, state);
+}
+
+// Newest-first page of the events about one user's data, read through the user's index.
+pub fn _get_user_audit_log(user_id: Principal, before: Option<u64>, limit: u32, state: &GeneralState) -> AuditPage {
+    let user = user_id.as_slice();
+    let index = state.user_audit_index.borrow();
+    let log = state.audit_log.borrow();
+    let events = index.range(UserAuditKey::new(user, 0)..UserAuditKey::new(user, before.unwrap_or(u64::MAX)))
+        .rev()
+        .filter_map(|entry| {
+            let seq = entry.key().seq;
+            log.get(&seq).map(|event| (seq, event))
+        });
+    _get_audit_page(limit, events)
+}
+
+// Newest-first page of every event in the canister. Controller only.
+pub fn _get_audit_log(before: Option<u64>, limit: u32, log: &AuditLog) -> AuditPage {
+    let log = log.borrow();
+    _get_audit_page(limit, log.range(..before.unwrap_or(u64::MAX)).rev().map(|entry| entry.into_pair()))
+}
+
+fn _get_audit_page(limit: u32, events: impl Iterator<Item = (u64, AuditEvent)>) -> AuditPage {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let mut entries = Vec::new();
+    let mut next = None;
+
+    for (seq, event) in events {
+        if entries.len() == limit {
+            next = Some(seq + 1);
+            break;
+        }
+        entries.push(to_entry(seq, event));
+    }
+
+    AuditPage { entries, next }
+}
+
+pub fn _get_audit_retention(retention: &AuditRetentionState) -> AuditRetention {
+    *retention.borrow().get()
+}
+
+pub fn _set_audit_retention(new_retention: AuditRetention, now: u64, state: &GeneralState) {
+    state.audit_retention.borrow_mut().set(new_retention);
+    _prune_audit_log(now, &new_retention, state);
+}
@@ -0,0 +1,81 @@
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::{
+    stable::types::GeneralState,
+    vault_type::capacity::{CapacityRecord, CapacityState},
+};
+
+/*
+    Capacity handshake with the factory canister. A shared canister tells the factory when it
+    becomes near full and when it closes, and the factory answers with the canister new users
+    should go to instead. Closed canisters turn new users away with that redirect.
+*/
+
+// Share of the user limit at which a canister counts as near full.
+const NEAR_FULL_PERCENT: u64 = 90;
+
+// Attempts made per notification before waiting for the next admitted user to try again.
+pub const MAX_NOTIFY_ATTEMPTS: u8 = 4;
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct CapacityInfo {
+    pub state: CapacityState,
+    pub users: u64,
+    pub max_users: u64,
+    // Canister new users should use once this one is closed.
+    pub redirect: Option<Principal>,
+}
+
+pub fn _capacity_state(users: u64, max_users: u64) -> CapacityState {
+    if users >= max_users {
+        CapacityState::Closed
+    } else if users + 1 >= max_users || users * 100 >= max_users * NEAR_FULL_PERCENT {
+        CapacityState::NearFull
+    } else {
+        CapacityState::Open
+    }
+}
+
+pub fn _get_capacity(state: &GeneralState) -> CapacityInfo {
+    let users = state.canister_owners.borrow().user.len() as u64;
+    let max_users = state.config.borrow().get().max_users;
+    CapacityInfo {
+        state: _capacity_state(users, max_users),
+        users,
+        max_users,
+        redirect: state.capacity.borrow().get().redirect,
+    }
+}
+
+// Registers a new user unless the canister is closed, in which case the error names the
+// canister to use instead when the factory has given one.
+pub fn _admit_user(user: Principal, state: &GeneralState) -> Result<(), String> {
+    if state.canister_owners.borrow().user.contains(&user) {
+        return Ok(());
+    }
+    let capacity = _get_capacity(state);
+    if capacity.state == CapacityState::Closed {
+        return Err(match capacity.redirect {
+            Some(redirect) => format!("Canister at max user capacity, use canister {}", redirect),
+            None => "Canister at max user capacity".into(),
+        });
+    }
+    state.canister_owners.borrow_mut().user.push(user);
+    Ok(())
+}
+
+// True when the factory hasn't heard about the current state yet.
+pub fn _needs_capacity_notification(state: &GeneralState) -> bool {
+    _get_capacity(state).state != state.capacity.borrow().get().notified
+}
+
+// Keeps the state the factory acknowledged, along with the redirect it answered with.
+pub fn _record_capacity_notification(notified: CapacityState, redirect: Option<Principal>, now: u64, state: &GeneralState) {
+    let redirect = redirect.or(state.capacity.borrow().get().redirect);
+    state.capacity.borrow_mut().set(CapacityRecord { notified, notified_at: now, redirect });
+}
+
+pub fn _set_capacity_redirect(redirect: Option<Principal>, state: &GeneralState) {
+    let record = CapacityRecord { redirect, ..state.capacity.borrow().get().clone() };
+    state.capacity.borrow_mut().set(record);
+}
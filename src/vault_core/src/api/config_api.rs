@@ -0,0 +1,134 @@
+use std::collections::BTreeSet;
+
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::{
+    api::{deserialiser::deserialise_vault_names, dev_api::_get_vault_names},
+    stable::types::{GeneralState, VaultNamesMap},
+    vault_type::limits::{Limits, Tier, TierLimits},
+};
+
+/*
+    Runtime configuration. Gathers the limits kept in stable memory, the user limit from the
+    install arguments and the cycles threshold into one record that controllers read and
+    change without installing a new wasm.
+*/
+
+pub const DEFAULT_MAX_VAULTS_PER_USER: u64 = 3;
+pub const DEFAULT_MAX_VAULT_SIZE_BYTES: u64 = 1024 * 1024 * 1024; // 1 GB
+pub const PREMIUM_MAX_VAULTS_PER_USER: u64 = 20;
+pub const PREMIUM_MAX_VAULT_SIZE_BYTES: u64 = 4 * 1024 * 1024 * 1024; // 4 GB
+pub const DEFAULT_MAX_USER_STORAGE: u64 = 400 * 1024 * 1024 * 1024; // 400 GB
+pub const DEFAULT_VETKD_MAX_INPUT_BYTES: u32 = 1024;
+
+// Bounds on what controllers may set.
+const MIN_VAULT_SIZE_BYTES: u64 = 1024 * 1024; // 1 MB
+const MAX_VETKD_INPUT_BYTES: u32 = 32 * 1024;
+
+// Users that fit in `storage` when each may fill `per_user` bytes.
+pub const fn _users_for_storage(storage: u64, per_user: u64) -> u64 {
+    storage / per_user
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct RuntimeConfig {
+    // The tier this canister serves, whose limits apply to its users.
+    pub tier: Tier,
+    pub max_users: u64,
+    pub min_cycles_balance: u128,
+    pub limits: Limits,
+}
+
+// Fields left out stay as they are.
+#[derive(CandidType, Deserialize, Clone, Default, Debug)]
+pub struct ConfigUpdate {
+    pub max_users: Option<u64>,
+    pub min_cycles_balance: Option<u128>,
+    pub max_user_storage_bytes: Option<u64>,
+    pub vetkd_max_input_bytes: Option<u32>,
+    pub free: Option<TierLimits>,
+    pub premium: Option<TierLimits>,
+}
+
+pub fn _get_config(tier: Tier, state: &GeneralState) -> RuntimeConfig {
+    RuntimeConfig {
+        tier,
+        max_users: state.config.borrow().get().max_users,
+        min_cycles_balance: state.cycles_settings.borrow().get().min_balance,
+        limits: *state.limits.borrow().get(),
+    }
+}
+
+fn _validate_tier(name: &str, limits: &TierLimits) -> Result<(), String> {
+    if limits.max_vaults_per_user == 0 {
+        return Err(format!("{} tier must allow at least one vault", name));
+    }
+    if limits.max_vault_size_bytes < MIN_VAULT_SIZE_BYTES {
+        return Err(format!("{} tier vault size is under {} bytes", name, MIN_VAULT_SIZE_BYTES));
+    }
+    Ok(())
+}
+
+// Applies the update once every field is valid and the users still fit the storage budget.
+// Returns the number of fields that changed.
+pub fn _update_config(update: ConfigUpdate, tier: Tier, state: &GeneralState) -> Result<u32, String> {
+    let current = _get_config(tier, state);
+    let mut config = current.clone();
+    config.max_users = update.max_users.unwrap_or(config.max_users);
+    config.min_cycles_balance = update.min_cycles_balance.unwrap_or(config.min_cycles_balance);
+    config.limits.max_user_storage_bytes = update.max_user_storage_bytes.unwrap_or(config.limits.max_user_storage_bytes);
+    config.limits.vetkd_max_input_bytes = update.vetkd_max_input_bytes.unwrap_or(config.limits.vetkd_max_input_bytes);
+    config.limits.free = update.free.unwrap_or(config.limits.free);
+    config.limits.premium = update.premium.unwrap_or(config.limits.premium);
+
+    if config.max_users == 0 {
+        return Err("max users can't be zero".into());
+    }
+    if !(1..=MAX_VETKD_INPUT_BYTES).contains(&config.limits.vetkd_max_input_bytes) {
+        return Err("vetKD input limit out of range".into());
+    }
+    _validate_tier("free", &config.limits.free)?;
+    _validate_tier("premium", &config.limits.premium)?;
+    let needed = config.max_users.saturating_mul(config.limits.tier(tier).storage_per_user());
+    if needed > config.limits.max_user_storage_bytes {
+        return Err(format!("{} users need {} bytes, over the {} byte storage budget", config.max_users, needed, config.limits.max_user_storage_bytes));
+    }
+
+    let changed = [
+        config.max_users != current.max_users,
+        config.min_cycles_balance != current.min_cycles_balance,
+        config.limits.max_user_storage_bytes != current.limits.max_user_storage_bytes,
+        config.limits.vetkd_max_input_bytes != current.limits.vetkd_max_input_bytes,
+        config.limits.free != current.limits.free,
+        config.limits.premium != current.limits.premium,
+    ];
+    let mut canister_config = state.config.borrow().get().clone();
+    canister_config.max_users = config.max_users;
+    state.config.borrow_mut().set(canister_config);
+    let mut cycles_settings = *state.cycles_settings.borrow().get();
+    cycles_settings.min_balance = config.min_cycles_balance;
+    state.cycles_settings.borrow_mut().set(cycles_settings);
+    state.limits.borrow_mut().set(config.limits);
+    Ok(changed.iter().filter(|changed| **changed).count() as u32)
+}
+
+// Turns away a names update that would take the user past the tier's vault limit. Users
+// already over a lowered limit can still rename and remove vaults.
+pub fn _assert_vault_limit(user_id: Principal, update: &Vec<u8>, max_vaults: u64, vnm: &VaultNamesMap) -> Result<(), String> {
+    if update.is_empty() {
+        return Ok(());
+    }
+    let existing: BTreeSet<Vec<u8>> = _get_vault_names(user_id, vnm).names.into_keys().collect();
+    let mut vaults = existing.clone();
+    for name in deserialise_vault_names(update).names {
+        if name.vault_name.is_empty() {
+            vaults.remove(&name.vault_id);
+        } else {
+            vaults.insert(name.vault_id);
+        }
+    }
+    if vaults.len() > existing.len() && vaults.len() as u64 > max_vaults {
+        return Err(format!("vault limit of {} reached", max_vaults));
+    }
+    Ok(())
+}
@@ -0,0 +1,57 @@
+use candid::{CandidType, Deserialize};
+
+use crate::{
+    stable::types::GeneralState,
+    vault_type::cycles::{CyclesSettings, TopUpRecord},
+};
+
+/*
+    Cycles monitoring. A timer checks the balance at a configurable interval and asks the
+    factory canister for a top-up when it falls under the threshold. Failed requests are
+    retried with exponential backoff, and the outcome of the last request is kept.
+*/
+
+pub const DEFAULT_MIN_CYCLES_BALANCE: u128 = 1_000_000_000;
+pub const DEFAULT_TOP_UP_AMOUNT: u128 = 1_000_000_000_000; // 1T cycles
+pub const DEFAULT_CYCLES_CHECK_INTERVAL_NS: u64 = 60 * 60 * 1_000_000_000; // 1 hour
+const MIN_CYCLES_CHECK_INTERVAL_NS: u64 = 60 * 1_000_000_000; // 1 minute
+const MAX_CYCLES_CHECK_INTERVAL_NS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000; // 7 days
+
+// Attempts made per top-up before giving up until the next check.
+pub const MAX_TOP_UP_ATTEMPTS: u8 = 4;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CyclesStatus {
+    pub balance: u128,
+    pub settings: CyclesSettings,
+    pub top_up_in_progress: bool,
+    pub last_top_up: Option<TopUpRecord>,
+}
+
+pub fn _needs_top_up(balance: u128, settings: &CyclesSettings) -> bool {
+    balance < settings.min_balance
+}
+
+pub fn _set_cycles_settings(settings: CyclesSettings, state: &GeneralState) -> Result<(), String> {
+    if !(MIN_CYCLES_CHECK_INTERVAL_NS..=MAX_CYCLES_CHECK_INTERVAL_NS).contains(&settings.check_interval_ns) {
+        return Err("check interval out of range".into());
+    }
+    if settings.top_up_amount == 0 {
+        return Err("top-up amount can't be zero".into());
+    }
+    state.cycles_settings.borrow_mut().set(settings);
+    Ok(())
+}
+
+pub fn _record_top_up(record: TopUpRecord, state: &GeneralState) {
+    state.last_top_up.borrow_mut().set(Some(record));
+}
+
+pub fn _get_cycles_status(balance: u128, top_up_in_progress: bool, state: &GeneralState) -> CyclesStatus {
+    CyclesStatus {
+        balance,
+        settings: *state.cycles_settings.borrow().get(),
+        top_up_in_progress,
+        last_top_up: state.last_top_up.borrow().get().clone(),
+    }
+}
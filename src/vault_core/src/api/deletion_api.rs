@@ -0,0 +1,255 @@
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::{
+    api::{deserialiser::deserialise_vault_names, registry_api::_refresh_vault_status, serial_api::{_delete_vault_batch, _first_user_vault}},
+    stable::types::{DeletionCursorsMap, GeneralState, PendingDeletionsMap},
+    vault_type::pending_deletion::{DeletionCursor, DeletionKind, DeletionStage, DeletionStatus, PendingDeletion, PendingDeletionKey},
+};
+
+/*
+    Delayed destructive operations. Deleting a vault or purging a user is scheduled rather than
+    carried out straight away. While pending, the affected vaults are read-only and the user can
+    cancel. Once the grace period has passed the deletion runs in bounded batches, one timer tick
+    at a time, with its cursor kept in stable memory so it survives upgrades.
+*/
+
+pub const DEFAULT_DELETION_GRACE_PERIOD_NS: u64 = 48 * 60 * 60 * 1_000_000_000; // 48 hours
+pub const MAX_DELETION_GRACE_PERIOD_NS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000; // 30 days
+
+// Entries removed from a single map before checking whether to yield.
+const DELETION_BATCH_SIZE: usize = 100;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PendingDeletionInfo {
+    pub kind: DeletionKind,
+    pub status: DeletionStatus,
+    pub vault_id: Option<Principal>,
+    pub requested_at: u64,
+    pub execute_at: u64,
+    // Progress once running: entries removed so far and the stage reached in the vault being
+    // deleted. User purges go through the stages once per vault.
+    pub removed: u64,
+    pub stage: Option<DeletionStage>,
+}
+impl PendingDeletionInfo {
+    fn from(pending: &PendingDeletion, cursor: Option<DeletionCursor>) -> Self {
+        Self {
+            kind: pending.kind,
+            status: pending.status,
+            vault_id: if pending.vault.is_empty() { None } else { Some(Principal::from_slice(&pending.vault)) },
+            requested_at: pending.requested_at,
+            execute_at: pending.execute_at,
+            removed: pending.removed,
+            stage: cursor.map(|cursor| cursor.stage),
+        }
+    }
+}
+
+// A deletion carried out to the end by `_run_due_deletions`.
+pub struct CompletedDeletion {
+    pub kind: DeletionKind,
+    pub user_id: Principal,
+    pub vault_id: Option<Principal>,
+    pub removed: u64,
+}
+
+pub fn _is_deletion_pending(user_id: Principal, vault_id: Option<Principal>, pd: &PendingDeletionsMap) -> bool {
+    let pd = pd.borrow();
+    if pd.contains_key(&PendingDeletionKey::for_user(user_id)) {
+        return true;
+    }
+    match vault_id {
+        Some(vault_id) => pd.contains_key(&PendingDeletionKey::for_vault(user_id, vault_id)),
+        None => false,
+    }
+}
+
+pub fn _assert_vault_writable(user_id: Principal, vault_id: Principal, pd: &PendingDeletionsMap) -> Result<(), String> {
+    if _is_deletion_pending(user_id, Some(vault_id), pd) {
+        return Err("vault is pending deletion and read-only".into());
+    }
+    Ok(())
+}
+
+// Vault name syncs can touch several vaults at once, so check every vault they name.
+pub fn _assert_names_writable(user_id: Principal, update: &Vec<u8>, pd: &PendingDeletionsMap) -> Result<(), String> {
+    if _is_deletion_pending(user_id, None, pd) {
+        return Err("user is pending deletion and read-only".into());
+    }
+    if update.is_empty() {
+        return Ok(());
+    }
+    for name in deserialise_vault_names(update).names.iter() {
+        let key = PendingDeletionKey { principals: [user_id.as_slice(), &name.vault_id].concat() };
+        if pd.borrow().contains_key(&key) {
+            return Err("vault is pending deletion and read-only".into());
+        }
+    }
+    Ok(())
+}
+
+fn _schedule(key: PendingDeletionKey, pending: PendingDeletion, pd: &PendingDeletionsMap) -> Result<PendingDeletionInfo, String> {
+    let mut pd = pd.borrow_mut();
+    if pd.contains_key(&key) {
+        return Err("deletion already scheduled".into());
+    }
+    let info = PendingDeletionInfo::from(&pending, None);
+    pd.insert(key, pending);
+    Ok(info)
+}
+
+pub fn _schedule_vault_deletion(user_id: Principal, vault_id: Principal, now: u64, state: &GeneralState) -> Result<PendingDeletionInfo, String> {
+    if _is_deletion_pending(user_id, None, &state.pending_deletions) {
+        return Err("user is already pending deletion".into());
+    }
+    let grace_period = *state.deletion_grace_period.borrow().get();
+    let pending = PendingDeletion {
+        kind: DeletionKind::Vault,
+        status: DeletionStatus::Scheduled,
+        requested_at: now,
+        execute_at: now.saturating_add(grace_period),
+        removed: 0,
+        user: user_id.as_slice().to_vec(),
+        vault: vault_id.as_slice().to_vec(),
+    };
+    let info = _schedule(PendingDeletionKey::for_vault(user_id, vault_id), pending, &state.pending_deletions)?;
+    _refresh_vault_status(user_id, Some(vault_id), state);
+    Ok(info)
+}
+
+pub fn _schedule_user_purge(user_id: Principal, now: u64, state: &GeneralState) -> Result<PendingDeletionInfo, String> {
+    let grace_period = *state.deletion_grace_period.borrow().get();
+    let info = _schedule(PendingDeletionKey::for_user(user_id), _user_purge(user_id, now, now.saturating_add(grace_period), DeletionStatus::Scheduled), &state.pending_deletions)?;
+    _refresh_vault_status(user_id, None, state);
+    Ok(info)
+}
+
+// Purges a user on the next timer tick, without a grace period or the chance to cancel. For
+// data the user never got to use, such as the remains of a failed import.
+pub fn _schedule_immediate_purge(user_id: Principal, now: u64, state: &GeneralState) -> Result<PendingDeletionInfo, String> {
+    let info = _schedule(PendingDeletionKey::for_user(user_id), _user_purge(user_id, now, now, DeletionStatus::Running), &state.pending_deletions)?;
+    _refresh_vault_status(user_id, None, state);
+    Ok(info)
+}
+
+fn _user_purge(user_id: Principal, now: u64, execute_at: u64, status: DeletionStatus) -> PendingDeletion {
+    PendingDeletion {
+        kind: DeletionKind::User,
+        status,
+        requested_at: now,
+        execute_at,
+        removed: 0,
+        user: user_id.as_slice().to_vec(),
+        vault: Vec::new(),
+    }
+}
+
+// Cancels a scheduled vault deletion, or the user purge when no vault is given.
+pub fn _cancel_deletion(user_id: Principal, vault_id: Option<Principal>, state: &GeneralState) -> Result<(), String> {
+    let key = match vault_id {
+        Some(vault_id) => PendingDeletionKey::for_vault(user_id, vault_id),
+        None => PendingDeletionKey::for_user(user_id),
+    };
+    let pending = state.pending_deletions.borrow().get(&key);
+    match pending {
+        None => return Err("no deletion scheduled".into()),
+        Some(pending) if pending.status == DeletionStatus::Running => return Err("deletion already running".into()),
+        Some(_) => state.pending_deletions.borrow_mut().remove(&key),
+    };
+    _refresh_vault_status(user_id, vault_id, state);
+    Ok(())
+}
+
+pub fn _get_pending_deletions(user_id: Principal, pd: &PendingDeletionsMap, cursors: &DeletionCursorsMap) -> Vec<PendingDeletionInfo> {
+    let user = user_id.as_slice();
+    let cursors = cursors.borrow();
+    pd.borrow()
+        .iter()
+        .filter(|entry| entry.value().user == user)
+        .map(|entry| PendingDeletionInfo::from(&entry.value(), cursors.get(entry.key())))
+        .collect()
+}
+
+// When the next deletion is due, if any. Used to arm the deletion timer.
+pub fn _next_deletion_due(pd: &PendingDeletionsMap) -> Option<u64> {
+    pd.borrow().values().map(|pending| pending.execute_at).min()
+}
+
+pub fn _set_deletion_grace_period(grace_period_ns: u64, state: &GeneralState) -> Result<(), String> {
+    if grace_period_ns > MAX_DELETION_GRACE_PERIOD_NS {
+        return Err("grace period too long".into());
+    }
+    state.deletion_grace_period.borrow_mut().set(grace_period_ns);
+    Ok(())
+}
+
+// Carries out deletions whose grace period has passed, stopping as soon as `should_yield`
+// asks to. Progress is kept in the pending entry and its cursor so the next call picks up
+// where this one stopped. Returns the deletions that completed.
+pub fn _run_due_deletions(now: u64, state: &GeneralState, should_yield: &dyn Fn() -> bool) -> Vec<CompletedDeletion> {
+    let due: Vec<PendingDeletionKey> = state.pending_deletions.borrow()
+        .iter()
+        .filter(|entry| entry.value().execute_at <= now)
+        .map(|entry| entry.key().clone())
+        .collect();
+
+    let mut completed = Vec::new();
+    for key in due {
+        let mut pending = match state.pending_deletions.borrow().get(&key) {
+            Some(pending) => pending,
+            None => continue,
+        };
+        pending.status = DeletionStatus::Running;
+        let user_id = Principal::from_slice(&pending.user);
+        let cursor = state.deletion_cursors.borrow().get(&key).unwrap_or_default();
+
+        let next = match pending.kind {
+            DeletionKind::Vault => {
+                let (removed, next) = _delete_vault_batch(&key.principals, cursor, state, DELETION_BATCH_SIZE, should_yield);
+                pending.removed += removed;
+                next
+            }
+            // Vaults go one after the other. A vault's registry entry is removed last, so the
+            // first vault left is always the one the cursor belongs to.
+            DeletionKind::User => {
+                let mut cursor = cursor;
+                loop {
+                    let Some(principals) = _first_user_vault(&pending.user, &state.vault_registry) else {
+                        break None;
+                    };
+                    let (removed, next) = _delete_vault_batch(&principals, cursor, state, DELETION_BATCH_SIZE, should_yield);
+                    pending.removed += removed;
+                    cursor = DeletionCursor::default();
+                    if next.is_some() || should_yield() {
+                        break Some(next.unwrap_or_default());
+                    }
+                }
+            }
+        };
+
+        match next {
+            None => {
+                state.pending_deletions.borrow_mut().remove(&key);
+                state.deletion_cursors.borrow_mut().remove(&key);
+                completed.push(CompletedDeletion {
+                    kind: pending.kind,
+                    user_id,
+                    vault_id: if pending.vault.is_empty() { None } else { Some(Principal::from_slice(&pending.vault)) },
+                    removed: pending.removed,
+                });
+            }
+            Some(cursor) => {
+                let vault_id = (!pending.vault.is_empty()).then(|| Principal::from_slice(&pending.vault));
+                state.deletion_cursors.borrow_mut().insert(key.clone(), cursor);
+                state.pending_deletions.borrow_mut().insert(key, pending);
+                _refresh_vault_status(user_id, vault_id, state);
+                break;
+            }
+        }
+
+        if should_yield() {
+            break;
+        }
+    }
+    completed
+}
@@ -1,4 +1,4 @@
-use crate::api::deserialiser_types::{SecureNotesData, SpreadsheetColumns, VaultNames};
+use crate::{api::deserialiser_types::{CustomRecordsData, DeleteIndexes, FoldersData, ItemLabelsData, RecordsData, SearchTokensData, TagsData, SecureNotesData, SpreadsheetColumns, TotpData, VaultNames}, vault_type::records::RecordKind};
 
 use super::deserialiser_types::{Cells, DeleteCells, LoginData, LoginMetadata, GlobalSyncData};
 
@@ -55,6 +55,53 @@ pub fn deserialise_secure_notes(data: Vec<u8>) -> SecureNotesData {
     SecureNotesData::new(data)
 }
 
+/*
+    TOTP seed deserialisers
+*/
+pub fn deserialise_totp(data: &[u8]) -> TotpData {
+    TotpData::new(data)
+}
+
+/*
+    Payment card and identity deserialiser
+*/
+pub fn deserialise_records(data: &[u8], kind: RecordKind) -> RecordsData {
+    RecordsData::new(data, kind)
+}
+
+/*
+    Custom record deserialiser
+*/
+pub fn deserialise_custom_records(data: &[u8]) -> CustomRecordsData {
+    CustomRecordsData::new(data)
+}
+
+/*
+    Folder, tag and item label deserialisers
+*/
+pub fn deserialise_folders(data: &[u8]) -> FoldersData {
+    FoldersData::new(data)
+}
+
+pub fn deserialise_tags(data: &[u8]) -> TagsData {
+    TagsData::new(data)
+}
+
+pub fn deserialise_item_labels(data: &[u8]) -> ItemLabelsData {
+    ItemLabelsData::new(data)
+}
+
+pub fn deserialise_search_tokens(data: &[u8]) -> SearchTokensData {
+    SearchTokensData::new(data)
+}
+
+/*
+    Deletes by index, for TOTP seeds, typed and custom records
+*/
+pub fn deserialise_delete_indexes(data: Vec<u8>) -> DeleteIndexes {
+    DeleteIndexes::new(data)
+}
+
 /*
  * Global sync deserialiser
  */
@@ -1,3 +1,5 @@
+use crate::vault_type::{attachments::ItemRef, records::{CardBrand, IdentityDocument, RecordKind}, spreadsheet::{ColumnKind, ColumnSettings, COLUMN_HIDDEN, COLUMN_TYPED}, totp::TotpAlgorithm};
+
 // Fixed-size header for vault name data. 
 pub struct VaultNameHeader {
     pub principal_size: u8,
@@ -137,30 +139,47 @@ impl DeleteCells {
 }
 
 // Identifies the name and other metadata (such as show/hide status) of a spreadsheet column
+// The hidden byte doubles as flags: COLUMN_HIDDEN, and COLUMN_TYPED when a kind (u8) and
+// settings follow x. Headers without it are from clients that predate column kinds.
 pub struct SpreadsheetColumnHeader {
     pub name_size : u16,
     pub hidden : u8,
-    pub x: u8
+    pub x: u8,
+    pub kind: ColumnKind,
+    pub settings: ColumnSettings,
 }
 impl SpreadsheetColumnHeader {
+    pub const SIZE: usize = 4;
+    pub const TYPED_SIZE: usize = Self::SIZE + 1 + ColumnSettings::SIZE;
+
     pub fn new(header : &Vec<u8>) -> Self {
         let name_size = u16::from_be_bytes([header[0], header[1]]);
         let hidden = u8::from_be_bytes([header[2]]);
         let x = u8::from_be_bytes([header[3]]);
-        Self { name_size, hidden, x }
+        if hidden & COLUMN_TYPED == 0 {
+            return Self { name_size, hidden, x, kind: ColumnKind::Text, settings: ColumnSettings::default() };
+        }
+        let kind = ColumnKind::from_byte(header[4]);
+        let settings = ColumnSettings::from_bytes(&header[5..Self::TYPED_SIZE]);
+        Self { name_size, hidden: hidden & COLUMN_HIDDEN, x, kind, settings }
     }
 }
 
 pub struct SpreadsheetColumnEntry {
     pub header: SpreadsheetColumnHeader,
-    pub name: Vec<u8>
+    pub name: Vec<u8>,
+    // Bytes taken by the entry, header included.
+    pub size: usize,
 }
 impl SpreadsheetColumnEntry {
     pub fn new(data: &Vec<u8>) -> Self {
         let header = SpreadsheetColumnHeader::new(data);
+        let start = if data[2] & COLUMN_TYPED != 0 { SpreadsheetColumnHeader::TYPED_SIZE } else { SpreadsheetColumnHeader::SIZE };
+        let size = start + header.name_size as usize;
         Self {
-            name: data[4..4+(header.name_size as usize)].to_vec(),
-            header
+            name: data[start..size].to_vec(),
+            header,
+            size,
         }
     }
 }
@@ -175,7 +194,7 @@ impl SpreadsheetColumns {
 
         while index < data.len() {
             let entry = SpreadsheetColumnEntry::new(&data[index..].to_vec());
-            index += 4 + (entry.header.name_size as usize);
+            index += entry.size;
             result.push(entry);
         }
 
@@ -321,18 +340,439 @@ impl SecureNotesData {
     }
 }
 
+/*
+    TOTP seeds
+*/
+
+// Fixed-size header for a TOTP seed. The generation parameters travel in the clear; a linked
+// login column is given by has_link and link_x.
+pub struct TotpHeader {
+    pub label_size: u8,
+    pub seed_size: u16,
+    pub x: u8,
+    pub algorithm: u8,
+    pub digits: u8,
+    pub period: u16,
+    pub has_link: u8,
+    pub link_x: u8,
+}
+impl TotpHeader {
+    pub const SIZE: usize = 10;
+
+    pub fn new(header: &[u8]) -> Self {
+        Self {
+            label_size: header[0],
+            seed_size: u16::from_be_bytes([header[1], header[2]]),
+            x: header[3],
+            algorithm: header[4],
+            digits: header[5],
+            period: u16::from_be_bytes([header[6], header[7]]),
+            has_link: header[8],
+            link_x: header[9],
+        }
+    }
+}
+
+// A seed and its label, both ciphertext. An empty seed removes the entry at x.
+pub struct TotpEntry {
+    pub header: TotpHeader,
+    pub label: Vec<u8>,
+    pub seed: Vec<u8>,
+}
+impl TotpEntry {
+    pub fn new(data: &[u8]) -> Self {
+        let header = TotpHeader::new(&data[..TotpHeader::SIZE]);
+        let label_end = TotpHeader::SIZE + header.label_size as usize;
+        let label = data[TotpHeader::SIZE..label_end].to_vec();
+        let seed = data[label_end..label_end + header.seed_size as usize].to_vec();
+        if !seed.is_empty() {
+            assert!(TotpAlgorithm::from_byte(header.algorithm).is_some(), "Unknown TOTP algorithm {}", header.algorithm);
+            assert!((6..=8).contains(&header.digits), "TOTP codes must have 6 to 8 digits");
+            assert!(header.period > 0, "TOTP period must be positive");
+        }
+        Self { header, label, seed }
+    }
+}
+
+pub struct TotpData {
+    pub seeds: Vec<TotpEntry>,
+}
+impl TotpData {
+    pub fn new(data: &[u8]) -> Self {
+        let mut index = 0;
+        let mut seeds = Vec::new();
+        while index < data.len() {
+            let entry = TotpEntry::new(&data[index..]);
+            index += TotpHeader::SIZE + entry.header.label_size as usize + entry.header.seed_size as usize;
+            seeds.push(entry);
+        }
+        Self { seeds }
+    }
+}
+
+/*
+    Payment cards and identity documents
+*/
+
+// Fixed-size header for a typed record. The type tag and expiry travel in the clear; the
+// fields follow as fields_size bytes of field id (u8), value size (u16) and value.
+pub struct RecordHeader {
+    pub fields_size: u16,
+    pub x: u8,
+    pub type_tag: u8,
+    pub has_expiry: u8,
+    pub expiry_year: u16,
+    pub expiry_month: u8,
+}
+impl RecordHeader {
+    pub const SIZE: usize = 8;
+
+    pub fn new(header: &[u8]) -> Self {
+        Self {
+            fields_size: u16::from_be_bytes([header[0], header[1]]),
+            x: header[2],
+            type_tag: header[3],
+            has_expiry: header[4],
+            expiry_year: u16::from_be_bytes([header[5], header[6]]),
+            expiry_month: header[7],
+        }
+    }
+}
+
+// A record and its ciphertext fields. A record without fields removes the entry at x.
+pub struct RecordEntry {
+    pub header: RecordHeader,
+    pub fields: Vec<(u8, Vec<u8>)>,
+}
+impl RecordEntry {
+    pub fn new(data: &[u8], kind: RecordKind) -> Self {
+        let header = RecordHeader::new(&data[..RecordHeader::SIZE]);
+        let body = &data[RecordHeader::SIZE..RecordHeader::SIZE + header.fields_size as usize];
+        let mut fields = Vec::new();
+        let mut index = 0;
+        while index < body.len() {
+            let size = u16::from_be_bytes([body[index + 1], body[index + 2]]) as usize;
+            fields.push((body[index], body[index + 3..index + 3 + size].to_vec()));
+            index += 3 + size;
+        }
+        if !fields.is_empty() {
+            let known = match kind {
+                RecordKind::PaymentCard => CardBrand::from_byte(header.type_tag).is_some(),
+                RecordKind::Identity => IdentityDocument::from_byte(header.type_tag).is_some(),
+            };
+            assert!(known, "Unknown {:?} type {}", kind, header.type_tag);
+            assert!(header.has_expiry == 0 || (1..=12).contains(&header.expiry_month), "Expiry month must be 1 to 12");
+        }
+        Self { header, fields }
+    }
+}
+
+pub struct RecordsData {
+    pub records: Vec<RecordEntry>,
+}
+impl RecordsData {
+    pub fn new(data: &[u8], kind: RecordKind) -> Self {
+        let mut index = 0;
+        let mut records = Vec::new();
+        while index < data.len() {
+            let entry = RecordEntry::new(&data[index..], kind);
+            index += RecordHeader::SIZE + entry.header.fields_size as usize;
+            records.push(entry);
+        }
+        Self { records }
+    }
+}
+
+/*
+    Custom records
+*/
+
+// Fixed-size header for a record made from template `template`. The values follow as
+// values_size bytes of value size (u16) and value, one per template field.
+pub struct CustomRecordHeader {
+    pub values_size: u16,
+    pub x: u8,
+    pub template: u8,
+}
+impl CustomRecordHeader {
+    pub const SIZE: usize = 4;
+
+    pub fn new(header: &[u8]) -> Self {
+        Self {
+            values_size: u16::from_be_bytes([header[0], header[1]]),
+            x: header[2],
+            template: header[3],
+        }
+    }
+}
+
+// A record and its values. A record without values removes the entry at x.
+pub struct CustomRecordEntry {
+    pub header: CustomRecordHeader,
+    pub values: Vec<Vec<u8>>,
+}
+impl CustomRecordEntry {
+    pub fn new(data: &[u8]) -> Self {
+        let header = CustomRecordHeader::new(&data[..CustomRecordHeader::SIZE]);
+        let body = &data[CustomRecordHeader::SIZE..CustomRecordHeader::SIZE + header.values_size as usize];
+        let mut values = Vec::new();
+        let mut index = 0;
+        while index < body.len() {
+            let size = u16::from_be_bytes([body[index], body[index + 1]]) as usize;
+            values.push(body[index + 2..index + 2 + size].to_vec());
+            index += 2 + size;
+        }
+        Self { header, values }
+    }
+}
+
+pub struct CustomRecordsData {
+    pub records: Vec<CustomRecordEntry>,
+}
+impl CustomRecordsData {
+    pub fn new(data: &[u8]) -> Self {
+        let mut index = 0;
+        let mut records = Vec::new();
+        while index < data.len() {
+            let entry = CustomRecordEntry::new(&data[index..]);
+            index += CustomRecordHeader::SIZE + entry.header.values_size as usize;
+            records.push(entry);
+        }
+        Self { records }
+    }
+}
+
+/*
+    Folders, tags and the labels of items
+*/
+
+// Fixed-size header for a folder, followed by its name. A folder without a name is removed.
+pub struct FolderHeader {
+    pub name_size: u16,
+    pub id: u64,
+    pub parent: Option<u64>,
+}
+impl FolderHeader {
+    pub const SIZE: usize = 19;
+
+    pub fn new(header: &[u8]) -> Self {
+        Self {
+            name_size: u16::from_be_bytes([header[0], header[1]]),
+            id: u64::from_be_bytes(header[2..10].try_into().unwrap()),
+            parent: (header[10] != 0).then(|| u64::from_be_bytes(header[11..19].try_into().unwrap())),
+        }
+    }
+}
+
+pub struct FolderEntry {
+    pub header: FolderHeader,
+    pub name: Vec<u8>,
+}
+
+pub struct FoldersData {
+    pub folders: Vec<FolderEntry>,
+}
+impl FoldersData {
+    pub fn new(data: &[u8]) -> Self {
+        let mut index = 0;
+        let mut folders = Vec::new();
+        while index < data.len() {
+            let header = FolderHeader::new(&data[index..index + FolderHeader::SIZE]);
+            let end = index + FolderHeader::SIZE + header.name_size as usize;
+            folders.push(FolderEntry { name: data[index + FolderHeader::SIZE..end].to_vec(), header });
+            index = end;
+        }
+        Self { folders }
+    }
+}
+
+// Fixed-size header for a tag, followed by its id and label. A tag without a label is removed.
+pub struct TagHeader {
+    pub label_size: u16,
+    pub id_size: u8,
+}
+impl TagHeader {
+    pub const SIZE: usize = 3;
+
+    pub fn new(header: &[u8]) -> Self {
+        Self { label_size: u16::from_be_bytes([header[0], header[1]]), id_size: header[2] }
+    }
+}
+
+pub struct TagEntry {
+    pub id: Vec<u8>,
+    pub label: Vec<u8>,
+}
+
+pub struct TagsData {
+    pub tags: Vec<TagEntry>,
+}
+impl TagsData {
+    pub fn new(data: &[u8]) -> Self {
+        let mut index = 0;
+        let mut tags = Vec::new();
+        while index < data.len() {
+            let header = TagHeader::new(&data[index..index + TagHeader::SIZE]);
+            let id_end = index + TagHeader::SIZE + header.id_size as usize;
+            let end = id_end + header.label_size as usize;
+            tags.push(TagEntry { id: data[index + TagHeader::SIZE..id_end].to_vec(), label: data[id_end..end].to_vec() });
+            index = end;
+        }
+        Self { tags }
+    }
+}
+
+// Fixed-size header for the labels of an item, followed by tags_size bytes of tag id size (u8)
+// and tag id. An item without folder or tags loses its labels.
+pub struct ItemLabelsHeader {
+    pub item: ItemRef,
+    pub folder: Option<u64>,
+    pub tags_size: u16,
+}
+impl ItemLabelsHeader {
+    pub const SIZE: usize = 15;
+
+    pub fn new(header: &[u8]) -> Self {
+        Self {
+            item: ItemRef::from_bytes(&header[0..4]).unwrap_or_else(|| panic!("Unknown item kind {}", header[0])),
+            folder: (header[4] != 0).then(|| u64::from_be_bytes(header[5..13].try_into().unwrap())),
+            tags_size: u16::from_be_bytes([header[13], header[14]]),
+        }
+    }
+}
+
+pub struct ItemLabelsEntry {
+    pub header: ItemLabelsHeader,
+    pub tags: Vec<Vec<u8>>,
+}
+
+pub struct ItemLabelsData {
+    pub items: Vec<ItemLabelsEntry>,
+}
+impl ItemLabelsData {
+    pub fn new(data: &[u8]) -> Self {
+        let mut index = 0;
+        let mut items = Vec::new();
+        while index < data.len() {
+            let header = ItemLabelsHeader::new(&data[index..index + ItemLabelsHeader::SIZE]);
+            let end = index + ItemLabelsHeader::SIZE + header.tags_size as usize;
+            let mut tags = Vec::new();
+            let mut tag = index + ItemLabelsHeader::SIZE;
+            while tag < end {
+                let tag_end = tag + 1 + usize::from(data[tag]);
+                tags.push(data[tag + 1..tag_end].to_vec());
+                tag = tag_end;
+            }
+            items.push(ItemLabelsEntry { header, tags });
+            index = end;
+        }
+        Self { items }
+    }
+}
+
+// Fixed-size header for the blind-index tokens of an item, followed by tokens_size bytes of
+// token size (u8) and token. An item without tokens leaves the index.
+pub struct SearchTokensHeader {
+    pub item: ItemRef,
+    pub tokens_size: u16,
+}
+impl SearchTokensHeader {
+    pub const SIZE: usize = 6;
+
+    pub fn new(header: &[u8]) -> Self {
+        Self {
+            item: ItemRef::from_bytes(&header[0..4]).unwrap_or_else(|| panic!("Unknown item kind {}", header[0])),
+            tokens_size: u16::from_be_bytes([header[4], header[5]]),
+        }
+    }
+}
+
+pub struct SearchTokensEntry {
+    pub header: SearchTokensHeader,
+    pub tokens: Vec<Vec<u8>>,
+}
+
+pub struct SearchTokensData {
+    pub items: Vec<SearchTokensEntry>,
+}
+impl SearchTokensData {
+    pub fn new(data: &[u8]) -> Self {
+        let mut index = 0;
+        let mut items = Vec::new();
+        while index < data.len() {
+            let header = SearchTokensHeader::new(&data[index..index + SearchTokensHeader::SIZE]);
+            let end = index + SearchTokensHeader::SIZE + header.tokens_size as usize;
+            let mut tokens = Vec::new();
+            let mut token = index + SearchTokensHeader::SIZE;
+            while token < end {
+                let token_end = token + 1 + usize::from(data[token]);
+                tokens.push(data[token + 1..token_end].to_vec());
+                token = token_end;
+            }
+            items.push(SearchTokensEntry { header, tokens });
+            index = end;
+        }
+        Self { items }
+    }
+}
+
+// Indexes of entries to delete, one byte each.
+pub struct DeleteIndexes {
+    pub indexes: Vec<u8>,
+}
+impl DeleteIndexes {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self { indexes: data }
+    }
+}
+
+// Leading byte of a global sync in the tagged format. Untagged syncs start with the top byte of
+// a 5-byte size, which is 0 for anything that fits in a message.
+pub const GLOBAL_SYNC_TAGGED: u8 = 1;
+
+// Sections of a tagged global sync. Each is a tag, a 5-byte size and the section in the format
+// of its own sync endpoint; logins use the login full sync format. Unknown tags are skipped so
+// older canisters accept syncs from newer clients.
+pub const SECTION_SPREADSHEET: u8 = 0;
+pub const SECTION_SPREADSHEET_COLUMNS: u8 = 1;
+pub const SECTION_SECURE_NOTES: u8 = 2;
+pub const SECTION_LOGINS: u8 = 3;
+pub const SECTION_TOTP: u8 = 4;
+pub const SECTION_PAYMENT_CARDS: u8 = 5;
+pub const SECTION_IDENTITIES: u8 = 6;
+pub const SECTION_CUSTOM_RECORDS: u8 = 7;
+pub const SECTION_FOLDERS: u8 = 8;
+pub const SECTION_TAGS: u8 = 9;
+pub const SECTION_ITEM_LABELS: u8 = 10;
+pub const SECTION_SEARCH_TOKENS: u8 = 11;
+
+fn read_size(data: &[u8], index: usize) -> usize {
+    u64::from_be_bytes([0, 0, 0, data[index], data[index + 1], data[index + 2], data[index + 3], data[index + 4]]) as usize
+}
+
 // Describes a global sync, containing complete login data and spreadsheet data.
 pub struct GlobalSyncData {
     pub spreadsheet : Cells,
     pub spreadsheet_columns: SpreadsheetColumns,
     pub secure_notes: SecureNotesData,
     pub logins : LoginData,
+    pub totp: TotpData,
+    pub payment_cards: RecordsData,
+    pub identities: RecordsData,
+    pub custom_records: CustomRecordsData,
+    pub folders: FoldersData,
+    pub tags: TagsData,
+    pub item_labels: ItemLabelsData,
+    pub search_tokens: SearchTokensData,
 }
 impl GlobalSyncData {
     pub fn new(data : Vec<u8>) -> Self {
-        let spreadsheet_size = u64::from_be_bytes([0, 0, 0, data[0], data[1], data[2], data[3], data[4]]) as usize;
-        let columns_size = u64::from_be_bytes([0, 0, 0, data[5], data[6], data[7], data[8], data[9]]) as usize;
-        let notes_size = u64::from_be_bytes([0, 0, 0, data[10], data[11], data[12], data[13], data[14]]) as usize;
+        if data[0] == GLOBAL_SYNC_TAGGED {
+            return Self::tagged(&data[1..]);
+        }
+        let spreadsheet_size = read_size(&data, 0);
+        let columns_size = read_size(&data, 5);
+        let notes_size = read_size(&data, 10);
         let mut index = 5 + 5 + 5;
 
         let spreadsheet = Cells::new(data[index..index + spreadsheet_size].to_vec());
@@ -345,6 +785,60 @@ impl GlobalSyncData {
         index += notes_size;
 
         let logins = LoginData::new(&data[index..].to_vec());
-        Self { spreadsheet, spreadsheet_columns, secure_notes, logins }
+        Self {
+            spreadsheet,
+            spreadsheet_columns,
+            secure_notes,
+            logins,
+            totp: TotpData::new(&[]),
+            payment_cards: RecordsData::new(&[], RecordKind::PaymentCard),
+            identities: RecordsData::new(&[], RecordKind::Identity),
+            custom_records: CustomRecordsData::new(&[]),
+            folders: FoldersData::new(&[]),
+            tags: TagsData::new(&[]),
+            item_labels: ItemLabelsData::new(&[]),
+            search_tokens: SearchTokensData::new(&[]),
+        }
+    }
+
+    // Sections left out are empty.
+    fn tagged(data: &[u8]) -> Self {
+        let mut sync = Self {
+            spreadsheet: Cells::new(Vec::new()),
+            spreadsheet_columns: SpreadsheetColumns::new(&Vec::new()),
+            secure_notes: SecureNotesData::new(Vec::new()),
+            logins: LoginData { metadata: LoginMetadata::new(Vec::new()), cells: Cells::new(Vec::new()) },
+            totp: TotpData::new(&[]),
+            payment_cards: RecordsData::new(&[], RecordKind::PaymentCard),
+            identities: RecordsData::new(&[], RecordKind::Identity),
+            custom_records: CustomRecordsData::new(&[]),
+            folders: FoldersData::new(&[]),
+            tags: TagsData::new(&[]),
+            item_labels: ItemLabelsData::new(&[]),
+            search_tokens: SearchTokensData::new(&[]),
+        };
+        let mut index = 0;
+        while index < data.len() {
+            let tag = data[index];
+            let size = read_size(data, index + 1);
+            let section = data[index + 6..index + 6 + size].to_vec();
+            index += 6 + size;
+            match tag {
+                SECTION_SPREADSHEET => sync.spreadsheet = Cells::new(section),
+                SECTION_SPREADSHEET_COLUMNS => sync.spreadsheet_columns = SpreadsheetColumns::new(&section),
+                SECTION_SECURE_NOTES => sync.secure_notes = SecureNotesData::new(section),
+                SECTION_LOGINS if !section.is_empty() => sync.logins = LoginData::new(&section),
+                SECTION_TOTP => sync.totp = TotpData::new(&section),
+                SECTION_PAYMENT_CARDS => sync.payment_cards = RecordsData::new(&section, RecordKind::PaymentCard),
+                SECTION_IDENTITIES => sync.identities = RecordsData::new(&section, RecordKind::Identity),
+                SECTION_CUSTOM_RECORDS => sync.custom_records = CustomRecordsData::new(&section),
+                SECTION_FOLDERS => sync.folders = FoldersData::new(&section),
+                SECTION_TAGS => sync.tags = TagsData::new(&section),
+                SECTION_ITEM_LABELS => sync.item_labels = ItemLabelsData::new(&section),
+                SECTION_SEARCH_TOKENS => sync.search_tokens = SearchTokensData::new(&section),
+                _ => {}
+            }
+        }
+        sync
     }
 }
\ No newline at end of file
@@ -2,7 +2,11 @@ use std::collections::HashMap;
 use ic_stable_structures::Storable;
 use candid::{Principal, CandidType, Deserialize};
 
-use crate::{stable::types::{ColumnsInfo, GeneralState, LoginsColumns, LoginsMap, NotesMap, SpreadsheetMap, VaultNamesMap}};
+use crate::{
+    api::{organization_api::{_get_organization, Organization}, registry_api::_user_vaults, serial_api::{_probe_cells, _probe_columns}, sheets_api::_list_sheets},
+    stable::types::{ColumnsInfo, CustomRecordsMap, GeneralState, LoginsColumns, LoginsMap, NotesMap, RecordsMap, SpreadsheetMap, TemplatesMap, TotpMap, VaultNamesMap},
+    vault_type::{records::{CardBrand, Expiry, IdentityDocument, RecordKey, RecordKind, TypedRecord}, sheets::sheet_principals, spreadsheet::{ColumnKind, ColumnSettings}, templates::{RecordTemplate, TemplateKey}, totp::{TotpKey, TotpParams}},
+};
 
 /* 
     Vault names devapi structures
@@ -50,26 +54,18 @@ pub fn _get_vault_name(user_id: Principal, vault_id: Principal, vnm: &VaultNames
     Spreadsheet devapi structures.
 */
 
-pub type FlexGridColumns = HashMap<u8, (Vec<u8>, bool)>;
+// Name, hidden, kind and settings of each column, keyed by x. Kind and settings come last so
+// clients that only know the first two still decode it.
+pub type FlexGridColumns = HashMap<u8, (Vec<u8>, bool, ColumnKind, ColumnSettings)>;
 pub fn _get_columns_info(user_id: Principal, vault_id: Principal, sc: &ColumnsInfo) -> FlexGridColumns {
-    let sc = sc.borrow();
-    let mut columns : FlexGridColumns = HashMap::new();
-
-    let compare_principals: Vec<u8> = {
-        let mut principals = Vec::new();
-        principals.extend(user_id.to_bytes().iter());
-        principals.extend(vault_id.to_bytes().iter());
-        principals
-    };
-
-    sc.iter().for_each(|entry| {
-        let (key, value) = entry.into_pair();
-        if key.principals_match(&compare_principals) {
-            columns.entry(key.x).or_insert_with(|| (value.name, value.hidden ));
-        }
-    });
+    _get_sheet_columns(user_id, vault_id, 0, sc)
+}
 
-    columns
+pub fn _get_sheet_columns(user_id: Principal, vault_id: Principal, sheet: u8, sc: &ColumnsInfo) -> FlexGridColumns {
+    _probe_columns(&sheet_principals(user_id, vault_id, sheet), sc)
+        .into_iter()
+        .map(|(key, value)| (key.x, (value.name, value.hidden, value.kind, value.settings)))
+        .collect()
 }
 
 #[derive(CandidType, Deserialize)]
@@ -83,32 +79,51 @@ pub struct Spreadsheet {
 }
 
 pub fn _get_spreadsheet(user_id: Principal, vault_id: Principal, sm: &SpreadsheetMap) -> Spreadsheet {
-    let cells = sm.borrow();
+    _get_sheet(user_id, vault_id, 0, sm)
+}
+
+pub fn _get_sheet(user_id: Principal, vault_id: Principal, sheet: u8, sm: &SpreadsheetMap) -> Spreadsheet {
     let mut spreadsheet = Spreadsheet {
         columns: HashMap::new(),
     };
 
-    let compare_principals: Vec<u8> = {
-        let mut principals = Vec::new();
-        principals.extend(user_id.to_bytes().iter());
-        principals.extend(vault_id.to_bytes().iter());
-        principals
-    };
-
-    cells.iter().for_each(|entry| {
-        let key = entry.key();
-        if key.principals_match(&compare_principals) {
-            spreadsheet.columns
-                .entry(key.x)
-                .or_insert_with(|| SpreadsheetColumn { rows: HashMap::new() })
-                .rows
-                .insert(key.y, entry.value().data.clone());
-        }
-    });
+    // Cells may sit in columns without a header, so every column is probed.
+    let every_column: Vec<u8> = (0..=u8::MAX).collect();
+    for (key, data) in _probe_cells(&sheet_principals(user_id, vault_id, sheet), &every_column, sm) {
+        spreadsheet.columns
+            .entry(key.x)
+            .or_insert_with(|| SpreadsheetColumn { rows: HashMap::new() })
+            .rows
+            .insert(key.y, data);
+    }
 
     spreadsheet
 }
 
+// A sheet of the flexible grid with its columns and cells.
+#[derive(CandidType, Deserialize)]
+pub struct Sheet {
+    pub sheet: u8,
+    pub position: u8,
+    pub name: Vec<u8>,
+    pub columns: FlexGridColumns,
+    pub spreadsheet: Spreadsheet,
+}
+
+// Every sheet of the vault in display order. The columns and cells of sheet 0 are left empty,
+// as VaultData already carries them.
+pub fn _get_sheets(user_id: Principal, vault_id: Principal, state: &GeneralState) -> Vec<Sheet> {
+    _list_sheets(user_id, vault_id, &state.sheets).into_iter()
+        .map(|info| {
+            let (columns, spreadsheet) = match info.sheet {
+                0 => (HashMap::new(), Spreadsheet { columns: HashMap::new() }),
+                sheet => (_get_sheet_columns(user_id, vault_id, sheet, &state.spreadsheet_columns), _get_sheet(user_id, vault_id, sheet, &state.spreadsheet_map)),
+            };
+            Sheet { sheet: info.sheet, position: info.position, name: info.name, columns, spreadsheet }
+        })
+        .collect()
+}
+
 /*
     Login devapi structures.
 */
@@ -200,6 +215,163 @@ pub fn _get_notes(user_id: Principal, vault_id: Principal, nm: &NotesMap) -> Not
     notes
 }
 
+#[derive(CandidType, Deserialize)]
+pub struct TotpSeed {
+    pub params: TotpParams,
+    pub login_column: Option<u8>,
+    pub label: Vec<u8>,
+    pub seed: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TotpSeeds {
+    pub seeds: HashMap<u8, TotpSeed>
+}
+
+pub fn _get_totp_seeds(user_id: Principal, vault_id: Principal, tm: &TotpMap) -> TotpSeeds {
+    let start = TotpKey::new(user_id, vault_id, 0);
+    let end = TotpKey::new(user_id, vault_id, u8::MAX);
+    let seeds = tm.borrow()
+        .range(start..=end)
+        .map(|entry| {
+            let (key, value) = entry.into_pair();
+            (key.index, TotpSeed { params: value.params, login_column: value.login_column, label: value.label, seed: value.seed })
+        })
+        .collect();
+
+    TotpSeeds { seeds }
+}
+
+/*
+    Payment cards and identity documents
+*/
+
+#[derive(CandidType, Deserialize)]
+pub struct PaymentCard {
+    pub brand: CardBrand,
+    pub expiry: Option<Expiry>,
+    pub fields: HashMap<u8, Vec<u8>>, // key is the field id
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct PaymentCards {
+    pub cards: HashMap<u8, PaymentCard>
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct Identity {
+    pub document: IdentityDocument,
+    pub expiry: Option<Expiry>,
+    pub fields: HashMap<u8, Vec<u8>>, // key is the field id
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct Identities {
+    pub identities: HashMap<u8, Identity>
+}
+
+fn _get_records(user_id: Principal, vault_id: Principal, kind: RecordKind, rm: &RecordsMap) -> Vec<(u8, TypedRecord)> {
+    let start = RecordKey::new(user_id, vault_id, kind, 0);
+    let end = RecordKey::new(user_id, vault_id, kind, u8::MAX);
+    rm.borrow().range(start..=end).map(|entry| (entry.key().index, entry.value())).collect()
+}
+
+pub fn _get_payment_cards(user_id: Principal, vault_id: Principal, rm: &RecordsMap) -> PaymentCards {
+    let cards = _get_records(user_id, vault_id, RecordKind::PaymentCard, rm)
+        .into_iter()
+        .map(|(index, record)| {
+            let brand = CardBrand::from_byte(record.type_tag).unwrap_or(CardBrand::Other);
+            (index, PaymentCard { brand, expiry: record.expiry, fields: record.fields.into_iter().collect() })
+        })
+        .collect();
+
+    PaymentCards { cards }
+}
+
+pub fn _get_identities(user_id: Principal, vault_id: Principal, rm: &RecordsMap) -> Identities {
+    let identities = _get_records(user_id, vault_id, RecordKind::Identity, rm)
+        .into_iter()
+        .map(|(index, record)| {
+            let document = IdentityDocument::from_byte(record.type_tag).unwrap_or(IdentityDocument::Other);
+            (index, Identity { document, expiry: record.expiry, fields: record.fields.into_iter().collect() })
+        })
+        .collect();
+
+    Identities { identities }
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub struct ExpiringRecord {
+    pub vault_id: Principal,
+    pub kind: RecordKind,
+    pub index: u8,
+    pub expiry: Expiry,
+}
+
+// Cards and documents of every vault of the user expiring between `from` and `to`, inclusive.
+pub fn _get_expiring_records(user_id: Principal, from: Expiry, to: Expiry, state: &GeneralState) -> Vec<ExpiringRecord> {
+    let mut expiring = Vec::new();
+    for vault in _user_vaults(user_id.as_slice(), &state.vault_registry) {
+        let vault_id = Principal::from_slice(&vault.vault);
+        for kind in [RecordKind::PaymentCard, RecordKind::Identity] {
+            for (index, record) in _get_records(user_id, vault_id, kind, &state.records) {
+                if let Some(expiry) = record.expiry.filter(|expiry| (from..=to).contains(expiry)) {
+                    expiring.push(ExpiringRecord { vault_id, kind, index, expiry });
+                }
+            }
+        }
+    }
+    expiring
+}
+
+/*
+    Record templates and the records made from them
+*/
+
+#[derive(CandidType, Deserialize)]
+pub struct RecordTemplates {
+    pub templates: HashMap<u8, RecordTemplate>
+}
+
+pub fn _get_record_templates(user_id: Principal, vault_id: Principal, tm: &TemplatesMap) -> RecordTemplates {
+    let start = TemplateKey::new(user_id, vault_id, 0);
+    let end = TemplateKey::new(user_id, vault_id, u8::MAX);
+    let templates = tm.borrow()
+        .range(start..=end)
+        .map(|entry| {
+            let (key, value) = entry.into_pair();
+            (key.index, value)
+        })
+        .collect();
+
+    RecordTemplates { templates }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CustomRecordValues {
+    pub template: u8,
+    pub values: Vec<Vec<u8>>, // one per template field, in order
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CustomRecords {
+    pub records: HashMap<u8, CustomRecordValues>
+}
+
+pub fn _get_custom_records(user_id: Principal, vault_id: Principal, cm: &CustomRecordsMap) -> CustomRecords {
+    let start = TemplateKey::new(user_id, vault_id, 0);
+    let end = TemplateKey::new(user_id, vault_id, u8::MAX);
+    let records = cm.borrow()
+        .range(start..=end)
+        .map(|entry| {
+            let (key, value) = entry.into_pair();
+            (key.index, CustomRecordValues { template: value.template, values: value.values })
+        })
+        .collect();
+
+    CustomRecords { records }
+}
+
 /*
     Global fetches
 */
@@ -208,22 +380,43 @@ pub struct VaultData {
     pub vault_name: Vec<u8>,
     pub spreadsheet_columns: FlexGridColumns,
     pub spreadsheet: Spreadsheet,
+    pub sheets: Vec<Sheet>,
     pub logins: Logins,
     pub notes: Notes,
+    pub totp: TotpSeeds,
+    pub payment_cards: PaymentCards,
+    pub identities: Identities,
+    pub record_templates: RecordTemplates,
+    pub custom_records: CustomRecords,
+    pub organization: Organization,
 }
 
 pub fn _get_vault(vault_name: &Vec<u8>, user_id: Principal, vault_id: Principal, state: &GeneralState) -> VaultData {
     let spreadsheet_columns = _get_columns_info(user_id, vault_id, &state.spreadsheet_columns);
     let spreadsheet = _get_spreadsheet(user_id, vault_id, &state.spreadsheet_map);
+    let sheets = _get_sheets(user_id, vault_id, state);
     let logins = _get_logins(user_id, vault_id, &state.logins_map, &state.logins_columns);
     let notes = _get_notes(user_id, vault_id, &state.notes_map);
+    let totp = _get_totp_seeds(user_id, vault_id, &state.totp_map);
+    let payment_cards = _get_payment_cards(user_id, vault_id, &state.records);
+    let identities = _get_identities(user_id, vault_id, &state.records);
+    let record_templates = _get_record_templates(user_id, vault_id, &state.record_templates);
+    let custom_records = _get_custom_records(user_id, vault_id, &state.custom_records);
+    let organization = _get_organization(user_id, vault_id, state);
 
     VaultData {
         vault_name: vault_name.to_vec(),
         spreadsheet_columns,
         spreadsheet,
+        sheets,
         logins,
-        notes
+        notes,
+        totp,
+        payment_cards,
+        identities,
+        record_templates,
+        custom_records,
+        organization
     }
 }
 
@@ -0,0 +1,401 @@
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::{
+    api::{machine_api::_vault_grant_keys, search_api::_index_item, serial_api::{_probe_cells, _probe_columns, SyncOutcome}, sheets_api::_assert_sheet},
+    stable::types::{GeneralState, SpreadsheetMap},
+    vault_type::{
+        attachments::{AttachmentKey, ItemKind, ItemRef},
+        history::{HistoryKey, HistoryKind},
+        logins::LoginSiteKey,
+        organization::ItemLabelsKey,
+        search::ItemTokensKey,
+        sheets::sheet_principals,
+        spreadsheet::{ColumnKey, SpreadsheetKey, SpreadsheetValue},
+        totp::TotpKey,
+        trash::{TrashItem, TrashKind},
+    },
+};
+
+/*
+    Structural edits of the flexible grid's sheets and of the logins grid: inserting, deleting,
+    moving and swapping rows or columns. Cells and column headers are re-keyed in one call, so
+    clients no longer re-send every shifted cell. Deleted rows and columns go to the trash with
+    their cells. References into the grid move along: attachment links, item labels, search
+    tokens and, for logins, TOTP seeds, machine grants and revision history. References to
+    deleted cells or columns are dropped.
+*/
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Grid {
+    // Sheet 0 of the flexible grid.
+    Spreadsheet,
+    Logins,
+    Sheet(u8),
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Axis {
+    // y
+    Row,
+    // x
+    Column,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GridEdit {
+    // Opens `count` empty lines at `at`, shifting the lines from `at` on.
+    Insert { at: u8, count: u8 },
+    // Removes `count` lines from `at`, shifting the lines after them back.
+    Delete { at: u8, count: u8 },
+    // Moves line `from` to `to`, shifting the lines in between.
+    Move { from: u8, to: u8 },
+    Swap { a: u8, b: u8 },
+}
+impl GridEdit {
+    fn check(self) -> Result<(), String> {
+        match self {
+            GridEdit::Insert { count: 0, .. } | GridEdit::Delete { count: 0, .. } => Err("count must be at least 1".into()),
+            GridEdit::Delete { at, count } if u16::from(at) + u16::from(count) > 256 => Err("lines to delete run past the grid".into()),
+            _ => Ok(()),
+        }
+    }
+
+    // Where the line at `position` ends up: None when deleted, an error when pushed off the grid.
+    fn apply(self, position: u8) -> Result<Option<u8>, String> {
+        let target = match self {
+            GridEdit::Insert { at, count } if position >= at => {
+                let target = u16::from(position) + u16::from(count);
+                return u8::try_from(target).map(Some).map_err(|_| format!("line {} would be pushed past the grid", position));
+            }
+            GridEdit::Delete { at, count } if position >= at => {
+                let end = u16::from(at) + u16::from(count);
+                if u16::from(position) < end { None } else { Some(position - count) }
+            }
+            GridEdit::Move { from, to } if position == from => Some(to),
+            GridEdit::Move { from, to } if from < to && (from + 1..=to).contains(&position) => Some(position - 1),
+            GridEdit::Move { from, to } if to < from && (to..from).contains(&position) => Some(position + 1),
+            GridEdit::Swap { a, b } if position == a => Some(b),
+            GridEdit::Swap { a, b } if position == b => Some(a),
+            _ => Some(position),
+        };
+        Ok(target)
+    }
+
+    // As `apply`, for references: those pushed off the grid are dropped like deleted ones.
+    fn follow(self, position: u8) -> Option<u8> {
+        self.apply(position).ok().flatten()
+    }
+}
+
+// The entries whose line changes, with where each goes. Fails before anything is written when
+// one would be pushed off the grid.
+fn _plan<K, V>(entries: Vec<(K, V)>, line: impl Fn(&K) -> u8, edit: GridEdit) -> Result<Vec<(K, Option<u8>, V)>, String> {
+    let mut moves = Vec::new();
+    for (key, value) in entries {
+        let from = line(&key);
+        let to = edit.apply(from)?;
+        if to != Some(from) {
+            moves.push((key, to, value));
+        }
+    }
+    Ok(moves)
+}
+
+fn _move_cell(key: &SpreadsheetKey, axis: Axis, to: u8) -> SpreadsheetKey {
+    match axis {
+        Axis::Column => SpreadsheetKey { principals: key.principals.clone(), x: to, y: key.y },
+        Axis::Row => SpreadsheetKey { principals: key.principals.clone(), x: key.x, y: to },
+    }
+}
+
+type Cell = (SpreadsheetKey, Vec<u8>);
+
+// Re-keys the cells in the given columns of a grid. Returns how many moved or were deleted, and
+// the deleted ones.
+fn _edit_cells(principals: &[u8], columns: &[u8], axis: Axis, edit: GridEdit, cells: &SpreadsheetMap) -> Result<(usize, Vec<Cell>), String> {
+    let entries = _probe_cells(principals, columns, cells);
+    let moves = _plan(entries, |key| if axis == Axis::Column { key.x } else { key.y }, edit)?;
+
+    // Every moved cell leaves before any arrives, so shifts don't overwrite each other.
+    let mut cells = cells.borrow_mut();
+    for (key, _, _) in moves.iter() {
+        cells.remove(key);
+    }
+    let count = moves.len();
+    let mut deleted = Vec::new();
+    for (key, to, data) in moves {
+        match to {
+            Some(to) => {
+                cells.insert(_move_cell(&key, axis, to), SpreadsheetValue::new(data));
+            }
+            None => deleted.push((key, data)),
+        }
+    }
+    Ok((count, deleted))
+}
+
+// Applies a structural edit to one of the vault's grids. Items counts the cells and column
+// headers that moved or were deleted.
+pub fn _edit_grid(user_id: Principal, vault_id: Principal, grid: Grid, axis: Axis, edit: GridEdit, state: &GeneralState) -> Result<SyncOutcome, String> {
+    edit.check()?;
+    let principals = [user_id.as_slice(), vault_id.as_slice()].concat();
+    let mut outcome = SyncOutcome::default();
+
+    match grid {
+        Grid::Spreadsheet | Grid::Sheet(_) => {
+            let sheet = if let Grid::Sheet(sheet) = grid { sheet } else { 0 };
+            _assert_sheet(user_id, vault_id, sheet, &state.sheets)?;
+            let principals = sheet_principals(user_id, vault_id, sheet);
+            // Headers are planned before any cell moves and written after, so an edit that
+            // can't be applied writes nothing.
+            let entries = _probe_columns(&principals, &state.spreadsheet_columns);
+            let xs: Vec<u8> = entries.iter().map(|(key, _)| key.x).collect();
+            let headers = match axis {
+                Axis::Column => _plan(entries, |key: &ColumnKey| key.x, edit)?,
+                Axis::Row => Vec::new(),
+            };
+            let (count, deleted) = _edit_cells(&principals, &xs, axis, edit, &state.spreadsheet_map)?;
+            let mut columns = state.spreadsheet_columns.borrow_mut();
+            for (key, _, _) in headers.iter() {
+                columns.remove(key);
+            }
+            outcome.items = (count + headers.len()) as u32;
+            for (key, to, value) in headers {
+                if let Some(x) = to {
+                    columns.insert(ColumnKey { principals: key.principals, x }, value);
+                }
+            }
+            outcome.removed = deleted.into_iter()
+                .map(|(key, data)| TrashItem::sheet_cell(sheet, key.x, key.y, data))
+                .collect();
+        }
+        Grid::Logins => {
+            let range = LoginSiteKey { principals: principals.clone(), x: 0 }..=LoginSiteKey { principals: principals.clone(), x: u8::MAX };
+            let entries: Vec<_> = state.logins_columns.borrow().range(range).map(|entry| entry.into_pair()).collect();
+            let xs: Vec<u8> = entries.iter().map(|(key, _)| key.x).collect();
+            let headers = match axis {
+                Axis::Column => _plan(entries, |key: &LoginSiteKey| key.x, edit)?,
+                Axis::Row => Vec::new(),
+            };
+            let (count, deleted) = _edit_cells(&principals, &xs, axis, edit, &state.logins_map)?;
+            let mut columns = state.logins_columns.borrow_mut();
+            for (key, _, _) in headers.iter() {
+                columns.remove(key);
+            }
+            outcome.items = (count + headers.len()) as u32;
+            // A deleted column goes to the trash whole, with its identities.
+            let mut deleted_columns = Vec::new();
+            for (key, to, label) in headers {
+                match to {
+                    Some(x) => {
+                        columns.insert(LoginSiteKey { principals: key.principals, x }, label);
+                    }
+                    None => deleted_columns.push((key.x, label)),
+                }
+            }
+            drop(columns);
+            let mut cells = deleted;
+            for (x, label) in deleted_columns {
+                let rows = cells.iter().filter(|(key, _)| key.x == x).map(|(key, data)| (key.y, data.clone())).collect();
+                cells.retain(|(key, _)| key.x != x);
+                outcome.removed.push(TrashItem::login_column(x, label, rows));
+            }
+            outcome.removed.extend(cells.into_iter().map(|(key, data)| TrashItem::cell(TrashKind::LoginCell, key.x, key.y, data)));
+            _follow_login_references(&principals, axis, edit, state);
+        }
+    }
+    _follow_attachment_links(&principals, grid, axis, edit, state);
+    _follow_item_labels(&principals, grid, axis, edit, state);
+    _follow_search_tokens(&principals, grid, axis, edit, state);
+    Ok(outcome)
+}
+
+// Where an edit takes a reference to an item: None when the edit leaves it alone, Some(None)
+// when the item was deleted.
+fn _follow_item(grid: Grid, axis: Axis, edit: GridEdit, mut item: ItemRef) -> Option<Option<ItemRef>> {
+    let sheet = match grid {
+        Grid::Spreadsheet => Some(0),
+        Grid::Sheet(sheet) => Some(sheet),
+        Grid::Logins => None,
+    };
+    let on_sheet = sheet == Some(item.sheet);
+    let line = match (grid, item.kind) {
+        (Grid::Spreadsheet | Grid::Sheet(_), ItemKind::SpreadsheetCell) if on_sheet => {
+            if axis == Axis::Column { &mut item.x } else { &mut item.y }
+        }
+        (Grid::Logins, ItemKind::LoginCell) => {
+            if axis == Axis::Column { &mut item.x } else { &mut item.y }
+        }
+        (Grid::Logins, ItemKind::LoginColumn) if axis == Axis::Column => &mut item.x,
+        (_, ItemKind::GridRow) if axis == Axis::Row && on_sheet => &mut item.y,
+        _ => return None,
+    };
+    let from = *line;
+    match edit.follow(from) {
+        Some(to) if to == from => None,
+        Some(to) => {
+            *line = to;
+            Some(Some(item))
+        }
+        None => Some(None),
+    }
+}
+
+fn _follow_attachment_links(principals: &[u8], grid: Grid, axis: Axis, edit: GridEdit, state: &GeneralState) {
+    let range = AttachmentKey { principals: principals.to_vec(), id: 0 }..=AttachmentKey { principals: principals.to_vec(), id: u64::MAX };
+    let mut attachments = state.attachments.borrow_mut();
+    let linked: Vec<_> = attachments.range(range)
+        .map(|entry| entry.into_pair())
+        .filter_map(|(key, manifest)| {
+            let link = _follow_item(grid, axis, edit, manifest.link?)?;
+            Some((key, manifest, link))
+        })
+        .collect();
+    for (key, mut manifest, link) in linked {
+        manifest.link = link;
+        attachments.insert(key, manifest);
+    }
+}
+
+// Labels are keyed by item, so those of moved items are re-keyed and those of deleted items
+// dropped.
+fn _follow_item_labels(principals: &[u8], grid: Grid, axis: Axis, edit: GridEdit, state: &GeneralState) {
+    let range = ItemLabelsKey { principals: principals.to_vec(), item: ItemRef::FIRST }..=ItemLabelsKey { principals: principals.to_vec(), item: ItemRef::LAST };
+    let mut item_labels = state.item_labels.borrow_mut();
+    let moves: Vec<_> = item_labels.range(range)
+        .map(|entry| entry.into_pair())
+        .filter_map(|(key, labels)| {
+            let item = _follow_item(grid, axis, edit, key.item)?;
+            Some((key, item, labels))
+        })
+        .collect();
+    for (key, _, _) in moves.iter() {
+        item_labels.remove(key);
+    }
+    for (key, item, labels) in moves {
+        if let Some(item) = item {
+            item_labels.insert(ItemLabelsKey { principals: key.principals, item }, labels);
+        }
+    }
+}
+
+// Moved items are taken out of the search index before any is put back, as with labels.
+fn _follow_search_tokens(principals: &[u8], grid: Grid, axis: Axis, edit: GridEdit, state: &GeneralState) {
+    let range = ItemTokensKey { principals: principals.to_vec(), item: ItemRef::FIRST }..=ItemTokensKey { principals: principals.to_vec(), item: ItemRef::LAST };
+    let moves: Vec<_> = state.item_tokens.borrow().range(range)
+        .map(|entry| entry.into_pair())
+        .filter_map(|(key, tokens)| {
+            let item = _follow_item(grid, axis, edit, key.item)?;
+            Some((key.item, item, tokens.tokens))
+        })
+        .collect();
+    for (from, _, _) in moves.iter() {
+        _index_item(principals, *from, Vec::new(), state);
+    }
+    for (_, to, tokens) in moves {
+        if let Some(to) = to {
+            _index_item(principals, to, tokens, state);
+        }
+    }
+}
+
+// TOTP seeds and machine grants name login columns; revisions are kept per login cell.
+fn _follow_login_references(principals: &[u8], axis: Axis, edit: GridEdit, state: &GeneralState) {
+    if axis == Axis::Column {
+        let range = TotpKey { principals: principals.to_vec(), index: 0 }..=TotpKey { principals: principals.to_vec(), index: u8::MAX };
+        let mut totp = state.totp_map.borrow_mut();
+        let seeds: Vec<_> = totp.range(range)
+            .map(|entry| entry.into_pair())
+            .filter(|(_, seed)| seed.login_column.is_some_and(|x| edit.follow(x) != Some(x)))
+            .collect();
+        for (key, mut seed) in seeds {
+            seed.login_column = seed.login_column.and_then(|x| edit.follow(x));
+            totp.insert(key, seed);
+        }
+        drop(totp);
+
+        let mut grants = state.machine_grants.borrow_mut();
+        let affected: Vec<_> = _vault_grant_keys(principals, usize::MAX, state).into_iter()
+            .filter_map(|key| grants.get(&key).map(|grant| (key, grant)))
+            .filter(|(_, grant)| grant.login_columns.iter().any(|x| edit.follow(*x) != Some(*x)))
+            .collect();
+        for (key, mut grant) in affected {
+            grant.login_columns = grant.login_columns.iter().filter_map(|x| edit.follow(*x)).collect();
+            grants.insert(key, grant);
+        }
+    }
+
+    let start = HistoryKey { principals: principals.to_vec(), kind: HistoryKind::LoginCell, x: 0, y: 0, revision: 0 };
+    let end = HistoryKey { principals: principals.to_vec(), kind: HistoryKind::LoginCell, x: u8::MAX, y: u8::MAX, revision: u64::MAX };
+    let mut history = state.history.borrow_mut();
+    let moves: Vec<_> = history.range(start..=end)
+        .map(|entry| entry.into_pair())
+        .filter(|(key, _)| {
+            let line = if axis == Axis::Column { key.x } else { key.y };
+            edit.follow(line) != Some(line)
+        })
+        .collect();
+    for (key, _) in moves.iter() {
+        history.remove(key);
+    }
+    for (mut key, entry) in moves {
+        let line = if axis == Axis::Column { &mut key.x } else { &mut key.y };
+        if let Some(to) = edit.follow(*line) {
+            *line = to;
+            history.insert(key, entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+
+    use super::{_edit_grid, Axis, Grid, GridEdit};
+    use crate::{
+        api::{machine_api::_put_grant, organization_api::{_list_tagged_items, _set_item_labels, _set_tag}, sheets_api::_create_sheet},
+        stable::types::GeneralState,
+        vault_type::{
+            attachments::{ItemKind, ItemRef},
+            machine_grants::{MachineGrant, MachineGrantKey},
+        },
+    };
+
+    // References to cells of a sheet other than 0 follow edits of that sheet only.
+    #[test]
+    fn sheet_cell_references_follow_their_sheet() {
+        let state = GeneralState::init();
+        let user_id = Principal::from_slice(&[1; 29]);
+        let vault_id = Principal::from_slice(&[2; 29]);
+        let sheet = _create_sheet(user_id, vault_id, b"sheet".to_vec(), &state.sheets).unwrap();
+        let cell = |sheet, y| ItemRef { kind: ItemKind::SpreadsheetCell, sheet, x: 1, y };
+        _set_tag(user_id, vault_id, b"t".to_vec(), b"tag".to_vec(), &state.tags).unwrap();
+        for item in [cell(0, 2), cell(sheet, 2)] {
+            _set_item_labels(user_id, vault_id, item, None, vec![b"t".to_vec()], &state).unwrap();
+        }
+
+        _edit_grid(user_id, vault_id, Grid::Sheet(sheet), Axis::Row, GridEdit::Insert { at: 0, count: 1 }, &state).unwrap();
+        assert_eq!(_list_tagged_items(user_id, vault_id, b"t".to_vec(), &state.item_labels), vec![cell(0, 2), cell(sheet, 3)]);
+        _edit_grid(user_id, vault_id, Grid::Spreadsheet, Axis::Row, GridEdit::Delete { at: 2, count: 1 }, &state).unwrap();
+        assert_eq!(_list_tagged_items(user_id, vault_id, b"t".to_vec(), &state.item_labels), vec![cell(sheet, 3)]);
+    }
+
+    // Deleting a login column updates the grants over that vault, and not those over a vault
+    // whose id starts with the same bytes.
+    #[test]
+    fn login_column_edits_reach_only_the_vaults_grants() {
+        let state = GeneralState::init();
+        let user_id = Principal::from_slice(&[1; 29]);
+        let vault_id = Principal::from_slice(&[2; 10]);
+        let longer_vault = Principal::from_slice(&[2; 11]);
+        let machine = Principal::from_slice(&[9; 29]);
+        for vault in [vault_id, longer_vault] {
+            _put_grant(MachineGrantKey::new(machine, user_id, vault), MachineGrant::new(0, false, vec![1, 3], Vec::new()), &state);
+        }
+
+        _edit_grid(user_id, vault_id, Grid::Logins, Axis::Column, GridEdit::Delete { at: 1, count: 1 }, &state).unwrap();
+        let grants = state.machine_grants.borrow();
+        assert_eq!(grants.get(&MachineGrantKey::new(machine, user_id, vault_id)).unwrap().login_columns, vec![2]);
+        assert_eq!(grants.get(&MachineGrantKey::new(machine, user_id, longer_vault)).unwrap().login_columns, vec![1, 3]);
+    }
+}
@@ -0,0 +1,142 @@
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::{
+    api::registry_api::{_record_vault_change, SizeChange},
+    stable::types::{GeneralState, HistoryMap},
+    vault_type::{
+        history::{HistoryEntry, HistoryItem, HistoryKey, HistoryKind, Revision},
+        logins::LoginSiteKey,
+        secure_notes::{SecureNote, SecureNoteKey},
+        spreadsheet::{SpreadsheetKey, SpreadsheetValue},
+    },
+};
+
+/*
+    Revision history. When a sync overwrites a login cell or a note, the previous ciphertext is
+    kept here so users can look up and roll back to older values. Only the most recent `depth`
+    revisions of each item are kept, and the depth depends on the canister tier.
+*/
+
+// Revisions kept per item in the shared (free tier) canister.
+pub const DEFAULT_HISTORY_DEPTH: u32 = 5;
+// Revisions kept per item in a dedicated (premium) canister.
+pub const DEDICATED_HISTORY_DEPTH: u32 = 20;
+pub const MAX_HISTORY_DEPTH: u32 = 100;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RevisionInfo {
+    pub revision: u64,
+    pub replaced_at: u64,
+    pub size: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RevisionData {
+    pub revision: u64,
+    pub replaced_at: u64,
+    // Note label. Empty for login cells.
+    pub label: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+fn _item_range(item: &HistoryKey) -> std::ops::RangeInclusive<HistoryKey> {
+    item.with_revision(0)..=item.with_revision(u64::MAX)
+}
+
+// Appends a revision to an item's history, dropping the oldest ones beyond `depth`.
+fn _push_revision(item: HistoryKey, entry: HistoryEntry, depth: u32, history: &HistoryMap) {
+    let mut history = history.borrow_mut();
+    if depth == 0 {
+        return;
+    }
+    let range = _item_range(&item);
+    let next = history.range(range.clone()).next_back().map_or(0, |entry| entry.key().revision + 1);
+    history.insert(item.with_revision(next), entry);
+
+    let revisions: Vec<HistoryKey> = history.keys_range(range).collect();
+    let excess = revisions.len().saturating_sub(depth as usize);
+    for key in revisions.iter().take(excess) {
+        history.remove(key);
+    }
+}
+
+pub fn _record_revisions(user_id: Principal, vault_id: Principal, replaced: Vec<Revision>, now: u64, state: &GeneralState) {
+    let depth = *state.history_depth.borrow().get();
+    for revision in replaced {
+        let item = HistoryKey::new(user_id, vault_id, revision.item, 0);
+        let entry = HistoryEntry { replaced_at: now, label: revision.label, data: revision.data };
+        _push_revision(item, entry, depth, &state.history);
+    }
+}
+
+// Prior revisions of an item, newest first.
+pub fn _list_revisions(user_id: Principal, vault_id: Principal, item: HistoryItem, history: &HistoryMap) -> Vec<RevisionInfo> {
+    let item = HistoryKey::new(user_id, vault_id, item, 0);
+    history.borrow()
+        .range(_item_range(&item))
+        .rev()
+        .map(|entry| {
+            let (key, entry) = entry.into_pair();
+            RevisionInfo {
+                revision: key.revision,
+                replaced_at: entry.replaced_at,
+                size: (entry.label.len() + entry.data.len()) as u64,
+            }
+        })
+        .collect()
+}
+
+pub fn _get_revision(user_id: Principal, vault_id: Principal, item: HistoryItem, revision: u64, history: &HistoryMap) -> Option<RevisionData> {
+    let key = HistoryKey::new(user_id, vault_id, item, revision);
+    history.borrow().get(&key).map(|entry| RevisionData {
+        revision,
+        replaced_at: entry.replaced_at,
+        label: entry.label,
+        data: entry.data,
+    })
+}
+
+// Writes a prior revision back as the item's current value. The value it replaces becomes the
+// newest revision, so a rollback can itself be undone.
+pub fn _rollback_item(user_id: Principal, vault_id: Principal, item: HistoryItem, revision: u64, now: u64, state: &GeneralState) -> Result<(), String> {
+    let history_key = HistoryKey::new(user_id, vault_id, item, revision);
+    let target = state.history.borrow().get(&history_key).ok_or("revision not found")?;
+    let mut change = SizeChange::default();
+
+    let current = match item.kind {
+        HistoryKind::LoginCell => {
+            if !state.logins_columns.borrow().contains_key(&LoginSiteKey::new(user_id, vault_id, item.x)) {
+                return Err("login column no longer exists".into());
+            }
+            let key = SpreadsheetKey::new(user_id, vault_id, item.x, item.y);
+            let size = target.data.len();
+            let old = state.logins_map.borrow_mut().insert(key, SpreadsheetValue::new(target.data));
+            change.stored(old.as_ref().map(|old| old.data.len()), size);
+            old.map(|old| (Vec::new(), old.data))
+        }
+        HistoryKind::Note => {
+            let key = SecureNoteKey { index: item.x, principals: history_key.principals.clone() };
+            let size = target.label.len() + target.data.len();
+            let old = state.notes_map.borrow_mut().insert(key, SecureNote::new(target.label, target.data));
+            change.stored(old.as_ref().map(|old| old.label.len() + old.note.len()), size);
+            old.map(|old| (old.label, old.note))
+        }
+    };
+
+    if let Some((label, data)) = current {
+        let depth = *state.history_depth.borrow().get();
+        _push_revision(history_key, HistoryEntry { replaced_at: now, label, data }, depth, &state.history);
+    }
+    _record_vault_change(user_id, vault_id, change, now, &state.vault_registry);
+    Ok(())
+}
+
+// Changes how many revisions are kept per item. Histories longer than the new depth are trimmed
+// the next time their item is overwritten.
+pub fn _set_history_depth(depth: u32, state: &GeneralState) -> Result<(), String> {
+    if depth > MAX_HISTORY_DEPTH {
+        return Err("history depth too large".into());
+    }
+    state.history_depth.borrow_mut().set(depth);
+    Ok(())
+}
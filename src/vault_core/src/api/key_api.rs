@@ -9,7 +9,7 @@ use ic_vetkeys::is_valid_transport_public_key_encoding;
 
 use crate::stable::types::KeyManagementState;
 
-const KEY_NAME: &str = "key_1"; // use "key_1" on mainnet. For BETA will use test_key_1
+pub const DEFAULT_KEY_NAME: &str = "key_1"; // use "key_1" on mainnet. For BETA will use test_key_1; set through InitArgs
 const DOMAIN: &str = "ghostkeys:v1";
 const KEY_CURVE: VetKDCurve = VetKDCurve::Bls12_381_G2;
 
@@ -18,10 +18,10 @@ const KEY_CURVE: VetKDCurve = VetKDCurve::Bls12_381_G2;
 */
 
 // Get algo-key info
-fn key_id() -> VetKDKeyId {
+fn key_id(key_name: &str) -> VetKDKeyId {
     VetKDKeyId {
         curve: KEY_CURVE,
-        name: KEY_NAME.to_string(),
+        name: key_name.to_string(),
     }
 }
 
@@ -69,11 +69,11 @@ pub fn storage_user_of(scope: &Scope) -> Principal {
     }
 }
 
-pub async fn derive_vetkey(args: GhostkeysVetKdArgs) -> Result<Vec<u8>, String> {
+pub async fn derive_vetkey(args: GhostkeysVetKdArgs, key_name: &str, max_input_bytes: u32) -> Result<Vec<u8>, String> {
     if !is_valid_transport_public_key_encoding(&args.transport_public_key) {
         return Err("invalid transport_public_key encoding".into());
     }
-    if args.input.len() > 1024 {
+    if args.input.len() > max_input_bytes as usize {
         return Err("input too large".into());
     }
 
@@ -81,7 +81,7 @@ pub async fn derive_vetkey(args: GhostkeysVetKdArgs) -> Result<Vec<u8>, String>
         input: args.input.clone(),
         context: build_context(&args.scope),
         transport_public_key: args.transport_public_key.clone(),
-        key_id: key_id(),
+        key_id: key_id(key_name),
     };
 
     let VetKDDeriveKeyResult { encrypted_key } = vetkd_derive_key(&req)
@@ -0,0 +1,242 @@
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::{
+    api::{dev_api::{_get_logins, _get_notes, _get_totp_seeds, _get_vault, _get_vault_name, CustomRecords, Identities, PaymentCards, RecordTemplates, Spreadsheet, VaultData}, organization_api::Organization},
+    stable::types::{GeneralState, MachineGrantsMap},
+    vault_type::machine_grants::{MachineGrant, MachineGrantKey, VaultMachineKey},
+};
+
+/*
+    Machine identities. A user can give a machine principal (CI runner, server, ...) read-only
+    access to one of their vaults, or to a subset of its login columns and notes, optionally
+    until an expiry date. Machines only ever receive ciphertext.
+*/
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub enum GrantScope {
+    Vault,
+    Items { login_columns: Vec<u8>, notes: Vec<u8> },
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MachineGrantArgs {
+    pub machine: Principal,
+    pub scope: GrantScope,
+    // Nanoseconds since the epoch. None means the grant does not expire.
+    pub expires_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MachineGrantInfo {
+    pub machine: Principal,
+    pub scope: GrantScope,
+    pub expires_at: Option<u64>,
+}
+
+// A grant as seen from the machine's side, identifying which vault it can read.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MachineVaultGrant {
+    pub owner: Principal,
+    pub vault_id: Principal,
+    pub scope: GrantScope,
+    pub expires_at: Option<u64>,
+}
+
+fn scope_of(grant: &MachineGrant) -> GrantScope {
+    if grant.whole_vault {
+        return GrantScope::Vault;
+    }
+    GrantScope::Items {
+        login_columns: grant.login_columns.clone(),
+        notes: grant.notes.clone(),
+    }
+}
+
+fn expiry_of(grant: &MachineGrant) -> Option<u64> {
+    if grant.expires_at == 0 { None } else { Some(grant.expires_at) }
+}
+
+// Grants are written and removed through these two, so the vault index stays in step.
+pub fn _put_grant(key: MachineGrantKey, grant: MachineGrant, state: &GeneralState) {
+    state.vault_machines.borrow_mut().insert(VaultMachineKey::of(&key), key.user_size);
+    state.machine_grants.borrow_mut().insert(key, grant);
+}
+
+pub fn _remove_grant(key: &MachineGrantKey, state: &GeneralState) -> bool {
+    state.vault_machines.borrow_mut().remove(&VaultMachineKey::of(key));
+    state.machine_grants.borrow_mut().remove(key).is_some()
+}
+
+// The keys of the grants given over a vault, at most `limit` of them.
+pub fn _vault_grant_keys(principals: &[u8], limit: usize, state: &GeneralState) -> Vec<MachineGrantKey> {
+    state.vault_machines.borrow()
+        .range(VaultMachineKey::vault_range(principals))
+        .take(limit)
+        .map(|entry| entry.key().grant_key(entry.value()))
+        .collect()
+}
+
+pub fn _grant_machine_access(user_id: Principal, vault_id: Principal, args: MachineGrantArgs, now: u64, state: &GeneralState) -> Result<(), String> {
+    if args.machine == user_id || args.machine == Principal::anonymous() {
+        return Err("invalid machine principal".into());
+    }
+    if _get_vault_name(user_id, vault_id, &state.vault_names_map).is_empty() {
+        return Err("vault not found".into());
+    }
+    let expires_at = args.expires_at.unwrap_or(0);
+    if args.expires_at.is_some() && expires_at <= now {
+        return Err("expiry is in the past".into());
+    }
+
+    let grant = match args.scope {
+        GrantScope::Vault => MachineGrant::new(expires_at, true, Vec::new(), Vec::new()),
+        GrantScope::Items { mut login_columns, mut notes } => {
+            if login_columns.is_empty() && notes.is_empty() {
+                return Err("grant does not cover any item".into());
+            }
+            login_columns.sort_unstable();
+            login_columns.dedup();
+            notes.sort_unstable();
+            notes.dedup();
+            MachineGrant::new(expires_at, false, login_columns, notes)
+        }
+    };
+
+    let key = MachineGrantKey::new(args.machine, user_id, vault_id);
+    _put_grant(key, grant, state);
+    Ok(())
+}
+
+pub fn _revoke_machine_access(user_id: Principal, vault_id: Principal, machine: Principal, state: &GeneralState) {
+    let key = MachineGrantKey::new(machine, user_id, vault_id);
+    _remove_grant(&key, state);
+}
+
+// Lists every machine with access to the given vault.
+pub fn _get_machine_grants(user_id: Principal, vault_id: Principal, state: &GeneralState) -> Vec<MachineGrantInfo> {
+    let principals = [user_id.as_slice(), vault_id.as_slice()].concat();
+    let grants = state.machine_grants.borrow();
+
+    _vault_grant_keys(&principals, usize::MAX, state)
+        .into_iter()
+        .filter_map(|key| {
+            let grant = grants.get(&key)?;
+            Some(MachineGrantInfo {
+                machine: Principal::from_slice(&key.machine),
+                scope: scope_of(&grant),
+                expires_at: expiry_of(&grant),
+            })
+        })
+        .collect()
+}
+
+// Lists the vaults a machine can currently read. Expired grants are left out.
+pub fn _get_vaults_for_machine(machine: Principal, now: u64, mg: &MachineGrantsMap) -> Vec<MachineVaultGrant> {
+    let mut result = Vec::new();
+
+    for entry in mg.borrow().range(MachineGrantKey::machine_range(machine)) {
+        let (key, grant) = entry.into_pair();
+        if grant.is_expired(now) {
+            continue;
+        }
+        result.push(MachineVaultGrant {
+            owner: key.user_id(),
+            vault_id: key.vault_id(),
+            scope: scope_of(&grant),
+            expires_at: expiry_of(&grant),
+        });
+    }
+
+    result
+}
+
+// Returns the ciphertext a machine has been granted for a vault. Whole-vault grants return the
+// same data the owner would see; item grants return only the listed login columns and notes,
+// along with the TOTP seeds linked to those columns. Cards and identities need a whole-vault grant.
+pub fn _get_machine_vault(machine: Principal, user_id: Principal, vault_id: Principal, now: u64, state: &GeneralState) -> Result<VaultData, String> {
+    let key = MachineGrantKey::new(machine, user_id, vault_id);
+    let grant = match state.machine_grants.borrow().get(&key) {
+        Some(grant) => grant,
+        None => return Err("no grant for this vault".into()),
+    };
+    if grant.is_expired(now) {
+        return Err("grant expired".into());
+    }
+
+    let vault_name = _get_vault_name(user_id, vault_id, &state.vault_names_map);
+    if grant.whole_vault {
+        return Ok(_get_vault(&vault_name, user_id, vault_id, state));
+    }
+
+    let mut logins = _get_logins(user_id, vault_id, &state.logins_map, &state.logins_columns);
+    logins.columns.retain(|x, _| grant.login_columns.contains(x));
+    let mut notes = _get_notes(user_id, vault_id, &state.notes_map);
+    notes.notes.retain(|index, _| grant.notes.contains(index));
+    let mut totp = _get_totp_seeds(user_id, vault_id, &state.totp_map);
+    totp.seeds.retain(|_, seed| seed.login_column.is_some_and(|x| grant.login_columns.contains(&x)));
+
+    Ok(VaultData {
+        vault_name,
+        spreadsheet_columns: Default::default(),
+        spreadsheet: Spreadsheet { columns: Default::default() },
+        sheets: Vec::new(),
+        logins,
+        notes,
+        totp,
+        payment_cards: PaymentCards { cards: Default::default() },
+        identities: Identities { identities: Default::default() },
+        record_templates: RecordTemplates { templates: Default::default() },
+        custom_records: CustomRecords { records: Default::default() },
+        organization: Organization::default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+
+    use super::{_get_machine_grants, _get_vaults_for_machine, _put_grant, _revoke_machine_access};
+    use crate::{
+        stable::types::GeneralState,
+        vault_type::machine_grants::{MachineGrant, MachineGrantKey},
+    };
+
+    // Grants are listed through the vault index, only for their own vault, and revoking takes
+    // them out of both.
+    #[test]
+    fn grants_are_found_by_vault() {
+        let state = GeneralState::init();
+        let user_id = Principal::from_slice(&[1; 29]);
+        let vault_id = Principal::from_slice(&[2; 10]);
+        let longer_vault = Principal::from_slice(&[2; 11]);
+        let machine = Principal::from_slice(&[9; 29]);
+        for vault in [vault_id, longer_vault] {
+            _put_grant(MachineGrantKey::new(machine, user_id, vault), MachineGrant::new(0, true, Vec::new(), Vec::new()), &state);
+        }
+        assert_eq!(_get_machine_grants(user_id, vault_id, &state).len(), 1);
+        assert_eq!(_get_machine_grants(user_id, longer_vault, &state).len(), 1);
+
+        _revoke_machine_access(user_id, vault_id, machine, &state);
+        assert!(_get_machine_grants(user_id, vault_id, &state).is_empty());
+        assert_eq!(state.vault_machines.borrow().len(), 1);
+        assert_eq!(state.machine_grants.borrow().len(), 1);
+    }
+
+    // A machine sees the vaults it was granted, and not those of a machine whose principal is
+    // one byte longer.
+    #[test]
+    fn machines_see_only_their_grants() {
+        let state = GeneralState::init();
+        let user_id = Principal::from_slice(&[1; 29]);
+        let vault_id = Principal::from_slice(&[2; 29]);
+        let machine = Principal::from_slice(&[9; 10]);
+        let longer_machine = Principal::from_slice(&[9; 11]);
+        _put_grant(MachineGrantKey::new(machine, user_id, vault_id), MachineGrant::new(0, true, Vec::new(), Vec::new()), &state);
+        _put_grant(MachineGrantKey::new(longer_machine, user_id, vault_id), MachineGrant::new(5, true, Vec::new(), Vec::new()), &state);
+
+        let vaults = _get_vaults_for_machine(machine, 10, &state.machine_grants);
+        assert_eq!(vaults.len(), 1);
+        assert_eq!((vaults[0].owner, vaults[0].vault_id), (user_id, vault_id));
+        assert!(_get_vaults_for_machine(longer_machine, 10, &state.machine_grants).is_empty());
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::{memory_manager::MemoryId, Memory};
+
+use crate::{api::capacity_api::_get_capacity, stable::types::GeneralState};
+
+/*
+    Operational metrics: memory use of each stable structure, users against the limit and
+    call counters of the heaviest update endpoints. Served as candid to controllers and as
+    Prometheus text over HTTP for scrapers.
+*/
+
+#[derive(CandidType, Deserialize, Clone, Default, PartialEq, Eq, Debug)]
+pub struct EndpointMetrics {
+    pub endpoint: String,
+    pub calls: u64,
+    // Calls that returned an error. Calls that trap roll back and go uncounted.
+    pub errors: u64,
+    pub instructions_total: u64,
+    pub instructions_max: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct SectionMetrics {
+    pub memory_id: u8,
+    pub name: String,
+    pub pages: u64,
+    pub entries: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct CanisterMetrics {
+    pub collected_at: u64,
+    // Counters live on the heap and start over on every upgrade.
+    pub counting_since: u64,
+    pub cycles_balance: u128,
+    pub stable_memory_pages: u64,
+    pub sections: Vec<SectionMetrics>,
+    pub users: u64,
+    pub max_users: u64,
+    pub vetkd_derivations: u64,
+    pub endpoints: Vec<EndpointMetrics>,
+}
+
+// Call counters since the last install or upgrade.
+#[derive(Default)]
+pub struct CallCounters {
+    pub since: u64,
+    pub vetkd_derivations: u64,
+    pub endpoints: BTreeMap<&'static str, EndpointMetrics>,
+}
+
+pub fn _record_call(endpoint: &'static str, instructions: u64, failed: bool, counters: &mut CallCounters) {
+    let metrics = counters.endpoints.entry(endpoint).or_insert_with(|| EndpointMetrics {
+        endpoint: endpoint.to_string(),
+        ..Default::default()
+    });
+    metrics.calls += 1;
+    if failed {
+        metrics.errors += 1;
+    }
+    metrics.instructions_total = metrics.instructions_total.saturating_add(instructions);
+    metrics.instructions_max = metrics.instructions_max.max(instructions);
+}
+
+pub fn _section_metrics(state: &GeneralState) -> Vec<SectionMetrics> {
+    state
+        .snapshot_sections()
+        .into_iter()
+        .map(|(memory_id, name, store)| SectionMetrics {
+            memory_id,
+            name: name.to_string(),
+            pages: state.memory_manager.get(MemoryId::new(memory_id)).size(),
+            entries: store.entry_count(),
+        })
+        .collect()
+}
+
+pub fn _collect_metrics(now: u64, cycles_balance: u128, stable_memory_pages: u64, counters: &CallCounters, state: &GeneralState) -> CanisterMetrics {
+    let capacity = _get_capacity(state);
+    CanisterMetrics {
+        collected_at: now,
+        counting_since: counters.since,
+        cycles_balance,
+        stable_memory_pages,
+        sections: _section_metrics(state),
+        users: capacity.users,
+        max_users: capacity.max_users,
+        vetkd_derivations: counters.vetkd_derivations,
+        endpoints: counters.endpoints.values().cloned().collect(),
+    }
+}
+
+fn _write_metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, String)]) {
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+    for (labels, value) in samples {
+        out.push_str(&format!("{}{} {}\n", name, labels, value));
+    }
+}
+
+// Prometheus text exposition format.
+pub fn _encode_prometheus(metrics: &CanisterMetrics) -> String {
+    let mut out = String::new();
+    let single = |value: String| vec![(String::new(), value)];
+    _write_metric(&mut out, "vault_cycles_balance", "gauge", "Liquid cycles balance.", &single(metrics.cycles_balance.to_string()));
+    _write_metric(&mut out, "vault_stable_memory_pages", "gauge", "Stable memory size in 64 KiB pages.", &single(metrics.stable_memory_pages.to_string()));
+    let section = |value: fn(&SectionMetrics) -> u64| -> Vec<(String, String)> {
+        metrics
+            .sections
+            .iter()
+            .map(|s| (format!("{{memory_id=\"{}\",name=\"{}\"}}", s.memory_id, s.name), value(s).to_string()))
+            .collect()
+    };
+    _write_metric(&mut out, "vault_memory_pages", "gauge", "Stable memory pages per memory id.", &section(|s| s.pages));
+    _write_metric(&mut out, "vault_entries", "gauge", "Entries per stable structure.", &section(|s| s.entries));
+    _write_metric(&mut out, "vault_users", "gauge", "Registered users.", &single(metrics.users.to_string()));
+    _write_metric(&mut out, "vault_max_users", "gauge", "User limit of the canister.", &single(metrics.max_users.to_string()));
+    _write_metric(&mut out, "vault_vetkd_derivations_total", "counter", "vetKD keys derived since the last upgrade.", &single(metrics.vetkd_derivations.to_string()));
+    let endpoint = |value: fn(&EndpointMetrics) -> u64| -> Vec<(String, String)> {
+        metrics
+            .endpoints
+            .iter()
+            .map(|e| (format!("{{endpoint=\"{}\"}}", e.endpoint), value(e).to_string()))
+            .collect()
+    };
+    _write_metric(&mut out, "vault_calls_total", "counter", "Calls since the last upgrade.", &endpoint(|e| e.calls));
+    _write_metric(&mut out, "vault_errors_total", "counter", "Calls that returned an error since the last upgrade.", &endpoint(|e| e.errors));
+    _write_metric(&mut out, "vault_instructions_total", "counter", "Instructions used since the last upgrade.", &endpoint(|e| e.instructions_total));
+    _write_metric(&mut out, "vault_instructions_max", "gauge", "Most instructions used by a single call.", &endpoint(|e| e.instructions_max));
+    out
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+}
+
+// Serves `/metrics`; every other path is not found.
+pub fn _http_response(request: &HttpRequest, metrics: impl FnOnce() -> CanisterMetrics) -> HttpResponse {
+    let path = request.url.split('?').next().unwrap_or_default();
+    if request.method != "GET" || path != "/metrics" {
+        return HttpResponse {
+            status_code: 404,
+            headers: vec![("Content-Type".into(), "text/plain".into())],
+            body: b"Not found".to_vec(),
+        };
+    }
+    let body = _encode_prometheus(&metrics()).into_bytes();
+    HttpResponse {
+        status_code: 200,
+        headers: vec![
+            ("Content-Type".into(), "text/plain; version=0.0.4".into()),
+            ("Content-Length".into(), body.len().to_string()),
+        ],
+        body,
+    }
+}
@@ -0,0 +1,805 @@
+use std::{borrow::Cow, cell::RefCell, collections::BTreeMap, ops::{Bound, RangeInclusive}};
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::{StableBTreeMap, Storable};
+
+use crate::{
+    api::{
+        deletion_api::{_is_deletion_pending, _schedule_user_purge, PendingDeletionInfo},
+        deserialiser::{deserialise_folders, deserialise_item_labels, deserialise_tags},
+        deserialiser_types::SpreadsheetColumnHeader,
+        dev_api::_get_vault_names,
+        machine_api::_put_grant,
+        organization_api::{_apply_folders, _apply_item_labels, _apply_tags, MAX_TAG_ID_BYTES},
+        registry_api::{_record_vault_change, _register_vaults, _user_vaults, SizeChange},
+        search_api::_search_tokens_sync,
+        serial_api::{_custom_records_sync, _login_data_sync, _login_metadata_sync, _records_sync, _secret_notes_sync, _sheet_columns_sync, _sheet_sync, _totp_sync, _vault_names_sync, _vault_spreadsheet_columns_sync, _vault_spreadsheet_sync, SyncOutcome, GRID_POSITIONS},
+        templates_api::_set_record_template,
+    },
+    stable::types::{GeneralState, Memory, MigrationGrantsMap},
+    vault_type::{
+        attachments::{AttachmentChunkKey, AttachmentKey, AttachmentManifest, ItemRef},
+        logins::LoginSiteKey,
+        organization::{Folder, FolderKey, ItemLabelsKey, TagKey},
+        records::{RecordKey, RecordKind},
+        search::ItemTokensKey,
+        secure_notes::SecureNoteKey,
+        sheets::{vault_sheet_principals, SheetKey, SheetRecord},
+        spreadsheet::{ColumnData, ColumnKey, SpreadsheetKey, COLUMN_TYPED},
+        templates::{RecordTemplate, TemplateKey},
+        totp::TotpKey,
+        vault_names::VaultNameKey,
+        machine_grants::{MachineGrant, MachineGrantKey, VaultMachineKey},
+        migration::{ExportCursor, MigrationChunk, MigrationExport, MigrationGrant, MigrationManifest, MigrationSection},
+    },
+};
+
+/*
+    Moving a user from a shared canister to a dedicated one. The user authorises the target
+    canister, which pulls the user's data page by page, applies it, checks it against the
+    source's manifest and then has the source purge the user through the delayed-delete path.
+    The source keeps its place in the export between pages and builds the manifest as they go,
+    so no call reads more than a page.
+*/
+
+// How long an authorisation lets the target export the user's data.
+pub const MIGRATION_GRANT_TTL_NS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000; // 7 days
+
+// Chunks stay well under the message size limit; a single item never comes close. Attachment
+// chunks of up to a megabyte go in a chunk of their own. A page also stops after probing two
+// grids' worth of positions, so sparse sheets can't take it past the instruction limit.
+const MAX_CHUNK_BYTES: usize = 512 * 1024;
+const MAX_PAGE_BYTES: usize = 1_500_000;
+const MAX_PAGE_PROBES: u32 = 2 * GRID_POSITIONS;
+
+// The sections of each vault, in export order. The import relies on it: custom records follow
+// the templates and attachments they refer to, and item labels the folders and tags they name.
+const VAULT_SECTIONS: [MigrationSection; 20] = [
+    MigrationSection::SpreadsheetColumns, MigrationSection::Spreadsheet, MigrationSection::Sheets, MigrationSection::SheetColumns,
+    MigrationSection::SheetCells, MigrationSection::LoginMetadata, MigrationSection::LoginData, MigrationSection::SecureNotes,
+    MigrationSection::Totp, MigrationSection::PaymentCards, MigrationSection::Identities, MigrationSection::Attachments,
+    MigrationSection::AttachmentChunks, MigrationSection::RecordTemplates, MigrationSection::CustomRecords, MigrationSection::Folders,
+    MigrationSection::Tags, MigrationSection::ItemLabels, MigrationSection::SearchTokens, MigrationSection::MachineGrants,
+];
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MigrationReport {
+    pub source: Principal,
+    pub vaults: u32,
+    pub items: u64,
+    pub checksum: Vec<u8>,
+    // The purge scheduled on the source, or why it couldn't be. The import stands either way.
+    pub source_purge: Result<PendingDeletionInfo, String>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MigrationPage {
+    pub chunks: Vec<MigrationChunk>,
+    // Cursor for the next page: the number of chunks exported so far. None once everything has
+    // been exported.
+    pub next: Option<u64>,
+}
+
+pub fn _authorize_migration(user_id: Principal, target: Principal, now: u64, grants: &MigrationGrantsMap) -> Result<(), String> {
+    if target == user_id || target == Principal::anonymous() {
+        return Err("invalid migration target".into());
+    }
+    grants.borrow_mut().insert(user_id, MigrationGrant { target, authorized_at: now });
+    Ok(())
+}
+
+pub fn _cancel_migration(user_id: Principal, state: &GeneralState) -> Result<(), String> {
+    state.migration_exports.borrow_mut().remove(&user_id);
+    state.migration_grants.borrow_mut().remove(&user_id).map(|_| ()).ok_or_else(|| "no migration authorised".into())
+}
+
+// The export is open to the target the user authorised, and to controllers while the
+// authorisation lasts.
+pub fn _assert_migration_caller(user_id: Principal, caller: Principal, is_controller: bool, now: u64, grants: &MigrationGrantsMap) -> Result<(), String> {
+    let grant = grants.borrow().get(&user_id).ok_or("no migration authorised")?;
+    if grant.authorized_at.saturating_add(MIGRATION_GRANT_TTL_NS) <= now {
+        return Err("migration authorisation expired".into());
+    }
+    if caller != grant.target && !is_controller {
+        return Err(format!("Unauthorized caller: {}", caller));
+    }
+    Ok(())
+}
+
+// A page being filled, one section after another, up to its limits.
+#[derive(Default)]
+struct ExportPage {
+    section: Option<MigrationSection>,
+    vault_id: Option<Principal>,
+    // Each chunk with the number of entries it holds.
+    chunks: Vec<(MigrationChunk, u32)>,
+    size: usize,
+    probes: u32,
+}
+impl ExportPage {
+    // Adds an entry of the current section, unless the page is full. Entries are grouped into
+    // chunks of at most MAX_CHUNK_BYTES.
+    fn push(&mut self, entry: Vec<u8>) -> bool {
+        if self.size > 0 && self.size + entry.len() > MAX_PAGE_BYTES {
+            return false;
+        }
+        self.size += entry.len();
+        let section = self.section.expect("page section");
+        match self.chunks.last_mut() {
+            Some((chunk, items)) if chunk.section == section && chunk.vault_id == self.vault_id && chunk.data.len() + entry.len() <= MAX_CHUNK_BYTES => {
+                chunk.data.extend(entry);
+                *items += 1;
+            }
+            _ => self.chunks.push((MigrationChunk { section, vault_id: self.vault_id, data: entry }, 1)),
+        }
+        true
+    }
+
+    // Counts a probe. False once the page has probed all it may.
+    fn probe(&mut self) -> bool {
+        self.probes += 1;
+        self.probes <= MAX_PAGE_PROBES
+    }
+}
+
+// Puts the entries of `range` on the page, from the stored key `from` if the section was left
+// part way. Returns the stored key of the first entry left for the next page.
+fn _export_range<K, V>(map: &RefCell<StableBTreeMap<K, V, Memory>>, range: RangeInclusive<K>, from: &[u8], page: &mut ExportPage, mut encode: impl FnMut(K, V) -> Option<Vec<u8>>) -> Option<Vec<u8>>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+{
+    let (start, end) = range.into_inner();
+    let start = if from.is_empty() { start } else { K::from_bytes(Cow::Borrowed(from)) };
+    for entry in map.borrow().range(start..=end) {
+        let (key, value) = entry.into_pair();
+        let resume = key.to_bytes().into_owned();
+        if let Some(bytes) = encode(key, value) {
+            if !page.push(bytes) {
+                return Some(resume);
+            }
+        }
+    }
+    None
+}
+
+// Puts what `probe` finds at each position from `start` to `end` on the page. Returns the first
+// position left for the next page.
+fn _export_probes(start: u32, end: u32, page: &mut ExportPage, mut probe: impl FnMut(u32) -> Option<Vec<u8>>) -> Option<u32> {
+    for position in start..end {
+        if !page.probe() {
+            return Some(position);
+        }
+        if let Some(bytes) = probe(position) {
+            if !page.push(bytes) {
+                return Some(position);
+            }
+        }
+    }
+    None
+}
+
+// The other sheets are probed one after another: the position is the sheet times `per_sheet`
+// plus the position within the sheet.
+fn _export_sheet_probes(sheets: &[u8], start: u32, per_sheet: u32, page: &mut ExportPage, mut probe: impl FnMut(u8, u32) -> Option<Vec<u8>>) -> Option<u32> {
+    for sheet in sheets.iter().copied().filter(|sheet| u32::from(*sheet) >= start / per_sheet) {
+        let base = u32::from(sheet) * per_sheet;
+        if let Some(position) = _export_probes(start.max(base) - base, per_sheet, page, |position| probe(sheet, position)) {
+            return Some(base + position);
+        }
+    }
+    None
+}
+
+fn _position(key: &[u8]) -> u32 {
+    key.try_into().map_or(0, u32::from_be_bytes)
+}
+
+fn _encode_cell(x: u8, y: u8, data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(4 + data.len());
+    bytes.extend((data.len() as u16).to_be_bytes());
+    bytes.push(x);
+    bytes.push(y);
+    bytes.extend(data);
+    bytes
+}
+
+fn _encode_column(x: u8, column: ColumnData) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(SpreadsheetColumnHeader::TYPED_SIZE + column.name.len());
+    bytes.extend((column.name.len() as u16).to_be_bytes());
+    bytes.push(COLUMN_TYPED | u8::from(column.hidden));
+    bytes.push(x);
+    bytes.push(column.kind.to_byte());
+    bytes.extend(column.settings.to_bytes());
+    bytes.extend(column.name);
+    bytes
+}
+
+// Splits entries that start with their sheet into one update per sheet. After the sheet, each
+// entry has a header of `header_size` bytes led by the size (u16) of what follows it.
+fn _by_sheet(data: &[u8], header_size: usize) -> Result<BTreeMap<u8, Vec<u8>>, String> {
+    let mut updates: BTreeMap<u8, Vec<u8>> = BTreeMap::new();
+    let mut index = 0;
+    while index < data.len() {
+        if index + 3 > data.len() {
+            return Err("sheet entry header is truncated".into());
+        }
+        let size = usize::from(u16::from_be_bytes([data[index + 1], data[index + 2]]));
+        let end = index + 1 + header_size + size;
+        if end > data.len() {
+            return Err("sheet entry is truncated".into());
+        }
+        updates.entry(data[index]).or_default().extend(&data[index + 1..end]);
+        index = end;
+    }
+    Ok(updates)
+}
+
+// The `len` bytes of a chunk from `start`, or an error if the chunk ends first.
+fn _take(data: &[u8], start: usize, len: usize) -> Result<&[u8], String> {
+    data.get(start..start + len).ok_or_else(|| "chunk entry is truncated".into())
+}
+
+// The user's first vault after `after`. Names sort by user and then vault, so this is a single
+// lookup and the vaults go in key order.
+fn _next_vault(user_id: Principal, after: &[u8], state: &GeneralState) -> Option<Vec<u8>> {
+    let user = user_id.as_slice();
+    let key = VaultNameKey { principals: [user, after].concat() };
+    state.vault_names_map.borrow()
+        .keys_range((Bound::Excluded(key), Bound::Unbounded))
+        .next()
+        .filter(|key| key.principals.len() > user.len() && key.principals.starts_with(user))
+        .map(|key| key.principals[user.len()..].to_vec())
+}
+
+// Where the export goes once the section at `cursor` is done, None after the last one.
+fn _next_section(user_id: Principal, cursor: &ExportCursor, state: &GeneralState) -> Option<ExportCursor> {
+    let next_vault = |after: &[u8]| _next_vault(user_id, after, state)
+        .map(|vault| ExportCursor { vault, section: VAULT_SECTIONS[0], key: Vec::new() });
+    match cursor.section {
+        MigrationSection::VaultNames => Some(ExportCursor { vault: Vec::new(), section: MigrationSection::KeyMetadata, key: Vec::new() }),
+        MigrationSection::KeyMetadata => next_vault(&[]),
+        section => match VAULT_SECTIONS.iter().skip_while(|next| **next != section).nth(1) {
+            Some(next) => Some(ExportCursor { vault: cursor.vault.clone(), section: *next, key: Vec::new() }),
+            None => next_vault(&cursor.vault),
+        },
+    }
+}
+
+// Puts the entries of the section at `cursor` on the page. Returns the key the section resumes
+// from on the next page, None once it is done.
+fn _export_section(user_id: Principal, cursor: &ExportCursor, page: &mut ExportPage, state: &GeneralState) -> Option<Vec<u8>> {
+    let from = cursor.key.as_slice();
+    let principals = [user_id.as_slice(), cursor.vault.as_slice()].concat();
+    let p = || principals.clone();
+    let position = |position: Option<u32>| position.map(|position| position.to_be_bytes().to_vec());
+    let sheets = || -> Vec<u8> {
+        let vault_id = Principal::from_slice(&cursor.vault);
+        state.sheets.borrow().range(SheetKey::new(user_id, vault_id, 1)..=SheetKey::new(user_id, vault_id, u8::MAX))
+            .map(|entry| entry.key().sheet)
+            .collect()
+    };
+    match cursor.section {
+        MigrationSection::VaultNames => {
+            let user = user_id.as_slice();
+            let start = VaultNameKey { principals: [user, from].concat() };
+            for entry in state.vault_names_map.borrow().range(start..) {
+                let (key, name) = entry.into_pair();
+                if !key.principals.starts_with(user) {
+                    break;
+                }
+                let vault_id = &key.principals[user.len()..];
+                if vault_id.is_empty() {
+                    continue;
+                }
+                let mut bytes = vec![vault_id.len() as u8];
+                bytes.extend((name.name.len() as u16).to_be_bytes());
+                bytes.extend(vault_id);
+                bytes.extend(name.name);
+                if !page.push(bytes) {
+                    return Some(vault_id.to_vec());
+                }
+            }
+            None
+        }
+        MigrationSection::KeyMetadata => {
+            let key = state.key_management.borrow().get(&user_id.to_text())?;
+            (!page.push(key)).then(Vec::new)
+        }
+        MigrationSection::SpreadsheetColumns => position(_export_probes(_position(from), 256, page, |x| {
+            state.spreadsheet_columns.borrow().get(&ColumnKey { principals: p(), x: x as u8 }).map(|column| _encode_column(x as u8, column))
+        })),
+        MigrationSection::Spreadsheet => position(_export_probes(_position(from), GRID_POSITIONS, page, |position| {
+            let (x, y) = ((position >> 8) as u8, position as u8);
+            state.spreadsheet_map.borrow().get(&SpreadsheetKey { principals: p(), x, y }).map(|value| _encode_cell(x, y, &value.data))
+        })),
+        MigrationSection::Sheets => {
+            let vault_id = Principal::from_slice(&cursor.vault);
+            _export_range(&state.sheets, SheetKey::new(user_id, vault_id, 0)..=SheetKey::new(user_id, vault_id, u8::MAX), from, page, |key, record| {
+                let mut bytes = vec![key.sheet, record.position];
+                bytes.extend((record.name.len() as u16).to_be_bytes());
+                bytes.extend(record.name);
+                Some(bytes)
+            })
+        }
+        MigrationSection::SheetColumns => position(_export_sheet_probes(&sheets(), _position(from), 256, page, |sheet, x| {
+            let key = ColumnKey { principals: vault_sheet_principals(&principals, sheet), x: x as u8 };
+            state.spreadsheet_columns.borrow().get(&key).map(|column| [vec![sheet], _encode_column(x as u8, column)].concat())
+        })),
+        MigrationSection::SheetCells => position(_export_sheet_probes(&sheets(), _position(from), GRID_POSITIONS, page, |sheet, position| {
+            let (x, y) = ((position >> 8) as u8, position as u8);
+            let key = SpreadsheetKey { principals: vault_sheet_principals(&principals, sheet), x, y };
+            state.spreadsheet_map.borrow().get(&key).map(|value| [vec![sheet], _encode_cell(x, y, &value.data)].concat())
+        })),
+        MigrationSection::LoginMetadata => _export_range(&state.logins_columns, LoginSiteKey { principals: p(), x: 0 }..=LoginSiteKey { principals: p(), x: u8::MAX }, from, page, |key, label| {
+            let mut bytes = Vec::new();
+            bytes.extend((label.len() as u16).to_be_bytes());
+            bytes.push(key.x);
+            bytes.extend(label);
+            Some(bytes)
+        }),
+        MigrationSection::LoginData => position(_export_probes(_position(from), GRID_POSITIONS, page, |position| {
+            let (x, y) = ((position >> 8) as u8, position as u8);
+            state.logins_map.borrow().get(&SpreadsheetKey { principals: p(), x, y }).map(|value| _encode_cell(x, y, &value.data))
+        })),
+        // Notes are keyed by index first, so they're probed too.
+        MigrationSection::SecureNotes => position(_export_probes(_position(from), 256, page, |index| {
+            let value = state.notes_map.borrow().get(&SecureNoteKey { index: index as u8, principals: p() })?;
+            let mut bytes = vec![value.label.len() as u8];
+            bytes.extend((value.note.len() as u16).to_be_bytes());
+            bytes.push(index as u8);
+            bytes.extend(value.label);
+            bytes.extend(value.note);
+            Some(bytes)
+        })),
+        MigrationSection::Totp => _export_range(&state.totp_map, TotpKey { principals: p(), index: 0 }..=TotpKey { principals: p(), index: u8::MAX }, from, page, |key, value| {
+            let mut bytes = vec![value.label.len() as u8];
+            bytes.extend((value.seed.len() as u16).to_be_bytes());
+            bytes.push(key.index);
+            bytes.push(value.params.algorithm.to_byte());
+            bytes.push(value.params.digits);
+            bytes.extend(value.params.period.to_be_bytes());
+            bytes.push(u8::from(value.login_column.is_some()));
+            bytes.push(value.login_column.unwrap_or_default());
+            bytes.extend(value.label);
+            bytes.extend(value.seed);
+            Some(bytes)
+        }),
+        MigrationSection::PaymentCards | MigrationSection::Identities => {
+            let kind = if cursor.section == MigrationSection::PaymentCards { RecordKind::PaymentCard } else { RecordKind::Identity };
+            _export_range(&state.records, RecordKey { principals: p(), kind, index: 0 }..=RecordKey { principals: p(), kind, index: u8::MAX }, from, page, |key, record| {
+                let fields: Vec<u8> = record.fields.iter().flat_map(|(field, value)| {
+                    let mut bytes = vec![*field];
+                    bytes.extend((value.len() as u16).to_be_bytes());
+                    bytes.extend(value);
+                    bytes
+                }).collect();
+                let expiry = record.expiry.map_or([0; 4], |expiry| {
+                    let year = expiry.year.to_be_bytes();
+                    [1, year[0], year[1], expiry.month]
+                });
+                let mut bytes = (fields.len() as u16).to_be_bytes().to_vec();
+                bytes.push(key.index);
+                bytes.push(record.type_tag);
+                bytes.extend(expiry);
+                bytes.extend(fields);
+                Some(bytes)
+            })
+        }
+        // Uploads still in progress stay behind, with their chunks.
+        MigrationSection::Attachments => _export_range(&state.attachments, AttachmentKey { principals: p(), id: 0 }..=AttachmentKey { principals: p(), id: u64::MAX }, from, page, |key, manifest| {
+            if !manifest.complete {
+                return None;
+            }
+            let manifest = manifest.into_bytes();
+            let mut bytes = key.id.to_be_bytes().to_vec();
+            bytes.extend((manifest.len() as u16).to_be_bytes());
+            bytes.extend(manifest);
+            Some(bytes)
+        }),
+        MigrationSection::AttachmentChunks => {
+            let range = AttachmentChunkKey { principals: p(), id: 0, index: 0 }..=AttachmentChunkKey { principals: p(), id: u64::MAX, index: u32::MAX };
+            let mut complete = (u64::MAX, false);
+            _export_range(&state.attachment_chunks, range, from, page, |key, data| {
+                if complete.0 != key.id {
+                    let manifest = state.attachments.borrow().get(&AttachmentKey { principals: p(), id: key.id });
+                    complete = (key.id, manifest.is_some_and(|manifest| manifest.complete));
+                }
+                if !complete.1 {
+                    return None;
+                }
+                let mut bytes = Vec::with_capacity(16 + data.len());
+                bytes.extend(key.id.to_be_bytes());
+                bytes.extend(key.index.to_be_bytes());
+                bytes.extend((data.len() as u32).to_be_bytes());
+                bytes.extend(data);
+                Some(bytes)
+            })
+        }
+        MigrationSection::RecordTemplates => _export_range(&state.record_templates, TemplateKey { principals: p(), index: 0 }..=TemplateKey { principals: p(), index: u8::MAX }, from, page, |key, template| {
+            let template = template.into_bytes();
+            let mut bytes = vec![key.index];
+            bytes.extend((template.len() as u16).to_be_bytes());
+            bytes.extend(template);
+            Some(bytes)
+        }),
+        MigrationSection::CustomRecords => _export_range(&state.custom_records, TemplateKey { principals: p(), index: 0 }..=TemplateKey { principals: p(), index: u8::MAX }, from, page, |key, record| {
+            let values: Vec<u8> = record.values.iter().flat_map(|value| {
+                let mut bytes = (value.len() as u16).to_be_bytes().to_vec();
+                bytes.extend(value);
+                bytes
+            }).collect();
+            let mut bytes = (values.len() as u16).to_be_bytes().to_vec();
+            bytes.push(key.index);
+            bytes.push(record.template);
+            bytes.extend(values);
+            Some(bytes)
+        }),
+        // Folders are put in an order where each comes after its parent, as the import checks.
+        // The key is the number of folders already exported in that order.
+        MigrationSection::Folders => {
+            let mut rest: Vec<(u64, Folder)> = state.folders.borrow()
+                .range(FolderKey { principals: p(), id: 0 }..=FolderKey { principals: p(), id: u64::MAX })
+                .map(|entry| {
+                    let (key, folder) = entry.into_pair();
+                    (key.id, folder)
+                }).collect();
+            let mut ordered = Vec::new();
+            let mut placed: Vec<u64> = Vec::new();
+            while !rest.is_empty() {
+                let (ready, others): (Vec<_>, Vec<_>) = rest.into_iter()
+                    .partition(|(_, folder)| folder.parent.is_none_or(|parent| placed.contains(&parent)));
+                placed.extend(ready.iter().map(|(id, _)| *id));
+                ordered.extend(ready);
+                rest = others;
+            }
+            let start = _position(from);
+            for (index, (id, folder)) in ordered.into_iter().enumerate().skip(start as usize) {
+                let mut bytes = (folder.name.len() as u16).to_be_bytes().to_vec();
+                bytes.extend(id.to_be_bytes());
+                bytes.push(u8::from(folder.parent.is_some()));
+                bytes.extend(folder.parent.unwrap_or(0).to_be_bytes());
+                bytes.extend(folder.name);
+                if !page.push(bytes) {
+                    return Some((index as u32).to_be_bytes().to_vec());
+                }
+            }
+            None
+        }
+        MigrationSection::Tags => _export_range(&state.tags, TagKey { principals: p(), id: Vec::new() }..=TagKey { principals: p(), id: vec![u8::MAX; MAX_TAG_ID_BYTES] }, from, page, |key, label| {
+            let mut bytes = (label.len() as u16).to_be_bytes().to_vec();
+            bytes.push(key.id.len() as u8);
+            bytes.extend(key.id);
+            bytes.extend(label);
+            Some(bytes)
+        }),
+        MigrationSection::ItemLabels => _export_range(&state.item_labels, ItemLabelsKey { principals: p(), item: ItemRef::FIRST }..=ItemLabelsKey { principals: p(), item: ItemRef::LAST }, from, page, |key, labels| {
+            let tags: Vec<u8> = labels.tags.iter().flat_map(|tag| [vec![tag.len() as u8], tag.clone()].concat()).collect();
+            let mut bytes = key.item.to_bytes().to_vec();
+            bytes.push(u8::from(labels.folder.is_some()));
+            bytes.extend(labels.folder.unwrap_or(0).to_be_bytes());
+            bytes.extend((tags.len() as u16).to_be_bytes());
+            bytes.extend(tags);
+            Some(bytes)
+        }),
+        MigrationSection::SearchTokens => _export_range(&state.item_tokens, ItemTokensKey { principals: p(), item: ItemRef::FIRST }..=ItemTokensKey { principals: p(), item: ItemRef::LAST }, from, page, |key, tokens| {
+            let tokens: Vec<u8> = tokens.tokens.iter().flat_map(|token| [vec![token.len() as u8], token.clone()].concat()).collect();
+            let mut bytes = key.item.to_bytes().to_vec();
+            bytes.extend((tokens.len() as u16).to_be_bytes());
+            bytes.extend(tokens);
+            Some(bytes)
+        }),
+        MigrationSection::MachineGrants => _export_range(&state.vault_machines, VaultMachineKey::vault_range(&principals), from, page, |key, user_size| {
+            let key = key.grant_key(user_size);
+            let grant = state.machine_grants.borrow().get(&key)?.into_bytes();
+            let mut bytes = vec![key.machine.len() as u8];
+            bytes.extend(key.machine);
+            bytes.extend((grant.len() as u16).to_be_bytes());
+            bytes.extend(grant);
+            Some(bytes)
+        }),
+    }
+}
+
+// The next page of the user's export. Without a cursor the export starts over; with one, it
+// carries on from where the last page stopped, which the cursor must name. Only that page is
+// read, and the manifest is built up as the pages go.
+pub fn _export_page(user_id: Principal, cursor: Option<u64>, now: u64, state: &GeneralState) -> Result<MigrationPage, String> {
+    let mut export = match cursor {
+        None => MigrationExport::new(now),
+        Some(chunks) => state.migration_exports.borrow().get(&user_id)
+            .filter(|export| export.cursor.is_some() && export.manifest.chunks == chunks)
+            .ok_or("export cursor is out of step, start the export over")?,
+    };
+    let mut page = ExportPage::default();
+    while let Some(cursor) = export.cursor.take() {
+        page.section = Some(cursor.section);
+        page.vault_id = (!cursor.vault.is_empty()).then(|| Principal::from_slice(&cursor.vault));
+        match _export_section(user_id, &cursor, &mut page, state) {
+            Some(key) => {
+                export.cursor = Some(ExportCursor { key, ..cursor });
+                break;
+            }
+            None => export.cursor = _next_section(user_id, &cursor, state),
+        }
+    }
+    for (chunk, items) in page.chunks.iter() {
+        export.manifest.add(chunk, *items);
+    }
+    let next = export.cursor.is_some().then_some(export.manifest.chunks);
+    state.migration_exports.borrow_mut().insert(user_id, export);
+    Ok(MigrationPage {
+        chunks: page.chunks.into_iter().map(|(chunk, _)| chunk).collect(),
+        next,
+    })
+}
+
+// The counts and checksum of the user's export, once its last page has gone out.
+pub fn _migration_manifest(user_id: Principal, state: &GeneralState) -> Result<MigrationManifest, String> {
+    let export = state.migration_exports.borrow().get(&user_id).ok_or("no export started")?;
+    if export.cursor.is_some() {
+        return Err("the export has not finished".into());
+    }
+    Ok(export.manifest)
+}
+
+// Ends the migration once the target has everything: the user is purged through the usual
+// grace period, so the move can still be undone by cancelling the deletion. The vaults must not
+// have changed since the export started, going by the registry.
+pub fn _complete_migration(user_id: Principal, checksum: &[u8], now: u64, state: &GeneralState) -> Result<PendingDeletionInfo, String> {
+    let manifest = _migration_manifest(user_id, state)?;
+    let started_at = state.migration_exports.borrow().get(&user_id).map_or(0, |export| export.started_at);
+    if manifest.checksum != checksum {
+        return Err("checksum mismatch".into());
+    }
+    let vaults = _user_vaults(user_id.as_slice(), &state.vault_registry);
+    let names = _get_vault_names(user_id, &state.vault_names_map).names.len() as u32;
+    if names != manifest.vaults || vaults.iter().any(|record| record.updated_at > started_at) {
+        return Err("the vaults changed during the migration, export them again".into());
+    }
+    let pending = _schedule_user_purge(user_id, now, state)?;
+    state.migration_grants.borrow_mut().remove(&user_id);
+    state.migration_exports.borrow_mut().remove(&user_id);
+    Ok(pending)
+}
+
+// Imports only go into a canister that holds nothing for the user yet, so the result can be
+// checked against the source's manifest.
+pub fn _assert_import_target_empty(user_id: Principal, state: &GeneralState) -> Result<(), String> {
+    if _is_deletion_pending(user_id, None, &state.pending_deletions) {
+        return Err("a previous import is still being cleaned up".into());
+    }
+    if !_get_vault_names(user_id, &state.vault_names_map).names.is_empty() || !_user_vaults(user_id.as_slice(), &state.vault_registry).is_empty() {
+        return Err("canister already holds vaults".into());
+    }
+    Ok(())
+}
+
+// Applies a chunk through the sync path of its section, registering the vaults it names and
+// counting the items it adds. Returns the number of items applied.
+pub fn _import_chunk(user_id: Principal, chunk: MigrationChunk, now: u64, state: &GeneralState) -> Result<u32, String> {
+    let vault_id = match chunk.section {
+        MigrationSection::VaultNames => {
+            _register_vaults(user_id, &chunk.data, now, &state.vault_registry);
+            return Ok(_vault_names_sync(user_id, &chunk.data, &state.vault_names_map));
+        }
+        MigrationSection::KeyMetadata => {
+            state.key_management.borrow_mut().insert(user_id.to_text(), chunk.data);
+            return Ok(1);
+        }
+        _ => chunk.vault_id.ok_or("chunk is missing its vault")?,
+    };
+    let count = match chunk.section {
+        MigrationSection::SpreadsheetColumns => _vault_spreadsheet_columns_sync(user_id, vault_id, chunk.data, &state.spreadsheet_columns),
+        MigrationSection::Spreadsheet => _counted(user_id, vault_id, _vault_spreadsheet_sync(user_id, vault_id, chunk.data, &state.spreadsheet_map), now, state),
+        MigrationSection::Sheets => {
+            let mut sheets = state.sheets.borrow_mut();
+            let (data, mut index, mut count) = (chunk.data, 0, 0);
+            while index < data.len() {
+                let header = _take(&data, index, 4)?;
+                let name_size = usize::from(u16::from_be_bytes([header[2], header[3]]));
+                let record = SheetRecord { position: header[1], name: _take(&data, index + 4, name_size)?.to_vec() };
+                sheets.insert(SheetKey::new(user_id, vault_id, header[0]), record);
+                index += 4 + name_size;
+                count += 1;
+            }
+            count
+        }
+        MigrationSection::SheetColumns => _by_sheet(&chunk.data, SpreadsheetColumnHeader::TYPED_SIZE)?.into_iter()
+            .map(|(sheet, update)| _sheet_columns_sync(user_id, vault_id, sheet, update, &state.spreadsheet_columns))
+            .sum(),
+        MigrationSection::SheetCells => _by_sheet(&chunk.data, 4)?.into_iter()
+            .map(|(sheet, update)| _counted(user_id, vault_id, _sheet_sync(user_id, vault_id, sheet, update, &state.spreadsheet_map), now, state))
+            .sum(),
+        MigrationSection::LoginMetadata => _counted(user_id, vault_id, _login_metadata_sync(user_id, vault_id, chunk.data, &state.logins_columns, &state.logins_map), now, state),
+        MigrationSection::LoginData => _counted(user_id, vault_id, _login_data_sync(user_id, vault_id, chunk.data, &state.logins_map), now, state),
+        MigrationSection::SecureNotes => _counted(user_id, vault_id, _secret_notes_sync(user_id, vault_id, chunk.data, &state.notes_map), now, state),
+        MigrationSection::Totp => _counted(user_id, vault_id, _totp_sync(user_id, vault_id, chunk.data, &state.totp_map), now, state),
+        MigrationSection::MachineGrants => {
+            let (data, mut index, mut count) = (chunk.data, 0, 0);
+            while index < data.len() {
+                let machine_size = usize::from(data[index]);
+                let machine = Principal::try_from_slice(_take(&data, index + 1, machine_size)?).map_err(|e| e.to_string())?;
+                index += 1 + machine_size;
+                let header = _take(&data, index, 2)?;
+                let grant_size = usize::from(u16::from_be_bytes([header[0], header[1]]));
+                let grant = MachineGrant::from_bytes(_take(&data, index + 2, grant_size)?.to_vec().into());
+                index += 2 + grant_size;
+                _put_grant(MachineGrantKey::new(machine, user_id, vault_id), grant, state);
+                count += 1;
+            }
+            count
+        }
+        MigrationSection::PaymentCards => _counted(user_id, vault_id, _records_sync(user_id, vault_id, RecordKind::PaymentCard, chunk.data, &state.records), now, state),
+        MigrationSection::Identities => _counted(user_id, vault_id, _records_sync(user_id, vault_id, RecordKind::Identity, chunk.data, &state.records), now, state),
+        MigrationSection::RecordTemplates => {
+            let (data, mut index, mut count) = (chunk.data, 0, 0);
+            while index < data.len() {
+                let header = _take(&data, index, 3)?;
+                let template_size = usize::from(u16::from_be_bytes([header[1], header[2]]));
+                let template = RecordTemplate::from_bytes(_take(&data, index + 3, template_size)?.to_vec().into());
+                _set_record_template(user_id, vault_id, header[0], template, state)?;
+                index += 3 + template_size;
+                count += 1;
+            }
+            count
+        }
+        MigrationSection::CustomRecords => _counted(user_id, vault_id, _custom_records_sync(user_id, vault_id, chunk.data, state)?, now, state),
+        MigrationSection::Folders => {
+            let folders = deserialise_folders(&chunk.data);
+            _apply_folders(user_id, vault_id, &folders, state)?;
+            folders.folders.len() as u32
+        }
+        MigrationSection::Tags => {
+            let tags = deserialise_tags(&chunk.data);
+            _apply_tags(user_id, vault_id, &tags, state)?;
+            tags.tags.len() as u32
+        }
+        MigrationSection::ItemLabels => {
+            let item_labels = deserialise_item_labels(&chunk.data);
+            _apply_item_labels(user_id, vault_id, &item_labels, state)?;
+            item_labels.items.len() as u32
+        }
+        MigrationSection::SearchTokens => _search_tokens_sync(user_id, vault_id, chunk.data, state)?,
+        MigrationSection::Attachments => {
+            let (data, mut index, mut count) = (chunk.data, 0, 0);
+            let mut change = SizeChange::default();
+            while index < data.len() {
+                let header = _take(&data, index, 10)?;
+                let id = u64::from_be_bytes(header[..8].try_into().unwrap());
+                let manifest_size = usize::from(u16::from_be_bytes([header[8], header[9]]));
+                let manifest = AttachmentManifest::from_bytes(_take(&data, index + 10, manifest_size)?.to_vec().into());
+                index += 10 + manifest_size;
+                change.stored(None, manifest.size as usize);
+                state.attachments.borrow_mut().insert(AttachmentKey::new(user_id, vault_id, id), manifest);
+                count += 1;
+            }
+            _record_vault_change(user_id, vault_id, change, now, &state.vault_registry);
+            count
+        }
+        MigrationSection::AttachmentChunks => {
+            let mut chunks = state.attachment_chunks.borrow_mut();
+            let (data, mut index, mut count) = (chunk.data, 0, 0);
+            while index < data.len() {
+                let header = _take(&data, index, 16)?;
+                let id = u64::from_be_bytes(header[..8].try_into().unwrap());
+                let chunk_index = u32::from_be_bytes(header[8..12].try_into().unwrap());
+                let size = u32::from_be_bytes(header[12..16].try_into().unwrap()) as usize;
+                chunks.insert(AttachmentKey::new(user_id, vault_id, id).chunk(chunk_index), _take(&data, index + 16, size)?.to_vec());
+                index += 16 + size;
+                count += 1;
+            }
+            count
+        }
+        MigrationSection::VaultNames | MigrationSection::KeyMetadata => unreachable!(),
+    };
+    Ok(count)
+}
+
+fn _counted(user_id: Principal, vault_id: Principal, outcome: SyncOutcome, now: u64, state: &GeneralState) -> u32 {
+    _record_vault_change(user_id, vault_id, outcome.size_change(), now, &state.vault_registry);
+    outcome.items
+}
+
+// Applies the chunks of a page in order, counting each into `imported` by the items it applied.
+pub fn _import_page(user_id: Principal, chunks: Vec<MigrationChunk>, imported: &mut MigrationManifest, now: u64, state: &GeneralState) -> Result<(), String> {
+    for chunk in chunks {
+        let items = _import_chunk(user_id, chunk.clone(), now, state)?;
+        imported.add(&chunk, items);
+    }
+    Ok(())
+}
+
+// Every chunk the source announced arrived and applied all of its items.
+pub fn _verify_import(expected: &MigrationManifest, imported: &MigrationManifest) -> Result<(), String> {
+    if imported.vaults != expected.vaults || imported.items != expected.items {
+        return Err(format!("imported {} vaults and {} items, expected {} and {}", imported.vaults, imported.items, expected.vaults, expected.items));
+    }
+    if imported.chunks != expected.chunks || imported.checksum != expected.checksum {
+        return Err("checksum mismatch".into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+
+    use super::{_export_page, _import_chunk, _import_page, _migration_manifest, _verify_import, MAX_PAGE_BYTES};
+    use crate::{
+        api::serial_api::_vault_names_sync,
+        stable::types::GeneralState,
+        vault_type::{
+            attachments::{AttachmentKey, AttachmentManifest},
+            migration::{MigrationChunk, MigrationManifest, MigrationSection},
+        },
+    };
+
+    // Attachments go out a page at a time, each page within the limit, and arrive whole. Uploads
+    // still in progress stay behind.
+    #[test]
+    fn export_pages_attachments() {
+        let source = GeneralState::init();
+        let target = GeneralState::init();
+        let user_id = Principal::from_slice(&[1; 29]);
+        let vault_id = Principal::from_slice(&[2; 29]);
+        let mut names = vec![29, 0, 1];
+        names.extend(vault_id.as_slice());
+        names.push(b'v');
+        _vault_names_sync(user_id, &names, &source.vault_names_map);
+
+        let chunk_size = 1024 * 1024;
+        for (id, complete) in [(1, true), (2, false)] {
+            let key = AttachmentKey::new(user_id, vault_id, id);
+            let manifest = AttachmentManifest { size: 3 * chunk_size as u64, chunk_size: chunk_size as u32, sha256: vec![7; 32], link: None, created_at: 1, updated_at: 1, complete, name: b"file".to_vec() };
+            source.attachments.borrow_mut().insert(key.clone(), manifest);
+            for index in 0..3 {
+                source.attachment_chunks.borrow_mut().insert(key.chunk(index), vec![index as u8; chunk_size]);
+            }
+        }
+
+        let mut imported = MigrationManifest::default();
+        let mut cursor = None;
+        let mut pages = 0;
+        loop {
+            let page = _export_page(user_id, cursor, 1_000, &source).unwrap();
+            assert!(page.chunks.iter().map(|chunk| chunk.data.len()).sum::<usize>() <= MAX_PAGE_BYTES);
+            assert!(page.chunks.iter().all(|chunk| chunk.section != MigrationSection::AttachmentChunks || chunk.data.len() == 16 + chunk_size));
+            _import_page(user_id, page.chunks, &mut imported, 0, &target).unwrap();
+            pages += 1;
+            match page.next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert!(pages >= 3);
+        assert!(_export_page(user_id, cursor, 1_000, &source).is_err());
+
+        let manifest = _migration_manifest(user_id, &source).unwrap();
+        _verify_import(&manifest, &imported).unwrap();
+        // The name, the manifest and its chunks.
+        assert_eq!(manifest.items, 1 + 1 + 3);
+        let key = AttachmentKey::new(user_id, vault_id, 1);
+        assert_eq!(target.attachment_chunks.borrow().get(&key.chunk(2)), Some(vec![2; chunk_size]));
+        assert!(target.attachments.borrow().get(&AttachmentKey::new(user_id, vault_id, 2)).is_none());
+        assert_eq!(target.attachment_chunks.borrow().len(), 3);
+    }
+
+    // A chunk cut short fails the import instead of panicking part way through it.
+    #[test]
+    fn truncated_chunks_are_rejected() {
+        let target = GeneralState::init();
+        let user_id = Principal::from_slice(&[1; 29]);
+        let vault_id = Principal::from_slice(&[2; 29]);
+        for (section, data) in [
+            (MigrationSection::SheetCells, vec![1, 0]),
+            (MigrationSection::SheetCells, vec![1, 0, 9, 0, 0, 1]),
+            (MigrationSection::Sheets, vec![1, 0, 0, 5, b'a']),
+            (MigrationSection::MachineGrants, vec![29, 1, 2]),
+            (MigrationSection::AttachmentChunks, vec![0; 12]),
+        ] {
+            let chunk = MigrationChunk { section, vault_id: Some(vault_id), data };
+            assert!(_import_chunk(user_id, chunk, 0, &target).is_err());
+        }
+    }
+}
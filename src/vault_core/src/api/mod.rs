@@ -2,4 +2,24 @@ pub mod key_api;
 pub mod deserialiser_types;
 pub mod deserialiser;
 pub mod serial_api;
-pub mod dev_api;
\ No newline at end of file
+pub mod dev_api;
+pub mod machine_api;
+pub mod audit_api;
+
+pub mod deletion_api;
+pub mod trash_api;
+pub mod history_api;
+pub mod access_api;
+pub mod cycles_api;
+pub mod capacity_api;
+pub mod migration_api;
+pub mod snapshot_api;
+pub mod metrics_api;
+pub mod config_api;
+pub mod registry_api;
+pub mod attachments_api;
+pub mod templates_api;
+pub mod grid_api;
+pub mod sheets_api;
+pub mod organization_api;
+pub mod search_api;
@@ -0,0 +1,345 @@
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::{
+    api::deserialiser_types::{FoldersData, ItemLabelsData, TagsData},
+    stable::types::{FoldersMap, GeneralState, ItemLabelsMap, TagsMap},
+    vault_type::{
+        attachments::{ItemKind, ItemRef},
+        organization::{Folder, FolderKey, ItemLabels, ItemLabelsKey, TagKey},
+    },
+};
+
+/*
+    Folders and tags across every kind of item. Folders form a tree per vault and an item sits
+    in at most one of them; tags are named by an opaque id the client picks, and an item can
+    carry several. Folder names and tag labels are ciphertext. Items are referred to the way
+    attachments refer to them and need not exist yet, so a sync can file the items it writes.
+    Items lose their labels when they are deleted.
+*/
+
+pub const MAX_FOLDERS: usize = 1024;
+pub const MAX_TAGS: usize = 256;
+pub const MAX_TAGS_PER_ITEM: usize = 32;
+// Tag ids are stored behind a one-byte length.
+pub const MAX_TAG_ID_BYTES: usize = 32;
+pub const MAX_NAME_BYTES: usize = 1024;
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub struct FolderInfo {
+    pub id: u64,
+    pub parent: Option<u64>,
+    pub name: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub struct TagInfo {
+    pub id: Vec<u8>,
+    pub label: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub struct LabelledItem {
+    pub item: ItemRef,
+    pub folder: Option<u64>,
+    pub tags: Vec<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct Organization {
+    pub folders: Vec<FolderInfo>,
+    pub tags: Vec<TagInfo>,
+    pub items: Vec<LabelledItem>,
+}
+
+fn _folder_range(user_id: Principal, vault_id: Principal) -> std::ops::RangeInclusive<FolderKey> {
+    FolderKey::new(user_id, vault_id, 0)..=FolderKey::new(user_id, vault_id, u64::MAX)
+}
+
+fn _tag_range(user_id: Principal, vault_id: Principal) -> std::ops::RangeInclusive<TagKey> {
+    TagKey::new(user_id, vault_id, Vec::new())..=TagKey::new(user_id, vault_id, vec![u8::MAX; MAX_TAG_ID_BYTES])
+}
+
+// Every item of the vault.
+fn _item_range(user_id: Principal, vault_id: Principal) -> std::ops::RangeInclusive<ItemLabelsKey> {
+    ItemLabelsKey::new(user_id, vault_id, ItemRef::FIRST)..=ItemLabelsKey::new(user_id, vault_id, ItemRef::LAST)
+}
+
+// Names and labels can't be empty, as an empty one removes the entry in a sync.
+fn _check_name(name: &[u8]) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_NAME_BYTES {
+        return Err(format!("names must be between 1 and {} bytes", MAX_NAME_BYTES));
+    }
+    Ok(())
+}
+
+fn _check_tag_id(id: &[u8]) -> Result<(), String> {
+    if id.is_empty() || id.len() > MAX_TAG_ID_BYTES {
+        return Err(format!("tag ids must be between 1 and {} bytes", MAX_TAG_ID_BYTES));
+    }
+    Ok(())
+}
+
+// Adds or replaces folder `id`. Its parent must exist and can't be the folder itself or one of
+// the folder's descendants.
+fn _put_folder(user_id: Principal, vault_id: Principal, id: u64, parent: Option<u64>, name: Vec<u8>, folders: &FoldersMap) -> Result<(), String> {
+    _check_name(&name)?;
+    let key = FolderKey::new(user_id, vault_id, id);
+    {
+        let folders = folders.borrow();
+        if !folders.contains_key(&key) && folders.range(_folder_range(user_id, vault_id)).count() >= MAX_FOLDERS {
+            return Err(format!("a vault can have at most {} folders", MAX_FOLDERS));
+        }
+        let mut ancestor = parent;
+        while let Some(current) = ancestor {
+            if current == id {
+                return Err(format!("folder {} can't be moved into itself", id));
+            }
+            ancestor = folders.get(&FolderKey::new(user_id, vault_id, current))
+                .ok_or_else(|| format!("no folder {}", current))?
+                .parent;
+        }
+    }
+    folders.borrow_mut().insert(key, Folder { parent, name });
+    Ok(())
+}
+
+// Adds a folder under `parent`, or at the top. Returns its id.
+pub fn _create_folder(user_id: Principal, vault_id: Principal, parent: Option<u64>, name: Vec<u8>, folders: &FoldersMap) -> Result<u64, String> {
+    let id = folders.borrow().range(_folder_range(user_id, vault_id)).next_back().map_or(0, |entry| entry.key().id + 1);
+    _put_folder(user_id, vault_id, id, parent, name, folders)?;
+    Ok(id)
+}
+
+// Renames a folder and moves it under `parent`, or to the top.
+pub fn _update_folder(user_id: Principal, vault_id: Principal, id: u64, parent: Option<u64>, name: Vec<u8>, folders: &FoldersMap) -> Result<(), String> {
+    if !folders.borrow().contains_key(&FolderKey::new(user_id, vault_id, id)) {
+        return Err(format!("no folder {}", id));
+    }
+    _put_folder(user_id, vault_id, id, parent, name, folders)
+}
+
+// Removes a folder holding no folders or items.
+pub fn _delete_folder(user_id: Principal, vault_id: Principal, id: u64, state: &GeneralState) -> Result<(), String> {
+    let key = FolderKey::new(user_id, vault_id, id);
+    if !state.folders.borrow().contains_key(&key) {
+        return Err(format!("no folder {}", id));
+    }
+    if state.folders.borrow().range(_folder_range(user_id, vault_id)).any(|entry| entry.value().parent == Some(id)) {
+        return Err(format!("folder {} has subfolders", id));
+    }
+    if !_list_folder_items(user_id, vault_id, id, &state.item_labels).is_empty() {
+        return Err(format!("folder {} is not empty", id));
+    }
+    state.folders.borrow_mut().remove(&key);
+    Ok(())
+}
+
+// Adds a tag or relabels it.
+pub fn _set_tag(user_id: Principal, vault_id: Principal, id: Vec<u8>, label: Vec<u8>, tags: &TagsMap) -> Result<(), String> {
+    _check_tag_id(&id)?;
+    _check_name(&label)?;
+    let key = TagKey::new(user_id, vault_id, id);
+    if !tags.borrow().contains_key(&key) && tags.borrow().range(_tag_range(user_id, vault_id)).count() >= MAX_TAGS {
+        return Err(format!("a vault can have at most {} tags", MAX_TAGS));
+    }
+    tags.borrow_mut().insert(key, label);
+    Ok(())
+}
+
+// Removes a tag and takes it off every item. Returns how many items carried it.
+pub fn _delete_tag(user_id: Principal, vault_id: Principal, id: Vec<u8>, state: &GeneralState) -> Result<u32, String> {
+    if state.tags.borrow_mut().remove(&TagKey::new(user_id, vault_id, id.clone())).is_none() {
+        return Err("no such tag".into());
+    }
+    let tagged: Vec<(ItemLabelsKey, ItemLabels)> = state.item_labels.borrow()
+        .range(_item_range(user_id, vault_id))
+        .map(|entry| entry.into_pair())
+        .filter(|(_, labels)| labels.tags.contains(&id))
+        .collect();
+    let count = tagged.len() as u32;
+    let mut item_labels = state.item_labels.borrow_mut();
+    for (key, mut labels) in tagged {
+        labels.tags.retain(|tag| *tag != id);
+        if labels.is_empty() {
+            item_labels.remove(&key);
+        } else {
+            item_labels.insert(key, labels);
+        }
+    }
+    Ok(count)
+}
+
+// Files an item in `folder` and gives it `tags`, replacing what it had. Both must exist; no
+// folder and no tags clears the item's labels.
+pub fn _set_item_labels(user_id: Principal, vault_id: Principal, item: ItemRef, folder: Option<u64>, mut tags: Vec<Vec<u8>>, state: &GeneralState) -> Result<(), String> {
+    if let Some(folder) = folder {
+        if !state.folders.borrow().contains_key(&FolderKey::new(user_id, vault_id, folder)) {
+            return Err(format!("no folder {}", folder));
+        }
+    }
+    tags.sort();
+    tags.dedup();
+    if tags.len() > MAX_TAGS_PER_ITEM {
+        return Err(format!("an item can have at most {} tags", MAX_TAGS_PER_ITEM));
+    }
+    if tags.iter().any(|tag| !state.tags.borrow().contains_key(&TagKey::new(user_id, vault_id, tag.clone()))) {
+        return Err("no such tag".into());
+    }
+    let key = ItemLabelsKey::new(user_id, vault_id, item);
+    let labels = ItemLabels { folder, tags };
+    if labels.is_empty() {
+        state.item_labels.borrow_mut().remove(&key);
+    } else {
+        state.item_labels.borrow_mut().insert(key, labels);
+    }
+    Ok(())
+}
+
+// Drops the labels of an item taken out of the vault.
+pub fn _drop_item_labels(user_id: Principal, vault_id: Principal, item: ItemRef, item_labels: &ItemLabelsMap) {
+    item_labels.borrow_mut().remove(&ItemLabelsKey::new(user_id, vault_id, item));
+}
+
+// Drops the labels of every cell and row of a deleted sheet.
+pub fn _drop_sheet_labels(user_id: Principal, vault_id: Principal, sheet: u8, item_labels: &ItemLabelsMap) {
+    let keys: Vec<ItemLabelsKey> = item_labels.borrow()
+        .keys_range(_item_range(user_id, vault_id))
+        .filter(|key| matches!(key.item.kind, ItemKind::SpreadsheetCell | ItemKind::GridRow) && key.item.sheet == sheet)
+        .collect();
+    let mut item_labels = item_labels.borrow_mut();
+    for key in keys {
+        item_labels.remove(&key);
+    }
+}
+
+pub fn _list_folder_items(user_id: Principal, vault_id: Principal, folder: u64, item_labels: &ItemLabelsMap) -> Vec<ItemRef> {
+    item_labels.borrow()
+        .range(_item_range(user_id, vault_id))
+        .filter(|entry| entry.value().folder == Some(folder))
+        .map(|entry| entry.key().item)
+        .collect()
+}
+
+pub fn _list_tagged_items(user_id: Principal, vault_id: Principal, tag: Vec<u8>, item_labels: &ItemLabelsMap) -> Vec<ItemRef> {
+    item_labels.borrow()
+        .range(_item_range(user_id, vault_id))
+        .filter(|entry| entry.value().tags.contains(&tag))
+        .map(|entry| entry.key().item)
+        .collect()
+}
+
+pub fn _get_organization(user_id: Principal, vault_id: Principal, state: &GeneralState) -> Organization {
+    let folders = state.folders.borrow()
+        .range(_folder_range(user_id, vault_id))
+        .map(|entry| {
+            let (key, folder) = entry.into_pair();
+            FolderInfo { id: key.id, parent: folder.parent, name: folder.name }
+        })
+        .collect();
+    let tags = state.tags.borrow()
+        .range(_tag_range(user_id, vault_id))
+        .map(|entry| {
+            let (key, label) = entry.into_pair();
+            TagInfo { id: key.id, label }
+        })
+        .collect();
+    let items = state.item_labels.borrow()
+        .range(_item_range(user_id, vault_id))
+        .map(|entry| {
+            let (key, labels) = entry.into_pair();
+            LabelledItem { item: key.item, folder: labels.folder, tags: labels.tags }
+        })
+        .collect();
+    Organization { folders, tags, items }
+}
+
+/*
+    Sync paths, shared by the global sync and migration imports. Entries apply in order, so a
+    folder may name a parent written earlier in the same update.
+*/
+
+pub fn _apply_folders(user_id: Principal, vault_id: Principal, data: &FoldersData, state: &GeneralState) -> Result<(), String> {
+    for entry in data.folders.iter() {
+        let id = entry.header.id;
+        if !entry.name.is_empty() {
+            _put_folder(user_id, vault_id, id, entry.header.parent, entry.name.clone(), &state.folders)?;
+        } else if state.folders.borrow().contains_key(&FolderKey::new(user_id, vault_id, id)) {
+            _delete_folder(user_id, vault_id, id, state)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn _apply_tags(user_id: Principal, vault_id: Principal, data: &TagsData, state: &GeneralState) -> Result<(), String> {
+    for entry in data.tags.iter() {
+        if !entry.label.is_empty() {
+            _set_tag(user_id, vault_id, entry.id.clone(), entry.label.clone(), &state.tags)?;
+        } else if state.tags.borrow().contains_key(&TagKey::new(user_id, vault_id, entry.id.clone())) {
+            _delete_tag(user_id, vault_id, entry.id.clone(), state)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn _apply_item_labels(user_id: Principal, vault_id: Principal, data: &ItemLabelsData, state: &GeneralState) -> Result<(), String> {
+    for entry in data.items.iter() {
+        _set_item_labels(user_id, vault_id, entry.header.item, entry.header.folder, entry.tags.clone(), state)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+
+    use super::{_create_folder, _delete_folder, _list_folder_items, _set_item_labels};
+    use crate::{
+        api::{
+            grid_api::{_edit_grid, Axis, Grid, GridEdit},
+            serial_api::_vault_spreadsheet_delete,
+            sheets_api::{_create_sheet, _delete_sheet},
+            trash_api::_move_to_trash,
+        },
+        stable::types::GeneralState,
+        vault_type::{
+            attachments::{ItemKind, ItemRef},
+            sheets::sheet_principals,
+            spreadsheet::{SpreadsheetKey, SpreadsheetValue},
+        },
+    };
+
+    fn cell(sheet: u8, y: u8) -> ItemRef {
+        ItemRef { kind: ItemKind::SpreadsheetCell, sheet, x: 0, y }
+    }
+
+    // Deleted items lose their labels, so listings stop naming them and their folder can go. An
+    // item a grid edit shifts into the slot of a deleted one keeps its own.
+    #[test]
+    fn deleted_items_lose_their_labels() {
+        let state = GeneralState::init();
+        let user_id = Principal::from_slice(&[1; 29]);
+        let vault_id = Principal::from_slice(&[2; 29]);
+        let sheet = _create_sheet(user_id, vault_id, b"sheet".to_vec(), &state.sheets).unwrap();
+        let folder = _create_folder(user_id, vault_id, None, b"folder".to_vec(), &state.folders).unwrap();
+        let row = ItemRef { kind: ItemKind::GridRow, sheet, x: 0, y: 4 };
+        for item in [cell(0, 1), cell(0, 2), cell(0, 3), cell(sheet, 1), row] {
+            let key = SpreadsheetKey { principals: sheet_principals(user_id, vault_id, item.sheet), x: item.x, y: item.y };
+            state.spreadsheet_map.borrow_mut().insert(key, SpreadsheetValue::new(vec![item.y]));
+            _set_item_labels(user_id, vault_id, item, Some(folder), Vec::new(), &state).unwrap();
+        }
+
+        let outcome = _vault_spreadsheet_delete(user_id, vault_id, vec![0, 3], &state.spreadsheet_map);
+        _move_to_trash(user_id, vault_id, outcome.removed, 0, &state);
+        let outcome = _edit_grid(user_id, vault_id, Grid::Spreadsheet, Axis::Row, GridEdit::Delete { at: 1, count: 1 }, &state).unwrap();
+        _move_to_trash(user_id, vault_id, outcome.removed, 0, &state);
+        assert_eq!(_list_folder_items(user_id, vault_id, folder, &state.item_labels), vec![cell(0, 1), cell(sheet, 1), row]);
+
+        let outcome = _delete_sheet(user_id, vault_id, sheet, &state).unwrap();
+        _move_to_trash(user_id, vault_id, outcome.removed, 0, &state);
+        assert_eq!(_list_folder_items(user_id, vault_id, folder, &state.item_labels), vec![cell(0, 1)]);
+        assert!(_delete_folder(user_id, vault_id, folder, &state).is_err());
+        let outcome = _vault_spreadsheet_delete(user_id, vault_id, vec![0, 1], &state.spreadsheet_map);
+        _move_to_trash(user_id, vault_id, outcome.removed, 0, &state);
+        _delete_folder(user_id, vault_id, folder, &state).unwrap();
+    }
+}
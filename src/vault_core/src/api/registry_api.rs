@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::Storable;
+
+use crate::{
+    api::{deserialiser::deserialise_vault_names, dev_api::VaultNames},
+    stable::types::{GeneralState, PendingDeletionsMap, VaultNamesMap, VaultRegistryMap},
+    vault_type::{
+        pending_deletion::{DeletionStatus, PendingDeletionKey},
+        records::TypedRecord,
+        templates::CustomRecord,
+        totp::TotpRecord,
+        trash::{TrashItem, TrashKind},
+        vault_names::VaultNameKey,
+        vault_registry::{VaultRecord, VaultStatus},
+    },
+};
+
+/*
+    Vault registry. A vault exists once a names sync has registered it; syncs into a vault that
+    was never registered are turned away. Each entry keeps when the vault was created and last
+    written, how many items it holds and their size, the key epoch, its status and an encrypted
+    metadata blob only the client can read.
+*/
+
+pub const MAX_VAULT_METADATA_BYTES: usize = 4 * 1024;
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub struct VaultInfo {
+    pub vault_id: Principal,
+    pub owner: Principal,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub item_count: u64,
+    pub size_bytes: u64,
+    pub key_epoch: u32,
+    pub status: VaultStatus,
+    pub metadata: Vec<u8>,
+}
+impl From<VaultRecord> for VaultInfo {
+    fn from(record: VaultRecord) -> Self {
+        Self {
+            vault_id: Principal::from_slice(&record.vault),
+            owner: Principal::from_slice(&record.owner),
+            created_at: record.created_at,
+            updated_at: record.updated_at,
+            item_count: record.item_count,
+            size_bytes: record.size_bytes,
+            key_epoch: record.key_epoch,
+            status: record.status,
+            metadata: record.metadata,
+        }
+    }
+}
+
+// Fields left out are kept as they are.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct VaultMetadataUpdate {
+    pub metadata: Option<Vec<u8>>,
+    pub key_epoch: Option<u32>,
+}
+
+// Change in the number and size of the items a vault holds.
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+pub struct SizeChange {
+    pub items: i64,
+    pub bytes: i64,
+}
+impl SizeChange {
+    // A value of `size` bytes written over one of `old` bytes, or into an empty slot.
+    pub fn stored(&mut self, old: Option<usize>, size: usize) {
+        if old.is_none() {
+            self.items += 1;
+        }
+        self.bytes += size as i64 - old.unwrap_or(0) as i64;
+    }
+    pub fn removed(&mut self, item: &TrashItem) {
+        let (items, bytes) = _item_size(item);
+        self.items -= items;
+        self.bytes -= bytes;
+    }
+    pub fn restored(&mut self, item: &TrashItem) {
+        let (items, bytes) = _item_size(item);
+        self.items += items;
+        self.bytes += bytes;
+    }
+}
+
+// Login column labels aren't items, but the identities removed with them are.
+fn _item_size(item: &TrashItem) -> (i64, i64) {
+    match item.kind {
+        TrashKind::LoginColumn => (item.rows.len() as i64, item.rows.iter().map(|(_, data)| data.len() as i64).sum()),
+        TrashKind::Note => (1, (item.label.len() + item.data.len()) as i64),
+        TrashKind::Totp => (1, TotpRecord::from_bytes(item.data.as_slice().into()).size() as i64),
+        TrashKind::PaymentCard | TrashKind::Identity => (1, TypedRecord::from_bytes(item.data.as_slice().into()).size() as i64),
+        TrashKind::CustomRecord => (1, CustomRecord::from_bytes(item.data.as_slice().into()).size() as i64),
+        TrashKind::SpreadsheetCell | TrashKind::LoginCell => (1, item.data.len() as i64),
+    }
+}
+
+fn _vault_key(user_id: Principal, vault_id: Principal) -> VaultNameKey {
+    VaultNameKey { principals: [user_id.as_slice(), vault_id.as_slice()].concat() }
+}
+
+// The user's registry entries. Keys sort by user, so this is a single range.
+pub fn _user_vaults(user: &[u8], registry: &VaultRegistryMap) -> Vec<VaultRecord> {
+    registry.borrow()
+        .range(VaultNameKey { principals: user.to_vec() }..)
+        .take_while(|entry| entry.key().principals.starts_with(user))
+        .filter(|entry| entry.key().principals.len() > user.len())
+        .map(|entry| entry.value())
+        .collect()
+}
+
+// Registers the vaults a names update names, and marks those already registered as updated.
+// Removing a name leaves the vault registered; vaults go away through `delete_vault`.
+pub fn _register_vaults(user_id: Principal, update: &Vec<u8>, now: u64, registry: &VaultRegistryMap) -> u32 {
+    if update.is_empty() {
+        return 0;
+    }
+    let mut registry = registry.borrow_mut();
+    let mut registered = 0;
+    for name in deserialise_vault_names(update).names.iter().filter(|name| !name.vault_name.is_empty()) {
+        let key = VaultNameKey::new(user_id, &name.vault_id);
+        let record = match registry.get(&key) {
+            Some(mut record) => {
+                record.updated_at = now;
+                record
+            }
+            None => {
+                registered += 1;
+                VaultRecord::new(user_id.as_slice(), &name.vault_id, now)
+            }
+        };
+        registry.insert(key, record);
+    }
+    registered
+}
+
+pub fn _assert_vault_registered(user_id: Principal, vault_id: Principal, registry: &VaultRegistryMap) -> Result<(), String> {
+    if !registry.borrow().contains_key(&_vault_key(user_id, vault_id)) {
+        return Err("vault is not registered, name it first".into());
+    }
+    Ok(())
+}
+
+// Marks a vault as written at `now` and applies the change in its items.
+pub fn _record_vault_change(user_id: Principal, vault_id: Principal, change: SizeChange, now: u64, registry: &VaultRegistryMap) {
+    let key = _vault_key(user_id, vault_id);
+    let mut registry = registry.borrow_mut();
+    let Some(mut record) = registry.get(&key) else {
+        return;
+    };
+    record.updated_at = now;
+    record.item_count = record.item_count.saturating_add_signed(change.items);
+    record.size_bytes = record.size_bytes.saturating_add_signed(change.bytes);
+    registry.insert(key, record);
+}
+
+// Names of the user's registered vaults. Names left behind by vaults the registry doesn't know
+// are not returned.
+pub fn _registered_vault_names(user_id: Principal, vnm: &VaultNamesMap, registry: &VaultRegistryMap) -> VaultNames {
+    let vnm = vnm.borrow();
+    let names = _user_vaults(user_id.as_slice(), registry)
+        .into_iter()
+        .filter_map(|record| {
+            let name = vnm.get(&VaultNameKey::new(user_id, &record.vault))?;
+            Some((record.vault, name.name))
+        })
+        .collect();
+    VaultNames { names }
+}
+
+pub fn _list_vaults(user_id: Principal, registry: &VaultRegistryMap) -> Vec<VaultInfo> {
+    _user_vaults(user_id.as_slice(), registry).into_iter().map(VaultInfo::from).collect()
+}
+
+pub fn _get_vault_info(user_id: Principal, vault_id: Principal, registry: &VaultRegistryMap) -> Option<VaultInfo> {
+    registry.borrow().get(&_vault_key(user_id, vault_id)).map(VaultInfo::from)
+}
+
+// Replaces the vault's metadata blob and moves its key epoch forward. Epochs never go back, so
+// a client holding a stale key can tell.
+pub fn _update_vault_metadata(user_id: Principal, vault_id: Principal, update: VaultMetadataUpdate, now: u64, registry: &VaultRegistryMap) -> Result<VaultInfo, String> {
+    let key = _vault_key(user_id, vault_id);
+    let mut record = registry.borrow().get(&key).ok_or("vault is not registered")?;
+    if let Some(metadata) = update.metadata {
+        if metadata.len() > MAX_VAULT_METADATA_BYTES {
+            return Err(format!("metadata exceeds {} bytes", MAX_VAULT_METADATA_BYTES));
+        }
+        record.metadata = metadata;
+    }
+    if let Some(key_epoch) = update.key_epoch {
+        if key_epoch < record.key_epoch {
+            return Err(format!("key epoch {} is behind the current {}", key_epoch, record.key_epoch));
+        }
+        record.key_epoch = key_epoch;
+    }
+    record.updated_at = now;
+    registry.borrow_mut().insert(key, record.clone());
+    Ok(VaultInfo::from(record))
+}
+
+// A running deletion of the vault or its user wins over a scheduled one.
+fn _status_of(user: &[u8], vault: &[u8], pd: &PendingDeletionsMap) -> VaultStatus {
+    let pd = pd.borrow();
+    [PendingDeletionKey { principals: user.to_vec() }, PendingDeletionKey { principals: [user, vault].concat() }]
+        .iter()
+        .filter_map(|key| pd.get(key))
+        .fold(VaultStatus::Active, |status, pending| match pending.status {
+            DeletionStatus::Running => VaultStatus::Deleting,
+            DeletionStatus::Scheduled if status == VaultStatus::Active => VaultStatus::PendingDeletion,
+            DeletionStatus::Scheduled => status,
+        })
+}
+
+// Brings the status of a vault, or of every vault of the user when none is given, in line with
+// the pending deletions.
+pub fn _refresh_vault_status(user_id: Principal, vault_id: Option<Principal>, state: &GeneralState) {
+    let user = user_id.as_slice();
+    let records = match vault_id {
+        Some(vault_id) => state.vault_registry.borrow().get(&_vault_key(user_id, vault_id)).into_iter().collect(),
+        None => _user_vaults(user, &state.vault_registry),
+    };
+    let mut registry = state.vault_registry.borrow_mut();
+    for mut record in records {
+        let status = _status_of(user, &record.vault, &state.pending_deletions);
+        if status != record.status {
+            record.status = status;
+            registry.insert(VaultNameKey { principals: [user, &record.vault].concat() }, record);
+        }
+    }
+}
+
+// Registers the named vaults of canisters that predate the registry, counting their items from
+// the data already held. Runs once, on the first upgrade that finds the registry empty.
+pub fn _backfill_registry(now: u64, state: &GeneralState) -> u32 {
+    if !state.vault_registry.borrow().is_empty() || state.vault_names_map.borrow().is_empty() {
+        return 0;
+    }
+    let mut sizes: HashMap<Vec<u8>, SizeChange> = HashMap::new();
+    for entry in state.spreadsheet_map.borrow().iter().chain(state.logins_map.borrow().iter()) {
+        sizes.entry(entry.key().principals.clone()).or_default().stored(None, entry.value().data.len());
+    }
+    for entry in state.notes_map.borrow().iter() {
+        let note = entry.value();
+        sizes.entry(entry.key().principals.clone()).or_default().stored(None, note.label.len() + note.note.len());
+    }
+    for entry in state.totp_map.borrow().iter() {
+        sizes.entry(entry.key().principals.clone()).or_default().stored(None, entry.value().size());
+    }
+    for entry in state.records.borrow().iter() {
+        sizes.entry(entry.key().principals.clone()).or_default().stored(None, entry.value().size());
+    }
+
+    let users = state.canister_owners.borrow().user.clone();
+    let mut registered = 0;
+    for user_id in users {
+        let user = user_id.as_slice();
+        let vaults: Vec<Vec<u8>> = state.vault_names_map.borrow()
+            .keys_range(VaultNameKey { principals: user.to_vec() }..)
+            .take_while(|key| key.principals.starts_with(user))
+            .filter(|key| key.principals.len() > user.len())
+            .map(|key| key.principals)
+            .collect();
+        for principals in vaults {
+            let mut record = VaultRecord::new(user, &principals[user.len()..], now);
+            let size = sizes.get(&principals).copied().unwrap_or_default();
+            record.item_count = size.items as u64;
+            record.size_bytes = size.bytes as u64;
+            state.vault_registry.borrow_mut().insert(VaultNameKey { principals }, record);
+            registered += 1;
+        }
+        _refresh_vault_status(user_id, None, state);
+    }
+    registered
+}
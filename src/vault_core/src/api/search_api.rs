@@ -0,0 +1,192 @@
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::{
+    api::{deserialiser::deserialise_search_tokens, deserialiser_types::SearchTokensData},
+    stable::types::{GeneralState, SearchIndexMap},
+    vault_type::{
+        attachments::{ItemKind, ItemRef},
+        search::{ItemTokens, ItemTokensKey, SearchTokenKey},
+    },
+};
+
+/*
+    Blind-index search. Clients derive tokens from the words and domains of an item with a keyed
+    hash only they hold, and attach them to the item; a search sends the tokens of the query
+    words and gets back the items carrying all of them. The canister matches opaque bytes and
+    never sees the words. Items are referred to as attachments refer to them. Tokens stay with
+    an item until the client replaces them or the item is deleted.
+*/
+
+// HMAC-SHA256 outputs, possibly truncated by the client.
+pub const MAX_TOKEN_BYTES: usize = 32;
+pub const MAX_TOKENS_PER_ITEM: usize = 256;
+pub const MAX_QUERY_TOKENS: usize = 16;
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub struct SearchHit {
+    pub vault_id: Principal,
+    pub item: ItemRef,
+}
+
+// Every item of a vault.
+fn _item_range(principals: &[u8]) -> std::ops::RangeInclusive<ItemTokensKey> {
+    ItemTokensKey { principals: principals.to_vec(), item: ItemRef::FIRST }..=ItemTokensKey { principals: principals.to_vec(), item: ItemRef::LAST }
+}
+
+// The items of a vault carrying `token`.
+fn _token_range(principals: &[u8], token: &[u8]) -> std::ops::RangeInclusive<SearchTokenKey> {
+    SearchTokenKey { principals: principals.to_vec(), token: token.to_vec(), item: ItemRef::FIRST }..=SearchTokenKey { principals: principals.to_vec(), token: token.to_vec(), item: ItemRef::LAST }
+}
+
+// Sorts and dedupes the tokens of an item, checking their number and sizes.
+fn _check_tokens(tokens: &mut Vec<Vec<u8>>) -> Result<(), String> {
+    tokens.sort();
+    tokens.dedup();
+    if tokens.len() > MAX_TOKENS_PER_ITEM {
+        return Err(format!("an item can have at most {} search tokens", MAX_TOKENS_PER_ITEM));
+    }
+    if tokens.iter().any(|token| token.is_empty() || token.len() > MAX_TOKEN_BYTES) {
+        return Err(format!("search tokens must be between 1 and {} bytes", MAX_TOKEN_BYTES));
+    }
+    Ok(())
+}
+
+// Replaces the tokens of an item in the index; no tokens takes it out. Keyed by the vault's
+// principals so grid edits can move entries.
+pub fn _index_item(principals: &[u8], item: ItemRef, tokens: Vec<Vec<u8>>, state: &GeneralState) {
+    let key = ItemTokensKey { principals: principals.to_vec(), item };
+    let old = state.item_tokens.borrow_mut().remove(&key).unwrap_or_default();
+    let mut index = state.search_index.borrow_mut();
+    for token in old.tokens {
+        index.remove(&SearchTokenKey { principals: principals.to_vec(), token, item });
+    }
+    for token in tokens.iter() {
+        index.insert(SearchTokenKey { principals: principals.to_vec(), token: token.clone(), item }, ());
+    }
+    if !tokens.is_empty() {
+        state.item_tokens.borrow_mut().insert(key, ItemTokens { tokens });
+    }
+}
+
+// Takes every cell and row of a deleted sheet out of the index.
+pub fn _drop_sheet_tokens(principals: &[u8], sheet: u8, state: &GeneralState) {
+    let items: Vec<ItemRef> = state.item_tokens.borrow()
+        .keys_range(_item_range(principals))
+        .map(|key| key.item)
+        .filter(|item| matches!(item.kind, ItemKind::SpreadsheetCell | ItemKind::GridRow) && item.sheet == sheet)
+        .collect();
+    for item in items {
+        _index_item(principals, item, Vec::new(), state);
+    }
+}
+
+// Writes the tokens of every item in `data`, after checking all of them.
+pub fn _apply_search_tokens(user_id: Principal, vault_id: Principal, data: SearchTokensData, state: &GeneralState) -> Result<(), String> {
+    let mut items = Vec::with_capacity(data.items.len());
+    for mut entry in data.items {
+        _check_tokens(&mut entry.tokens)?;
+        items.push((entry.header.item, entry.tokens));
+    }
+    let principals = [user_id.as_slice(), vault_id.as_slice()].concat();
+    for (item, tokens) in items {
+        _index_item(&principals, item, tokens, state);
+    }
+    Ok(())
+}
+
+// Returns the number of items whose tokens were written.
+pub fn _search_tokens_sync(user_id: Principal, vault_id: Principal, update: Vec<u8>, state: &GeneralState) -> Result<u32, String> {
+    let data = deserialise_search_tokens(&update);
+    let count = data.items.len() as u32;
+    _apply_search_tokens(user_id, vault_id, data, state)?;
+    Ok(count)
+}
+
+// The tokens of every indexed item of a vault.
+pub fn _get_item_tokens(user_id: Principal, vault_id: Principal, state: &GeneralState) -> Vec<(ItemRef, Vec<Vec<u8>>)> {
+    let principals = [user_id.as_slice(), vault_id.as_slice()].concat();
+    state.item_tokens.borrow()
+        .range(_item_range(&principals))
+        .map(|entry| {
+            let (key, tokens) = entry.into_pair();
+            (key.item, tokens.tokens)
+        })
+        .collect()
+}
+
+// The items of the given vaults carrying every token of the query, by vault then item. Only the
+// caller's vaults are searched.
+pub fn _search_items(user_id: Principal, mut vault_ids: Vec<Principal>, mut tokens: Vec<Vec<u8>>, index: &SearchIndexMap) -> Result<Vec<SearchHit>, String> {
+    tokens.sort();
+    tokens.dedup();
+    if tokens.is_empty() || tokens.len() > MAX_QUERY_TOKENS {
+        return Err(format!("a search takes between 1 and {} tokens", MAX_QUERY_TOKENS));
+    }
+    vault_ids.sort();
+    vault_ids.dedup();
+    let index = index.borrow();
+    let mut hits = Vec::new();
+    for vault_id in vault_ids {
+        let principals = [user_id.as_slice(), vault_id.as_slice()].concat();
+        let carries = |token: &Vec<u8>, item: ItemRef| {
+            index.contains_key(&SearchTokenKey { principals: principals.clone(), token: token.clone(), item })
+        };
+        hits.extend(index.range(_token_range(&principals, &tokens[0]))
+            .map(|entry| entry.key().item)
+            .filter(|item| tokens[1..].iter().all(|token| carries(token, *item)))
+            .map(|item| SearchHit { vault_id, item }));
+    }
+    Ok(hits)
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+
+    use super::{_index_item, _search_items};
+    use crate::{
+        api::{
+            serial_api::{_custom_records_deletes, _vault_spreadsheet_delete},
+            sheets_api::{_create_sheet, _delete_sheet},
+            trash_api::_move_to_trash,
+        },
+        stable::types::GeneralState,
+        vault_type::{
+            attachments::{ItemKind, ItemRef},
+            spreadsheet::{SpreadsheetKey, SpreadsheetValue},
+            templates::{CustomRecord, TemplateKey},
+        },
+    };
+
+    // Searches stop finding items once they are deleted, whether by a sync or with their sheet.
+    #[test]
+    fn deleted_items_leave_the_index() {
+        let state = GeneralState::init();
+        let user_id = Principal::from_slice(&[1; 29]);
+        let vault_id = Principal::from_slice(&[2; 29]);
+        let principals = [user_id.as_slice(), vault_id.as_slice()].concat();
+        let hits = |state: &GeneralState| -> Vec<ItemRef> {
+            _search_items(user_id, vec![vault_id], vec![b"bank".to_vec()], &state.search_index).unwrap().into_iter().map(|hit| hit.item).collect()
+        };
+        let sheet = _create_sheet(user_id, vault_id, b"sheet".to_vec(), &state.sheets).unwrap();
+        let cell = ItemRef { kind: ItemKind::SpreadsheetCell, sheet: 0, x: 1, y: 2 };
+        let record = ItemRef { kind: ItemKind::CustomRecord, sheet: 0, x: 4, y: 0 };
+        let row = ItemRef { kind: ItemKind::GridRow, sheet, x: 0, y: 7 };
+        state.spreadsheet_map.borrow_mut().insert(SpreadsheetKey::new(user_id, vault_id, 1, 2), SpreadsheetValue::new(vec![1]));
+        state.custom_records.borrow_mut().insert(TemplateKey::new(user_id, vault_id, 4), CustomRecord { template: 0, values: vec![b"x".to_vec()] });
+        for item in [cell, record, row] {
+            _index_item(&principals, item, vec![b"bank".to_vec()], &state);
+        }
+        assert_eq!(hits(&state), vec![cell, record, row]);
+
+        let outcome = _vault_spreadsheet_delete(user_id, vault_id, vec![1, 2], &state.spreadsheet_map);
+        _move_to_trash(user_id, vault_id, outcome.removed, 0, &state);
+        let outcome = _custom_records_deletes(user_id, vault_id, vec![4], &state.custom_records);
+        _move_to_trash(user_id, vault_id, outcome.removed, 0, &state);
+        assert_eq!(hits(&state), vec![row]);
+
+        _delete_sheet(user_id, vault_id, sheet, &state).unwrap();
+        assert!(hits(&state).is_empty());
+        assert!(state.item_tokens.borrow().is_empty());
+    }
+}
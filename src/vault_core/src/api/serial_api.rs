@@ -1,16 +1,52 @@
+use std::{cell::RefCell, ops::RangeInclusive};
+
 use candid::Principal;
-use ic_stable_structures::Storable;
+use ic_stable_structures::{StableBTreeMap, Storable};
 use crate::{
-    api::{deserialiser::{deserialise_column_data, deserialise_delete_cells, deserialise_global_sync, deserialise_login_data_sync, deserialise_login_full_sync, deserialise_login_metadata, deserialise_secure_notes, deserialise_spreadsheet, deserialise_vault_names}, dev_api::_get_vault_names}, 
-    stable::types::{ColumnsInfo, GeneralState, LoginsColumns, LoginsMap, NotesMap, SpreadsheetMap, VaultNamesMap}, 
+    api::{deserialiser::{deserialise_column_data, deserialise_custom_records, deserialise_delete_cells, deserialise_global_sync, deserialise_login_data_sync, deserialise_login_full_sync, deserialise_login_metadata, deserialise_records, deserialise_secure_notes, deserialise_spreadsheet, deserialise_totp, deserialise_delete_indexes, deserialise_vault_names}, organization_api::{_apply_folders, _apply_item_labels, _apply_tags, MAX_TAG_ID_BYTES}, machine_api::{_remove_grant, _vault_grant_keys}, registry_api::SizeChange, search_api::{_apply_search_tokens, MAX_TOKEN_BYTES}, templates_api::_validate_custom_record}, 
+    stable::types::{ColumnsInfo, CustomRecordsMap, GeneralState, LoginsColumns, LoginsMap, Memory, NotesMap, RecordsMap, SpreadsheetMap, TotpMap, VaultNamesMap, VaultRegistryMap}, 
     vault_type::{
         logins::LoginSiteKey, 
         secure_notes::{SecureNote, SecureNoteKey}, 
+        totp::{TotpAlgorithm, TotpKey, TotpParams, TotpRecord},
+        attachments::{AttachmentChunkKey, AttachmentKey, ItemRef},
+        organization::{FolderKey, ItemLabelsKey, TagKey},
+        search::{ItemTokensKey, SearchTokenKey},
+        records::{Expiry, RecordKey, RecordKind, TypedRecord},
+        templates::{CustomRecord, TemplateKey},
+        sheets::{sheet_principals, vault_sheet_principals, SheetKey},
         spreadsheet::{ColumnData, ColumnKey, SpreadsheetKey, SpreadsheetValue}, 
+        history::{HistoryItem, HistoryKey, HistoryKind, Revision},
+        pending_deletion::{DeletionCursor, DeletionStage},
+        trash::{TrashItem, TrashKey, TrashKind},
         vault_names::{VaultNameKey, VaultNameValue}
     }
 };
 
+// What a sync or delete did: how many items it carried, the items it removed so the caller
+// can move them to the trash, the previous values of login cells and notes it overwrote, and
+// the change in size from the values it wrote.
+#[derive(Default)]
+pub struct SyncOutcome {
+    pub items: u32,
+    pub removed: Vec<TrashItem>,
+    pub replaced: Vec<Revision>,
+    pub stored: SizeChange,
+}
+impl SyncOutcome {
+    fn new(items: usize, removed: Vec<TrashItem>) -> Self {
+        Self { items: items as u32, removed, replaced: Vec::new(), stored: SizeChange::default() }
+    }
+    // The change in the vault's items, writes and removals together.
+    pub fn size_change(&self) -> SizeChange {
+        let mut change = self.stored;
+        for item in self.removed.iter() {
+            change.removed(item);
+        }
+        change
+    }
+}
+
 // Internal function to process a set of deserialised vault name data
 fn _process_vault_names(user_id: Principal, names: &super::deserialiser_types::VaultNames, vnm: &VaultNamesMap) {
     let mut names_map = vnm.borrow_mut();
@@ -24,205 +60,251 @@ fn _process_vault_names(user_id: Principal, names: &super::deserialiser_types::V
         names_map.insert(key, VaultNameValue::new(&name.vault_name));
     }
 }
-pub fn _vault_names_sync(user_id: Principal, update: &Vec<u8>, vnm: &VaultNamesMap) {
+pub fn _vault_names_sync(user_id: Principal, update: &Vec<u8>, vnm: &VaultNamesMap) -> u32 {
     if update.is_empty() {
-        return
+        return 0;
     }
 
     let names = deserialise_vault_names(update);
     _process_vault_names(user_id, &names, vnm);
-
+    names.names.len() as u32
 }
 
-fn _process_spreadsheet_columns(user_id: Principal, vault_id: Principal, columns: &super::deserialiser_types::SpreadsheetColumns, sc: &ColumnsInfo) {
+fn _process_spreadsheet_columns(user_id: Principal, vault_id: Principal, sheet: u8, columns: &super::deserialiser_types::SpreadsheetColumns, sc: &ColumnsInfo) {
+    let principals = sheet_principals(user_id, vault_id, sheet);
     let mut sc = sc.borrow_mut();
 
     for column in columns.columns.iter() {
-        let key = ColumnKey::new(user_id, vault_id, column.header.x);
+        let key = ColumnKey { principals: principals.clone(), x: column.header.x };
         let hidden = if column.header.hidden > 0 { true } else { false };
         if column.name.is_empty() && !hidden {
             sc.remove(&key);
             continue;
         }
-        let value = ColumnData::new(hidden, column.name.clone());
+        let value = ColumnData::new(hidden, column.name.clone(), column.header.kind, column.header.settings);
         sc.insert(key, value);
     }
 }
 
-pub fn _vault_spreadsheet_columns_sync(user_id: Principal, vault_id: Principal, update: Vec<u8>, sc: &ColumnsInfo) {
+pub fn _vault_spreadsheet_columns_sync(user_id: Principal, vault_id: Principal, update: Vec<u8>, sc: &ColumnsInfo) -> u32 {
+    _sheet_columns_sync(user_id, vault_id, 0, update, sc)
+}
+
+// As `_vault_spreadsheet_columns_sync`, for the columns of any sheet.
+pub fn _sheet_columns_sync(user_id: Principal, vault_id: Principal, sheet: u8, update: Vec<u8>, sc: &ColumnsInfo) -> u32 {
     if update.is_empty() {
-        return;
+        return 0;
     }
 
     let column_data = deserialise_column_data(&update);
-    _process_spreadsheet_columns(user_id, vault_id, &column_data, sc);
+    _process_spreadsheet_columns(user_id, vault_id, sheet, &column_data, sc);
+    column_data.columns.len() as u32
 }
 
 // Internal common code to process a set of deserialised spreadsheet data.
-fn _process_spreadsheet(user_id: Principal, vault_id: Principal, cells: &super::deserialiser_types::Cells, sm: &SpreadsheetMap) {
+fn _process_spreadsheet(user_id: Principal, vault_id: Principal, sheet: u8, cells: &super::deserialiser_types::Cells, sm: &SpreadsheetMap, outcome: &mut SyncOutcome) {
+    let principals = sheet_principals(user_id, vault_id, sheet);
     let mut spreadsheets = sm.borrow_mut();
     for cell in cells.cells.iter()
     {
-        let key = SpreadsheetKey::new(user_id, vault_id, cell.header.x, cell.header.y);
+        let key = SpreadsheetKey { principals: principals.clone(), x: cell.header.x, y: cell.header.y };
         if cell.data.is_empty() {
-            spreadsheets.remove(&key);
+            if let Some(old) = spreadsheets.remove(&key) {
+                outcome.removed.push(TrashItem::sheet_cell(sheet, key.x, key.y, old.data));
+            }
             continue;
         }
-        spreadsheets.insert(key, SpreadsheetValue::new(cell.data.clone()));
+        let old = spreadsheets.insert(key, SpreadsheetValue::new(cell.data.clone()));
+        outcome.stored.stored(old.map(|old| old.data.len()), cell.data.len());
     }
 }
 
 // Interface function to deserialise and process a full sync of spreadsheet data
-pub fn _vault_spreadsheet_sync(user_id: Principal, vault_id: Principal, update: Vec<u8>, sm: &SpreadsheetMap) {
+pub fn _vault_spreadsheet_sync(user_id: Principal, vault_id: Principal, update: Vec<u8>, sm: &SpreadsheetMap) -> SyncOutcome {
+    _sheet_sync(user_id, vault_id, 0, update, sm)
+}
+
+// As `_vault_spreadsheet_sync`, for the cells of any sheet.
+pub fn _sheet_sync(user_id: Principal, vault_id: Principal, sheet: u8, update: Vec<u8>, sm: &SpreadsheetMap) -> SyncOutcome {
     if update.is_empty() {
-        return;
+        return SyncOutcome::default();
     }
 
     let cell_data = deserialise_spreadsheet(update);
-    _process_spreadsheet(user_id, vault_id, &cell_data, sm);
+    let mut outcome = SyncOutcome::new(cell_data.cells.len(), Vec::new());
+    _process_spreadsheet(user_id, vault_id, sheet, &cell_data, sm, &mut outcome);
+    outcome
 }
 
 // Interface function to deserialise and process a delete update of spreadsheet data
-pub fn _vault_spreadsheet_delete(user_id: Principal, vault_id: Principal, update: Vec<u8>, sm: &SpreadsheetMap) {
+pub fn _vault_spreadsheet_delete(user_id: Principal, vault_id: Principal, update: Vec<u8>, sm: &SpreadsheetMap) -> SyncOutcome {
+    _sheet_deletes(user_id, vault_id, 0, update, sm)
+}
+
+// As `_vault_spreadsheet_delete`, for the cells of any sheet.
+pub fn _sheet_deletes(user_id: Principal, vault_id: Principal, sheet: u8, update: Vec<u8>, sm: &SpreadsheetMap) -> SyncOutcome {
     if update.is_empty() {
-        return;
+        return SyncOutcome::default();
     }
     
+    let principals = sheet_principals(user_id, vault_id, sheet);
     let deletes = deserialise_delete_cells(update);
     let mut spreadsheets = sm.borrow_mut();
+    let mut removed = Vec::new();
     for cell in deletes.cells.iter()
     {
-        let key = SpreadsheetKey::new(user_id, vault_id, cell.x, cell.y);
-        spreadsheets.remove(&key);
+        let key = SpreadsheetKey { principals: principals.clone(), x: cell.x, y: cell.y };
+        if let Some(old) = spreadsheets.remove(&key) {
+            removed.push(TrashItem::sheet_cell(sheet, cell.x, cell.y, old.data));
+        }
     }
+    SyncOutcome::new(deletes.cells.len(), removed)
 }
 
-// This function deletes all login identities associated with a given column (x value).
-fn _delete_login_identities(user_id: Principal, vault_id: Principal, x: u8, lm: &LoginsMap) {
+// This function deletes all login identities associated with a given column (x value), returning
+// the removed identities keyed by y.
+fn _delete_login_identities(user_id: Principal, vault_id: Principal, x: u8, lm: &LoginsMap) -> Vec<(u8, Vec<u8>)> {
     let mut logins = lm.borrow_mut();
     let mut principals: Vec<u8> = Vec::new();
     principals.extend(user_id.as_slice());
     principals.extend(vault_id.as_slice());
 
-    let keys_to_delete: Vec<_> = logins
-        .iter()
-        .filter_map(|entry| {
-            let SpreadsheetKey {
-                principals: column_principals,
-                x: cell_x,
-                y,
-            } = entry.key();
-            (cell_x == &x && column_principals.cmp(&principals).is_eq()).then(|| SpreadsheetKey {
-                principals: principals.to_vec(), // Principals are not needed for deletion
-                x: *cell_x,
-                y: *y,
-            })
-        }).collect();
-
-    // StableBTreeMap does not support bulk delete, so we have to do it one by one.
-    // It also doesn't let you mutate the map while iterating over it, hence the 
-    // two-pass approach.
-    for key in keys_to_delete {
-        logins.remove(&key);
+    // Keys start with the position, so the column's rows are looked up one by one rather than
+    // scanning the whole map.
+    let mut removed = Vec::new();
+    for y in 0..=u8::MAX {
+        let key = SpreadsheetKey { principals: principals.clone(), x, y };
+        if let Some(old) = logins.remove(&key) {
+            removed.push((y, old.data));
+        }
     }
+    removed
 }
 
 // Internal common code to process a set of deserialised login metadata.
-fn _process_metadata(user_id: Principal, vault_id: Principal, metadata: &super::deserialiser_types::LoginMetadata, lc: &LoginsColumns, lm: &LoginsMap) {
+fn _process_metadata(user_id: Principal, vault_id: Principal, metadata: &super::deserialiser_types::LoginMetadata, lc: &LoginsColumns, lm: &LoginsMap) -> Vec<TrashItem> {
     let mut columns = lc.borrow_mut();
+    let mut removed = Vec::new();
     for meta in metadata.metadatas.iter() {
         let column_key = LoginSiteKey::new(user_id, vault_id, meta.header.x);
         if meta.data.is_empty() {
-            columns.remove(&column_key);
-            _delete_login_identities(user_id, vault_id, column_key.x, lm);
+            let label = columns.remove(&column_key);
+            let rows = _delete_login_identities(user_id, vault_id, column_key.x, lm);
+            if label.is_some() || !rows.is_empty() {
+                removed.push(TrashItem::login_column(column_key.x, label.unwrap_or_default(), rows));
+            }
             continue;
         }
         let column_name = meta.data.clone();
         columns.insert(column_key, column_name);
     }
+    removed
 }
 
 
 // Internal common code to process a set of deserialised login identity data.
-fn _process_login_data(user_id: Principal, vault_id: Principal,  cells: &super::deserialiser_types::Cells, lm: &LoginsMap) {
+fn _process_login_data(user_id: Principal, vault_id: Principal,  cells: &super::deserialiser_types::Cells, lm: &LoginsMap, outcome: &mut SyncOutcome) {
     let mut logins = lm.borrow_mut();
     for cell in cells.cells.iter() {
         let key = SpreadsheetKey::new(user_id, vault_id, cell.header.x, cell.header.y);
+        let (x, y) = (key.x, key.y);
         if cell.data.is_empty() {
-            logins.remove(&key);
+            if let Some(old) = logins.remove(&key) {
+                outcome.removed.push(TrashItem::cell(TrashKind::LoginCell, x, y, old.data));
+            }
             continue;
         }
-        logins.insert(key, SpreadsheetValue::new(cell.data.clone()));
+        let old = logins.insert(key, SpreadsheetValue::new(cell.data.clone()));
+        outcome.stored.stored(old.as_ref().map(|old| old.data.len()), cell.data.len());
+        if let Some(old) = old {
+            if old.data != cell.data {
+                outcome.replaced.push(Revision { item: HistoryItem { kind: HistoryKind::LoginCell, x, y }, label: Vec::new(), data: old.data });
+            }
+        }
     }
 }
 
 // Interface function to deserialise and process a full sync of login metadata and identity data
-pub fn _login_full_sync(user_id: Principal, vault_id: Principal, update: Vec<u8>, lc: &LoginsColumns, lm: &LoginsMap) {
+pub fn _login_full_sync(user_id: Principal, vault_id: Principal, update: Vec<u8>, lc: &LoginsColumns, lm: &LoginsMap) -> SyncOutcome {
     if update.is_empty() {
-        return;
+        return SyncOutcome::default();
     }
 
     let login_data = deserialise_login_full_sync(&update);
     
-    _process_metadata(user_id, vault_id, &login_data.metadata, lc, lm);
-    _process_login_data(user_id, vault_id, &login_data.cells, lm);
+    let removed = _process_metadata(user_id, vault_id, &login_data.metadata, lc, lm);
+    let mut outcome = SyncOutcome::new(login_data.metadata.metadatas.len() + login_data.cells.cells.len(), removed);
+    _process_login_data(user_id, vault_id, &login_data.cells, lm, &mut outcome);
+    outcome
 }
 
 // Interface function to deserialise and process a metadata-only sync of login data
-pub fn _login_metadata_sync(user_id: Principal, vault_id: Principal, update: Vec<u8>, lc: &LoginsColumns, lm: &LoginsMap) {
+pub fn _login_metadata_sync(user_id: Principal, vault_id: Principal, update: Vec<u8>, lc: &LoginsColumns, lm: &LoginsMap) -> SyncOutcome {
     if update.is_empty() {
-        return;
+        return SyncOutcome::default();
     }
 
     let login_data = deserialise_login_metadata(update);
-    _process_metadata(user_id, vault_id, &login_data, lc, lm);
+    let removed = _process_metadata(user_id, vault_id, &login_data, lc, lm);
+    SyncOutcome::new(login_data.metadatas.len(), removed)
 }
 
 // Interface function to deserialise and process a metadata-only delete of login data. Note this 
 // alse deletes all associated login identities for the deleted columns.
-pub fn _login_metadata_delete(user_id: Principal, vault_id: Principal, update: Vec<u8>, lc: &LoginsColumns, lm: &LoginsMap) {
+pub fn _login_metadata_delete(user_id: Principal, vault_id: Principal, update: Vec<u8>, lc: &LoginsColumns, lm: &LoginsMap) -> SyncOutcome {
     if update.is_empty() {
-        return;
+        return SyncOutcome::default();
     }
     
     let deletes = deserialise_login_metadata(update);
     let mut columns = lc.borrow_mut();
+    let mut removed = Vec::new();
 
     for cell in deletes.metadatas.iter()
     {
         let column_key = LoginSiteKey::new(user_id, vault_id, cell.header.x);
-        columns.remove(&column_key);
+        let label = columns.remove(&column_key);
 
         // Also remove all associated login identities for this column
-        _delete_login_identities(user_id, vault_id, column_key.x, lm);
+        let rows = _delete_login_identities(user_id, vault_id, column_key.x, lm);
+        if label.is_some() || !rows.is_empty() {
+            removed.push(TrashItem::login_column(column_key.x, label.unwrap_or_default(), rows));
+        }
     }
+    SyncOutcome::new(deletes.metadatas.len(), removed)
 }
 
-pub fn _login_data_sync(user_id: Principal, vault_id: Principal, update: Vec<u8>, lm: &LoginsMap) {
+pub fn _login_data_sync(user_id: Principal, vault_id: Principal, update: Vec<u8>, lm: &LoginsMap) -> SyncOutcome {
     if update.is_empty() {
-        return;
+        return SyncOutcome::default();
     }
 
     let login_data = deserialise_login_data_sync(&update);
     
-    _process_login_data(user_id, vault_id, &login_data, lm);
+    let mut outcome = SyncOutcome::new(login_data.cells.len(), Vec::new());
+    _process_login_data(user_id, vault_id, &login_data, lm, &mut outcome);
+    outcome
 }
 
-pub fn _login_data_deletes(user_id: Principal, vault_id: Principal, update: Vec<u8>, lm: &LoginsMap) {
+pub fn _login_data_deletes(user_id: Principal, vault_id: Principal, update: Vec<u8>, lm: &LoginsMap) -> SyncOutcome {
     if update.is_empty() {
-        return;
+        return SyncOutcome::default();
     }
     
     let deletes = deserialise_delete_cells(update);
     let mut logins = lm.borrow_mut();
+    let mut removed = Vec::new();
     for cell in deletes.cells.iter()
     {
         let key = SpreadsheetKey::new(user_id, vault_id, cell.x, cell.y);
-        logins.remove(&key);
+        if let Some(old) = logins.remove(&key) {
+            removed.push(TrashItem::cell(TrashKind::LoginCell, cell.x, cell.y, old.data));
+        }
     }
+    SyncOutcome::new(deletes.cells.len(), removed)
 }
 
-fn _process_notes_data(user_id: Principal, vault_id: Principal, notes_data: &super::deserialiser_types::SecureNotesData, nm: &NotesMap) {
+fn _process_notes_data(user_id: Principal, vault_id: Principal, notes_data: &super::deserialiser_types::SecureNotesData, nm: &NotesMap, outcome: &mut SyncOutcome) {
     let mut nm = nm.borrow_mut();
 
     for note in notes_data.notes.iter() {
@@ -233,107 +315,450 @@ fn _process_notes_data(user_id: Principal, vault_id: Principal, notes_data: &sup
         };
         if note.label.is_empty()
         {
-            nm.remove(&key);
+            if let Some(old) = nm.remove(&key) {
+                outcome.removed.push(TrashItem::note(note.header.x, old.label, old.note));
+            }
             continue;
         }
-        nm.insert(key, SecureNote::new(note.label.clone(), note.note.clone()));
+        let old = nm.insert(key, SecureNote::new(note.label.clone(), note.note.clone()));
+        outcome.stored.stored(old.as_ref().map(|old| old.label.len() + old.note.len()), note.label.len() + note.note.len());
+        if let Some(old) = old {
+            if old.label != note.label || old.note != note.note {
+                outcome.replaced.push(Revision { item: HistoryItem { kind: HistoryKind::Note, x: note.header.x, y: 0 }, label: old.label, data: old.note });
+            }
+        }
     }
 }
 
-pub fn _secret_notes_sync(user_id: Principal, vault_id: Principal, update: Vec<u8>, nm: &NotesMap) {
+pub fn _secret_notes_sync(user_id: Principal, vault_id: Principal, update: Vec<u8>, nm: &NotesMap) -> SyncOutcome {
     if update.is_empty() {
-        return;
+        return SyncOutcome::default();
     }
 
     let notes = deserialise_secure_notes(update);
-    _process_notes_data(user_id, vault_id, &notes, nm);
+    let mut outcome = SyncOutcome::new(notes.notes.len(), Vec::new());
+    _process_notes_data(user_id, vault_id, &notes, nm, &mut outcome);
+    outcome
+}
+
+fn _process_totp(user_id: Principal, vault_id: Principal, totp: &super::deserialiser_types::TotpData, tm: &TotpMap, outcome: &mut SyncOutcome) {
+    let mut tm = tm.borrow_mut();
+    for entry in totp.seeds.iter() {
+        let key = TotpKey::new(user_id, vault_id, entry.header.x);
+        if entry.seed.is_empty() {
+            if let Some(old) = tm.remove(&key) {
+                outcome.removed.push(TrashItem::totp(key.index, old));
+            }
+            continue;
+        }
+        let record = TotpRecord {
+            params: TotpParams {
+                algorithm: TotpAlgorithm::from_byte(entry.header.algorithm).unwrap_or(TotpAlgorithm::Sha1),
+                digits: entry.header.digits,
+                period: entry.header.period,
+            },
+            login_column: (entry.header.has_link != 0).then_some(entry.header.link_x),
+            label: entry.label.clone(),
+            seed: entry.seed.clone(),
+        };
+        let size = record.size();
+        let old = tm.insert(key, record);
+        outcome.stored.stored(old.map(|old| old.size()), size);
+    }
 }
 
-pub fn _global_sync(user_id: Principal, vault_id: Principal, update: Vec<u8>, state: &GeneralState) {
+pub fn _totp_sync(user_id: Principal, vault_id: Principal, update: Vec<u8>, tm: &TotpMap) -> SyncOutcome {
     if update.is_empty() {
-        return;
+        return SyncOutcome::default();
+    }
+
+    let totp = deserialise_totp(&update);
+    let mut outcome = SyncOutcome::new(totp.seeds.len(), Vec::new());
+    _process_totp(user_id, vault_id, &totp, tm, &mut outcome);
+    outcome
+}
+
+pub fn _totp_deletes(user_id: Principal, vault_id: Principal, update: Vec<u8>, tm: &TotpMap) -> SyncOutcome {
+    if update.is_empty() {
+        return SyncOutcome::default();
+    }
+
+    let deletes = deserialise_delete_indexes(update);
+    let mut tm = tm.borrow_mut();
+    let mut removed = Vec::new();
+    for index in deletes.indexes.iter() {
+        if let Some(old) = tm.remove(&TotpKey::new(user_id, vault_id, *index)) {
+            removed.push(TrashItem::totp(*index, old));
+        }
+    }
+    SyncOutcome::new(deletes.indexes.len(), removed)
+}
+
+fn _process_records(user_id: Principal, vault_id: Principal, kind: RecordKind, records: &super::deserialiser_types::RecordsData, rm: &RecordsMap, outcome: &mut SyncOutcome) {
+    let mut rm = rm.borrow_mut();
+    for entry in records.records.iter() {
+        let key = RecordKey::new(user_id, vault_id, kind, entry.header.x);
+        if entry.fields.is_empty() {
+            if let Some(old) = rm.remove(&key) {
+                outcome.removed.push(TrashItem::record(kind, key.index, old));
+            }
+            continue;
+        }
+        let record = TypedRecord {
+            type_tag: entry.header.type_tag,
+            expiry: (entry.header.has_expiry != 0).then_some(Expiry { year: entry.header.expiry_year, month: entry.header.expiry_month }),
+            fields: entry.fields.clone(),
+        };
+        let size = record.size();
+        let old = rm.insert(key, record);
+        outcome.stored.stored(old.map(|old| old.size()), size);
+    }
+}
+
+pub fn _records_sync(user_id: Principal, vault_id: Principal, kind: RecordKind, update: Vec<u8>, rm: &RecordsMap) -> SyncOutcome {
+    if update.is_empty() {
+        return SyncOutcome::default();
+    }
+
+    let records = deserialise_records(&update, kind);
+    let mut outcome = SyncOutcome::new(records.records.len(), Vec::new());
+    _process_records(user_id, vault_id, kind, &records, rm, &mut outcome);
+    outcome
+}
+
+pub fn _records_deletes(user_id: Principal, vault_id: Principal, kind: RecordKind, update: Vec<u8>, rm: &RecordsMap) -> SyncOutcome {
+    if update.is_empty() {
+        return SyncOutcome::default();
+    }
+
+    let deletes = deserialise_delete_indexes(update);
+    let mut rm = rm.borrow_mut();
+    let mut removed = Vec::new();
+    for index in deletes.indexes.iter() {
+        if let Some(old) = rm.remove(&RecordKey::new(user_id, vault_id, kind, *index)) {
+            removed.push(TrashItem::record(kind, *index, old));
+        }
+    }
+    SyncOutcome::new(deletes.indexes.len(), removed)
+}
+
+// Checks every record before writing any, so a sync that doesn't fit its templates changes nothing.
+fn _process_custom_records(user_id: Principal, vault_id: Principal, records: &super::deserialiser_types::CustomRecordsData, state: &GeneralState, outcome: &mut SyncOutcome) -> Result<(), String> {
+    let mut writes = Vec::with_capacity(records.records.len());
+    for entry in records.records.iter() {
+        let key = TemplateKey::new(user_id, vault_id, entry.header.x);
+        if entry.values.is_empty() {
+            writes.push((key, None));
+            continue;
+        }
+        let record = CustomRecord { template: entry.header.template, values: entry.values.clone() };
+        _validate_custom_record(user_id, vault_id, &record, state).map_err(|error| format!("record {}: {}", entry.header.x, error))?;
+        writes.push((key, Some(record)));
+    }
+
+    let mut cm = state.custom_records.borrow_mut();
+    for (key, record) in writes {
+        match record {
+            Some(record) => {
+                let size = record.size();
+                let old = cm.insert(key, record);
+                outcome.stored.stored(old.map(|old| old.size()), size);
+            }
+            None => {
+                if let Some(old) = cm.remove(&key) {
+                    outcome.removed.push(TrashItem::custom_record(key.index, old));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn _custom_records_sync(user_id: Principal, vault_id: Principal, update: Vec<u8>, state: &GeneralState) -> Result<SyncOutcome, String> {
+    if update.is_empty() {
+        return Ok(SyncOutcome::default());
+    }
+
+    let records = deserialise_custom_records(&update);
+    let mut outcome = SyncOutcome::new(records.records.len(), Vec::new());
+    _process_custom_records(user_id, vault_id, &records, state, &mut outcome)?;
+    Ok(outcome)
+}
+
+pub fn _custom_records_deletes(user_id: Principal, vault_id: Principal, update: Vec<u8>, cm: &CustomRecordsMap) -> SyncOutcome {
+    if update.is_empty() {
+        return SyncOutcome::default();
+    }
+
+    let deletes = deserialise_delete_indexes(update);
+    let mut cm = cm.borrow_mut();
+    let mut removed = Vec::new();
+    for index in deletes.indexes.iter() {
+        if let Some(old) = cm.remove(&TemplateKey::new(user_id, vault_id, *index)) {
+            removed.push(TrashItem::custom_record(*index, old));
+        }
+    }
+    SyncOutcome::new(deletes.indexes.len(), removed)
+}
+
+// Errors once sections may have been written, so callers must trap to roll the sync back.
+pub fn _global_sync(user_id: Principal, vault_id: Principal, update: Vec<u8>, state: &GeneralState) -> Result<SyncOutcome, String> {
+    if update.is_empty() {
+        return Ok(SyncOutcome::default());
     }
     let global_data = deserialise_global_sync(update);
 
-    _process_login_data(user_id, vault_id, &global_data.logins.cells, &state.logins_map);
-    _process_notes_data(user_id, vault_id, &global_data.secure_notes, &state.notes_map);
-    _process_metadata(user_id, vault_id, &global_data.logins.metadata, &state.logins_columns, &state.logins_map);
-    _process_spreadsheet(user_id, vault_id, &global_data.spreadsheet, &state.spreadsheet_map);
-    _process_spreadsheet_columns(user_id, vault_id, &global_data.spreadsheet_columns, &state.spreadsheet_columns);
+    let items = global_data.logins.cells.cells.len()
+        + global_data.logins.metadata.metadatas.len()
+        + global_data.secure_notes.notes.len()
+        + global_data.spreadsheet.cells.len()
+        + global_data.spreadsheet_columns.columns.len()
+        + global_data.totp.seeds.len()
+        + global_data.payment_cards.records.len()
+        + global_data.identities.records.len()
+        + global_data.custom_records.records.len()
+        + global_data.folders.folders.len()
+        + global_data.tags.tags.len()
+        + global_data.item_labels.items.len()
+        + global_data.search_tokens.items.len();
+    let mut outcome = SyncOutcome::new(items, Vec::new());
+
+    _process_login_data(user_id, vault_id, &global_data.logins.cells, &state.logins_map, &mut outcome);
+    _process_notes_data(user_id, vault_id, &global_data.secure_notes, &state.notes_map, &mut outcome);
+    outcome.removed.extend(_process_metadata(user_id, vault_id, &global_data.logins.metadata, &state.logins_columns, &state.logins_map));
+    _process_spreadsheet(user_id, vault_id, 0, &global_data.spreadsheet, &state.spreadsheet_map, &mut outcome);
+    _process_spreadsheet_columns(user_id, vault_id, 0, &global_data.spreadsheet_columns, &state.spreadsheet_columns);
+    _process_totp(user_id, vault_id, &global_data.totp, &state.totp_map, &mut outcome);
+    _process_records(user_id, vault_id, RecordKind::PaymentCard, &global_data.payment_cards, &state.records, &mut outcome);
+    _process_records(user_id, vault_id, RecordKind::Identity, &global_data.identities, &state.records, &mut outcome);
+    // Last, so records can refer to seeds written by the same sync.
+    _process_custom_records(user_id, vault_id, &global_data.custom_records, state, &mut outcome)?;
+    // Folders before the labels that file items in them, parents before children.
+    _apply_folders(user_id, vault_id, &global_data.folders, state)?;
+    _apply_tags(user_id, vault_id, &global_data.tags, state)?;
+    _apply_item_labels(user_id, vault_id, &global_data.item_labels, state)?;
+    _apply_search_tokens(user_id, vault_id, global_data.search_tokens, state)?;
+
+    Ok(outcome)
+}
+
+// Cell positions probed per batch by the stages that can't range over a vault: one column.
+const PROBES_PER_BATCH: u32 = 256;
+// Every x, y position of a grid.
+pub const GRID_POSITIONS: u32 = 256 * 256;
+// Attachment chunks removed per batch. Each can hold up to a megabyte.
+const ATTACHMENT_CHUNKS_PER_BATCH: usize = 16;
+
+// Removes up to `limit` entries in `range`. Returns how many were removed.
+// StableBTreeMap does not support bulk delete or mutation while iterating, hence the two passes.
+fn _remove_range<K, V>(map: &RefCell<StableBTreeMap<K, V, Memory>>, range: RangeInclusive<K>, limit: usize) -> usize
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+{
+    let mut map = map.borrow_mut();
+    let keys_to_remove: Vec<K> = map.range(range).take(limit).map(|entry| entry.key().clone()).collect();
+    for key in keys_to_remove.iter() {
+        map.remove(key);
+    }
+    keys_to_remove.len()
+}
+
+// Removes the given keys where present. Returns how many were removed.
+fn _remove_keys<K, V>(map: &RefCell<StableBTreeMap<K, V, Memory>>, keys: impl Iterator<Item = K>) -> usize
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+{
+    let mut map = map.borrow_mut();
+    keys.filter(|key| map.remove(key).is_some()).count()
+}
+
+// The cells of one grid: a vault's sheet or its logins. Keys start with the cell position rather
+// than the vault, so the vault's cells are found by probing positions, never by scanning the
+// cells of every user. Only the rows of `columns`, the grid's columns with headers, are probed.
+pub fn _probe_cells(principals: &[u8], columns: &[u8], cells: &SpreadsheetMap) -> Vec<(SpreadsheetKey, Vec<u8>)> {
+    let cells = cells.borrow();
+    columns.iter()
+        .flat_map(|x| (0..=u8::MAX).map(move |y| (*x, y)))
+        .filter_map(|(x, y)| {
+            let key = SpreadsheetKey { principals: principals.to_vec(), x, y };
+            cells.get(&key).map(|value| (key, value.data))
+        })
+        .collect()
 }
 
-fn _process_delete_vault(principals: Vec<u8>, state: &GeneralState) {
-     let mut lc = state.logins_columns.borrow_mut();
-    let keys_to_remove: Vec<_> = lc.iter()
-        .filter(|entry| entry.key().principals_match(&principals))
-        .map(|entry| entry.key().clone())
-        .collect();
+// The column headers of one sheet, probed the same way.
+pub fn _probe_columns(principals: &[u8], columns: &ColumnsInfo) -> Vec<(ColumnKey, ColumnData)> {
+    let columns = columns.borrow();
+    (0..=u8::MAX)
+        .filter_map(|x| {
+            let key = ColumnKey { principals: principals.to_vec(), x };
+            columns.get(&key).map(|value| (key, value))
+        })
+        .collect()
+}
 
-    for key in keys_to_remove {
-        lc.remove(&key);
+// Keys of cells and columns start with their position rather than the vault, so these stages
+// probe each position of the vault in turn from `position`. Returns the next position to
+// probe, None once past the last.
+fn _probe_batch(position: u32, end: u32, mut remove: impl FnMut(u32) -> bool) -> (usize, Option<u32>) {
+    let batch_end = position.saturating_add(PROBES_PER_BATCH).min(end);
+    let removed = (position..batch_end).filter(|position| remove(*position)).count();
+    (removed, (batch_end < end).then_some(batch_end))
+}
+
+// One batch of a deletion stage. Returns the entries removed and the position to resume the
+// stage from, None once the stage is done.
+fn _delete_stage_batch(stage: DeletionStage, principals: &[u8], position: u32, state: &GeneralState, batch_size: usize) -> (usize, Option<u32>) {
+    let p = || principals.to_vec();
+    let ranged = |removed: usize| (removed, (removed == batch_size).then_some(0));
+    match stage {
+        DeletionStage::LoginColumns => {
+            ranged(_remove_range(&state.logins_columns, LoginSiteKey { principals: p(), x: 0 }..=LoginSiteKey { principals: p(), x: u8::MAX }, batch_size))
+        }
+        DeletionStage::SpreadsheetColumns => (_remove_keys(&state.spreadsheet_columns, (0..=u8::MAX).map(|x| ColumnKey { principals: p(), x })), None),
+        DeletionStage::Spreadsheet => _probe_batch(position, GRID_POSITIONS, |position| {
+            let key = SpreadsheetKey { principals: p(), x: (position >> 8) as u8, y: position as u8 };
+            state.spreadsheet_map.borrow_mut().remove(&key).is_some()
+        }),
+        // The other sheets are probed like sheet 0, one after another: the position is the sheet
+        // times GRID_POSITIONS plus the cell position. Once a sheet's cells are gone its columns
+        // and record go; the record of sheet 0 goes last.
+        DeletionStage::Sheets => {
+            let range = SheetKey { principals: p(), sheet: 1 }..=SheetKey { principals: p(), sheet: u8::MAX };
+            let next_sheet = state.sheets.borrow().range(range)
+                .map(|entry| entry.key().sheet)
+                .find(|sheet| u32::from(*sheet) >= position / GRID_POSITIONS);
+            let Some(sheet) = next_sheet else {
+                return (_remove_keys(&state.sheets, std::iter::once(SheetKey { principals: p(), sheet: 0 })), None);
+            };
+            let base = u32::from(sheet) * GRID_POSITIONS;
+            let sheet_principals = vault_sheet_principals(principals, sheet);
+            let (mut removed, next) = _probe_batch(position.max(base) - base, GRID_POSITIONS, |position| {
+                let key = SpreadsheetKey { principals: sheet_principals.clone(), x: (position >> 8) as u8, y: position as u8 };
+                state.spreadsheet_map.borrow_mut().remove(&key).is_some()
+            });
+            if let Some(next) = next {
+                return (removed, Some(base + next));
+            }
+            removed += _remove_keys(&state.spreadsheet_columns, (0..=u8::MAX).map(|x| ColumnKey { principals: sheet_principals.clone(), x }));
+            removed += _remove_keys(&state.sheets, std::iter::once(SheetKey { principals: p(), sheet }));
+            (removed, Some(base + GRID_POSITIONS))
+        }
+        DeletionStage::Logins => _probe_batch(position, GRID_POSITIONS, |position| {
+            let key = SpreadsheetKey { principals: p(), x: (position >> 8) as u8, y: position as u8 };
+            state.logins_map.borrow_mut().remove(&key).is_some()
+        }),
+        DeletionStage::SecureNotes => (_remove_keys(&state.notes_map, (0..=u8::MAX).map(|index| SecureNoteKey { index, principals: p() })), None),
+        DeletionStage::Totp => ranged(_remove_range(&state.totp_map, TotpKey { principals: p(), index: 0 }..=TotpKey { principals: p(), index: u8::MAX }, batch_size)),
+        DeletionStage::Records => {
+            let range = RecordKey { principals: p(), kind: RecordKind::PaymentCard, index: 0 }..=RecordKey { principals: p(), kind: RecordKind::Identity, index: u8::MAX };
+            ranged(_remove_range(&state.records, range, batch_size))
+        }
+        DeletionStage::CustomRecords => ranged(_remove_range(&state.custom_records, TemplateKey { principals: p(), index: 0 }..=TemplateKey { principals: p(), index: u8::MAX }, batch_size)),
+        DeletionStage::RecordTemplates => ranged(_remove_range(&state.record_templates, TemplateKey { principals: p(), index: 0 }..=TemplateKey { principals: p(), index: u8::MAX }, batch_size)),
+        // Labels first, so a partly deleted vault never labels with a missing folder or tag.
+        DeletionStage::Organization => {
+            let mut removed = _remove_range(&state.item_labels, ItemLabelsKey { principals: p(), item: ItemRef::FIRST }..=ItemLabelsKey { principals: p(), item: ItemRef::LAST }, batch_size);
+            if removed < batch_size {
+                let tags = TagKey { principals: p(), id: Vec::new() }..=TagKey { principals: p(), id: vec![u8::MAX; MAX_TAG_ID_BYTES] };
+                removed += _remove_range(&state.tags, tags, batch_size - removed);
+            }
+            if removed < batch_size {
+                removed += _remove_range(&state.folders, FolderKey { principals: p(), id: 0 }..=FolderKey { principals: p(), id: u64::MAX }, batch_size - removed);
+            }
+            ranged(removed)
+        }
+        DeletionStage::SearchIndex => {
+            let index = SearchTokenKey { principals: p(), token: Vec::new(), item: ItemRef::FIRST }..=SearchTokenKey { principals: p(), token: vec![u8::MAX; MAX_TOKEN_BYTES], item: ItemRef::LAST };
+            let mut removed = _remove_range(&state.search_index, index, batch_size);
+            if removed < batch_size {
+                removed += _remove_range(&state.item_tokens, ItemTokensKey { principals: p(), item: ItemRef::FIRST }..=ItemTokensKey { principals: p(), item: ItemRef::LAST }, batch_size - removed);
+            }
+            ranged(removed)
+        }
+        DeletionStage::AttachmentChunks => {
+            let limit = batch_size.min(ATTACHMENT_CHUNKS_PER_BATCH);
+            let range = AttachmentChunkKey { principals: p(), id: 0, index: 0 }..=AttachmentChunkKey { principals: p(), id: u64::MAX, index: u32::MAX };
+            let removed = _remove_range(&state.attachment_chunks, range, limit);
+            (removed, (removed == limit).then_some(0))
+        }
+        DeletionStage::Attachments => ranged(_remove_range(&state.attachments, AttachmentKey { principals: p(), id: 0 }..=AttachmentKey { principals: p(), id: u64::MAX }, batch_size)),
+        // Grants are keyed by machine first. There are few of them, so they're filtered.
+        DeletionStage::MachineGrants => {
+            let keys = _vault_grant_keys(principals, batch_size, state);
+            for key in keys.iter() {
+                _remove_grant(key, state);
+            }
+            ranged(keys.len())
+        }
+        DeletionStage::Trash => ranged(_remove_range(&state.trash, TrashKey { principals: p(), id: 0 }..=TrashKey { principals: p(), id: u64::MAX }, batch_size)),
+        DeletionStage::History => {
+            let first = HistoryKey { principals: p(), kind: HistoryKind::LoginCell, x: 0, y: 0, revision: 0 };
+            let last = HistoryKey { principals: p(), kind: HistoryKind::Note, x: u8::MAX, y: u8::MAX, revision: u64::MAX };
+            ranged(_remove_range(&state.history, first..=last, batch_size))
+        }
+        DeletionStage::VaultName => {
+            let registered = _remove_keys(&state.vault_registry, std::iter::once(VaultNameKey { principals: p() }));
+            (registered + _remove_keys(&state.vault_names_map, std::iter::once(VaultNameKey { principals: p() })), None)
+        }
     }
-    
-    // Remove from spreadsheet_columns
-    let mut sc = state.spreadsheet_columns.borrow_mut();
-    let keys_to_remove: Vec<_> = sc.iter()
-        .filter(|entry| entry.key().principals_match(&principals))
-        .map(|entry| entry.key().clone())
-        .collect();
-    for key in keys_to_remove {
-        sc.remove(&key);
-    }
-
-    // Remove from spreadsheet_map
-    let mut sm = state.spreadsheet_map.borrow_mut();
-    let keys_to_remove: Vec<_> = sm.iter()
-        .filter(|entry| entry.key().principals_match(&principals))
-        .map(|entry| entry.key().clone())
-        .collect();
-    for key in keys_to_remove {
-        sm.remove(&key);
-    }
-
-    // Remove from logins_map
-    let mut lm = state.logins_map.borrow_mut();
-    let keys_to_remove: Vec<_> = lm.iter()
-        .filter(|entry| entry.key().principals_match(&principals))
-        .map(|entry| entry.key().clone())
-        .collect();
-    for key in keys_to_remove {
-        lm.remove(&key);
-    }
-
-    // Remove from notes_map
-    let mut nm = state.notes_map.borrow_mut();
-    let keys_to_remove: Vec<_> = nm.iter()
-        .filter(|entry| entry.key().principals == principals)
-        .map(|entry| entry.key().clone())
-        .collect();
-    for key in keys_to_remove {
-        nm.remove(&key);
-    }
-
-    // Remove from vault_names_map
-    let mut vnm = state.vault_names_map.borrow_mut();
-    let keys_to_remove: Vec<_> = vnm.iter()
-        .filter(|entry| entry.key().principals == principals)
-        .map(|entry| entry.key().clone())
-        .collect();
-    for key in keys_to_remove {
-        vnm.remove(&key);
-    }
-}
-
-pub fn _delete_vault(user_id: Principal, vault_id: Principal, state: &GeneralState) {
-    let principals = vec![user_id.into_bytes(), vault_id.into_bytes()].concat();
-    _process_delete_vault(principals, state);
-}
-
-pub fn _purge_user(user_id: Principal, state: &GeneralState) {
-    let vault_names = _get_vault_names(user_id, &state.vault_names_map);
-
-    for (vault_id, _) in vault_names.names {
-        let principals = vec![user_id.into_bytes(), vault_id.clone().into_bytes()].concat();
-        _process_delete_vault(principals, state);
-    }
-}
\ No newline at end of file
+}
+
+// Deletes a vault's entries stage by stage from `cursor`, a bounded batch at a time, until
+// everything is gone or `should_yield` asks to stop. Returns the number of entries removed
+// and the cursor to resume from, None once the vault is gone.
+pub fn _delete_vault_batch(principals: &[u8], cursor: DeletionCursor, state: &GeneralState, batch_size: usize, should_yield: &dyn Fn() -> bool) -> (u64, Option<DeletionCursor>) {
+    let mut cursor = cursor;
+    let mut removed: u64 = 0;
+    loop {
+        let (count, next) = _delete_stage_batch(cursor.stage, principals, cursor.position, state, batch_size);
+        removed += count as u64;
+        cursor = match (next, cursor.stage.next()) {
+            (Some(position), _) => DeletionCursor { stage: cursor.stage, position },
+            (None, Some(stage)) => DeletionCursor { stage, position: 0 },
+            (None, None) => return (removed, None),
+        };
+        if should_yield() {
+            return (removed, Some(cursor));
+        }
+    }
+}
+
+// The user_id + vault_id principals of the user's first registered vault, if any. The registry
+// sorts by user, so this is a single lookup.
+pub fn _first_user_vault(user: &[u8], registry: &VaultRegistryMap) -> Option<Vec<u8>> {
+    registry.borrow()
+        .range(VaultNameKey { principals: user.to_vec() }..)
+        .next()
+        .map(|entry| entry.key().principals.clone())
+        .filter(|principals| principals.len() > user.len() && principals.starts_with(user))
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+
+    use super::_global_sync;
+    use crate::stable::types::GeneralState;
+
+    // A section that can't be applied fails the sync instead of panicking, so the endpoint can
+    // trap with its message.
+    #[test]
+    fn global_sync_returns_section_errors() {
+        let state = GeneralState::init();
+        let user_id = Principal::from_slice(&[1; 29]);
+        let vault_id = Principal::from_slice(&[2; 29]);
+        let record = [0, 3, 0, 0, 0, 1, b'x'];
+        let mut update = vec![1, 7, 0, 0, 0, 0, record.len() as u8];
+        update.extend(record);
+        assert!(_global_sync(user_id, vault_id, update, &state).is_err());
+
+        // A note filed in a folder that doesn't exist.
+        let labels = [&[3, 0, 1, 0, 1][..], &9u64.to_be_bytes(), &[0, 0]].concat();
+        let mut update = vec![1, 10, 0, 0, 0, 0, labels.len() as u8];
+        update.extend(labels);
+        assert_eq!(_global_sync(user_id, vault_id, update, &state).err(), Some("no folder 9".to_string()));
+    }
+}
@@ -0,0 +1,210 @@
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::{
+    api::{organization_api::_drop_sheet_labels, search_api::_drop_sheet_tokens, serial_api::{_probe_cells, _probe_columns, SyncOutcome}},
+    stable::types::{GeneralState, SheetsMap},
+    vault_type::{
+        sheets::{sheet_principals, SheetKey, SheetRecord},
+        spreadsheet::ColumnKey,
+        trash::TrashItem,
+    },
+};
+
+/*
+    Sheets of the flexible grid. Every vault has sheet 0, which holds what the vault stored
+    before sheets existed and can't be deleted. Further sheets take the lowest free id. Names
+    are ciphertext; positions order the sheets for display and run from 0 without gaps.
+*/
+
+pub const MAX_SHEETS: usize = 64;
+pub const MAX_SHEET_NAME_BYTES: usize = 1024;
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub struct SheetInfo {
+    pub sheet: u8,
+    pub position: u8,
+    pub name: Vec<u8>,
+}
+
+fn _vault_range(user_id: Principal, vault_id: Principal) -> std::ops::RangeInclusive<SheetKey> {
+    SheetKey::new(user_id, vault_id, 0)..=SheetKey::new(user_id, vault_id, u8::MAX)
+}
+
+pub fn _sheet_exists(user_id: Principal, vault_id: Principal, sheet: u8, sheets: &SheetsMap) -> bool {
+    sheet == 0 || sheets.borrow().contains_key(&SheetKey::new(user_id, vault_id, sheet))
+}
+
+pub fn _assert_sheet(user_id: Principal, vault_id: Principal, sheet: u8, sheets: &SheetsMap) -> Result<(), String> {
+    if !_sheet_exists(user_id, vault_id, sheet, sheets) {
+        return Err(format!("no sheet {}", sheet));
+    }
+    Ok(())
+}
+
+fn _check_name(name: &[u8]) -> Result<(), String> {
+    if name.len() > MAX_SHEET_NAME_BYTES {
+        return Err(format!("sheet name exceeds {} bytes", MAX_SHEET_NAME_BYTES));
+    }
+    Ok(())
+}
+
+// The vault's sheets in display order.
+pub fn _list_sheets(user_id: Principal, vault_id: Principal, sheets: &SheetsMap) -> Vec<SheetInfo> {
+    let mut list: Vec<SheetInfo> = sheets.borrow()
+        .range(_vault_range(user_id, vault_id))
+        .map(|entry| {
+            let (key, record) = entry.into_pair();
+            SheetInfo { sheet: key.sheet, position: record.position, name: record.name }
+        })
+        .collect();
+    if !list.iter().any(|info| info.sheet == 0) {
+        list.push(SheetInfo { sheet: 0, position: 0, name: Vec::new() });
+    }
+    list.sort_by_key(|info| (info.position, info.sheet));
+    list
+}
+
+// Writes the sheets back, positioned in the order given.
+fn _store_order(user_id: Principal, vault_id: Principal, list: Vec<SheetInfo>, sheets: &SheetsMap) {
+    let mut sheets = sheets.borrow_mut();
+    for (position, info) in list.into_iter().enumerate() {
+        sheets.insert(SheetKey::new(user_id, vault_id, info.sheet), SheetRecord { position: position as u8, name: info.name });
+    }
+}
+
+// Adds an empty sheet after the others. Returns its id.
+pub fn _create_sheet(user_id: Principal, vault_id: Principal, name: Vec<u8>, sheets: &SheetsMap) -> Result<u8, String> {
+    _check_name(&name)?;
+    let mut list = _list_sheets(user_id, vault_id, sheets);
+    if list.len() >= MAX_SHEETS {
+        return Err(format!("a vault can have at most {} sheets", MAX_SHEETS));
+    }
+    let sheet = (1..=u8::MAX).find(|sheet| list.iter().all(|info| info.sheet != *sheet)).unwrap();
+    list.push(SheetInfo { sheet, position: 0, name });
+    _store_order(user_id, vault_id, list, sheets);
+    Ok(sheet)
+}
+
+pub fn _rename_sheet(user_id: Principal, vault_id: Principal, sheet: u8, name: Vec<u8>, sheets: &SheetsMap) -> Result<(), String> {
+    _check_name(&name)?;
+    _assert_sheet(user_id, vault_id, sheet, sheets)?;
+    let key = SheetKey::new(user_id, vault_id, sheet);
+    let position = sheets.borrow().get(&key).map_or(0, |record| record.position);
+    sheets.borrow_mut().insert(key, SheetRecord { position, name });
+    Ok(())
+}
+
+// Puts the sheets in the order given, which must name every sheet of the vault once.
+pub fn _reorder_sheets(user_id: Principal, vault_id: Principal, order: Vec<u8>, sheets: &SheetsMap) -> Result<(), String> {
+    let mut list = _list_sheets(user_id, vault_id, sheets);
+    if order.len() != list.len() {
+        return Err(format!("vault has {} sheets, got {}", list.len(), order.len()));
+    }
+    let mut ordered = Vec::with_capacity(list.len());
+    for sheet in order {
+        let index = list.iter()
+            .position(|info| info.sheet == sheet)
+            .ok_or_else(|| format!("sheet {} is unknown or named twice", sheet))?;
+        ordered.push(list.swap_remove(index));
+    }
+    _store_order(user_id, vault_id, ordered, sheets);
+    Ok(())
+}
+
+// Removes a sheet and its columns, and the labels and search tokens of its cells and rows. Its cells go to the
+// trash, and can only be restored while a sheet with the same id exists. Items counts the cells and columns removed.
+pub fn _delete_sheet(user_id: Principal, vault_id: Principal, sheet: u8, state: &GeneralState) -> Result<SyncOutcome, String> {
+    if sheet == 0 {
+        return Err("sheet 0 can't be deleted".into());
+    }
+    _assert_sheet(user_id, vault_id, sheet, &state.sheets)?;
+    let principals = sheet_principals(user_id, vault_id, sheet);
+    let mut outcome = SyncOutcome::default();
+    _drop_sheet_labels(user_id, vault_id, sheet, &state.item_labels);
+    _drop_sheet_tokens(&[user_id.as_slice(), vault_id.as_slice()].concat(), sheet, state);
+
+    let xs: Vec<u8> = _probe_columns(&principals, &state.spreadsheet_columns).into_iter().map(|(key, _)| key.x).collect();
+    for (key, _) in _probe_cells(&principals, &xs, &state.spreadsheet_map) {
+        if let Some(old) = state.spreadsheet_map.borrow_mut().remove(&key) {
+            outcome.removed.push(TrashItem::sheet_cell(sheet, key.x, key.y, old.data));
+        }
+    }
+    let mut columns = state.spreadsheet_columns.borrow_mut();
+    for x in xs.iter() {
+        columns.remove(&ColumnKey { principals: principals.clone(), x: *x });
+    }
+    drop(columns);
+    outcome.items = (outcome.removed.len() + xs.len()) as u32;
+
+    let mut list = _list_sheets(user_id, vault_id, &state.sheets);
+    list.retain(|info| info.sheet != sheet);
+    state.sheets.borrow_mut().remove(&SheetKey::new(user_id, vault_id, sheet));
+    _store_order(user_id, vault_id, list, &state.sheets);
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+
+    use super::{_create_sheet, _delete_sheet, _list_sheets};
+    use crate::{
+        api::serial_api::{_delete_vault_batch, _probe_cells},
+        stable::types::GeneralState,
+        vault_type::{
+            pending_deletion::DeletionCursor,
+            sheets::sheet_principals,
+            spreadsheet::{ColumnData, ColumnKey, ColumnKind, ColumnSettings, SpreadsheetKey, SpreadsheetValue},
+        },
+    };
+
+    // Seven named columns, with `cells` cells spread across them.
+    fn fill(principals: &[u8], cells: u8, state: &GeneralState) {
+        for x in 0..7 {
+            let column = ColumnData::new(false, vec![b'a' + x], ColumnKind::Text, ColumnSettings::default());
+            state.spreadsheet_columns.borrow_mut().insert(ColumnKey { principals: principals.to_vec(), x }, column);
+        }
+        for y in 0..cells {
+            state.spreadsheet_map.borrow_mut().insert(SpreadsheetKey { principals: principals.to_vec(), x: y % 7, y }, SpreadsheetValue::new(vec![y]));
+        }
+    }
+
+    // Deleting a sheet, or the vault holding it, leaves the cells of every other sheet and vault,
+    // however many there are, and stops once the vault is gone.
+    #[test]
+    fn delete_sheet_among_other_vaults() {
+        let state = GeneralState::init();
+        let user_id = Principal::from_slice(&[1; 29]);
+        let vault_id = Principal::from_slice(&[2; 29]);
+        let other_vault = Principal::from_slice(&[3; 29]);
+        let first = _create_sheet(user_id, vault_id, b"first".to_vec(), &state.sheets).unwrap();
+        let second = _create_sheet(user_id, vault_id, b"second".to_vec(), &state.sheets).unwrap();
+        let other = _create_sheet(user_id, other_vault, b"other".to_vec(), &state.sheets).unwrap();
+        fill(&sheet_principals(user_id, vault_id, first), 200, &state);
+        fill(&sheet_principals(user_id, vault_id, second), 150, &state);
+        fill(&sheet_principals(user_id, other_vault, other), 250, &state);
+        fill(&sheet_principals(user_id, other_vault, 0), 250, &state);
+
+        let outcome = _delete_sheet(user_id, vault_id, first, &state).unwrap();
+        assert_eq!(outcome.removed.len(), 200);
+        let columns: Vec<u8> = (0..7).collect();
+        assert_eq!(outcome.items, 200 + 7);
+        assert!(_probe_cells(&sheet_principals(user_id, vault_id, first), &columns, &state.spreadsheet_map).is_empty());
+        assert_eq!(_probe_cells(&sheet_principals(user_id, vault_id, second), &columns, &state.spreadsheet_map).len(), 150);
+        assert_eq!(state.spreadsheet_map.borrow().len(), 650);
+
+        let principals = sheet_principals(user_id, vault_id, 0);
+        let mut cursor = Some(DeletionCursor::default());
+        let mut batches = 0;
+        while let Some(current) = cursor {
+            let (removed, next) = _delete_vault_batch(&principals, current, &state, 100, &|| true);
+            assert!(removed <= 2 * 256 + 1);
+            cursor = next;
+            batches += 1;
+        }
+        assert!(batches < 10_000);
+        assert_eq!(_list_sheets(user_id, vault_id, &state.sheets).len(), 1);
+        assert_eq!(state.spreadsheet_map.borrow().len(), 500);
+        assert_eq!(_list_sheets(user_id, other_vault, &state.sheets).len(), 2);
+    }
+}
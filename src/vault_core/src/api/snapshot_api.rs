@@ -0,0 +1,171 @@
+use sha2::{Digest, Sha256};
+
+use crate::{
+    stable::types::GeneralState,
+    vault_type::snapshot::{SnapshotChunk, SnapshotCursor, SnapshotLock, SnapshotManifest, SnapshotMode, SnapshotSection},
+};
+
+/*
+    Snapshots of the whole canister state for off-canister backups. A controller locks the
+    canister against writes, pulls every stable structure chunk by chunk as stored and checks
+    the chunks against the manifest. A restore writes the chunks back into an empty canister
+    and only unlocks it once the result matches the manifest.
+*/
+
+// Bumped whenever the layout of a stable structure changes, so old snapshots aren't restored
+// into a canister that would misread them.
+pub const SNAPSHOT_VERSION: u32 = 15;
+
+// Keeps a chunk and its encoding under the message size limit.
+const MAX_CHUNK_BYTES: usize = 1_500_000;
+
+pub fn _assert_not_frozen(state: &GeneralState) -> Result<(), String> {
+    match state.snapshot_lock.borrow().get().mode {
+        SnapshotMode::Idle => Ok(()),
+        SnapshotMode::Exporting => Err("canister is read-only while a snapshot is taken".into()),
+        SnapshotMode::Restoring => Err("canister is being restored from a snapshot".into()),
+    }
+}
+
+fn _assert_mode(mode: SnapshotMode, state: &GeneralState) -> Result<(), String> {
+    let current = state.snapshot_lock.borrow().get().mode;
+    if current != mode {
+        return Err(format!("snapshot is {:?}, expected {:?}", current, mode));
+    }
+    Ok(())
+}
+
+fn _set_mode(mode: SnapshotMode, now: u64, state: &GeneralState) {
+    state.snapshot_lock.borrow_mut().set(SnapshotLock { mode, started_at: now });
+}
+
+// Hash of a chunk: memory id, then each entry as key size (u32), key, value size (u32), value.
+pub fn _chunk_hash(memory_id: u8, entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update([memory_id]);
+    for (key, value) in entries {
+        hasher.update((key.len() as u32).to_be_bytes());
+        hasher.update(key);
+        hasher.update((value.len() as u32).to_be_bytes());
+        hasher.update(value);
+    }
+    hasher.finalize().to_vec()
+}
+
+// The chunk at the cursor, starting from the first section when there is none.
+pub fn _snapshot_chunk(cursor: Option<SnapshotCursor>, state: &GeneralState) -> Result<SnapshotChunk, String> {
+    let sections = state.snapshot_sections();
+    let index = match &cursor {
+        Some(cursor) => sections
+            .iter()
+            .position(|(memory_id, _, _)| *memory_id == cursor.memory_id)
+            .ok_or_else(|| format!("unknown snapshot section {}", cursor.memory_id))?,
+        None => 0,
+    };
+    let (memory_id, _, store) = sections[index];
+    let after = cursor.and_then(|cursor| cursor.after);
+    let (entries, more) = store.entries_after(after.as_deref(), MAX_CHUNK_BYTES);
+
+    let next = if more {
+        entries.last().map(|(key, _)| SnapshotCursor { memory_id, after: Some(key.clone()) })
+    } else {
+        sections.get(index + 1).map(|(memory_id, _, _)| SnapshotCursor { memory_id: *memory_id, after: None })
+    };
+    Ok(SnapshotChunk {
+        memory_id,
+        hash: _chunk_hash(memory_id, &entries),
+        entries,
+        next,
+    })
+}
+
+// Walks every chunk of the current state. Only consistent while writes are blocked.
+pub fn _snapshot_manifest(now: u64, state: &GeneralState) -> SnapshotManifest {
+    let sections = state
+        .snapshot_sections()
+        .iter()
+        .map(|(memory_id, _, store)| SnapshotSection { memory_id: *memory_id, entries: store.entry_count() })
+        .collect();
+    let mut root = Sha256::new();
+    let mut chunks = 0;
+    let mut cursor = None;
+    loop {
+        let chunk = _snapshot_chunk(cursor, state).expect("cursor comes from the previous chunk");
+        root.update(&chunk.hash);
+        chunks += 1;
+        match chunk.next {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+    SnapshotManifest {
+        version: SNAPSHOT_VERSION,
+        taken_at: now,
+        sections,
+        chunks,
+        root_hash: root.finalize().to_vec(),
+    }
+}
+
+// Blocks writes and describes the state the chunks that follow will hold.
+pub fn _begin_snapshot(now: u64, state: &GeneralState) -> Result<SnapshotManifest, String> {
+    _assert_not_frozen(state)?;
+    _set_mode(SnapshotMode::Exporting, now, state);
+    Ok(_snapshot_manifest(now, state))
+}
+
+pub fn _export_snapshot_chunk(cursor: Option<SnapshotCursor>, state: &GeneralState) -> Result<SnapshotChunk, String> {
+    _assert_mode(SnapshotMode::Exporting, state)?;
+    _snapshot_chunk(cursor, state)
+}
+
+pub fn _end_snapshot(state: &GeneralState) -> Result<(), String> {
+    _assert_mode(SnapshotMode::Exporting, state)?;
+    _set_mode(SnapshotMode::Idle, 0, state);
+    Ok(())
+}
+
+// Only a canister without any data can be restored into, so nothing gets mixed in.
+pub fn _begin_restore(manifest: &SnapshotManifest, now: u64, state: &GeneralState) -> Result<(), String> {
+    _assert_not_frozen(state)?;
+    if manifest.version != SNAPSHOT_VERSION {
+        return Err(format!("snapshot version {} can't be restored, expected {}", manifest.version, SNAPSHOT_VERSION));
+    }
+    if let Some((memory_id, _, _)) = state.snapshot_sections().iter().find(|(_, _, store)| !store.is_empty()) {
+        return Err(format!("canister already holds data in section {}", memory_id));
+    }
+    _set_mode(SnapshotMode::Restoring, now, state);
+    Ok(())
+}
+
+// Writes back a chunk whose entries match its hash. Returns the number of entries written.
+pub fn _restore_snapshot_chunk(chunk: SnapshotChunk, state: &GeneralState) -> Result<u64, String> {
+    _assert_mode(SnapshotMode::Restoring, state)?;
+    if _chunk_hash(chunk.memory_id, &chunk.entries) != chunk.hash {
+        return Err(format!("hash mismatch in a chunk of section {}", chunk.memory_id));
+    }
+    let sections = state.snapshot_sections();
+    let (_, _, store) = sections
+        .iter()
+        .find(|(memory_id, _, _)| *memory_id == chunk.memory_id)
+        .ok_or_else(|| format!("unknown snapshot section {}", chunk.memory_id))?;
+    for (key, value) in &chunk.entries {
+        store.insert_raw(key, value);
+    }
+    Ok(chunk.entries.len() as u64)
+}
+
+// Unblocks writes once the restored state matches the manifest. Stays locked otherwise, so a
+// missing chunk can still be sent.
+pub fn _finish_restore(manifest: &SnapshotManifest, state: &GeneralState) -> Result<(), String> {
+    _assert_mode(SnapshotMode::Restoring, state)?;
+    let restored = _snapshot_manifest(manifest.taken_at, state);
+    if restored.sections != manifest.sections {
+        return Err("restored entry counts don't match the manifest".into());
+    }
+    if restored.chunks != manifest.chunks || restored.root_hash != manifest.root_hash {
+        return Err("restored state doesn't match the manifest hash".into());
+    }
+    _set_mode(SnapshotMode::Idle, 0, state);
+    Ok(())
+}
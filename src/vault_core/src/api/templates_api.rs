@@ -0,0 +1,113 @@
+use candid::Principal;
+
+use crate::{
+    stable::types::GeneralState,
+    vault_type::{
+        attachments::AttachmentKey,
+        templates::{CustomRecord, FieldType, RecordTemplate, TemplateKey},
+        totp::TotpKey,
+    },
+};
+
+/*
+    Client-defined record types. A template names its fields and gives each a type; records made
+    from it hold one value per field. Values of text, secret and URL fields are ciphertext the
+    canister can't read, so only their presence is checked. Dates, TOTP seeds and attachments are
+    checked against what the vault holds, so a record never points at something missing.
+*/
+
+pub const MAX_TEMPLATE_FIELDS: usize = 32;
+pub const MAX_TEMPLATE_NAME_BYTES: usize = 1024;
+// Field names are stored behind a one-byte length.
+pub const MAX_FIELD_NAME_BYTES: usize = u8::MAX as usize;
+
+fn _vault_range(user_id: Principal, vault_id: Principal) -> std::ops::RangeInclusive<TemplateKey> {
+    TemplateKey::new(user_id, vault_id, 0)..=TemplateKey::new(user_id, vault_id, u8::MAX)
+}
+
+fn _check_template(template: &RecordTemplate) -> Result<(), String> {
+    if template.fields.is_empty() || template.fields.len() > MAX_TEMPLATE_FIELDS {
+        return Err(format!("a template must have between 1 and {} fields", MAX_TEMPLATE_FIELDS));
+    }
+    if template.name.len() > MAX_TEMPLATE_NAME_BYTES {
+        return Err(format!("template name exceeds {} bytes", MAX_TEMPLATE_NAME_BYTES));
+    }
+    if let Some(field) = template.fields.iter().position(|field| field.name.len() > MAX_FIELD_NAME_BYTES) {
+        return Err(format!("name of field {} exceeds {} bytes", field, MAX_FIELD_NAME_BYTES));
+    }
+    Ok(())
+}
+
+fn _check_date(value: &[u8]) -> bool {
+    value.len() == 4 && (1..=12).contains(&value[2]) && (1..=31).contains(&value[3])
+}
+
+// Checks a record against its template and the rest of the vault.
+pub fn _validate_record(user_id: Principal, vault_id: Principal, template: &RecordTemplate, record: &CustomRecord, state: &GeneralState) -> Result<(), String> {
+    if record.values.len() != template.fields.len() {
+        return Err(format!("template {} has {} fields, got {} values", record.template, template.fields.len(), record.values.len()));
+    }
+    for (index, (field, value)) in template.fields.iter().zip(record.values.iter()).enumerate() {
+        if value.is_empty() {
+            if field.required {
+                return Err(format!("field {} is required", index));
+            }
+            continue;
+        }
+        let valid = match field.field_type {
+            FieldType::Text | FieldType::Secret | FieldType::Url => true,
+            FieldType::Date => _check_date(value),
+            FieldType::Totp => value.len() == 1 && state.totp_map.borrow().contains_key(&TotpKey::new(user_id, vault_id, value[0])),
+            FieldType::AttachmentRef => <[u8; 8]>::try_from(value.as_slice())
+                .ok()
+                .and_then(|id| state.attachments.borrow().get(&AttachmentKey::new(user_id, vault_id, u64::from_be_bytes(id))))
+                .is_some_and(|manifest| manifest.complete),
+        };
+        if !valid {
+            return Err(format!("field {} is not a valid {:?}", index, field.field_type));
+        }
+    }
+    Ok(())
+}
+
+// Checks a record against the template it names, as currently stored.
+pub fn _validate_custom_record(user_id: Principal, vault_id: Principal, record: &CustomRecord, state: &GeneralState) -> Result<(), String> {
+    let template = state.record_templates.borrow()
+        .get(&TemplateKey::new(user_id, vault_id, record.template))
+        .ok_or_else(|| format!("no template {}", record.template))?;
+    _validate_record(user_id, vault_id, &template, record, state)
+}
+
+// Records of the vault made from template `index`.
+fn _records_of(user_id: Principal, vault_id: Principal, index: u8, state: &GeneralState) -> Vec<(u8, CustomRecord)> {
+    state.custom_records.borrow()
+        .range(_vault_range(user_id, vault_id))
+        .map(|entry| entry.into_pair())
+        .filter(|(_, record)| record.template == index)
+        .map(|(key, record)| (key.index, record))
+        .collect()
+}
+
+// Adds or replaces a template. A replacement must still fit every record made from it.
+pub fn _set_record_template(user_id: Principal, vault_id: Principal, index: u8, template: RecordTemplate, state: &GeneralState) -> Result<(), String> {
+    _check_template(&template)?;
+    for (x, record) in _records_of(user_id, vault_id, index, state) {
+        _validate_record(user_id, vault_id, &template, &record, state)
+            .map_err(|error| format!("record {} would no longer fit: {}", x, error))?;
+    }
+    state.record_templates.borrow_mut().insert(TemplateKey::new(user_id, vault_id, index), template);
+    Ok(())
+}
+
+// Removes a template no record is made from.
+pub fn _delete_record_template(user_id: Principal, vault_id: Principal, index: u8, state: &GeneralState) -> Result<(), String> {
+    let key = TemplateKey::new(user_id, vault_id, index);
+    if !state.record_templates.borrow().contains_key(&key) {
+        return Err(format!("no template {}", index));
+    }
+    if let Some((x, _)) = _records_of(user_id, vault_id, index, state).first() {
+        return Err(format!("template {} is used by record {}", index, x));
+    }
+    state.record_templates.borrow_mut().remove(&key);
+    Ok(())
+}
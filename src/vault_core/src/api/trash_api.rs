@@ -0,0 +1,286 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::Storable;
+
+use crate::{
+    api::{organization_api::_drop_item_labels, registry_api::{_record_vault_change, SizeChange}, search_api::_index_item, sheets_api::_sheet_exists, templates_api::_validate_custom_record},
+    stable::types::{GeneralState, TrashMap},
+    vault_type::{
+        attachments::{ItemKind, ItemRef},
+        logins::LoginSiteKey,
+        secure_notes::{SecureNote, SecureNoteKey},
+        records::{RecordKey, RecordKind, TypedRecord},
+        sheets::sheet_principals,
+        spreadsheet::{SpreadsheetKey, SpreadsheetValue},
+        templates::{CustomRecord, TemplateKey},
+        totp::{TotpKey, TotpRecord},
+        trash::{TrashEntry, TrashItem, TrashKey, TrashKind},
+    },
+};
+
+/*
+    Per-vault trash. Cells, login columns, notes, TOTP seeds, typed and custom records removed by a sync are kept here with their
+    deletion time and original coordinates, so they can be restored until the retention period
+    runs out.
+*/
+
+pub const DEFAULT_TRASH_RETENTION_NS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000; // 30 days
+pub const MAX_TRASH_RETENTION_NS: u64 = 365 * 24 * 60 * 60 * 1_000_000_000; // 365 days
+
+// Upper bound on expired entries dropped per call, so expiry never blows the instruction limit.
+const MAX_EXPIRE_PER_CALL: usize = 64;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TrashItemInfo {
+    pub id: u64,
+    pub kind: TrashKind,
+    pub x: u8,
+    pub y: u8,
+    pub deleted_at: u64,
+    pub label: Vec<u8>,
+    pub data: Vec<u8>,
+    pub rows: Vec<(u8, Vec<u8>)>,
+}
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct RestoreResult {
+    pub restored: Vec<u64>,
+    // Items left in the trash because their slot is taken again, their login column or sheet is
+    // gone, or they no longer fit their template.
+    pub conflicts: Vec<u64>,
+}
+
+fn _vault_range(principals: &[u8]) -> std::ops::RangeInclusive<TrashKey> {
+    TrashKey { principals: principals.to_vec(), id: 0 }..=TrashKey { principals: principals.to_vec(), id: u64::MAX }
+}
+
+fn _is_expired(entry: &TrashEntry, now: u64, retention: u64) -> bool {
+    retention != 0 && now.saturating_sub(entry.deleted_at) > retention
+}
+
+// Drops a bounded number of expired entries of a vault, oldest first.
+fn _expire_trash(principals: &[u8], now: u64, retention: u64, trash: &TrashMap) {
+    let expired: Vec<TrashKey> = trash.borrow()
+        .range(_vault_range(principals))
+        .filter(|entry| _is_expired(&entry.value(), now, retention))
+        .take(MAX_EXPIRE_PER_CALL)
+        .map(|entry| entry.key().clone())
+        .collect();
+    let mut trash = trash.borrow_mut();
+    for key in expired {
+        trash.remove(&key);
+    }
+}
+
+// How labels and search tokens refer to a trashed item and, for a login column, to its identities.
+fn _item_refs(item: &TrashItem) -> Vec<ItemRef> {
+    let at = |kind, x, y| ItemRef { kind, sheet: 0, x, y };
+    match item.kind {
+        TrashKind::SpreadsheetCell => vec![ItemRef { kind: ItemKind::SpreadsheetCell, sheet: item.sheet(), x: item.x, y: item.y }],
+        TrashKind::LoginCell => vec![at(ItemKind::LoginCell, item.x, item.y)],
+        TrashKind::LoginColumn => std::iter::once(at(ItemKind::LoginColumn, item.x, 0))
+            .chain(item.rows.iter().map(|(y, _)| at(ItemKind::LoginCell, item.x, *y)))
+            .collect(),
+        TrashKind::Note => vec![at(ItemKind::Note, item.x, 0)],
+        TrashKind::Totp => vec![at(ItemKind::Totp, item.x, 0)],
+        TrashKind::PaymentCard => vec![at(ItemKind::PaymentCard, item.x, 0)],
+        TrashKind::Identity => vec![at(ItemKind::Identity, item.x, 0)],
+        TrashKind::CustomRecord => vec![at(ItemKind::CustomRecord, item.x, 0)],
+    }
+}
+
+// Whether the vault holds an item at `item` again, as when a grid edit shifts another item into
+// the slot of a deleted one.
+fn _item_exists(user_id: Principal, vault_id: Principal, item: ItemRef, state: &GeneralState) -> bool {
+    match item.kind {
+        ItemKind::SpreadsheetCell => {
+            let key = SpreadsheetKey { principals: sheet_principals(user_id, vault_id, item.sheet), x: item.x, y: item.y };
+            state.spreadsheet_map.borrow().contains_key(&key)
+        }
+        ItemKind::LoginCell => state.logins_map.borrow().contains_key(&SpreadsheetKey::new(user_id, vault_id, item.x, item.y)),
+        ItemKind::LoginColumn => state.logins_columns.borrow().contains_key(&LoginSiteKey::new(user_id, vault_id, item.x)),
+        ItemKind::Note => {
+            let key = SecureNoteKey { index: item.x, principals: [user_id.as_slice(), vault_id.as_slice()].concat() };
+            state.notes_map.borrow().contains_key(&key)
+        }
+        ItemKind::Totp => state.totp_map.borrow().contains_key(&TotpKey::new(user_id, vault_id, item.x)),
+        ItemKind::PaymentCard => state.records.borrow().contains_key(&RecordKey::new(user_id, vault_id, RecordKind::PaymentCard, item.x)),
+        ItemKind::Identity => state.records.borrow().contains_key(&RecordKey::new(user_id, vault_id, RecordKind::Identity, item.x)),
+        ItemKind::CustomRecord => state.custom_records.borrow().contains_key(&TemplateKey::new(user_id, vault_id, item.x)),
+        ItemKind::GridRow => true,
+    }
+}
+
+// Items go to the trash without their labels and search tokens, which don't come back on
+// restore.
+pub fn _move_to_trash(user_id: Principal, vault_id: Principal, items: Vec<TrashItem>, now: u64, state: &GeneralState) {
+    let principals = TrashKey::new(user_id, vault_id, 0).principals;
+    let retention = *state.trash_retention.borrow().get();
+    _expire_trash(&principals, now, retention, &state.trash);
+    if items.is_empty() {
+        return;
+    }
+    for item in items.iter().flat_map(_item_refs) {
+        if !_item_exists(user_id, vault_id, item, state) {
+            _drop_item_labels(user_id, vault_id, item, &state.item_labels);
+            _index_item(&principals, item, Vec::new(), state);
+        }
+    }
+
+    let mut trash = state.trash.borrow_mut();
+    let first_id = trash.range(_vault_range(&principals)).next_back().map_or(0, |entry| entry.key().id + 1);
+    for (id, item) in (first_id..).zip(items) {
+        trash.insert(TrashKey { principals: principals.clone(), id }, TrashEntry { deleted_at: now, item });
+    }
+}
+
+pub fn _list_trash(user_id: Principal, vault_id: Principal, now: u64, state: &GeneralState) -> Vec<TrashItemInfo> {
+    let principals = TrashKey::new(user_id, vault_id, 0).principals;
+    let retention = *state.trash_retention.borrow().get();
+    state.trash.borrow()
+        .range(_vault_range(&principals))
+        .filter_map(|entry| {
+            let (key, entry) = entry.into_pair();
+            if _is_expired(&entry, now, retention) {
+                return None;
+            }
+            let item = entry.item;
+            Some(TrashItemInfo {
+                id: key.id,
+                kind: item.kind,
+                x: item.x,
+                y: item.y,
+                deleted_at: entry.deleted_at,
+                label: item.label,
+                data: item.data,
+                rows: item.rows,
+            })
+        })
+        .collect()
+}
+
+// Puts an item back where it was. Returns false, leaving the vault untouched, when its slot is
+// occupied again.
+fn _restore_item(user_id: Principal, vault_id: Principal, item: &TrashItem, state: &GeneralState) -> bool {
+    match item.kind {
+        TrashKind::SpreadsheetCell => {
+            // The cell's sheet may have been deleted since.
+            let sheet = item.sheet();
+            let key = SpreadsheetKey { principals: sheet_principals(user_id, vault_id, sheet), x: item.x, y: item.y };
+            let mut spreadsheet = state.spreadsheet_map.borrow_mut();
+            if !_sheet_exists(user_id, vault_id, sheet, &state.sheets) || spreadsheet.contains_key(&key) {
+                return false;
+            }
+            spreadsheet.insert(key, SpreadsheetValue::new(item.data.clone()));
+        }
+        TrashKind::LoginCell => {
+            // An identity without its column would be invisible to the client.
+            let column_key = LoginSiteKey::new(user_id, vault_id, item.x);
+            let key = SpreadsheetKey::new(user_id, vault_id, item.x, item.y);
+            let mut logins = state.logins_map.borrow_mut();
+            if !state.logins_columns.borrow().contains_key(&column_key) || logins.contains_key(&key) {
+                return false;
+            }
+            logins.insert(key, SpreadsheetValue::new(item.data.clone()));
+        }
+        TrashKind::LoginColumn => {
+            let column_key = LoginSiteKey::new(user_id, vault_id, item.x);
+            let mut columns = state.logins_columns.borrow_mut();
+            let mut logins = state.logins_map.borrow_mut();
+            if columns.contains_key(&column_key) {
+                return false;
+            }
+            let rows: Vec<(SpreadsheetKey, &Vec<u8>)> = item.rows.iter()
+                .map(|(y, data)| (SpreadsheetKey::new(user_id, vault_id, item.x, *y), data))
+                .collect();
+            if rows.iter().any(|(key, _)| logins.contains_key(key)) {
+                return false;
+            }
+            columns.insert(column_key, item.label.clone());
+            for (key, data) in rows {
+                logins.insert(key, SpreadsheetValue::new(data.clone()));
+            }
+        }
+        TrashKind::Note => {
+            let key = SecureNoteKey {
+                index: item.x,
+                principals: [user_id.as_slice(), vault_id.as_slice()].concat(),
+            };
+            let mut notes = state.notes_map.borrow_mut();
+            if notes.contains_key(&key) {
+                return false;
+            }
+            notes.insert(key, SecureNote::new(item.label.clone(), item.data.clone()));
+        }
+        TrashKind::Totp => {
+            let key = TotpKey::new(user_id, vault_id, item.x);
+            let mut totp = state.totp_map.borrow_mut();
+            if totp.contains_key(&key) {
+                return false;
+            }
+            totp.insert(key, TotpRecord::from_bytes(item.data.as_slice().into()));
+        }
+        TrashKind::PaymentCard | TrashKind::Identity => {
+            let kind = if item.kind == TrashKind::PaymentCard { RecordKind::PaymentCard } else { RecordKind::Identity };
+            let key = RecordKey::new(user_id, vault_id, kind, item.x);
+            let mut records = state.records.borrow_mut();
+            if records.contains_key(&key) {
+                return false;
+            }
+            records.insert(key, TypedRecord::from_bytes(item.data.as_slice().into()));
+        }
+        TrashKind::CustomRecord => {
+            // The template may have changed or gone since; the record must still fit it.
+            let key = TemplateKey::new(user_id, vault_id, item.x);
+            let record = CustomRecord::from_bytes(item.data.as_slice().into());
+            if state.custom_records.borrow().contains_key(&key) || _validate_custom_record(user_id, vault_id, &record, state).is_err() {
+                return false;
+            }
+            state.custom_records.borrow_mut().insert(key, record);
+        }
+    }
+    true
+}
+
+pub fn _restore_items(user_id: Principal, vault_id: Principal, ids: Vec<u64>, now: u64, state: &GeneralState) -> RestoreResult {
+    let retention = *state.trash_retention.borrow().get();
+    let mut result = RestoreResult::default();
+    let mut change = SizeChange::default();
+    for id in ids {
+        let key = TrashKey::new(user_id, vault_id, id);
+        let entry = match state.trash.borrow().get(&key) {
+            Some(entry) if !_is_expired(&entry, now, retention) => entry,
+            _ => continue,
+        };
+        if _restore_item(user_id, vault_id, &entry.item, state) {
+            change.restored(&entry.item);
+            state.trash.borrow_mut().remove(&key);
+            result.restored.push(id);
+        } else {
+            result.conflicts.push(id);
+        }
+    }
+    if !result.restored.is_empty() {
+        _record_vault_change(user_id, vault_id, change, now, &state.vault_registry);
+    }
+    result
+}
+
+// Permanently removes the given items, or the whole trash of the vault when no ids are given.
+// Returns how many items were removed.
+pub fn _empty_trash(user_id: Principal, vault_id: Principal, ids: Option<Vec<u64>>, trash: &TrashMap) -> u32 {
+    let principals = TrashKey::new(user_id, vault_id, 0).principals;
+    let keys: Vec<TrashKey> = match ids {
+        Some(ids) => ids.into_iter().map(|id| TrashKey { principals: principals.clone(), id }).collect(),
+        None => trash.borrow().keys_range(_vault_range(&principals)).collect(),
+    };
+    let mut trash = trash.borrow_mut();
+    keys.iter().filter(|key| trash.remove(key).is_some()).count() as u32
+}
+
+pub fn _set_trash_retention(retention_ns: u64, state: &GeneralState) -> Result<(), String> {
+    if retention_ns > MAX_TRASH_RETENTION_NS {
+        return Err("trash retention too long".into());
+    }
+    state.trash_retention.borrow_mut().set(retention_ns);
+    Ok(())
+}
@@ -1,3 +1,4 @@
 pub mod vault_type;
 pub mod stable;
-pub mod api;
\ No newline at end of file
+pub mod api;
+pub mod service;
@@ -0,0 +1,57 @@
+use std::{cell::Cell, time::Duration};
+
+use candid::Principal;
+use ic_cdk::call::Call;
+use ic_cdk_timers::set_timer;
+
+use crate::{
+    api::capacity_api::{_get_capacity, _needs_capacity_notification, _record_capacity_notification, MAX_NOTIFY_ATTEMPTS},
+    stable::util::_retry_backoff_ns,
+};
+
+use super::with_state;
+
+thread_local! {
+    // Set while a notification and its retries are under way, so admitted users don't pile up calls.
+    static NOTIFY_IN_PROGRESS: Cell<bool> = const { Cell::new(false) };
+}
+
+// Seconds the factory has to acknowledge a capacity change.
+const NOTIFY_TIMEOUT_SECONDS: u32 = 60;
+
+// Tells the factory about a capacity change it hasn't acknowledged yet.
+pub fn notify_capacity_change() {
+    let needed = with_state(_needs_capacity_notification);
+    if !needed || NOTIFY_IN_PROGRESS.with(|in_progress| in_progress.get()) {
+        return;
+    }
+    NOTIFY_IN_PROGRESS.with(|in_progress| in_progress.set(true));
+    ic_cdk::futures::spawn(notify_factory(1));
+}
+
+// The factory answers with the canister to send new users to, if it has one ready.
+async fn notify_factory(attempt: u8) {
+    let (factory, capacity) = with_state(|state| (state.config.borrow().get().factory, _get_capacity(state)));
+    let result = Call::bounded_wait(factory, "notify_canister_at_capacity")
+        .with_arg(capacity.clone())
+        .change_timeout(NOTIFY_TIMEOUT_SECONDS)
+        .await;
+
+    match result {
+        Ok(response) => {
+            let redirect = response.candid::<Option<Principal>>().unwrap_or(None);
+            with_state(|state| _record_capacity_notification(capacity.state, redirect, ic_cdk::api::time(), state));
+        }
+        Err(e) if attempt < MAX_NOTIFY_ATTEMPTS => {
+            ic_cdk::println!("Capacity notification attempt {} failed: {}", attempt, e);
+            let delay = Duration::from_nanos(_retry_backoff_ns(attempt));
+            set_timer(delay, move || ic_cdk::futures::spawn(notify_factory(attempt + 1)));
+            return;
+        }
+        // Left unacknowledged, so the next admitted user tries again.
+        Err(e) => ic_cdk::println!("Capacity notification failed: {}", e),
+    }
+    NOTIFY_IN_PROGRESS.with(|in_progress| in_progress.set(false));
+    // Users may have been admitted while the call was in flight.
+    notify_capacity_change();
+}
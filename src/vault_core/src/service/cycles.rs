@@ -0,0 +1,80 @@
+use std::{cell::{Cell, RefCell}, time::Duration};
+
+use ic_cdk::{api::canister_liquid_cycle_balance, call::Call};
+use ic_cdk_timers::{clear_timer, set_timer, set_timer_interval, TimerId};
+
+use crate::{
+    api::{
+        cycles_api::{_needs_top_up, _record_top_up, MAX_TOP_UP_ATTEMPTS},
+        snapshot_api::_assert_not_frozen,
+    },
+    stable::util::_retry_backoff_ns,
+    vault_type::cycles::{TopUpRecord, TopUpStatus},
+};
+
+use super::with_state;
+
+thread_local! {
+    static CYCLES_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+    // Set while a top-up request and its retries are under way, so checks don't pile up requests.
+    static TOP_UP_IN_PROGRESS: Cell<bool> = const { Cell::new(false) };
+}
+
+// Seconds the factory has to answer a top-up request.
+const TOP_UP_TIMEOUT_SECONDS: u32 = 60;
+
+pub fn top_up_in_progress() -> bool {
+    TOP_UP_IN_PROGRESS.with(|in_progress| in_progress.get())
+}
+
+// (Re)starts the periodic balance check at the configured interval.
+pub fn arm_cycles_monitor() {
+    let interval = with_state(|state| state.cycles_settings.borrow().get().check_interval_ns);
+    CYCLES_TIMER.with(|timer| {
+        if let Some(id) = timer.borrow_mut().take() {
+            clear_timer(id);
+        }
+        *timer.borrow_mut() = Some(set_timer_interval(Duration::from_nanos(interval), check_cycles));
+    });
+}
+
+// Requests a top-up when the balance is under the threshold and no request is under way. Waits
+// for the next check while a snapshot is taken or restored.
+pub fn check_cycles() {
+    let balance = canister_liquid_cycle_balance();
+    let needed = with_state(|state| _assert_not_frozen(state).is_ok() && _needs_top_up(balance, state.cycles_settings.borrow().get()));
+    if !needed || top_up_in_progress() {
+        return;
+    }
+    TOP_UP_IN_PROGRESS.with(|in_progress| in_progress.set(true));
+    ic_cdk::futures::spawn(request_top_up(balance, 1));
+}
+
+async fn request_top_up(balance: u128, attempt: u8) {
+    let (factory, amount) = with_state(|state| (state.config.borrow().get().factory, state.cycles_settings.borrow().get().top_up_amount));
+    let result = Call::bounded_wait(factory, "top_up")
+        .with_arg(amount)
+        .change_timeout(TOP_UP_TIMEOUT_SECONDS)
+        .await;
+
+    let error = match result {
+        Ok(_) => String::new(),
+        Err(e) if attempt < MAX_TOP_UP_ATTEMPTS => {
+            ic_cdk::println!("Top-up attempt {} failed: {}", attempt, e);
+            let delay = Duration::from_nanos(_retry_backoff_ns(attempt));
+            set_timer(delay, move || ic_cdk::futures::spawn(request_top_up(balance, attempt + 1)));
+            return;
+        }
+        Err(e) => e.to_string(),
+    };
+    let record = TopUpRecord {
+        status: if error.is_empty() { TopUpStatus::Succeeded } else { TopUpStatus::Failed },
+        finished_at: ic_cdk::api::time(),
+        balance,
+        amount,
+        attempts: attempt,
+        error,
+    };
+    with_state(|state| _record_top_up(record, state));
+    TOP_UP_IN_PROGRESS.with(|in_progress| in_progress.set(false));
+}
@@ -0,0 +1,909 @@
+use candid::Principal;
+use ic_cdk::api::msg_caller;
+
+use crate::{
+    api::{
+        attachments_api::{_begin_attachment, _delete_attachment, _finish_attachment, _get_attachment_chunk, _link_attachment, _list_attachments, _upload_attachment_chunk, AttachmentInfo, AttachmentUpload},
+        config_api::{_assert_vault_limit, _get_config, _update_config, ConfigUpdate, RuntimeConfig},
+        audit_api::{_get_audit_log, _get_audit_retention, _get_user_audit_log, _set_audit_retention, AuditPage},
+        cycles_api::{_get_cycles_status, _set_cycles_settings, CyclesStatus},
+        registry_api::{_get_vault_info, _list_vaults, _record_vault_change, _register_vaults, _registered_vault_names, _update_vault_metadata, SizeChange, VaultInfo, VaultMetadataUpdate},
+        deletion_api::{_assert_names_writable, _cancel_deletion, _get_pending_deletions, _schedule_user_purge, _schedule_vault_deletion, _set_deletion_grace_period, PendingDeletionInfo},
+        dev_api::{_get_columns_info, _get_logins, _get_notes, _get_custom_records, _get_expiring_records, _get_identities, _get_payment_cards, _get_record_templates, _get_sheet, _get_sheet_columns, _get_spreadsheet, _get_totp_seeds, _get_vault, _get_vault_name, CustomRecords, ExpiringRecord, FlexGridColumns, Identities, Logins, Notes, PaymentCards, RecordTemplates, Spreadsheet, TotpSeeds, VaultData, VaultNames},
+        grid_api::{_edit_grid, Axis, Grid, GridEdit},
+        history_api::{_get_revision, _list_revisions, _rollback_item, _set_history_depth, RevisionData, RevisionInfo},
+        search_api::{_search_items, _search_tokens_sync, SearchHit},
+        organization_api::{_create_folder, _delete_folder, _delete_tag, _get_organization, _list_folder_items, _list_tagged_items, _set_item_labels, _set_tag, _update_folder, Organization},
+        machine_api::{_get_machine_grants, _get_machine_vault, _get_vaults_for_machine, _grant_machine_access, _revoke_machine_access, MachineGrantArgs, MachineGrantInfo, MachineVaultGrant},
+        serial_api::{_custom_records_deletes, _custom_records_sync, _global_sync, _login_data_deletes, _login_data_sync, _login_full_sync, _login_metadata_delete, _login_metadata_sync, _records_deletes, _records_sync, _secret_notes_sync, _sheet_columns_sync, _sheet_deletes, _sheet_sync, _totp_deletes, _totp_sync, _vault_names_sync, _vault_spreadsheet_columns_sync, _vault_spreadsheet_delete, _vault_spreadsheet_sync},
+        sheets_api::{_assert_sheet, _create_sheet, _delete_sheet, _list_sheets, _rename_sheet, _reorder_sheets, SheetInfo},
+        templates_api::{_delete_record_template, _set_record_template},
+        trash_api::{_empty_trash, _list_trash, _restore_items, _set_trash_retention, RestoreResult, TrashItemInfo},
+    },
+    vault_type::{attachments::ItemRef, audit_log::{AuditOp, AuditRetention}, records::{Expiry, RecordKind}, templates::RecordTemplate, cycles::CyclesSettings, history::HistoryItem},
+};
+
+use super::{account_owner, arm_deletion_timer, assert_controller, assert_not_frozen, assert_vault_writable, audit, check_vault_writable, record_sync, vault_user, with_state, cycles::{arm_cycles_monitor, top_up_in_progress}, metrics::{track, track_result}, policy::VaultPolicy};
+
+/*
+    Vault update endpoints
+*/
+
+pub fn vault_names_sync<P: VaultPolicy>(update: Vec<u8>) {
+    track("vault_names_sync", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        if let Err(e) = _assert_names_writable(user_id, &update, &state.pending_deletions) {
+            ic_cdk::trap(e);
+        }
+        let max_vaults = state.limits.borrow().get().tier(P::TENANCY.tier()).max_vaults_per_user;
+        if let Err(e) = _assert_vault_limit(user_id, &update, max_vaults, &state.vault_names_map) {
+            ic_cdk::trap(e);
+        }
+        _register_vaults(user_id, &update, ic_cdk::api::time(), &state.vault_registry);
+        let count = _vault_names_sync(user_id, &update, &state.vault_names_map);
+        audit(state, user_id, None, AuditOp::VaultNamesSync, count);
+    }))
+}
+
+pub fn vault_spreadsheet_columns_sync<P: VaultPolicy>(vault_id: Principal, update: Vec<u8>) {
+    track("vault_spreadsheet_columns_sync", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        let count = _vault_spreadsheet_columns_sync(user_id, vault_id, update, &state.spreadsheet_columns);
+        _record_vault_change(user_id, vault_id, SizeChange::default(), ic_cdk::api::time(), &state.vault_registry);
+        audit(state, user_id, Some(vault_id), AuditOp::SpreadsheetColumnsSync, count);
+    }))
+}
+
+pub fn vault_spreadsheet_sync<P: VaultPolicy>(vault_id: Principal, update: Vec<u8>) {
+    track("vault_spreadsheet_sync", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        let outcome = _vault_spreadsheet_sync(user_id, vault_id, update, &state.spreadsheet_map);
+        record_sync(state, user_id, vault_id, AuditOp::SpreadsheetSync, outcome);
+    }))
+}
+
+pub fn vault_spreadsheet_deletes<P: VaultPolicy>(vault_id: Principal, update: Vec<u8>) {
+    track("vault_spreadsheet_deletes", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        let outcome = _vault_spreadsheet_delete(user_id, vault_id, update, &state.spreadsheet_map);
+        record_sync(state, user_id, vault_id, AuditOp::SpreadsheetDelete, outcome);
+    }))
+}
+
+// The spreadsheet syncs, for any sheet of the flexible grid. Sheet 0 is the one the endpoints
+// above write to.
+pub fn vault_sheet_columns_sync<P: VaultPolicy>(vault_id: Principal, sheet: u8, update: Vec<u8>) {
+    track("vault_sheet_columns_sync", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        _assert_sheet(user_id, vault_id, sheet, &state.sheets).unwrap_or_else(|e| ic_cdk::trap(e));
+        let count = _sheet_columns_sync(user_id, vault_id, sheet, update, &state.spreadsheet_columns);
+        _record_vault_change(user_id, vault_id, SizeChange::default(), ic_cdk::api::time(), &state.vault_registry);
+        audit(state, user_id, Some(vault_id), AuditOp::SpreadsheetColumnsSync, count);
+    }))
+}
+
+pub fn vault_sheet_sync<P: VaultPolicy>(vault_id: Principal, sheet: u8, update: Vec<u8>) {
+    track("vault_sheet_sync", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        _assert_sheet(user_id, vault_id, sheet, &state.sheets).unwrap_or_else(|e| ic_cdk::trap(e));
+        let outcome = _sheet_sync(user_id, vault_id, sheet, update, &state.spreadsheet_map);
+        record_sync(state, user_id, vault_id, AuditOp::SpreadsheetSync, outcome);
+    }))
+}
+
+pub fn vault_sheet_deletes<P: VaultPolicy>(vault_id: Principal, sheet: u8, update: Vec<u8>) {
+    track("vault_sheet_deletes", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        _assert_sheet(user_id, vault_id, sheet, &state.sheets).unwrap_or_else(|e| ic_cdk::trap(e));
+        let outcome = _sheet_deletes(user_id, vault_id, sheet, update, &state.spreadsheet_map);
+        record_sync(state, user_id, vault_id, AuditOp::SpreadsheetDelete, outcome);
+    }))
+}
+
+pub fn vault_login_full_sync<P: VaultPolicy>(vault_id: Principal, update: Vec<u8>) {
+    track("vault_login_full_sync", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        let outcome = _login_full_sync(user_id, vault_id, update, &state.logins_columns, &state.logins_map);
+        record_sync(state, user_id, vault_id, AuditOp::LoginFullSync, outcome);
+    }))
+}
+
+pub fn vault_login_metadata_sync<P: VaultPolicy>(vault_id: Principal, update: Vec<u8>) {
+    track("vault_login_metadata_sync", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        let outcome = _login_metadata_sync(user_id, vault_id, update, &state.logins_columns, &state.logins_map);
+        record_sync(state, user_id, vault_id, AuditOp::LoginMetadataSync, outcome);
+    }))
+}
+
+pub fn vault_login_metadata_delete<P: VaultPolicy>(vault_id: Principal, update: Vec<u8>) {
+    track("vault_login_metadata_delete", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        let outcome = _login_metadata_delete(user_id, vault_id, update, &state.logins_columns, &state.logins_map);
+        record_sync(state, user_id, vault_id, AuditOp::LoginMetadataDelete, outcome);
+    }))
+}
+
+pub fn vault_login_data_sync<P: VaultPolicy>(vault_id: Principal, update: Vec<u8>) {
+    track("vault_login_data_sync", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        let outcome = _login_data_sync(user_id, vault_id, update, &state.logins_map);
+        record_sync(state, user_id, vault_id, AuditOp::LoginDataSync, outcome);
+    }))
+}
+
+pub fn vault_login_data_deletes<P: VaultPolicy>(vault_id: Principal, update: Vec<u8>) {
+    track("vault_login_data_deletes", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        let outcome = _login_data_deletes(user_id, vault_id, update, &state.logins_map);
+        record_sync(state, user_id, vault_id, AuditOp::LoginDataDelete, outcome);
+    }))
+}
+
+pub fn vault_secrets_sync<P: VaultPolicy>(vault_id: Principal, update: Vec<u8>) {
+    track("vault_secrets_sync", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        let outcome = _secret_notes_sync(user_id, vault_id, update, &state.notes_map);
+        record_sync(state, user_id, vault_id, AuditOp::SecureNotesSync, outcome);
+    }))
+}
+
+pub fn vault_totp_sync<P: VaultPolicy>(vault_id: Principal, update: Vec<u8>) {
+    track("vault_totp_sync", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        let outcome = _totp_sync(user_id, vault_id, update, &state.totp_map);
+        record_sync(state, user_id, vault_id, AuditOp::TotpSync, outcome);
+    }))
+}
+
+pub fn vault_totp_deletes<P: VaultPolicy>(vault_id: Principal, update: Vec<u8>) {
+    track("vault_totp_deletes", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        let outcome = _totp_deletes(user_id, vault_id, update, &state.totp_map);
+        record_sync(state, user_id, vault_id, AuditOp::TotpDelete, outcome);
+    }))
+}
+
+pub fn vault_cards_sync<P: VaultPolicy>(vault_id: Principal, update: Vec<u8>) {
+    track("vault_cards_sync", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        let outcome = _records_sync(user_id, vault_id, RecordKind::PaymentCard, update, &state.records);
+        record_sync(state, user_id, vault_id, AuditOp::PaymentCardsSync, outcome);
+    }))
+}
+
+pub fn vault_cards_deletes<P: VaultPolicy>(vault_id: Principal, update: Vec<u8>) {
+    track("vault_cards_deletes", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        let outcome = _records_deletes(user_id, vault_id, RecordKind::PaymentCard, update, &state.records);
+        record_sync(state, user_id, vault_id, AuditOp::PaymentCardsDelete, outcome);
+    }))
+}
+
+pub fn vault_identities_sync<P: VaultPolicy>(vault_id: Principal, update: Vec<u8>) {
+    track("vault_identities_sync", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        let outcome = _records_sync(user_id, vault_id, RecordKind::Identity, update, &state.records);
+        record_sync(state, user_id, vault_id, AuditOp::IdentitiesSync, outcome);
+    }))
+}
+
+pub fn vault_identities_deletes<P: VaultPolicy>(vault_id: Principal, update: Vec<u8>) {
+    track("vault_identities_deletes", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        let outcome = _records_deletes(user_id, vault_id, RecordKind::Identity, update, &state.records);
+        record_sync(state, user_id, vault_id, AuditOp::IdentitiesDelete, outcome);
+    }))
+}
+
+// Records that don't fit their template are refused, leaving the vault as it was.
+pub fn vault_custom_records_sync<P: VaultPolicy>(vault_id: Principal, update: Vec<u8>) {
+    track("vault_custom_records_sync", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        let outcome = _custom_records_sync(user_id, vault_id, update, state).unwrap_or_else(|e| ic_cdk::trap(e));
+        record_sync(state, user_id, vault_id, AuditOp::CustomRecordsSync, outcome);
+    }))
+}
+
+pub fn vault_custom_records_deletes<P: VaultPolicy>(vault_id: Principal, update: Vec<u8>) {
+    track("vault_custom_records_deletes", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        let outcome = _custom_records_deletes(user_id, vault_id, update, &state.custom_records);
+        record_sync(state, user_id, vault_id, AuditOp::CustomRecordsDelete, outcome);
+    }))
+}
+
+// Replaces the blind-index tokens of the items in the update.
+pub fn vault_search_tokens_sync<P: VaultPolicy>(vault_id: Principal, update: Vec<u8>) {
+    track("vault_search_tokens_sync", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        let count = _search_tokens_sync(user_id, vault_id, update, state).unwrap_or_else(|e| ic_cdk::trap(e));
+        audit(state, user_id, Some(vault_id), AuditOp::SearchTokensSync, count);
+    }))
+}
+
+// The items of the caller's vaults carrying every token given.
+pub fn search_items<P: VaultPolicy>(vault_ids: Vec<Principal>, tokens: Vec<Vec<u8>>) -> Result<Vec<SearchHit>, String> {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _search_items(user_id, vault_ids, tokens, &state.search_index)
+    })
+}
+
+// Inserts, deletes, moves or swaps rows or columns of a grid. Returns how many cells and
+// column headers moved or were deleted.
+pub fn vault_grid_edit<P: VaultPolicy>(vault_id: Principal, grid: Grid, axis: Axis, edit: GridEdit) -> Result<u32, String> {
+    track_result("vault_grid_edit", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        check_vault_writable(state, user_id, vault_id)?;
+        let outcome = _edit_grid(user_id, vault_id, grid, axis, edit, state)?;
+        let items = outcome.items;
+        record_sync(state, user_id, vault_id, AuditOp::GridEdit, outcome);
+        Ok(items)
+    }))
+}
+
+pub fn global_sync<P: VaultPolicy>(vault_id: Principal, update: Vec<u8>) {
+    track("global_sync", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        let outcome = _global_sync(user_id, vault_id, update, state).unwrap_or_else(|e| ic_cdk::trap(e));
+        record_sync(state, user_id, vault_id, AuditOp::GlobalSync, outcome);
+    }))
+}
+
+/*
+    Destructive operations. These are scheduled and only carried out once the grace period has
+    passed; until then the vault is read-only and the deletion can be cancelled.
+*/
+
+pub fn delete_vault<P: VaultPolicy>(vault_id: Principal) -> Result<PendingDeletionInfo, String> {
+    let pending = track_result("delete_vault", || with_state(|state| {
+        let user_id = account_owner::<P>(state);
+        let pending = _schedule_vault_deletion(user_id, vault_id, ic_cdk::api::time(), state)?;
+        audit(state, user_id, Some(vault_id), AuditOp::DeletionScheduled, 0);
+        Ok::<_, String>(pending)
+    }))?;
+    arm_deletion_timer();
+    Ok(pending)
+}
+
+pub fn purge_user<P: VaultPolicy>() -> Result<PendingDeletionInfo, String> {
+    let pending = track_result("purge_user", || with_state(|state| {
+        let user_id = account_owner::<P>(state);
+        let pending = _schedule_user_purge(user_id, ic_cdk::api::time(), state)?;
+        audit(state, user_id, None, AuditOp::DeletionScheduled, 0);
+        Ok::<_, String>(pending)
+    }))?;
+    arm_deletion_timer();
+    Ok(pending)
+}
+
+// Cancels the deletion of the given vault, or the user purge when no vault is given.
+pub fn cancel_deletion<P: VaultPolicy>(vault_id: Option<Principal>) -> Result<(), String> {
+    track_result("cancel_deletion", || with_state(|state| {
+        let user_id = account_owner::<P>(state);
+        _cancel_deletion(user_id, vault_id, state)?;
+        audit(state, user_id, vault_id, AuditOp::DeletionCancelled, 0);
+        Ok(())
+    }))
+}
+
+pub fn get_pending_deletions<P: VaultPolicy>() -> Vec<PendingDeletionInfo> {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _get_pending_deletions(user_id, &state.pending_deletions, &state.deletion_cursors)
+    })
+}
+
+pub fn get_deletion_grace_period() -> u64 {
+    with_state(|state| *state.deletion_grace_period.borrow().get())
+}
+
+pub fn set_deletion_grace_period(grace_period_ns: u64) -> Result<(), String> {
+    assert_controller();
+    with_state(|state| {
+        assert_not_frozen(state);
+        _set_deletion_grace_period(grace_period_ns, state)?;
+        audit(state, msg_caller(), None, AuditOp::GracePeriodUpdate, 0);
+        Ok(())
+    })
+}
+
+/*
+    Trash. Items removed by a sync stay restorable until the trash retention period runs out.
+*/
+
+pub fn list_trash<P: VaultPolicy>(vault_id: Principal) -> Vec<TrashItemInfo> {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _list_trash(user_id, vault_id, ic_cdk::api::time(), state)
+    })
+}
+
+pub fn restore_items<P: VaultPolicy>(vault_id: Principal, ids: Vec<u64>) -> RestoreResult {
+    track("restore_items", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        let result = _restore_items(user_id, vault_id, ids, ic_cdk::api::time(), state);
+        audit(state, user_id, Some(vault_id), AuditOp::TrashRestore, result.restored.len() as u32);
+        result
+    }))
+}
+
+// Permanently removes the given items, or everything in the vault's trash when no ids are given.
+pub fn empty_trash<P: VaultPolicy>(vault_id: Principal, ids: Option<Vec<u64>>) -> u32 {
+    track("empty_trash", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        let removed = _empty_trash(user_id, vault_id, ids, &state.trash);
+        audit(state, user_id, Some(vault_id), AuditOp::TrashEmpty, removed);
+        removed
+    }))
+}
+
+pub fn get_trash_retention() -> u64 {
+    with_state(|state| *state.trash_retention.borrow().get())
+}
+
+pub fn set_trash_retention(retention_ns: u64) -> Result<(), String> {
+    assert_controller();
+    with_state(|state| {
+        assert_not_frozen(state);
+        _set_trash_retention(retention_ns, state)?;
+        audit(state, msg_caller(), None, AuditOp::RetentionUpdate, 0);
+        Ok(())
+    })
+}
+
+/*
+    Revision history of login cells and notes.
+*/
+
+pub fn list_revisions<P: VaultPolicy>(vault_id: Principal, item: HistoryItem) -> Vec<RevisionInfo> {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _list_revisions(user_id, vault_id, item, &state.history)
+    })
+}
+
+pub fn get_revision<P: VaultPolicy>(vault_id: Principal, item: HistoryItem, revision: u64) -> Option<RevisionData> {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _get_revision(user_id, vault_id, item, revision, &state.history)
+    })
+}
+
+pub fn rollback_item<P: VaultPolicy>(vault_id: Principal, item: HistoryItem, revision: u64) -> Result<(), String> {
+    track_result("rollback_item", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        assert_vault_writable(state, user_id, vault_id);
+        _rollback_item(user_id, vault_id, item, revision, ic_cdk::api::time(), state)?;
+        audit(state, user_id, Some(vault_id), AuditOp::ItemRollback, 1);
+        Ok(())
+    }))
+}
+
+pub fn get_history_depth() -> u32 {
+    with_state(|state| *state.history_depth.borrow().get())
+}
+
+pub fn set_history_depth(depth: u32) -> Result<(), String> {
+    assert_controller();
+    with_state(|state| {
+        assert_not_frozen(state);
+        _set_history_depth(depth, state)?;
+        audit(state, msg_caller(), None, AuditOp::HistoryDepthUpdate, depth);
+        Ok(())
+    })
+}
+
+/*
+    Vault query endpoints
+*/
+
+pub fn get_vault_names<P: VaultPolicy>() -> VaultNames {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _registered_vault_names(user_id, &state.vault_names_map, &state.vault_registry)
+    })
+}
+
+pub fn get_vault_name<P: VaultPolicy>(vault_id: Principal) -> Vec<u8> {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _get_vault_name(user_id, vault_id, &state.vault_names_map)
+    })
+}
+
+pub fn get_spreadsheet_columns<P: VaultPolicy>(vault_id: Principal) -> FlexGridColumns {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _get_columns_info(user_id, vault_id, &state.spreadsheet_columns)
+    })
+}
+
+pub fn get_spreadsheet<P: VaultPolicy>(vault_id: Principal) -> Spreadsheet {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _get_spreadsheet(user_id, vault_id, &state.spreadsheet_map)
+    })
+}
+
+pub fn get_logins<P: VaultPolicy>(vault_id: Principal) -> Logins {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _get_logins(user_id, vault_id, &state.logins_map, &state.logins_columns)
+    })
+}
+
+pub fn get_secure_notes<P: VaultPolicy>(vault_id: Principal) -> Notes {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _get_notes(user_id, vault_id, &state.notes_map)
+    })
+}
+
+pub fn get_totp_seeds<P: VaultPolicy>(vault_id: Principal) -> TotpSeeds {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _get_totp_seeds(user_id, vault_id, &state.totp_map)
+    })
+}
+
+pub fn get_payment_cards<P: VaultPolicy>(vault_id: Principal) -> PaymentCards {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _get_payment_cards(user_id, vault_id, &state.records)
+    })
+}
+
+pub fn get_identities<P: VaultPolicy>(vault_id: Principal) -> Identities {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _get_identities(user_id, vault_id, &state.records)
+    })
+}
+
+pub fn get_custom_records<P: VaultPolicy>(vault_id: Principal) -> CustomRecords {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _get_custom_records(user_id, vault_id, &state.custom_records)
+    })
+}
+
+pub fn get_expiring_records<P: VaultPolicy>(from: Expiry, to: Expiry) -> Vec<ExpiringRecord> {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _get_expiring_records(user_id, from, to, state)
+    })
+}
+
+pub fn get_user_vault<P: VaultPolicy>(vault_id: Principal) -> VaultData {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        let vault_name = _get_vault_name(user_id, vault_id, &state.vault_names_map);
+        _get_vault(&vault_name, user_id, vault_id, state)
+    })
+}
+
+/*
+    Vault registry. Metadata is encrypted by the client; the canister only stores it.
+*/
+
+pub fn list_vaults<P: VaultPolicy>() -> Vec<VaultInfo> {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _list_vaults(user_id, &state.vault_registry)
+    })
+}
+
+pub fn get_vault_info<P: VaultPolicy>(vault_id: Principal) -> Option<VaultInfo> {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _get_vault_info(user_id, vault_id, &state.vault_registry)
+    })
+}
+
+pub fn update_vault_metadata<P: VaultPolicy>(vault_id: Principal, update: VaultMetadataUpdate) -> Result<VaultInfo, String> {
+    track_result("update_vault_metadata", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        check_vault_writable(state, user_id, vault_id)?;
+        let info = _update_vault_metadata(user_id, vault_id, update, ic_cdk::api::time(), &state.vault_registry)?;
+        audit(state, user_id, Some(vault_id), AuditOp::VaultMetadataUpdate, 1);
+        Ok(info)
+    }))
+}
+
+/*
+    Record template endpoints. Templates describe the fields of custom records.
+*/
+
+pub fn set_record_template<P: VaultPolicy>(vault_id: Principal, index: u8, template: RecordTemplate) -> Result<(), String> {
+    track_result("set_record_template", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        check_vault_writable(state, user_id, vault_id)?;
+        _set_record_template(user_id, vault_id, index, template, state)?;
+        audit(state, user_id, Some(vault_id), AuditOp::TemplateUpdate, 1);
+        Ok(())
+    }))
+}
+
+pub fn delete_record_template<P: VaultPolicy>(vault_id: Principal, index: u8) -> Result<(), String> {
+    track_result("delete_record_template", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        check_vault_writable(state, user_id, vault_id)?;
+        _delete_record_template(user_id, vault_id, index, state)?;
+        audit(state, user_id, Some(vault_id), AuditOp::TemplateDelete, 1);
+        Ok(())
+    }))
+}
+
+pub fn get_record_templates<P: VaultPolicy>(vault_id: Principal) -> RecordTemplates {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _get_record_templates(user_id, vault_id, &state.record_templates)
+    })
+}
+
+/*
+    Sheet endpoints. Names are encrypted by the client.
+*/
+
+// Returns the id of the new sheet.
+pub fn create_sheet<P: VaultPolicy>(vault_id: Principal, name: Vec<u8>) -> Result<u8, String> {
+    track_result("create_sheet", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        check_vault_writable(state, user_id, vault_id)?;
+        let sheet = _create_sheet(user_id, vault_id, name, &state.sheets)?;
+        audit(state, user_id, Some(vault_id), AuditOp::SheetCreate, 1);
+        Ok(sheet)
+    }))
+}
+
+pub fn rename_sheet<P: VaultPolicy>(vault_id: Principal, sheet: u8, name: Vec<u8>) -> Result<(), String> {
+    track_result("rename_sheet", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        check_vault_writable(state, user_id, vault_id)?;
+        _rename_sheet(user_id, vault_id, sheet, name, &state.sheets)?;
+        audit(state, user_id, Some(vault_id), AuditOp::SheetRename, 1);
+        Ok(())
+    }))
+}
+
+// `order` lists every sheet of the vault, first to last.
+pub fn reorder_sheets<P: VaultPolicy>(vault_id: Principal, order: Vec<u8>) -> Result<(), String> {
+    track_result("reorder_sheets", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        check_vault_writable(state, user_id, vault_id)?;
+        let count = order.len() as u32;
+        _reorder_sheets(user_id, vault_id, order, &state.sheets)?;
+        audit(state, user_id, Some(vault_id), AuditOp::SheetReorder, count);
+        Ok(())
+    }))
+}
+
+// Deletes a sheet with its columns, moving its cells to the trash. Returns how many cells and
+// columns were removed.
+pub fn delete_sheet<P: VaultPolicy>(vault_id: Principal, sheet: u8) -> Result<u32, String> {
+    track_result("delete_sheet", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        check_vault_writable(state, user_id, vault_id)?;
+        let outcome = _delete_sheet(user_id, vault_id, sheet, state)?;
+        let items = outcome.items;
+        record_sync(state, user_id, vault_id, AuditOp::SheetDelete, outcome);
+        Ok(items)
+    }))
+}
+
+pub fn list_sheets<P: VaultPolicy>(vault_id: Principal) -> Vec<SheetInfo> {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _list_sheets(user_id, vault_id, &state.sheets)
+    })
+}
+
+pub fn get_sheet<P: VaultPolicy>(vault_id: Principal, sheet: u8) -> Spreadsheet {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _get_sheet(user_id, vault_id, sheet, &state.spreadsheet_map)
+    })
+}
+
+pub fn get_sheet_columns<P: VaultPolicy>(vault_id: Principal, sheet: u8) -> FlexGridColumns {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _get_sheet_columns(user_id, vault_id, sheet, &state.spreadsheet_columns)
+    })
+}
+
+/*
+    Folder and tag endpoints. Folder names and tag labels are encrypted by the client.
+*/
+
+// Returns the id of the new folder.
+pub fn create_folder<P: VaultPolicy>(vault_id: Principal, parent: Option<u64>, name: Vec<u8>) -> Result<u64, String> {
+    track_result("create_folder", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        check_vault_writable(state, user_id, vault_id)?;
+        let id = _create_folder(user_id, vault_id, parent, name, &state.folders)?;
+        audit(state, user_id, Some(vault_id), AuditOp::FolderCreate, 1);
+        Ok(id)
+    }))
+}
+
+// Renames a folder and moves it under `parent`, or to the top.
+pub fn update_folder<P: VaultPolicy>(vault_id: Principal, id: u64, parent: Option<u64>, name: Vec<u8>) -> Result<(), String> {
+    track_result("update_folder", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        check_vault_writable(state, user_id, vault_id)?;
+        _update_folder(user_id, vault_id, id, parent, name, &state.folders)?;
+        audit(state, user_id, Some(vault_id), AuditOp::FolderUpdate, 1);
+        Ok(())
+    }))
+}
+
+// Only empty folders can be deleted.
+pub fn delete_folder<P: VaultPolicy>(vault_id: Principal, id: u64) -> Result<(), String> {
+    track_result("delete_folder", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        check_vault_writable(state, user_id, vault_id)?;
+        _delete_folder(user_id, vault_id, id, state)?;
+        audit(state, user_id, Some(vault_id), AuditOp::FolderDelete, 1);
+        Ok(())
+    }))
+}
+
+pub fn set_tag<P: VaultPolicy>(vault_id: Principal, id: Vec<u8>, label: Vec<u8>) -> Result<(), String> {
+    track_result("set_tag", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        check_vault_writable(state, user_id, vault_id)?;
+        _set_tag(user_id, vault_id, id, label, &state.tags)?;
+        audit(state, user_id, Some(vault_id), AuditOp::TagUpdate, 1);
+        Ok(())
+    }))
+}
+
+// Takes the tag off every item. Returns how many items carried it.
+pub fn delete_tag<P: VaultPolicy>(vault_id: Principal, id: Vec<u8>) -> Result<u32, String> {
+    track_result("delete_tag", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        check_vault_writable(state, user_id, vault_id)?;
+        let count = _delete_tag(user_id, vault_id, id, state)?;
+        audit(state, user_id, Some(vault_id), AuditOp::TagDelete, count + 1);
+        Ok(count)
+    }))
+}
+
+// Replaces the folder and tags of an item; no folder and no tags clears them.
+pub fn set_item_labels<P: VaultPolicy>(vault_id: Principal, item: ItemRef, folder: Option<u64>, tags: Vec<Vec<u8>>) -> Result<(), String> {
+    track_result("set_item_labels", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        check_vault_writable(state, user_id, vault_id)?;
+        _set_item_labels(user_id, vault_id, item, folder, tags, state)?;
+        audit(state, user_id, Some(vault_id), AuditOp::ItemLabelsUpdate, 1);
+        Ok(())
+    }))
+}
+
+pub fn list_folder_items<P: VaultPolicy>(vault_id: Principal, folder: u64) -> Vec<ItemRef> {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _list_folder_items(user_id, vault_id, folder, &state.item_labels)
+    })
+}
+
+pub fn list_tagged_items<P: VaultPolicy>(vault_id: Principal, tag: Vec<u8>) -> Vec<ItemRef> {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _list_tagged_items(user_id, vault_id, tag, &state.item_labels)
+    })
+}
+
+pub fn get_organization<P: VaultPolicy>(vault_id: Principal) -> Organization {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _get_organization(user_id, vault_id, state)
+    })
+}
+
+/*
+    Attachment endpoints. Blobs are encrypted by the client and uploaded in chunks.
+*/
+
+pub fn begin_attachment<P: VaultPolicy>(vault_id: Principal, upload: AttachmentUpload) -> Result<u64, String> {
+    track_result("begin_attachment", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        check_vault_writable(state, user_id, vault_id)?;
+        let max_vault_bytes = state.limits.borrow().get().tier(P::TENANCY.tier()).max_vault_size_bytes;
+        _begin_attachment(user_id, vault_id, upload, max_vault_bytes, ic_cdk::api::time(), state)
+    }))
+}
+
+pub fn upload_attachment_chunk<P: VaultPolicy>(vault_id: Principal, id: u64, index: u32, data: Vec<u8>) -> Result<(), String> {
+    track_result("upload_attachment_chunk", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        check_vault_writable(state, user_id, vault_id)?;
+        _upload_attachment_chunk(user_id, vault_id, id, index, data, state)
+    }))
+}
+
+pub fn finish_attachment<P: VaultPolicy>(vault_id: Principal, id: u64) -> Result<AttachmentInfo, String> {
+    track_result("finish_attachment", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        check_vault_writable(state, user_id, vault_id)?;
+        let info = _finish_attachment(user_id, vault_id, id, ic_cdk::api::time(), state)?;
+        audit(state, user_id, Some(vault_id), AuditOp::AttachmentUpload, 1);
+        Ok(info)
+    }))
+}
+
+pub fn link_attachment<P: VaultPolicy>(vault_id: Principal, id: u64, link: Option<ItemRef>) -> Result<AttachmentInfo, String> {
+    track_result("link_attachment", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        check_vault_writable(state, user_id, vault_id)?;
+        let info = _link_attachment(user_id, vault_id, id, link, ic_cdk::api::time(), &state.attachments)?;
+        audit(state, user_id, Some(vault_id), AuditOp::AttachmentLink, 1);
+        Ok(info)
+    }))
+}
+
+pub fn delete_attachment<P: VaultPolicy>(vault_id: Principal, id: u64) -> Result<(), String> {
+    track_result("delete_attachment", || with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        check_vault_writable(state, user_id, vault_id)?;
+        _delete_attachment(user_id, vault_id, id, ic_cdk::api::time(), state)?;
+        audit(state, user_id, Some(vault_id), AuditOp::AttachmentDelete, 1);
+        Ok(())
+    }))
+}
+
+pub fn list_attachments<P: VaultPolicy>(vault_id: Principal) -> Vec<AttachmentInfo> {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _list_attachments(user_id, vault_id, &state.attachments)
+    })
+}
+
+pub fn get_attachment_chunk<P: VaultPolicy>(vault_id: Principal, id: u64, index: u32) -> Result<Vec<u8>, String> {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _get_attachment_chunk(user_id, vault_id, id, index, &state.attachments, &state.attachment_chunks)
+    })
+}
+
+/*
+    Machine identity endpoints
+*/
+
+pub fn grant_machine_access<P: VaultPolicy>(vault_id: Principal, args: MachineGrantArgs) -> Result<(), String> {
+    track_result("grant_machine_access", || with_state(|state| {
+        let user_id = account_owner::<P>(state);
+        check_vault_writable(state, user_id, vault_id)?;
+        _grant_machine_access(user_id, vault_id, args, ic_cdk::api::time(), state)?;
+        audit(state, user_id, Some(vault_id), AuditOp::MachineGrant, 1);
+        Ok(())
+    }))
+}
+
+pub fn revoke_machine_access<P: VaultPolicy>(vault_id: Principal, machine: Principal) {
+    with_state(|state| {
+        let user_id = account_owner::<P>(state);
+        _revoke_machine_access(user_id, vault_id, machine, state);
+        audit(state, user_id, Some(vault_id), AuditOp::MachineRevoke, 1);
+    })
+}
+
+pub fn get_machine_grants<P: VaultPolicy>(vault_id: Principal) -> Vec<MachineGrantInfo> {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _get_machine_grants(user_id, vault_id, state)
+    })
+}
+
+// Called by the machine itself, which is never a vault user.
+pub fn machine_get_grants() -> Vec<MachineVaultGrant> {
+    let machine = msg_caller();
+    with_state(|state| {
+        _get_vaults_for_machine(machine, ic_cdk::api::time(), &state.machine_grants)
+    })
+}
+
+pub fn machine_get_vault(owner: Principal, vault_id: Principal) -> Result<VaultData, String> {
+    let machine = msg_caller();
+    with_state(|state| {
+        _get_machine_vault(machine, owner, vault_id, ic_cdk::api::time(), state)
+    })
+}
+
+/*
+    Audit log endpoints
+*/
+
+pub fn get_my_audit_log<P: VaultPolicy>(before: Option<u64>, limit: u32) -> AuditPage {
+    with_state(|state| {
+        let user_id = vault_user::<P>(state);
+        _get_user_audit_log(user_id, before, limit, state)
+    })
+}
+
+// Every event in the canister. Controller only.
+pub fn get_audit_log(before: Option<u64>, limit: u32) -> AuditPage {
+    assert_controller();
+    with_state(|state| _get_audit_log(before, limit, &state.audit_log))
+}
+
+pub fn get_audit_retention() -> AuditRetention {
+    assert_controller();
+    with_state(|state| _get_audit_retention(&state.audit_retention))
+}
+
+pub fn set_audit_retention(retention: AuditRetention) {
+    assert_controller();
+    with_state(|state| {
+        assert_not_frozen(state);
+        _set_audit_retention(retention, ic_cdk::api::time(), state);
+        audit(state, msg_caller(), None, AuditOp::RetentionUpdate, 0);
+    })
+}
+
+/*
+    Cycles. Controller only.
+*/
+
+pub fn get_cycles_status() -> CyclesStatus {
+    assert_controller();
+    with_state(|state| _get_cycles_status(ic_cdk::api::canister_liquid_cycle_balance(), top_up_in_progress(), state))
+}
+
+pub fn set_cycles_settings(settings: CyclesSettings) -> Result<(), String> {
+    assert_controller();
+    with_state(|state| {
+        assert_not_frozen(state);
+        _set_cycles_settings(settings, state)?;
+        audit(state, msg_caller(), None, AuditOp::CyclesSettingsUpdate, 0);
+        Ok::<(), String>(())
+    })?;
+    // Picks up a changed interval straight away.
+    arm_cycles_monitor();
+    Ok(())
+}
+
+/*
+    Runtime configuration. Anyone may read the limits; only controllers change them.
+*/
+
+pub fn get_config<P: VaultPolicy>() -> RuntimeConfig {
+    with_state(|state| _get_config(P::TENANCY.tier(), state))
+}
+
+pub fn update_config<P: VaultPolicy>(update: ConfigUpdate) -> Result<RuntimeConfig, String> {
+    assert_controller();
+    with_state(|state| {
+        assert_not_frozen(state);
+        let changed = _update_config(update, P::TENANCY.tier(), state)?;
+        audit(state, msg_caller(), None, AuditOp::ConfigUpdate, changed);
+        Ok(_get_config(P::TENANCY.tier(), state))
+    })
+}
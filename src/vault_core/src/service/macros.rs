@@ -0,0 +1,553 @@
+/*
+    Generates the standard endpoint set of a vault canister for the given `VaultPolicy`:
+    inspect_message, init, post_upgrade, vetKD key derivation and every vault, registry, trash, history,
+    deletion, template, attachment, machine grant, audit, cycles, config, snapshot and metrics endpoint. Canister-specific endpoints stay in the
+    canister itself.
+
+    Types in endpoint signatures are spelled `::vault_core::...` rather than `$crate::...`:
+    export_candid! re-parses them at the crate root, where `$crate` means nothing.
+*/
+#[macro_export]
+macro_rules! vault_endpoints {
+    ($policy:ty) => {
+        #[::ic_cdk::inspect_message]
+        fn inspect_message() {
+            $crate::service::inspect_message::<$policy>()
+        }
+
+        #[::ic_cdk::init]
+        fn init(args: ::vault_core::vault_type::canister_config::InitArgs) {
+            $crate::service::init::<$policy>(args)
+        }
+
+        #[::ic_cdk::post_upgrade]
+        fn post_upgrade(args: Option<::vault_core::vault_type::canister_config::InitArgs>) {
+            $crate::service::post_upgrade::<$policy>(args)
+        }
+
+        #[::ic_cdk::update]
+        async fn derive_vetkd_encrypted_key(args: ::vault_core::api::key_api::GhostkeysVetKdArgs) -> Result<Vec<u8>, String> {
+            $crate::service::derive_vetkd_encrypted_key::<$policy>(args).await
+        }
+
+        #[::ic_cdk::update]
+        fn vault_names_sync(update: Vec<u8>) {
+            $crate::service::endpoints::vault_names_sync::<$policy>(update)
+        }
+
+        #[::ic_cdk::update]
+        fn vault_spreadsheet_columns_sync(vault_id: ::candid::Principal, update: Vec<u8>) {
+            $crate::service::endpoints::vault_spreadsheet_columns_sync::<$policy>(vault_id, update)
+        }
+
+        #[::ic_cdk::update]
+        fn vault_spreadsheet_sync(vault_id: ::candid::Principal, update: Vec<u8>) {
+            $crate::service::endpoints::vault_spreadsheet_sync::<$policy>(vault_id, update)
+        }
+
+        #[::ic_cdk::update]
+        fn vault_spreadsheet_deletes(vault_id: ::candid::Principal, update: Vec<u8>) {
+            $crate::service::endpoints::vault_spreadsheet_deletes::<$policy>(vault_id, update)
+        }
+
+        #[::ic_cdk::update]
+        fn vault_sheet_columns_sync(vault_id: ::candid::Principal, sheet: u8, update: Vec<u8>) {
+            $crate::service::endpoints::vault_sheet_columns_sync::<$policy>(vault_id, sheet, update)
+        }
+
+        #[::ic_cdk::update]
+        fn vault_sheet_sync(vault_id: ::candid::Principal, sheet: u8, update: Vec<u8>) {
+            $crate::service::endpoints::vault_sheet_sync::<$policy>(vault_id, sheet, update)
+        }
+
+        #[::ic_cdk::update]
+        fn vault_sheet_deletes(vault_id: ::candid::Principal, sheet: u8, update: Vec<u8>) {
+            $crate::service::endpoints::vault_sheet_deletes::<$policy>(vault_id, sheet, update)
+        }
+
+        #[::ic_cdk::update]
+        fn vault_login_full_sync(vault_id: ::candid::Principal, update: Vec<u8>) {
+            $crate::service::endpoints::vault_login_full_sync::<$policy>(vault_id, update)
+        }
+
+        #[::ic_cdk::update]
+        fn vault_login_metadata_sync(vault_id: ::candid::Principal, update: Vec<u8>) {
+            $crate::service::endpoints::vault_login_metadata_sync::<$policy>(vault_id, update)
+        }
+
+        #[::ic_cdk::update]
+        fn vault_login_metadata_delete(vault_id: ::candid::Principal, update: Vec<u8>) {
+            $crate::service::endpoints::vault_login_metadata_delete::<$policy>(vault_id, update)
+        }
+
+        #[::ic_cdk::update]
+        fn vault_login_data_sync(vault_id: ::candid::Principal, update: Vec<u8>) {
+            $crate::service::endpoints::vault_login_data_sync::<$policy>(vault_id, update)
+        }
+
+        #[::ic_cdk::update]
+        fn vault_login_data_deletes(vault_id: ::candid::Principal, update: Vec<u8>) {
+            $crate::service::endpoints::vault_login_data_deletes::<$policy>(vault_id, update)
+        }
+
+        #[::ic_cdk::update]
+        fn vault_secrets_sync(vault_id: ::candid::Principal, update: Vec<u8>) {
+            $crate::service::endpoints::vault_secrets_sync::<$policy>(vault_id, update)
+        }
+
+        #[::ic_cdk::update]
+        fn vault_totp_sync(vault_id: ::candid::Principal, update: Vec<u8>) {
+            $crate::service::endpoints::vault_totp_sync::<$policy>(vault_id, update)
+        }
+
+        #[::ic_cdk::update]
+        fn vault_totp_deletes(vault_id: ::candid::Principal, update: Vec<u8>) {
+            $crate::service::endpoints::vault_totp_deletes::<$policy>(vault_id, update)
+        }
+
+        #[::ic_cdk::update]
+        fn vault_cards_sync(vault_id: ::candid::Principal, update: Vec<u8>) {
+            $crate::service::endpoints::vault_cards_sync::<$policy>(vault_id, update)
+        }
+
+        #[::ic_cdk::update]
+        fn vault_cards_deletes(vault_id: ::candid::Principal, update: Vec<u8>) {
+            $crate::service::endpoints::vault_cards_deletes::<$policy>(vault_id, update)
+        }
+
+        #[::ic_cdk::update]
+        fn vault_identities_sync(vault_id: ::candid::Principal, update: Vec<u8>) {
+            $crate::service::endpoints::vault_identities_sync::<$policy>(vault_id, update)
+        }
+
+        #[::ic_cdk::update]
+        fn vault_identities_deletes(vault_id: ::candid::Principal, update: Vec<u8>) {
+            $crate::service::endpoints::vault_identities_deletes::<$policy>(vault_id, update)
+        }
+
+        #[::ic_cdk::update]
+        fn vault_custom_records_sync(vault_id: ::candid::Principal, update: Vec<u8>) {
+            $crate::service::endpoints::vault_custom_records_sync::<$policy>(vault_id, update)
+        }
+
+        #[::ic_cdk::update]
+        fn vault_custom_records_deletes(vault_id: ::candid::Principal, update: Vec<u8>) {
+            $crate::service::endpoints::vault_custom_records_deletes::<$policy>(vault_id, update)
+        }
+
+        #[::ic_cdk::update]
+        fn vault_search_tokens_sync(vault_id: ::candid::Principal, update: Vec<u8>) {
+            $crate::service::endpoints::vault_search_tokens_sync::<$policy>(vault_id, update)
+        }
+
+        #[::ic_cdk::query]
+        fn search_items(vault_ids: Vec<::candid::Principal>, tokens: Vec<Vec<u8>>) -> Result<Vec<::vault_core::api::search_api::SearchHit>, String> {
+            $crate::service::endpoints::search_items::<$policy>(vault_ids, tokens)
+        }
+
+        #[::ic_cdk::update]
+        fn vault_grid_edit(vault_id: ::candid::Principal, grid: ::vault_core::api::grid_api::Grid, axis: ::vault_core::api::grid_api::Axis, edit: ::vault_core::api::grid_api::GridEdit) -> Result<u32, String> {
+            $crate::service::endpoints::vault_grid_edit::<$policy>(vault_id, grid, axis, edit)
+        }
+
+        #[::ic_cdk::update]
+        fn global_sync(vault_id: ::candid::Principal, update: Vec<u8>) {
+            $crate::service::endpoints::global_sync::<$policy>(vault_id, update)
+        }
+
+        #[::ic_cdk::update]
+        fn delete_vault(vault_id: ::candid::Principal) -> Result<::vault_core::api::deletion_api::PendingDeletionInfo, String> {
+            $crate::service::endpoints::delete_vault::<$policy>(vault_id)
+        }
+
+        #[::ic_cdk::update]
+        fn purge_user() -> Result<::vault_core::api::deletion_api::PendingDeletionInfo, String> {
+            $crate::service::endpoints::purge_user::<$policy>()
+        }
+
+        #[::ic_cdk::update]
+        fn cancel_deletion(vault_id: Option<::candid::Principal>) -> Result<(), String> {
+            $crate::service::endpoints::cancel_deletion::<$policy>(vault_id)
+        }
+
+        #[::ic_cdk::query]
+        fn get_pending_deletions() -> Vec<::vault_core::api::deletion_api::PendingDeletionInfo> {
+            $crate::service::endpoints::get_pending_deletions::<$policy>()
+        }
+
+        #[::ic_cdk::query]
+        fn get_deletion_grace_period() -> u64 {
+            $crate::service::endpoints::get_deletion_grace_period()
+        }
+
+        #[::ic_cdk::update]
+        fn set_deletion_grace_period(grace_period_ns: u64) -> Result<(), String> {
+            $crate::service::endpoints::set_deletion_grace_period(grace_period_ns)
+        }
+
+        #[::ic_cdk::query]
+        fn list_trash(vault_id: ::candid::Principal) -> Vec<::vault_core::api::trash_api::TrashItemInfo> {
+            $crate::service::endpoints::list_trash::<$policy>(vault_id)
+        }
+
+        #[::ic_cdk::update]
+        fn restore_items(vault_id: ::candid::Principal, ids: Vec<u64>) -> ::vault_core::api::trash_api::RestoreResult {
+            $crate::service::endpoints::restore_items::<$policy>(vault_id, ids)
+        }
+
+        #[::ic_cdk::update]
+        fn empty_trash(vault_id: ::candid::Principal, ids: Option<Vec<u64>>) -> u32 {
+            $crate::service::endpoints::empty_trash::<$policy>(vault_id, ids)
+        }
+
+        #[::ic_cdk::query]
+        fn get_trash_retention() -> u64 {
+            $crate::service::endpoints::get_trash_retention()
+        }
+
+        #[::ic_cdk::update]
+        fn set_trash_retention(retention_ns: u64) -> Result<(), String> {
+            $crate::service::endpoints::set_trash_retention(retention_ns)
+        }
+
+        #[::ic_cdk::query]
+        fn list_revisions(vault_id: ::candid::Principal, item: ::vault_core::vault_type::history::HistoryItem) -> Vec<::vault_core::api::history_api::RevisionInfo> {
+            $crate::service::endpoints::list_revisions::<$policy>(vault_id, item)
+        }
+
+        #[::ic_cdk::query]
+        fn get_revision(vault_id: ::candid::Principal, item: ::vault_core::vault_type::history::HistoryItem, revision: u64) -> Option<::vault_core::api::history_api::RevisionData> {
+            $crate::service::endpoints::get_revision::<$policy>(vault_id, item, revision)
+        }
+
+        #[::ic_cdk::update]
+        fn rollback_item(vault_id: ::candid::Principal, item: ::vault_core::vault_type::history::HistoryItem, revision: u64) -> Result<(), String> {
+            $crate::service::endpoints::rollback_item::<$policy>(vault_id, item, revision)
+        }
+
+        #[::ic_cdk::query]
+        fn get_history_depth() -> u32 {
+            $crate::service::endpoints::get_history_depth()
+        }
+
+        #[::ic_cdk::update]
+        fn set_history_depth(depth: u32) -> Result<(), String> {
+            $crate::service::endpoints::set_history_depth(depth)
+        }
+
+        #[::ic_cdk::query]
+        fn get_vault_names() -> ::vault_core::api::dev_api::VaultNames {
+            $crate::service::endpoints::get_vault_names::<$policy>()
+        }
+
+        #[::ic_cdk::query]
+        fn get_vault_name(vault_id: ::candid::Principal) -> Vec<u8> {
+            $crate::service::endpoints::get_vault_name::<$policy>(vault_id)
+        }
+
+        #[::ic_cdk::query]
+        fn get_spreadsheet_columns(vault_id: ::candid::Principal) -> ::vault_core::api::dev_api::FlexGridColumns {
+            $crate::service::endpoints::get_spreadsheet_columns::<$policy>(vault_id)
+        }
+
+        #[::ic_cdk::query]
+        fn get_spreadsheet(vault_id: ::candid::Principal) -> ::vault_core::api::dev_api::Spreadsheet {
+            $crate::service::endpoints::get_spreadsheet::<$policy>(vault_id)
+        }
+
+        #[::ic_cdk::query]
+        fn get_logins(vault_id: ::candid::Principal) -> ::vault_core::api::dev_api::Logins {
+            $crate::service::endpoints::get_logins::<$policy>(vault_id)
+        }
+
+        #[::ic_cdk::query]
+        fn get_secure_notes(vault_id: ::candid::Principal) -> ::vault_core::api::dev_api::Notes {
+            $crate::service::endpoints::get_secure_notes::<$policy>(vault_id)
+        }
+
+        #[::ic_cdk::query]
+        fn get_totp_seeds(vault_id: ::candid::Principal) -> ::vault_core::api::dev_api::TotpSeeds {
+            $crate::service::endpoints::get_totp_seeds::<$policy>(vault_id)
+        }
+
+        #[::ic_cdk::query]
+        fn get_payment_cards(vault_id: ::candid::Principal) -> ::vault_core::api::dev_api::PaymentCards {
+            $crate::service::endpoints::get_payment_cards::<$policy>(vault_id)
+        }
+
+        #[::ic_cdk::query]
+        fn get_identities(vault_id: ::candid::Principal) -> ::vault_core::api::dev_api::Identities {
+            $crate::service::endpoints::get_identities::<$policy>(vault_id)
+        }
+
+        #[::ic_cdk::query]
+        fn get_custom_records(vault_id: ::candid::Principal) -> ::vault_core::api::dev_api::CustomRecords {
+            $crate::service::endpoints::get_custom_records::<$policy>(vault_id)
+        }
+
+        #[::ic_cdk::query]
+        fn get_expiring_records(from: ::vault_core::vault_type::records::Expiry, to: ::vault_core::vault_type::records::Expiry) -> Vec<::vault_core::api::dev_api::ExpiringRecord> {
+            $crate::service::endpoints::get_expiring_records::<$policy>(from, to)
+        }
+
+        #[::ic_cdk::query]
+        fn get_user_vault(vault_id: ::candid::Principal) -> ::vault_core::api::dev_api::VaultData {
+            $crate::service::endpoints::get_user_vault::<$policy>(vault_id)
+        }
+
+        #[::ic_cdk::query]
+        fn list_vaults() -> Vec<::vault_core::api::registry_api::VaultInfo> {
+            $crate::service::endpoints::list_vaults::<$policy>()
+        }
+
+        #[::ic_cdk::query]
+        fn get_vault_info(vault_id: ::candid::Principal) -> Option<::vault_core::api::registry_api::VaultInfo> {
+            $crate::service::endpoints::get_vault_info::<$policy>(vault_id)
+        }
+
+        #[::ic_cdk::update]
+        fn update_vault_metadata(vault_id: ::candid::Principal, update: ::vault_core::api::registry_api::VaultMetadataUpdate) -> Result<::vault_core::api::registry_api::VaultInfo, String> {
+            $crate::service::endpoints::update_vault_metadata::<$policy>(vault_id, update)
+        }
+
+        #[::ic_cdk::update]
+        fn set_record_template(vault_id: ::candid::Principal, index: u8, template: ::vault_core::vault_type::templates::RecordTemplate) -> Result<(), String> {
+            $crate::service::endpoints::set_record_template::<$policy>(vault_id, index, template)
+        }
+
+        #[::ic_cdk::update]
+        fn delete_record_template(vault_id: ::candid::Principal, index: u8) -> Result<(), String> {
+            $crate::service::endpoints::delete_record_template::<$policy>(vault_id, index)
+        }
+
+        #[::ic_cdk::query]
+        fn get_record_templates(vault_id: ::candid::Principal) -> ::vault_core::api::dev_api::RecordTemplates {
+            $crate::service::endpoints::get_record_templates::<$policy>(vault_id)
+        }
+
+        #[::ic_cdk::update]
+        fn create_sheet(vault_id: ::candid::Principal, name: Vec<u8>) -> Result<u8, String> {
+            $crate::service::endpoints::create_sheet::<$policy>(vault_id, name)
+        }
+
+        #[::ic_cdk::update]
+        fn rename_sheet(vault_id: ::candid::Principal, sheet: u8, name: Vec<u8>) -> Result<(), String> {
+            $crate::service::endpoints::rename_sheet::<$policy>(vault_id, sheet, name)
+        }
+
+        #[::ic_cdk::update]
+        fn reorder_sheets(vault_id: ::candid::Principal, order: Vec<u8>) -> Result<(), String> {
+            $crate::service::endpoints::reorder_sheets::<$policy>(vault_id, order)
+        }
+
+        #[::ic_cdk::update]
+        fn delete_sheet(vault_id: ::candid::Principal, sheet: u8) -> Result<u32, String> {
+            $crate::service::endpoints::delete_sheet::<$policy>(vault_id, sheet)
+        }
+
+        #[::ic_cdk::query]
+        fn list_sheets(vault_id: ::candid::Principal) -> Vec<::vault_core::api::sheets_api::SheetInfo> {
+            $crate::service::endpoints::list_sheets::<$policy>(vault_id)
+        }
+
+        #[::ic_cdk::query]
+        fn get_sheet(vault_id: ::candid::Principal, sheet: u8) -> ::vault_core::api::dev_api::Spreadsheet {
+            $crate::service::endpoints::get_sheet::<$policy>(vault_id, sheet)
+        }
+
+        #[::ic_cdk::query]
+        fn get_sheet_columns(vault_id: ::candid::Principal, sheet: u8) -> ::vault_core::api::dev_api::FlexGridColumns {
+            $crate::service::endpoints::get_sheet_columns::<$policy>(vault_id, sheet)
+        }
+
+        #[::ic_cdk::update]
+        fn create_folder(vault_id: ::candid::Principal, parent: Option<u64>, name: Vec<u8>) -> Result<u64, String> {
+            $crate::service::endpoints::create_folder::<$policy>(vault_id, parent, name)
+        }
+
+        #[::ic_cdk::update]
+        fn update_folder(vault_id: ::candid::Principal, id: u64, parent: Option<u64>, name: Vec<u8>) -> Result<(), String> {
+            $crate::service::endpoints::update_folder::<$policy>(vault_id, id, parent, name)
+        }
+
+        #[::ic_cdk::update]
+        fn delete_folder(vault_id: ::candid::Principal, id: u64) -> Result<(), String> {
+            $crate::service::endpoints::delete_folder::<$policy>(vault_id, id)
+        }
+
+        #[::ic_cdk::update]
+        fn set_tag(vault_id: ::candid::Principal, id: Vec<u8>, label: Vec<u8>) -> Result<(), String> {
+            $crate::service::endpoints::set_tag::<$policy>(vault_id, id, label)
+        }
+
+        #[::ic_cdk::update]
+        fn delete_tag(vault_id: ::candid::Principal, id: Vec<u8>) -> Result<u32, String> {
+            $crate::service::endpoints::delete_tag::<$policy>(vault_id, id)
+        }
+
+        #[::ic_cdk::update]
+        fn set_item_labels(vault_id: ::candid::Principal, item: ::vault_core::vault_type::attachments::ItemRef, folder: Option<u64>, tags: Vec<Vec<u8>>) -> Result<(), String> {
+            $crate::service::endpoints::set_item_labels::<$policy>(vault_id, item, folder, tags)
+        }
+
+        #[::ic_cdk::query]
+        fn list_folder_items(vault_id: ::candid::Principal, folder: u64) -> Vec<::vault_core::vault_type::attachments::ItemRef> {
+            $crate::service::endpoints::list_folder_items::<$policy>(vault_id, folder)
+        }
+
+        #[::ic_cdk::query]
+        fn list_tagged_items(vault_id: ::candid::Principal, tag: Vec<u8>) -> Vec<::vault_core::vault_type::attachments::ItemRef> {
+            $crate::service::endpoints::list_tagged_items::<$policy>(vault_id, tag)
+        }
+
+        #[::ic_cdk::query]
+        fn get_organization(vault_id: ::candid::Principal) -> ::vault_core::api::organization_api::Organization {
+            $crate::service::endpoints::get_organization::<$policy>(vault_id)
+        }
+
+        #[::ic_cdk::update]
+        fn begin_attachment(vault_id: ::candid::Principal, upload: ::vault_core::api::attachments_api::AttachmentUpload) -> Result<u64, String> {
+            $crate::service::endpoints::begin_attachment::<$policy>(vault_id, upload)
+        }
+
+        #[::ic_cdk::update]
+        fn upload_attachment_chunk(vault_id: ::candid::Principal, id: u64, index: u32, data: Vec<u8>) -> Result<(), String> {
+            $crate::service::endpoints::upload_attachment_chunk::<$policy>(vault_id, id, index, data)
+        }
+
+        #[::ic_cdk::update]
+        fn finish_attachment(vault_id: ::candid::Principal, id: u64) -> Result<::vault_core::api::attachments_api::AttachmentInfo, String> {
+            $crate::service::endpoints::finish_attachment::<$policy>(vault_id, id)
+        }
+
+        #[::ic_cdk::update]
+        fn link_attachment(vault_id: ::candid::Principal, id: u64, link: Option<::vault_core::vault_type::attachments::ItemRef>) -> Result<::vault_core::api::attachments_api::AttachmentInfo, String> {
+            $crate::service::endpoints::link_attachment::<$policy>(vault_id, id, link)
+        }
+
+        #[::ic_cdk::update]
+        fn delete_attachment(vault_id: ::candid::Principal, id: u64) -> Result<(), String> {
+            $crate::service::endpoints::delete_attachment::<$policy>(vault_id, id)
+        }
+
+        #[::ic_cdk::query]
+        fn list_attachments(vault_id: ::candid::Principal) -> Vec<::vault_core::api::attachments_api::AttachmentInfo> {
+            $crate::service::endpoints::list_attachments::<$policy>(vault_id)
+        }
+
+        #[::ic_cdk::query]
+        fn get_attachment_chunk(vault_id: ::candid::Principal, id: u64, index: u32) -> Result<Vec<u8>, String> {
+            $crate::service::endpoints::get_attachment_chunk::<$policy>(vault_id, id, index)
+        }
+
+        #[::ic_cdk::update]
+        fn grant_machine_access(vault_id: ::candid::Principal, args: ::vault_core::api::machine_api::MachineGrantArgs) -> Result<(), String> {
+            $crate::service::endpoints::grant_machine_access::<$policy>(vault_id, args)
+        }
+
+        #[::ic_cdk::update]
+        fn revoke_machine_access(vault_id: ::candid::Principal, machine: ::candid::Principal) {
+            $crate::service::endpoints::revoke_machine_access::<$policy>(vault_id, machine)
+        }
+
+        #[::ic_cdk::query]
+        fn get_machine_grants(vault_id: ::candid::Principal) -> Vec<::vault_core::api::machine_api::MachineGrantInfo> {
+            $crate::service::endpoints::get_machine_grants::<$policy>(vault_id)
+        }
+
+        #[::ic_cdk::query]
+        fn machine_get_grants() -> Vec<::vault_core::api::machine_api::MachineVaultGrant> {
+            $crate::service::endpoints::machine_get_grants()
+        }
+
+        #[::ic_cdk::query]
+        fn machine_get_vault(owner: ::candid::Principal, vault_id: ::candid::Principal) -> Result<::vault_core::api::dev_api::VaultData, String> {
+            $crate::service::endpoints::machine_get_vault(owner, vault_id)
+        }
+
+        #[::ic_cdk::query]
+        fn get_my_audit_log(before: Option<u64>, limit: u32) -> ::vault_core::api::audit_api::AuditPage {
+            $crate::service::endpoints::get_my_audit_log::<$policy>(before, limit)
+        }
+
+        #[::ic_cdk::query]
+        fn get_audit_log(before: Option<u64>, limit: u32) -> ::vault_core::api::audit_api::AuditPage {
+            $crate::service::endpoints::get_audit_log(before, limit)
+        }
+
+        #[::ic_cdk::query]
+        fn get_audit_retention() -> ::vault_core::vault_type::audit_log::AuditRetention {
+            $crate::service::endpoints::get_audit_retention()
+        }
+
+        #[::ic_cdk::update]
+        fn set_audit_retention(retention: ::vault_core::vault_type::audit_log::AuditRetention) {
+            $crate::service::endpoints::set_audit_retention(retention)
+        }
+
+        #[::ic_cdk::query]
+        fn get_cycles_status() -> ::vault_core::api::cycles_api::CyclesStatus {
+            $crate::service::endpoints::get_cycles_status()
+        }
+
+        #[::ic_cdk::update]
+        fn set_cycles_settings(settings: ::vault_core::vault_type::cycles::CyclesSettings) -> Result<(), String> {
+            $crate::service::endpoints::set_cycles_settings(settings)
+        }
+
+        #[::ic_cdk::query]
+        fn get_config() -> ::vault_core::api::config_api::RuntimeConfig {
+            $crate::service::endpoints::get_config::<$policy>()
+        }
+
+        #[::ic_cdk::update]
+        fn update_config(update: ::vault_core::api::config_api::ConfigUpdate) -> Result<::vault_core::api::config_api::RuntimeConfig, String> {
+            $crate::service::endpoints::update_config::<$policy>(update)
+        }
+
+        #[::ic_cdk::query]
+        fn get_snapshot_status() -> ::vault_core::vault_type::snapshot::SnapshotLock {
+            $crate::service::snapshot::get_snapshot_status()
+        }
+
+        #[::ic_cdk::update]
+        fn begin_snapshot() -> Result<::vault_core::vault_type::snapshot::SnapshotManifest, String> {
+            $crate::service::snapshot::begin_snapshot()
+        }
+
+        #[::ic_cdk::query]
+        fn export_snapshot_chunk(cursor: Option<::vault_core::vault_type::snapshot::SnapshotCursor>) -> Result<::vault_core::vault_type::snapshot::SnapshotChunk, String> {
+            $crate::service::snapshot::export_snapshot_chunk(cursor)
+        }
+
+        #[::ic_cdk::update]
+        fn end_snapshot() -> Result<(), String> {
+            $crate::service::snapshot::end_snapshot()
+        }
+
+        #[::ic_cdk::update]
+        fn begin_restore(manifest: ::vault_core::vault_type::snapshot::SnapshotManifest) -> Result<(), String> {
+            $crate::service::snapshot::begin_restore(manifest)
+        }
+
+        #[::ic_cdk::update]
+        fn restore_snapshot_chunk(chunk: ::vault_core::vault_type::snapshot::SnapshotChunk) -> Result<u64, String> {
+            $crate::service::snapshot::restore_snapshot_chunk(chunk)
+        }
+
+        #[::ic_cdk::update]
+        fn finish_restore(manifest: ::vault_core::vault_type::snapshot::SnapshotManifest) -> Result<(), String> {
+            $crate::service::snapshot::finish_restore(manifest)
+        }
+
+        #[::ic_cdk::query]
+        fn get_canister_metrics() -> ::vault_core::api::metrics_api::CanisterMetrics {
+            $crate::service::metrics::get_canister_metrics()
+        }
+
+        #[::ic_cdk::query]
+        fn http_request(request: ::vault_core::api::metrics_api::HttpRequest) -> ::vault_core::api::metrics_api::HttpResponse {
+            $crate::service::metrics::http_request(request)
+        }
+    };
+}
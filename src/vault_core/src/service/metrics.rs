@@ -0,0 +1,58 @@
+use std::cell::RefCell;
+
+use crate::api::metrics_api::{_collect_metrics, _http_response, _record_call, CallCounters, CanisterMetrics, HttpRequest, HttpResponse};
+
+use super::{assert_controller, with_state};
+
+thread_local! {
+    static COUNTERS: RefCell<CallCounters> = RefCell::new(CallCounters::default());
+}
+
+// Starts the counters over. Called on install and upgrade, which empty the heap anyway.
+pub fn reset_counters() {
+    COUNTERS.with(|counters| {
+        *counters.borrow_mut() = CallCounters {
+            since: ic_cdk::api::time(),
+            ..Default::default()
+        }
+    });
+}
+
+// Counts a call to `endpoint` along with the instructions the message has used so far.
+pub fn record_call(endpoint: &'static str, failed: bool) {
+    let instructions = ic_cdk::api::instruction_counter();
+    COUNTERS.with(|counters| _record_call(endpoint, instructions, failed, &mut counters.borrow_mut()));
+}
+
+pub fn track<R>(endpoint: &'static str, f: impl FnOnce() -> R) -> R {
+    let result = f();
+    record_call(endpoint, false);
+    result
+}
+
+pub fn track_result<T>(endpoint: &'static str, f: impl FnOnce() -> Result<T, String>) -> Result<T, String> {
+    let result = f();
+    record_call(endpoint, result.is_err());
+    result
+}
+
+pub fn record_vetkd_derivation() {
+    COUNTERS.with(|counters| counters.borrow_mut().vetkd_derivations += 1);
+}
+
+fn collect() -> CanisterMetrics {
+    let balance = ic_cdk::api::canister_liquid_cycle_balance();
+    let pages = ic_cdk::stable::stable_size();
+    COUNTERS.with(|counters| with_state(|state| _collect_metrics(ic_cdk::api::time(), balance, pages, &counters.borrow(), state)))
+}
+
+pub fn get_canister_metrics() -> CanisterMetrics {
+    assert_controller();
+    collect()
+}
+
+// Reached through the HTTP gateway, which can't authenticate the scraper. Only aggregates are
+// served, never vault data.
+pub fn http_request(request: HttpRequest) -> HttpResponse {
+    _http_response(&request, collect)
+}
@@ -0,0 +1,95 @@
+use std::cell::Cell;
+
+use candid::Principal;
+use ic_cdk::call::Call;
+
+use crate::{
+    api::{
+        deletion_api::{_schedule_immediate_purge, PendingDeletionInfo},
+        migration_api::{_assert_import_target_empty, _import_page, _verify_import, MigrationPage, MigrationReport},
+    },
+    vault_type::{audit_log::AuditOp, migration::MigrationManifest},
+};
+
+use super::{account_owner, arm_deletion_timer, audit, policy::VaultPolicy, with_state};
+
+thread_local! {
+    static IMPORT_IN_PROGRESS: Cell<bool> = const { Cell::new(false) };
+}
+
+// Holds IMPORT_IN_PROGRESS while an import runs. Dropping it clears the flag, including when a
+// callback traps and the import's future is dropped in cleanup.
+struct ImportGuard;
+impl ImportGuard {
+    fn acquire() -> Result<Self, String> {
+        if IMPORT_IN_PROGRESS.with(|in_progress| in_progress.replace(true)) {
+            return Err("an import is already running".into());
+        }
+        Ok(Self)
+    }
+}
+impl Drop for ImportGuard {
+    fn drop(&mut self) {
+        IMPORT_IN_PROGRESS.with(|in_progress| in_progress.set(false));
+    }
+}
+
+async fn call_source<R: candid::CandidType + for<'de> candid::Deserialize<'de>>(call: Call<'_, '_>) -> Result<R, String> {
+    let response = call.await.map_err(|e| format!("source canister call failed: {}", e))?;
+    let result: Result<R, String> = response.candid().map_err(|e| format!("unexpected source response: {}", e))?;
+    result
+}
+
+// Pulls the owner's data from a shared canister they authorised this canister on, checks what
+// arrived against the source's manifest and has the source purge them. Owner only.
+pub async fn import_from_shared<P: VaultPolicy>(source: Principal) -> Result<MigrationReport, String> {
+    let owner = with_state(|state| account_owner::<P>(state));
+    with_state(|state| _assert_import_target_empty(owner, state))?;
+    let guard = ImportGuard::acquire()?;
+    let imported = import_pages(owner, source).await;
+    drop(guard);
+
+    let manifest = match imported {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            // Empties the canister for the next attempt, in batches as any purge.
+            with_state(|state| {
+                if let Err(e) = _schedule_immediate_purge(owner, ic_cdk::api::time(), state) {
+                    ic_cdk::println!("Cleanup of failed import not scheduled: {}", e);
+                }
+                state.key_management.borrow_mut().remove(&owner.to_text());
+            });
+            arm_deletion_timer();
+            return Err(e);
+        }
+    };
+    with_state(|state| audit(state, owner, None, AuditOp::MigrationImported, manifest.items as u32));
+
+    // Unbounded so the answer is never lost: whether the source scheduled the purge matters.
+    let source_purge: Result<PendingDeletionInfo, String> = call_source(
+        Call::unbounded_wait(source, "complete_migration").with_args(&(owner, manifest.checksum.clone())),
+    ).await;
+    Ok(MigrationReport {
+        source,
+        vaults: manifest.vaults,
+        items: manifest.items,
+        checksum: manifest.checksum,
+        source_purge,
+    })
+}
+
+async fn import_pages(owner: Principal, source: Principal) -> Result<MigrationManifest, String> {
+    let mut imported = MigrationManifest::default();
+    let mut cursor: Option<u64> = None;
+    loop {
+        let page: MigrationPage = call_source(Call::bounded_wait(source, "export_migration_page").with_args(&(owner, cursor))).await?;
+        with_state(|state| _import_page(owner, page.chunks, &mut imported, ic_cdk::api::time(), state))?;
+        match page.next {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+    let manifest: MigrationManifest = call_source(Call::bounded_wait(source, "get_migration_manifest").with_arg(owner)).await?;
+    _verify_import(&manifest, &imported)?;
+    Ok(manifest)
+}
@@ -0,0 +1,231 @@
+use std::{cell::RefCell, time::Duration};
+
+use candid::Principal;
+use ic_cdk::api::msg_caller;
+use ic_cdk_timers::{clear_timer, set_timer, TimerId};
+
+use crate::{
+    api::{
+        audit_api::_record_audit_event,
+        deletion_api::{_assert_vault_writable, _next_deletion_due, _run_due_deletions},
+        history_api::_record_revisions,
+        key_api::{derive_vetkey, storage_user_of, GhostkeysVetKdArgs},
+        registry_api::{_assert_vault_registered, _backfill_registry, _record_vault_change},
+        serial_api::SyncOutcome,
+        snapshot_api::_assert_not_frozen,
+        trash_api::_move_to_trash,
+    },
+    stable::{
+        types::GeneralState,
+        util::{_apply_init_args, _inspect_message, _is_controller, _restore_owners},
+    },
+    vault_type::{audit_log::{AuditEvent, AuditOp}, canister_config::InitArgs, pending_deletion::DeletionKind},
+};
+
+pub mod capacity;
+pub mod cycles;
+pub mod endpoints;
+pub mod macros;
+pub mod metrics;
+pub mod migration;
+pub mod policy;
+pub mod snapshot;
+
+use policy::VaultPolicy;
+
+/*
+    Endpoint layer shared by the shared and dedicated canisters. Each canister supplies a
+    `VaultPolicy` and invokes `vault_endpoints!`, which generates thin endpoints over the
+    functions here and in `endpoints`.
+*/
+
+thread_local! {
+    static GENERAL_STATE: GeneralState = GeneralState::init();
+    static DELETION_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+}
+
+// Instructions a deletion timer tick may use before yielding to the next tick.
+const DELETION_INSTRUCTION_BUDGET: u64 = 5_000_000_000;
+
+pub fn with_state<R>(f: impl FnOnce(&GeneralState) -> R) -> R {
+    GENERAL_STATE.with(f)
+}
+
+// Helper for cost-related. Every update call that costs cycles should trigger this, on top of
+// the periodic check.
+pub fn maintain_canister_status() {
+    cycles::check_cycles();
+}
+
+// The principal whose vaults the caller acts on. Traps when the policy turns the caller away.
+pub fn vault_user<P: VaultPolicy>(state: &GeneralState) -> Principal {
+    assert_not_frozen(state);
+    P::vault_user(msg_caller(), state).unwrap_or_else(|e| ic_cdk::trap(e))
+}
+
+// The principal for account-level operations. Traps when the policy turns the caller away.
+pub fn account_owner<P: VaultPolicy>(state: &GeneralState) -> Principal {
+    assert_not_frozen(state);
+    P::account_owner(msg_caller(), state).unwrap_or_else(|e| ic_cdk::trap(e))
+}
+
+// Traps on updates while a snapshot is taken or restored. Queries change nothing and carry on.
+pub fn assert_not_frozen(state: &GeneralState) {
+    if !ic_cdk::api::in_replicated_execution() {
+        return;
+    }
+    if let Err(e) = _assert_not_frozen(state) {
+        ic_cdk::trap(e);
+    }
+}
+
+// Records an audit event about `user_id`'s data, made by the caller.
+pub fn audit(state: &GeneralState, user_id: Principal, vault_id: Option<Principal>, op: AuditOp, item_count: u32) {
+    let event = AuditEvent::new(msg_caller(), user_id, vault_id, op, item_count, ic_cdk::api::time());
+    _record_audit_event(event, state);
+}
+
+// Audits a sync, updates the vault's registry entry, moves whatever it removed to the vault's
+// trash and keeps the values it overwrote.
+pub fn record_sync(state: &GeneralState, user_id: Principal, vault_id: Principal, op: AuditOp, outcome: SyncOutcome) {
+    let now = ic_cdk::api::time();
+    audit(state, user_id, Some(vault_id), op, outcome.items);
+    _record_vault_change(user_id, vault_id, outcome.size_change(), now, &state.vault_registry);
+    _move_to_trash(user_id, vault_id, outcome.removed, now, state);
+    _record_revisions(user_id, vault_id, outcome.replaced, now, state);
+}
+
+pub fn assert_controller() {
+    let caller = msg_caller();
+    with_state(|state| {
+        if !_is_controller(caller, &state.canister_owners) {
+            ic_cdk::trap(format!("Unauthorized caller: {}", caller));
+        }
+    });
+}
+
+// Errs unless the vault is registered and not pending deletion.
+pub fn check_vault_writable(state: &GeneralState, user_id: Principal, vault_id: Principal) -> Result<(), String> {
+    _assert_vault_registered(user_id, vault_id, &state.vault_registry)?;
+    _assert_vault_writable(user_id, vault_id, &state.pending_deletions)
+}
+
+// Traps unless the vault is registered and not pending deletion.
+pub fn assert_vault_writable(state: &GeneralState, user_id: Principal, vault_id: Principal) {
+    if let Err(e) = check_vault_writable(state, user_id, vault_id) {
+        ic_cdk::trap(e);
+    }
+}
+
+// Arms the timer for the next due deletion, replacing any timer already set.
+pub fn arm_deletion_timer() {
+    let next_due = with_state(|state| _next_deletion_due(&state.pending_deletions));
+    DELETION_TIMER.with(|timer| {
+        if let Some(id) = timer.borrow_mut().take() {
+            clear_timer(id);
+        }
+        if let Some(due) = next_due {
+            let delay = Duration::from_nanos(due.saturating_sub(ic_cdk::api::time()));
+            *timer.borrow_mut() = Some(set_timer(delay, run_due_deletions));
+        }
+    });
+}
+
+fn run_due_deletions() {
+    DELETION_TIMER.with(|timer| timer.borrow_mut().take());
+    // Left for when the snapshot ends, which re-arms the timer.
+    if with_state(|state| _assert_not_frozen(state).is_err()) {
+        return;
+    }
+    metrics::track("run_due_deletions", || with_state(|state| {
+        let should_yield = || ic_cdk::api::instruction_counter() > DELETION_INSTRUCTION_BUDGET;
+        for done in _run_due_deletions(ic_cdk::api::time(), state, &should_yield) {
+            let op = match done.kind {
+                DeletionKind::Vault => AuditOp::DeleteVault,
+                DeletionKind::User => AuditOp::PurgeUser,
+            };
+            let event = AuditEvent::new(done.user_id, done.user_id, done.vault_id, op, done.removed as u32, ic_cdk::api::time());
+            _record_audit_event(event, state);
+        }
+    }));
+    // Re-arms straight away if there is work left over.
+    arm_deletion_timer();
+}
+
+pub fn init<P: VaultPolicy>(args: InitArgs) {
+    with_state(|state| {
+        if let Err(e) = _apply_init_args(args, P::DEFAULT_MAX_USERS, state) {
+            ic_cdk::trap(e);
+        }
+        _restore_owners(state);
+        state.history_depth.borrow_mut().set(P::TENANCY.history_depth());
+    });
+    metrics::reset_counters();
+    cycles::arm_cycles_monitor();
+}
+
+// Arguments are optional on upgrade; without them the stored config is kept as is.
+pub fn post_upgrade<P: VaultPolicy>(args: Option<InitArgs>) {
+    with_state(|state| {
+        if let Some(args) = args {
+            if let Err(e) = _apply_init_args(args, P::DEFAULT_MAX_USERS, state) {
+                ic_cdk::trap(e);
+            }
+        }
+        _restore_owners(state);
+        _backfill_registry(ic_cdk::api::time(), state);
+    });
+    metrics::reset_counters();
+    // Timers don't survive upgrades, so re-arm for any deletions still pending.
+    arm_deletion_timer();
+    cycles::arm_cycles_monitor();
+}
+
+pub fn inspect_message<P: VaultPolicy>() {
+    with_state(|state| {
+        if P::accepts_caller(msg_caller(), state) {
+            ic_cdk::api::accept_message();
+            return;
+        }
+        let always_accept: Vec<String> = P::ALWAYS_ACCEPT.iter().map(|method| method.to_string()).collect();
+        _inspect_message(&always_accept, &state.canister_owners)
+    })
+}
+
+/*
+    Key-management Specific Endpoints
+*/
+
+pub async fn derive_vetkd_encrypted_key<P: VaultPolicy>(args: GhostkeysVetKdArgs) -> Result<Vec<u8>, String> {
+    let result = derive_or_get_key::<P>(args).await;
+    metrics::record_call("derive_vetkd_encrypted_key", result.is_err());
+    result
+}
+
+async fn derive_or_get_key<P: VaultPolicy>(args: GhostkeysVetKdArgs) -> Result<Vec<u8>, String> {
+    let owner_principal = storage_user_of(&args.scope);
+    with_state(|state| {
+        assert_not_frozen(state);
+        P::admit_user(owner_principal, state)
+    })?;
+
+    if let Some(existing_key) = with_state(|state| state.key_management.borrow().get(&owner_principal.to_text())) {
+        return Ok(existing_key);
+    }
+
+    maintain_canister_status();
+    let caller = msg_caller();
+    let (key_name, max_input) = with_state(|state| (state.config.borrow().get().vetkd_key_name.clone(), state.limits.borrow().get().vetkd_max_input_bytes));
+    let encrypted_key = derive_vetkey(args, &key_name, max_input).await?;
+
+    with_state(|state| {
+        state.key_management
+            .borrow_mut()
+            .insert(owner_principal.to_text(), encrypted_key.clone());
+        let event = AuditEvent::new(caller, owner_principal, None, AuditOp::DeriveVetKey, 1, ic_cdk::api::time());
+        _record_audit_event(event, state);
+    });
+    metrics::record_vetkd_derivation();
+
+    Ok(encrypted_key)
+}
@@ -0,0 +1,60 @@
+use candid::Principal;
+
+use crate::{api::history_api::{DEDICATED_HISTORY_DEPTH, DEFAULT_HISTORY_DEPTH}, stable::types::GeneralState, vault_type::limits::Tier};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TenancyMode {
+    // Many users share the canister, each acting on their own data.
+    Shared,
+    // The canister holds a single owner's data.
+    Dedicated,
+}
+impl TenancyMode {
+    // Revisions kept per item for a canister of this tier.
+    pub fn history_depth(self) -> u32 {
+        match self {
+            TenancyMode::Shared => DEFAULT_HISTORY_DEPTH,
+            TenancyMode::Dedicated => DEDICATED_HISTORY_DEPTH,
+        }
+    }
+
+    // The tier whose limits apply to the canister's users.
+    pub fn tier(self) -> Tier {
+        match self {
+            TenancyMode::Shared => Tier::Free,
+            TenancyMode::Dedicated => Tier::Premium,
+        }
+    }
+}
+
+/*
+    Hooks through which a canister decides who may do what. The standard endpoints generated by
+    `vault_endpoints!` only ever go through these, so both canisters share the same endpoint code.
+*/
+pub trait VaultPolicy {
+    const TENANCY: TenancyMode;
+
+    // User limit applied when the install arguments don't set one.
+    const DEFAULT_MAX_USERS: u64;
+
+    // Methods inspect_message accepts from any caller.
+    const ALWAYS_ACCEPT: &'static [&'static str];
+
+    // The principal whose vaults the caller reads and writes.
+    fn vault_user(caller: Principal, state: &GeneralState) -> Result<Principal, String>;
+
+    // The principal for account-level operations: deletions and machine grants.
+    fn account_owner(caller: Principal, state: &GeneralState) -> Result<Principal, String> {
+        Self::vault_user(caller, state)
+    }
+
+    // Quota check run before a vetKD key is derived for `user`.
+    fn admit_user(_user: Principal, _state: &GeneralState) -> Result<(), String> {
+        Ok(())
+    }
+
+    // Callers inspect_message lets through besides known users and controllers.
+    fn accepts_caller(_caller: Principal, _state: &GeneralState) -> bool {
+        false
+    }
+}
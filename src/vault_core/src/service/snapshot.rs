@@ -0,0 +1,69 @@
+use ic_cdk::api::msg_caller;
+
+use crate::{
+    api::snapshot_api::{_assert_not_frozen, _begin_restore, _begin_snapshot, _end_snapshot, _export_snapshot_chunk, _finish_restore, _restore_snapshot_chunk},
+    stable::util::_restore_owners,
+    vault_type::{
+        audit_log::AuditOp,
+        snapshot::{SnapshotChunk, SnapshotCursor, SnapshotLock, SnapshotManifest},
+    },
+};
+
+use super::{arm_deletion_timer, assert_controller, audit, cycles::arm_cycles_monitor, with_state};
+
+/*
+    Snapshot export and restore. Controller only.
+*/
+
+pub fn get_snapshot_status() -> SnapshotLock {
+    assert_controller();
+    with_state(|state| state.snapshot_lock.borrow().get().clone())
+}
+
+// The audit event goes in before the lock, so the snapshot records that it was taken.
+pub fn begin_snapshot() -> Result<SnapshotManifest, String> {
+    assert_controller();
+    with_state(|state| {
+        _assert_not_frozen(state)?;
+        audit(state, msg_caller(), None, AuditOp::SnapshotTaken, 0);
+        _begin_snapshot(ic_cdk::api::time(), state)
+    })
+}
+
+pub fn export_snapshot_chunk(cursor: Option<SnapshotCursor>) -> Result<SnapshotChunk, String> {
+    assert_controller();
+    with_state(|state| _export_snapshot_chunk(cursor, state))
+}
+
+pub fn end_snapshot() -> Result<(), String> {
+    assert_controller();
+    with_state(_end_snapshot)?;
+    // Deletions that fell due during the export were skipped.
+    arm_deletion_timer();
+    Ok(())
+}
+
+pub fn begin_restore(manifest: SnapshotManifest) -> Result<(), String> {
+    assert_controller();
+    with_state(|state| _begin_restore(&manifest, ic_cdk::api::time(), state))
+}
+
+pub fn restore_snapshot_chunk(chunk: SnapshotChunk) -> Result<u64, String> {
+    assert_controller();
+    with_state(|state| _restore_snapshot_chunk(chunk, state))
+}
+
+// The restored config replaces the one the canister was installed with, owner and factory
+// included. Pass new install arguments on the next upgrade to change them.
+pub fn finish_restore(manifest: SnapshotManifest) -> Result<(), String> {
+    assert_controller();
+    with_state(|state| {
+        _finish_restore(&manifest, state)?;
+        _restore_owners(state);
+        audit(state, msg_caller(), None, AuditOp::SnapshotRestored, manifest.chunks as u32);
+        Ok::<(), String>(())
+    })?;
+    arm_deletion_timer();
+    arm_cycles_monitor();
+    Ok(())
+}
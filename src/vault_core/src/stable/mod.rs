@@ -1,3 +1,4 @@
 pub mod state;
 pub mod util;
-pub mod types;
\ No newline at end of file
+pub mod types;
+pub mod snapshot;
@@ -0,0 +1,128 @@
+use std::{borrow::Cow, cell::RefCell, ops::Bound};
+
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable};
+
+use crate::stable::types::{GeneralState, Memory};
+
+// A key and value as stored.
+pub type RawEntry = (Vec<u8>, Vec<u8>);
+
+/*
+    Byte-level access to the stable structures, so a snapshot can copy each of them without
+    knowing its key and value types. Entries go out and come back exactly as stored.
+*/
+pub trait SnapshotStore {
+    fn entry_count(&self) -> u64;
+    // Whether a restore can write into the structure without mixing in existing data.
+    fn is_empty(&self) -> bool;
+    // Entries after the given key, in key order, until `max_bytes` is reached. Always returns
+    // at least one entry when there is one. The flag tells whether entries were left over.
+    fn entries_after(&self, after: Option<&[u8]>, max_bytes: usize) -> (Vec<RawEntry>, bool);
+    fn insert_raw(&self, key: &[u8], value: &[u8]);
+}
+
+impl<K: Storable + Ord + Clone, V: Storable> SnapshotStore for RefCell<StableBTreeMap<K, V, Memory>> {
+    fn entry_count(&self) -> u64 {
+        self.borrow().len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.borrow().is_empty()
+    }
+
+    fn entries_after(&self, after: Option<&[u8]>, max_bytes: usize) -> (Vec<RawEntry>, bool) {
+        let start = match after {
+            Some(key) => Bound::Excluded(K::from_bytes(Cow::Borrowed(key))),
+            None => Bound::Unbounded,
+        };
+        let map = self.borrow();
+        let mut entries = Vec::new();
+        let mut size = 0;
+        for entry in map.range((start, Bound::Unbounded)) {
+            let (key, value) = entry.into_pair();
+            let (key, value) = (key.into_bytes(), value.into_bytes());
+            if !entries.is_empty() && size + key.len() + value.len() > max_bytes {
+                return (entries, true);
+            }
+            size += key.len() + value.len();
+            entries.push((key, value));
+        }
+        (entries, false)
+    }
+
+    fn insert_raw(&self, key: &[u8], value: &[u8]) {
+        self.borrow_mut().insert(K::from_bytes(Cow::Borrowed(key)), V::from_bytes(Cow::Borrowed(value)));
+    }
+}
+
+// A cell is a single entry with an empty key.
+impl<T: Storable> SnapshotStore for RefCell<StableCell<T, Memory>> {
+    fn entry_count(&self) -> u64 {
+        1
+    }
+
+    // A cell always holds a value, the default on a fresh canister, and a restore overwrites it.
+    fn is_empty(&self) -> bool {
+        true
+    }
+
+    fn entries_after(&self, after: Option<&[u8]>, _max_bytes: usize) -> (Vec<RawEntry>, bool) {
+        match after {
+            Some(_) => (Vec::new(), false),
+            None => (vec![(Vec::new(), self.borrow().get().to_bytes().into_owned())], false),
+        }
+    }
+
+    fn insert_raw(&self, _key: &[u8], value: &[u8]) {
+        self.borrow_mut().set(T::from_bytes(Cow::Borrowed(value)));
+    }
+}
+
+impl GeneralState {
+    // Every structure a snapshot covers, by memory id and field name, in export order. Keep in
+    // step with `init`. The snapshot lock itself is left out.
+    pub fn snapshot_sections(&self) -> Vec<(u8, &'static str, &dyn SnapshotStore)> {
+        vec![
+            (1, "key_management", &self.key_management),
+            (2, "spreadsheet_columns", &self.spreadsheet_columns),
+            (3, "spreadsheet_map", &self.spreadsheet_map),
+            (4, "logins_map", &self.logins_map),
+            (5, "logins_columns", &self.logins_columns),
+            (6, "notes_map", &self.notes_map),
+            (7, "vault_names_map", &self.vault_names_map),
+            (8, "machine_grants", &self.machine_grants),
+            (9, "audit_log", &self.audit_log),
+            (10, "audit_retention", &self.audit_retention),
+            (11, "pending_deletions", &self.pending_deletions),
+            (12, "deletion_grace_period", &self.deletion_grace_period),
+            (13, "trash", &self.trash),
+            (14, "trash_retention", &self.trash_retention),
+            (15, "history", &self.history),
+            (16, "history_depth", &self.history_depth),
+            (17, "delegates", &self.delegates),
+            (18, "config", &self.config),
+            (19, "cycles_settings", &self.cycles_settings),
+            (20, "last_top_up", &self.last_top_up),
+            (21, "capacity", &self.capacity),
+            (22, "migration_grants", &self.migration_grants),
+            (24, "limits", &self.limits),
+            (25, "deletion_cursors", &self.deletion_cursors),
+            (26, "vault_registry", &self.vault_registry),
+            (27, "totp_map", &self.totp_map),
+            (28, "attachments", &self.attachments),
+            (29, "attachment_chunks", &self.attachment_chunks),
+            (30, "records", &self.records),
+            (31, "record_templates", &self.record_templates),
+            (32, "custom_records", &self.custom_records),
+            (33, "sheets", &self.sheets),
+            (34, "folders", &self.folders),
+            (35, "tags", &self.tags),
+            (36, "item_labels", &self.item_labels),
+            (37, "search_index", &self.search_index),
+            (38, "item_tokens", &self.item_tokens),
+            (39, "vault_machines", &self.vault_machines),
+            (40, "migration_exports", &self.migration_exports),
+            (41, "user_audit_index", &self.user_audit_index),
+        ]
+    }
+}
@@ -1,7 +1,7 @@
-use crate::stable::types::{CanisterOwners, GeneralState};
+use crate::{api::{deletion_api::DEFAULT_DELETION_GRACE_PERIOD_NS, history_api::DEFAULT_HISTORY_DEPTH, trash_api::DEFAULT_TRASH_RETENTION_NS}, stable::types::{CanisterOwners, GeneralState}, vault_type::{audit_log::AuditRetention, capacity::CapacityRecord, canister_config::CanisterConfig, cycles::CyclesSettings, limits::Limits, snapshot::SnapshotLock}};
 use candid::Principal;
 use ic_stable_structures::{
-    memory_manager::{MemoryId, MemoryManager}, DefaultMemoryImpl, StableBTreeMap
+    memory_manager::{MemoryId, MemoryManager}, DefaultMemoryImpl, StableBTreeMap, StableCell
 };
 use std::{cell::RefCell};
 
@@ -19,6 +19,40 @@ impl GeneralState {
         let logins_columns = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(5))));
         let notes_map = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(6))));
         let vault_names_map =  RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(7))));
+        let machine_grants = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(8))));
+        let audit_log = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(9))));
+        let audit_retention = RefCell::new(StableCell::init(memory_manager.get(MemoryId::new(10)), AuditRetention::default()));
+        let pending_deletions = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(11))));
+        let deletion_grace_period = RefCell::new(StableCell::init(memory_manager.get(MemoryId::new(12)), DEFAULT_DELETION_GRACE_PERIOD_NS));
+        let trash = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(13))));
+        let trash_retention = RefCell::new(StableCell::init(memory_manager.get(MemoryId::new(14)), DEFAULT_TRASH_RETENTION_NS));
+        let history = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(15))));
+        let history_depth = RefCell::new(StableCell::init(memory_manager.get(MemoryId::new(16)), DEFAULT_HISTORY_DEPTH));
+        let delegates = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(17))));
+        let config = RefCell::new(StableCell::init(memory_manager.get(MemoryId::new(18)), CanisterConfig::default()));
+        let cycles_settings = RefCell::new(StableCell::init(memory_manager.get(MemoryId::new(19)), CyclesSettings::default()));
+        let last_top_up = RefCell::new(StableCell::init(memory_manager.get(MemoryId::new(20)), None));
+        let capacity = RefCell::new(StableCell::init(memory_manager.get(MemoryId::new(21)), CapacityRecord::default()));
+        let migration_grants = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(22))));
+        let snapshot_lock = RefCell::new(StableCell::init(memory_manager.get(MemoryId::new(23)), SnapshotLock::default()));
+        let limits = RefCell::new(StableCell::init(memory_manager.get(MemoryId::new(24)), Limits::default()));
+        let deletion_cursors = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(25))));
+        let vault_registry = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(26))));
+        let totp_map = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(27))));
+        let attachments = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(28))));
+        let attachment_chunks = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(29))));
+        let records = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(30))));
+        let record_templates = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(31))));
+        let custom_records = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(32))));
+        let sheets = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(33))));
+        let folders = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(34))));
+        let tags = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(35))));
+        let item_labels = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(36))));
+        let search_index = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(37))));
+        let item_tokens = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(38))));
+        let vault_machines = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(39))));
+        let migration_exports = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(40))));
+        let user_audit_index = RefCell::new(StableBTreeMap::init(memory_manager.get(MemoryId::new(41))));
         Self {
             memory_manager,
             canister_owners,
@@ -28,7 +62,41 @@ impl GeneralState {
             logins_map,
             logins_columns,
             notes_map,
-            vault_names_map
+            vault_names_map,
+            machine_grants,
+            audit_log,
+            audit_retention,
+            pending_deletions,
+            deletion_grace_period,
+            trash,
+            trash_retention,
+            history,
+            history_depth,
+            delegates,
+            config,
+            cycles_settings,
+            last_top_up,
+            capacity,
+            migration_grants,
+            snapshot_lock,
+            limits,
+            deletion_cursors,
+            vault_registry,
+            totp_map,
+            attachments,
+            attachment_chunks,
+            records,
+            record_templates,
+            custom_records,
+            sheets,
+            folders,
+            tags,
+            item_labels,
+            search_index,
+            item_tokens,
+            vault_machines,
+            migration_exports,
+            user_audit_index
         }
     }
 }
@@ -3,17 +3,19 @@ use std::cell::RefCell;
 use candid::Principal;
 use ic_stable_structures::{
     memory_manager::{MemoryManager, VirtualMemory},
-    DefaultMemoryImpl, StableBTreeMap
+    DefaultMemoryImpl, StableBTreeMap, StableCell
 };
 
 use crate::vault_type::{
-    logins::LoginSiteKey, secure_notes::{SecureNote, SecureNoteKey}, spreadsheet::{ColumnData, ColumnKey, SpreadsheetKey, SpreadsheetValue}, vault_names::{VaultNameKey, VaultNameValue}
+    attachments::{AttachmentChunkKey, AttachmentKey, AttachmentManifest}, records::{RecordKey, TypedRecord}, templates::{CustomRecord, RecordTemplate, TemplateKey}, sheets::{SheetKey, SheetRecord}, organization::{Folder, FolderKey, ItemLabels, ItemLabelsKey, TagKey}, search::{ItemTokens, ItemTokensKey, SearchTokenKey}, audit_log::{AuditEvent, AuditRetention, UserAuditKey}, capacity::CapacityRecord, canister_config::CanisterConfig, cycles::{CyclesSettings, TopUpRecord}, history::{HistoryEntry, HistoryKey}, limits::Limits, logins::LoginSiteKey, pending_deletion::{DeletionCursor, PendingDeletion, PendingDeletionKey}, machine_grants::{MachineGrant, MachineGrantKey, VaultMachineKey}, migration::{MigrationExport, MigrationGrant}, secure_notes::{SecureNote, SecureNoteKey}, snapshot::SnapshotLock, spreadsheet::{ColumnData, ColumnKey, SpreadsheetKey, SpreadsheetValue}, totp::{TotpKey, TotpRecord}, trash::{TrashEntry, TrashKey}, vault_names::{VaultNameKey, VaultNameValue}, vault_registry::VaultRecord
 };
 
 // Stable memory for vaults
-type Memory = VirtualMemory<DefaultMemoryImpl>;
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
 
 pub type VaultNamesMap = RefCell<StableBTreeMap<VaultNameKey, VaultNameValue, Memory>>;
+// Every vault of every user, keyed like the names. A vault exists once it is registered here.
+pub type VaultRegistryMap = RefCell<StableBTreeMap<VaultNameKey, VaultRecord, Memory>>;
 
 // Stable memory for spreadsheets.
 pub type SpreadsheetMap = RefCell<StableBTreeMap<SpreadsheetKey, SpreadsheetValue, Memory>>;
@@ -24,7 +26,81 @@ pub type LoginsMap = RefCell<StableBTreeMap<SpreadsheetKey, SpreadsheetValue, Me
 pub type LoginsColumns = RefCell<StableBTreeMap<LoginSiteKey, Vec<u8>, Memory>>;
 pub type NotesMap = RefCell<StableBTreeMap<SecureNoteKey, SecureNote, Memory>>;
 
-// Stable memory for canister management 
+// Stable memory for encrypted TOTP seeds and their generation parameters.
+pub type TotpMap = RefCell<StableBTreeMap<TotpKey, TotpRecord, Memory>>;
+
+// Stable memory for attachment manifests and the encrypted chunks of their blobs.
+pub type AttachmentsMap = RefCell<StableBTreeMap<AttachmentKey, AttachmentManifest, Memory>>;
+pub type AttachmentChunksMap = RefCell<StableBTreeMap<AttachmentChunkKey, Vec<u8>, Memory>>;
+
+// Stable memory for payment cards and identity documents.
+pub type RecordsMap = RefCell<StableBTreeMap<RecordKey, TypedRecord, Memory>>;
+
+// Stable memory for client-defined record templates and the records made from them.
+pub type TemplatesMap = RefCell<StableBTreeMap<TemplateKey, RecordTemplate, Memory>>;
+pub type CustomRecordsMap = RefCell<StableBTreeMap<TemplateKey, CustomRecord, Memory>>;
+
+// Stable memory for the names and order of the sheets of each vault's flexible grid.
+pub type SheetsMap = RefCell<StableBTreeMap<SheetKey, SheetRecord, Memory>>;
+
+// Stable memory for folders, tags with their encrypted labels, and the folder and tags of items.
+pub type FoldersMap = RefCell<StableBTreeMap<FolderKey, Folder, Memory>>;
+pub type TagsMap = RefCell<StableBTreeMap<TagKey, Vec<u8>, Memory>>;
+pub type ItemLabelsMap = RefCell<StableBTreeMap<ItemLabelsKey, ItemLabels, Memory>>;
+
+// Stable memory for the blind index: the items carrying each token, and the tokens of each item.
+pub type SearchIndexMap = RefCell<StableBTreeMap<SearchTokenKey, (), Memory>>;
+pub type ItemTokensMap = RefCell<StableBTreeMap<ItemTokensKey, ItemTokens, Memory>>;
+
+// Stable memory for read-only grants given to machine principals.
+pub type MachineGrantsMap = RefCell<StableBTreeMap<MachineGrantKey, MachineGrant, Memory>>;
+pub type VaultMachinesMap = RefCell<StableBTreeMap<VaultMachineKey, u8, Memory>>;
+
+// Stable memory for the audit log, keyed by a sequence number that only ever grows.
+pub type AuditLog = RefCell<StableBTreeMap<u64, AuditEvent, Memory>>;
+pub type AuditRetentionState = RefCell<StableCell<AuditRetention, Memory>>;
+// The sequence numbers of each user's events, kept in step with the log.
+pub type UserAuditIndex = RefCell<StableBTreeMap<UserAuditKey, (), Memory>>;
+
+// Stable memory for destructive operations waiting out their grace period.
+pub type PendingDeletionsMap = RefCell<StableBTreeMap<PendingDeletionKey, PendingDeletion, Memory>>;
+pub type DeletionGracePeriod = RefCell<StableCell<u64, Memory>>;
+// Progress of the running deletions, so each timer tick carries on where the last one stopped.
+pub type DeletionCursorsMap = RefCell<StableBTreeMap<PendingDeletionKey, DeletionCursor, Memory>>;
+
+// Stable memory for removed items, kept per vault until restored, emptied or expired.
+pub type TrashMap = RefCell<StableBTreeMap<TrashKey, TrashEntry, Memory>>;
+pub type TrashRetention = RefCell<StableCell<u64, Memory>>;
+
+// Stable memory for prior values of overwritten login cells and notes, bounded per item.
+pub type HistoryMap = RefCell<StableBTreeMap<HistoryKey, HistoryEntry, Memory>>;
+pub type HistoryDepth = RefCell<StableCell<u32, Memory>>;
+
+// Stable memory for the delegates of a dedicated canister's owner, with the time they were added.
+pub type DelegatesMap = RefCell<StableBTreeMap<Principal, u64, Memory>>;
+
+// Stable memory for the install arguments the canister was set up with.
+pub type CanisterConfigState = RefCell<StableCell<CanisterConfig, Memory>>;
+
+// Stable memory for the cycles monitor: its thresholds and the outcome of the last top-up.
+pub type CyclesSettingsState = RefCell<StableCell<CyclesSettings, Memory>>;
+pub type LastTopUp = RefCell<StableCell<Option<TopUpRecord>, Memory>>;
+
+// Stable memory for the capacity state last acknowledged by the factory.
+pub type CapacityRecordState = RefCell<StableCell<CapacityRecord, Memory>>;
+
+// Stable memory for users' consent to export their data to another canister, keyed by user.
+pub type MigrationGrantsMap = RefCell<StableBTreeMap<Principal, MigrationGrant, Memory>>;
+// Stable memory for the progress of each user's export, keyed by user.
+pub type MigrationExportsMap = RefCell<StableBTreeMap<Principal, MigrationExport, Memory>>;
+
+// Stable memory for the limits controllers can change without a new wasm.
+pub type LimitsState = RefCell<StableCell<Limits, Memory>>;
+
+// Stable memory for the snapshot under way, if any. Survives upgrades so writes stay blocked.
+pub type SnapshotLockState = RefCell<StableCell<SnapshotLock, Memory>>;
+
+// Canister management. Rebuilt from the config and key management on init and upgrade.
 pub struct CanisterOwners {
     pub controller: Principal,
     pub user: Vec<Principal>,
@@ -46,5 +122,39 @@ pub struct GeneralState {
     pub logins_map: LoginsMap,
     pub logins_columns: LoginsColumns,
     pub notes_map: NotesMap,
-    pub vault_names_map: VaultNamesMap
+    pub vault_names_map: VaultNamesMap,
+    pub machine_grants: MachineGrantsMap,
+    pub audit_log: AuditLog,
+    pub audit_retention: AuditRetentionState,
+    pub pending_deletions: PendingDeletionsMap,
+    pub deletion_grace_period: DeletionGracePeriod,
+    pub trash: TrashMap,
+    pub trash_retention: TrashRetention,
+    pub history: HistoryMap,
+    pub history_depth: HistoryDepth,
+    pub delegates: DelegatesMap,
+    pub config: CanisterConfigState,
+    pub cycles_settings: CyclesSettingsState,
+    pub last_top_up: LastTopUp,
+    pub capacity: CapacityRecordState,
+    pub migration_grants: MigrationGrantsMap,
+    pub snapshot_lock: SnapshotLockState,
+    pub limits: LimitsState,
+    pub deletion_cursors: DeletionCursorsMap,
+    pub vault_registry: VaultRegistryMap,
+    pub totp_map: TotpMap,
+    pub attachments: AttachmentsMap,
+    pub attachment_chunks: AttachmentChunksMap,
+    pub records: RecordsMap,
+    pub record_templates: TemplatesMap,
+    pub custom_records: CustomRecordsMap,
+    pub sheets: SheetsMap,
+    pub folders: FoldersMap,
+    pub tags: TagsMap,
+    pub item_labels: ItemLabelsMap,
+    pub search_index: SearchIndexMap,
+    pub item_tokens: ItemTokensMap,
+    pub vault_machines: VaultMachinesMap,
+    pub migration_exports: MigrationExportsMap,
+    pub user_audit_index: UserAuditIndex
 }
@@ -1,38 +1,62 @@
-use ic_cdk::{api::canister_liquid_cycle_balance, call::Call};
-use crate::stable::types::CanisterOwnersState;
+use crate::{
+    api::key_api::DEFAULT_KEY_NAME,
+    stable::types::{CanisterOwnersState, GeneralState},
+    vault_type::canister_config::{CanisterConfig, InitArgs},
+};
 use candid::{Principal};
 
+// Wait before the first retry of a failed inter-canister call. Doubles with every attempt after that.
+const RETRY_BACKOFF_NS: u64 = 30 * 1_000_000_000; // 30 seconds
 
-// Define constants
-// Threshold at which the canister should request a top-up.
-// This must be a value sufficient to serve a request and continue storing data until the top-up is received.
-pub const MIN_CYCLES_BALANCE: u128 = 1_000_000_000;
+// Wait before retrying after `attempt` failed attempts.
+pub fn _retry_backoff_ns(attempt: u8) -> u64 {
+    RETRY_BACKOFF_NS.saturating_mul(1 << u32::from(attempt.saturating_sub(1)).min(16))
+}
 
-/// Maintains the status of the canister, performing any tasks needed to keep it operational.
-/// Every update call should trigger this function.
-pub fn maintain_status(canister_owners: &CanisterOwnersState) {
-    let can_cycles = canister_liquid_cycle_balance();
+// Stores the install arguments, falling back to the tier's user limit when none is given.
+pub fn _apply_init_args(args: InitArgs, default_max_users: u64, state: &GeneralState) -> Result<(), String> {
+    if args.owner == Principal::anonymous() {
+        return Err("owner can't be the anonymous principal".into());
+    }
+    let config = CanisterConfig {
+        owner: args.owner,
+        factory: args.factory,
+        vetkd_key_name: args.vetkd_key_name.unwrap_or_else(|| DEFAULT_KEY_NAME.to_string()),
+        max_users: args.max_users.unwrap_or(default_max_users),
+    };
+    ic_cdk::println!("Canister initialized with owner: {}, factory: {}", config.owner, config.factory);
+    state.config.borrow_mut().set(config);
+    Ok(())
+}
 
-    if can_cycles < MIN_CYCLES_BALANCE {
-        // get the owning principal (currently factory can)
-        let owner = canister_owners.borrow().controller;
-        // call the top_up method on the canister
-        let _result = Call::unbounded_wait(
-            owner,
-            "top_up",
-        );
+// Rebuilds the owners from the config: the owner first, then every user that holds a vetKey.
+pub fn _restore_owners(state: &GeneralState) {
+    let config = state.config.borrow().get().clone();
+    let mut owners = state.canister_owners.borrow_mut();
+    owners.controller = config.factory;
+    owners.user.clear();
+    if !config.is_initialised() {
+        return;
+    }
+    owners.user.push(config.owner);
+    for user_id in state.key_management.borrow().keys() {
+        if let Ok(user) = Principal::from_text(&user_id) {
+            if user != config.owner {
+                owners.user.push(user);
+            }
+        }
     }
 }
 
-pub fn _init_controllers(user: Principal, controller: Principal, canister_owners: &CanisterOwnersState) {
-    ic_cdk::println!("Canister initialized with user: {}, controller: {}", user, controller);
-    canister_owners.borrow_mut().user.push(user);
-    canister_owners.borrow_mut().controller = controller;
+// True for the canister's controllers and for the owning principal (factory canister).
+pub fn _is_controller(caller: Principal, canister_owners: &CanisterOwnersState) -> bool {
+    ic_cdk::api::is_controller(&caller) || canister_owners.borrow().controller == caller
 }
 
 pub fn _inspect_message(always_accept: &Vec<String>, canister_owners: &CanisterOwnersState) {
     // if the message sender is known to us then accept the message
     if canister_owners.borrow().user.contains(&ic_cdk::api::msg_caller())
+        || _is_controller(ic_cdk::api::msg_caller(), canister_owners)
         || always_accept.contains(&ic_cdk::api::msg_method_name())
     {
         ic_cdk::api::accept_message();
@@ -0,0 +1,266 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::storable::Storable;
+
+// Kinds of vault items an attachment, folder or tag can belong to.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum ItemKind {
+    SpreadsheetCell,
+    LoginCell,
+    LoginColumn,
+    Note,
+    Totp,
+    PaymentCard,
+    Identity,
+    CustomRecord,
+    // A row of a sheet of the flexible grid; y is the row.
+    GridRow,
+}
+impl ItemKind {
+    pub fn to_byte(self) -> u8 {
+        self as u8
+    }
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(ItemKind::SpreadsheetCell),
+            1 => Some(ItemKind::LoginCell),
+            2 => Some(ItemKind::LoginColumn),
+            3 => Some(ItemKind::Note),
+            4 => Some(ItemKind::Totp),
+            5 => Some(ItemKind::PaymentCard),
+            6 => Some(ItemKind::Identity),
+            7 => Some(ItemKind::CustomRecord),
+            8 => Some(ItemKind::GridRow),
+            _ => None,
+        }
+    }
+}
+
+// Leads stored item references. References stored before sheets existed are the kind, x and y
+// alone, and their grid rows kept the sheet in x.
+pub const ITEM_REF_VERSION: u8 = 1;
+
+// Identifies an item within a vault. sheet is only used for spreadsheet cells and grid rows, y
+// for cells and grid rows.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct ItemRef {
+    pub kind: ItemKind,
+    pub sheet: u8,
+    pub x: u8,
+    pub y: u8,
+}
+impl ItemRef {
+    // Bounds every item of a vault, for ranges over keys ending in an item.
+    pub const FIRST: ItemRef = ItemRef { kind: ItemKind::SpreadsheetCell, sheet: 0, x: 0, y: 0 };
+    pub const LAST: ItemRef = ItemRef { kind: ItemKind::GridRow, sheet: u8::MAX, x: u8::MAX, y: u8::MAX };
+    // Kind, sheet, x and y, as clients send them.
+    pub const SIZE: usize = 4;
+    pub const STORED_SIZE: usize = 1 + Self::SIZE;
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        [self.kind.to_byte(), self.sheet, self.x, self.y]
+    }
+    // None for kinds this canister doesn't know.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(Self { kind: ItemKind::from_byte(bytes[0])?, sheet: bytes[1], x: bytes[2], y: bytes[3] })
+    }
+
+    pub fn to_stored(self) -> [u8; Self::STORED_SIZE] {
+        let [kind, sheet, x, y] = self.to_bytes();
+        [ITEM_REF_VERSION, kind, sheet, x, y]
+    }
+    // Reads a stored reference of either layout, told apart by their size.
+    pub fn from_stored(bytes: &[u8]) -> Self {
+        let item = if bytes.len() == Self::STORED_SIZE {
+            Self::from_bytes(&bytes[1..])
+        } else {
+            ItemKind::from_byte(bytes[0]).map(|kind| match kind {
+                ItemKind::GridRow => Self { kind, sheet: bytes[1], x: 0, y: bytes[2] },
+                _ => Self { kind, sheet: 0, x: bytes[1], y: bytes[2] },
+            })
+        };
+        item.expect("stored item reference of an unknown kind")
+    }
+}
+
+// Identifies an attachment. Principals are length-prefixed so a vault's attachments sort
+// together and new ids can be taken from the end of that range.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AttachmentKey {
+    pub principals: Vec<u8>,
+    pub id: u64,
+}
+impl AttachmentKey {
+    pub fn new(user_id: Principal, vault_id: Principal, id: u64) -> Self {
+        let mut principals = Vec::new();
+        principals.extend(user_id.as_slice());
+        principals.extend(vault_id.as_slice());
+        Self { principals, id }
+    }
+    pub fn chunk(&self, index: u32) -> AttachmentChunkKey {
+        AttachmentChunkKey { principals: self.principals.clone(), id: self.id, index }
+    }
+}
+impl Storable for AttachmentKey {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 512, is_fixed_size: false };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.push(self.principals.len() as u8);
+        bytes.extend(self.principals.iter());
+        bytes.extend(self.id.to_be_bytes());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let principals_size = usize::from(bytes[0]);
+        let principals = bytes[1..1 + principals_size].to_vec();
+        let id = u64::from_be_bytes(bytes[1 + principals_size..9 + principals_size].try_into().unwrap());
+        Self { principals, id }
+    }
+}
+
+// One chunk of an attachment's ciphertext. The chunks of an attachment sort together, in order.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AttachmentChunkKey {
+    pub principals: Vec<u8>,
+    pub id: u64,
+    pub index: u32,
+}
+impl Storable for AttachmentChunkKey {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 512, is_fixed_size: false };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.push(self.principals.len() as u8);
+        bytes.extend(self.principals.iter());
+        bytes.extend(self.id.to_be_bytes());
+        bytes.extend(self.index.to_be_bytes());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let principals_size = usize::from(bytes[0]);
+        let id_end = 9 + principals_size;
+        Self {
+            principals: bytes[1..1 + principals_size].to_vec(),
+            id: u64::from_be_bytes(bytes[1 + principals_size..id_end].try_into().unwrap()),
+            index: u32::from_be_bytes(bytes[id_end..id_end + 4].try_into().unwrap()),
+        }
+    }
+}
+
+// Link flags of stored manifests. Manifests stored before sheets existed flag no link with 0 and
+// a link with 1, followed by its kind, x and y either way.
+const LINK_NONE: u8 = 2;
+const LINK_STORED: u8 = 3;
+
+// What is known about an attachment besides its chunks. The name is ciphertext; size and hash
+// are of the encrypted blob.
+#[derive(Clone, PartialEq, Debug)]
+pub struct AttachmentManifest {
+    pub size: u64,
+    // Size of every chunk but the last, which holds the rest.
+    pub chunk_size: u32,
+    pub sha256: Vec<u8>,
+    pub link: Option<ItemRef>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    // Set once every chunk is in and the hash matched. Only complete attachments can be read.
+    pub complete: bool,
+    pub name: Vec<u8>,
+}
+impl AttachmentManifest {
+    pub fn chunk_count(&self) -> u32 {
+        self.size.div_ceil(u64::from(self.chunk_size)) as u32
+    }
+    // Expected length of the chunk at `index`.
+    pub fn chunk_len(&self, index: u32) -> usize {
+        let start = u64::from(index) * u64::from(self.chunk_size);
+        self.size.saturating_sub(start).min(u64::from(self.chunk_size)) as usize
+    }
+}
+impl Storable for AttachmentManifest {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(64 + self.name.len());
+        bytes.extend(self.size.to_be_bytes());
+        bytes.extend(self.chunk_size.to_be_bytes());
+        bytes.extend(self.created_at.to_be_bytes());
+        bytes.extend(self.updated_at.to_be_bytes());
+        bytes.push(u8::from(self.complete));
+        match self.link {
+            Some(link) => {
+                bytes.push(LINK_STORED);
+                bytes.extend(link.to_stored());
+            }
+            None => bytes.push(LINK_NONE),
+        }
+        bytes.push(self.sha256.len() as u8);
+        bytes.extend(self.sha256.iter());
+        bytes.extend(self.name.iter());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let (link, link_end) = match bytes[29] {
+            0 => (None, 33),
+            1 => (Some(ItemRef::from_stored(&bytes[30..33])), 33),
+            LINK_NONE => (None, 30),
+            _ => (Some(ItemRef::from_stored(&bytes[30..30 + ItemRef::STORED_SIZE])), 30 + ItemRef::STORED_SIZE),
+        };
+        let hash_end = link_end + 1 + usize::from(bytes[link_end]);
+        Self {
+            size: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            chunk_size: u32::from_be_bytes(bytes[8..12].try_into().unwrap()),
+            created_at: u64::from_be_bytes(bytes[12..20].try_into().unwrap()),
+            updated_at: u64::from_be_bytes(bytes[20..28].try_into().unwrap()),
+            complete: bytes[28] != 0,
+            link,
+            sha256: bytes[link_end + 1..hash_end].to_vec(),
+            name: bytes[hash_end..].to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::storable::Storable;
+
+    use super::{AttachmentManifest, ItemKind, ItemRef};
+
+    fn manifest(link: Option<ItemRef>) -> AttachmentManifest {
+        AttachmentManifest { size: 10, chunk_size: 4, sha256: vec![7; 32], link, created_at: 1, updated_at: 2, complete: true, name: b"name".to_vec() }
+    }
+
+    // References round-trip with their sheet, and those stored before sheets existed read as
+    // sheet 0, or as the sheet a grid row kept in x.
+    #[test]
+    fn item_refs_read_both_layouts() {
+        let cell = ItemRef { kind: ItemKind::SpreadsheetCell, sheet: 3, x: 4, y: 5 };
+        assert_eq!(ItemRef::from_stored(&cell.to_stored()), cell);
+        assert_eq!(ItemRef::from_stored(&[0, 4, 5]), ItemRef { kind: ItemKind::SpreadsheetCell, sheet: 0, x: 4, y: 5 });
+        assert_eq!(ItemRef::from_stored(&[8, 2, 9]), ItemRef { kind: ItemKind::GridRow, sheet: 2, x: 0, y: 9 });
+        assert_eq!(ItemRef::from_bytes(&[9, 0, 0, 0]), None);
+
+        for link in [None, Some(cell)] {
+            let stored = manifest(link);
+            assert_eq!(AttachmentManifest::from_bytes(stored.to_bytes()), stored);
+        }
+        let mut legacy = manifest(None).to_bytes().into_owned();
+        legacy.splice(29..30, [1, 0, 4, 5]);
+        assert_eq!(AttachmentManifest::from_bytes(legacy.into()).link, Some(ItemRef { kind: ItemKind::SpreadsheetCell, sheet: 0, x: 4, y: 5 }));
+    }
+}
@@ -0,0 +1,276 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::storable::Storable;
+
+// Kinds of operation recorded in the audit log. Stored as a single byte, so variants must
+// only ever be appended.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AuditOp {
+    VaultNamesSync,
+    SpreadsheetColumnsSync,
+    SpreadsheetSync,
+    SpreadsheetDelete,
+    LoginFullSync,
+    LoginMetadataSync,
+    LoginMetadataDelete,
+    LoginDataSync,
+    LoginDataDelete,
+    SecureNotesSync,
+    GlobalSync,
+    DeleteVault,
+    PurgeUser,
+    DeriveVetKey,
+    MachineGrant,
+    MachineRevoke,
+    RetentionUpdate,
+    DeletionScheduled,
+    DeletionCancelled,
+    GracePeriodUpdate,
+    TrashRestore,
+    TrashEmpty,
+    ItemRollback,
+    HistoryDepthUpdate,
+    DelegateAdded,
+    DelegateRemoved,
+    CyclesSettingsUpdate,
+    MigrationAuthorized,
+    MigrationCompleted,
+    MigrationImported,
+    SnapshotTaken,
+    SnapshotRestored,
+    ConfigUpdate,
+    VaultMetadataUpdate,
+    TotpSync,
+    TotpDelete,
+    AttachmentUpload,
+    AttachmentLink,
+    AttachmentDelete,
+    PaymentCardsSync,
+    PaymentCardsDelete,
+    IdentitiesSync,
+    IdentitiesDelete,
+    TemplateUpdate,
+    TemplateDelete,
+    CustomRecordsSync,
+    CustomRecordsDelete,
+    GridEdit,
+    SheetCreate,
+    SheetRename,
+    SheetReorder,
+    SheetDelete,
+    FolderCreate,
+    FolderUpdate,
+    FolderDelete,
+    TagUpdate,
+    TagDelete,
+    ItemLabelsUpdate,
+    SearchTokensSync,
+    Unknown,
+}
+impl AuditOp {
+    const ALL: [AuditOp; 59] = [
+        AuditOp::VaultNamesSync,
+        AuditOp::SpreadsheetColumnsSync,
+        AuditOp::SpreadsheetSync,
+        AuditOp::SpreadsheetDelete,
+        AuditOp::LoginFullSync,
+        AuditOp::LoginMetadataSync,
+        AuditOp::LoginMetadataDelete,
+        AuditOp::LoginDataSync,
+        AuditOp::LoginDataDelete,
+        AuditOp::SecureNotesSync,
+        AuditOp::GlobalSync,
+        AuditOp::DeleteVault,
+        AuditOp::PurgeUser,
+        AuditOp::DeriveVetKey,
+        AuditOp::MachineGrant,
+        AuditOp::MachineRevoke,
+        AuditOp::RetentionUpdate,
+        AuditOp::DeletionScheduled,
+        AuditOp::DeletionCancelled,
+        AuditOp::GracePeriodUpdate,
+        AuditOp::TrashRestore,
+        AuditOp::TrashEmpty,
+        AuditOp::ItemRollback,
+        AuditOp::HistoryDepthUpdate,
+        AuditOp::DelegateAdded,
+        AuditOp::DelegateRemoved,
+        AuditOp::CyclesSettingsUpdate,
+        AuditOp::MigrationAuthorized,
+        AuditOp::MigrationCompleted,
+        AuditOp::MigrationImported,
+        AuditOp::SnapshotTaken,
+        AuditOp::SnapshotRestored,
+        AuditOp::ConfigUpdate,
+        AuditOp::VaultMetadataUpdate,
+        AuditOp::TotpSync,
+        AuditOp::TotpDelete,
+        AuditOp::AttachmentUpload,
+        AuditOp::AttachmentLink,
+        AuditOp::AttachmentDelete,
+        AuditOp::PaymentCardsSync,
+        AuditOp::PaymentCardsDelete,
+        AuditOp::IdentitiesSync,
+        AuditOp::IdentitiesDelete,
+        AuditOp::TemplateUpdate,
+        AuditOp::TemplateDelete,
+        AuditOp::CustomRecordsSync,
+        AuditOp::CustomRecordsDelete,
+        AuditOp::GridEdit,
+        AuditOp::SheetCreate,
+        AuditOp::SheetRename,
+        AuditOp::SheetReorder,
+        AuditOp::SheetDelete,
+        AuditOp::FolderCreate,
+        AuditOp::FolderUpdate,
+        AuditOp::FolderDelete,
+        AuditOp::TagUpdate,
+        AuditOp::TagDelete,
+        AuditOp::ItemLabelsUpdate,
+        AuditOp::SearchTokensSync,
+    ];
+
+    pub fn to_byte(self) -> u8 {
+        self as u8
+    }
+    pub fn from_byte(byte: u8) -> Self {
+        Self::ALL.get(usize::from(byte)).copied().unwrap_or(AuditOp::Unknown)
+    }
+}
+
+// A single audit event. Holds who did what to which vault and how many items were touched,
+// never any of the data itself.
+pub struct AuditEvent {
+    pub timestamp: u64,
+    pub op: AuditOp,
+    pub item_count: u32,
+    pub caller: Vec<u8>,
+    // Owner of the data the event is about. Usually the caller.
+    pub user: Vec<u8>,
+    // Empty when the operation is not about a single vault.
+    pub vault: Vec<u8>,
+}
+impl AuditEvent {
+    pub fn new(caller: Principal, user_id: Principal, vault_id: Option<Principal>, op: AuditOp, item_count: u32, timestamp: u64) -> Self {
+        Self {
+            timestamp,
+            op,
+            item_count,
+            caller: caller.as_slice().to_vec(),
+            user: user_id.as_slice().to_vec(),
+            vault: vault_id.map(|v| v.as_slice().to_vec()).unwrap_or_default(),
+        }
+    }
+}
+impl Storable for AuditEvent {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 128, is_fixed_size: false };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.extend(self.timestamp.to_be_bytes());
+        bytes.push(self.op.to_byte());
+        bytes.extend(self.item_count.to_be_bytes());
+        bytes.push(self.caller.len() as u8);
+        bytes.extend(self.caller.iter());
+        bytes.push(self.user.len() as u8);
+        bytes.extend(self.user.iter());
+        bytes.extend(self.vault.iter());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let timestamp = u64::from_be_bytes(bytes[0..8].try_into().unwrap());
+        let op = AuditOp::from_byte(bytes[8]);
+        let item_count = u32::from_be_bytes(bytes[9..13].try_into().unwrap());
+        let mut index = 13;
+        let caller_size = usize::from(bytes[index]);
+        let caller = bytes[index + 1..index + 1 + caller_size].to_vec();
+        index += 1 + caller_size;
+        let user_size = usize::from(bytes[index]);
+        let user = bytes[index + 1..index + 1 + user_size].to_vec();
+        index += 1 + user_size;
+        let vault = bytes[index..].to_vec();
+        Self {
+            timestamp,
+            op,
+            item_count,
+            caller,
+            user,
+            vault,
+        }
+    }
+}
+
+// How long audit events are kept. Whichever limit is hit first applies; 0 disables a limit.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct AuditRetention {
+    pub max_entries: u64,
+    pub max_age_ns: u64,
+}
+impl Default for AuditRetention {
+    fn default() -> Self {
+        Self {
+            max_entries: 1_000_000,
+            max_age_ns: 365 * 24 * 60 * 60 * 1_000_000_000,
+        }
+    }
+}
+impl Storable for AuditRetention {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 16, is_fixed_size: true };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.extend(self.max_entries.to_be_bytes());
+        bytes.extend(self.max_age_ns.to_be_bytes());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            max_entries: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            max_age_ns: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+        }
+    }
+}
+
+// Indexes the audit log by the user an event is about, so a user's events are one range.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UserAuditKey {
+    pub user: Vec<u8>,
+    pub seq: u64,
+}
+impl UserAuditKey {
+    pub fn new(user: &[u8], seq: u64) -> Self {
+        Self { user: user.to_vec(), seq }
+    }
+}
+impl Storable for UserAuditKey {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 38, is_fixed_size: false };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.push(self.user.len() as u8);
+        bytes.extend(self.user.iter());
+        bytes.extend(self.seq.to_be_bytes());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let user_size = usize::from(bytes[0]);
+        Self {
+            user: bytes[1..1 + user_size].to_vec(),
+            seq: u64::from_be_bytes(bytes[1 + user_size..9 + user_size].try_into().unwrap()),
+        }
+    }
+}
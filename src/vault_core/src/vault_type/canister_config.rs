@@ -0,0 +1,76 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::storable::Storable;
+
+use crate::api::key_api::DEFAULT_KEY_NAME;
+
+// Arguments the factory installs a canister with. Passed again on upgrade to change them.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InitArgs {
+    // The first user of a shared canister, or the owner of a dedicated one.
+    pub owner: Principal,
+    // The factory canister: tops the canister up and is told when it's full.
+    pub factory: Principal,
+    // Defaults to the mainnet key.
+    pub vetkd_key_name: Option<String>,
+    // Defaults to the tier's limit.
+    pub max_users: Option<u64>,
+}
+
+// Install arguments as applied to the canister. Kept in stable memory so upgrades don't lose them.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CanisterConfig {
+    pub owner: Principal,
+    pub factory: Principal,
+    pub vetkd_key_name: String,
+    pub max_users: u64,
+}
+impl CanisterConfig {
+    pub fn is_initialised(&self) -> bool {
+        self.owner != Principal::anonymous()
+    }
+}
+impl Default for CanisterConfig {
+    fn default() -> Self {
+        Self {
+            owner: Principal::anonymous(),
+            factory: Principal::anonymous(),
+            vetkd_key_name: DEFAULT_KEY_NAME.to_string(),
+            max_users: 0,
+        }
+    }
+}
+impl Storable for CanisterConfig {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.push(self.owner.as_slice().len() as u8);
+        bytes.extend(self.owner.as_slice());
+        bytes.push(self.factory.as_slice().len() as u8);
+        bytes.extend(self.factory.as_slice());
+        bytes.extend(self.max_users.to_be_bytes());
+        bytes.extend(self.vetkd_key_name.as_bytes());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let owner_size = usize::from(bytes[0]);
+        let owner = Principal::from_slice(&bytes[1..1 + owner_size]);
+        let factory_start = 2 + owner_size;
+        let factory_size = usize::from(bytes[factory_start - 1]);
+        let factory = Principal::from_slice(&bytes[factory_start..factory_start + factory_size]);
+        let max_users_start = factory_start + factory_size;
+        let max_users = u64::from_be_bytes(bytes[max_users_start..max_users_start + 8].try_into().unwrap());
+        let vetkd_key_name = String::from_utf8_lossy(&bytes[max_users_start + 8..]).into_owned();
+        Self {
+            owner,
+            factory,
+            vetkd_key_name,
+            max_users,
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::storable::Storable;
+
+// How close a shared canister is to its user limit.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CapacityState {
+    Open,
+    // Still taking users, but the factory should have the next canister ready.
+    NearFull,
+    // No new users. They are sent to the redirect canister instead.
+    Closed,
+}
+impl CapacityState {
+    fn to_byte(self) -> u8 {
+        match self {
+            CapacityState::Open => 0,
+            CapacityState::NearFull => 1,
+            CapacityState::Closed => 2,
+        }
+    }
+    fn from_byte(byte: u8) -> Self {
+        match byte {
+            1 => CapacityState::NearFull,
+            2 => CapacityState::Closed,
+            _ => CapacityState::Open,
+        }
+    }
+}
+
+// What the factory was last told, and where it wants new users to go.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CapacityRecord {
+    pub notified: CapacityState,
+    pub notified_at: u64,
+    pub redirect: Option<Principal>,
+}
+impl Default for CapacityRecord {
+    fn default() -> Self {
+        Self {
+            notified: CapacityState::Open,
+            notified_at: 0,
+            redirect: None,
+        }
+    }
+}
+impl Storable for CapacityRecord {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 39, is_fixed_size: false };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.push(self.notified.to_byte());
+        bytes.extend(self.notified_at.to_be_bytes());
+        if let Some(redirect) = self.redirect {
+            bytes.extend(redirect.as_slice());
+        }
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            notified: CapacityState::from_byte(bytes[0]),
+            notified_at: u64::from_be_bytes(bytes[1..9].try_into().unwrap()),
+            redirect: if bytes.len() > 9 { Some(Principal::from_slice(&bytes[9..])) } else { None },
+        }
+    }
+}
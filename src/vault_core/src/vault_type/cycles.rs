@@ -0,0 +1,94 @@
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::storable::Storable;
+
+use crate::api::cycles_api::{DEFAULT_CYCLES_CHECK_INTERVAL_NS, DEFAULT_MIN_CYCLES_BALANCE, DEFAULT_TOP_UP_AMOUNT};
+
+// When the cycles monitor asks the factory for a top-up, and for how much.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CyclesSettings {
+    // Balance under which a top-up is requested. Must be enough to keep serving requests until it arrives.
+    pub min_balance: u128,
+    pub top_up_amount: u128,
+    pub check_interval_ns: u64,
+}
+impl Default for CyclesSettings {
+    fn default() -> Self {
+        Self {
+            min_balance: DEFAULT_MIN_CYCLES_BALANCE,
+            top_up_amount: DEFAULT_TOP_UP_AMOUNT,
+            check_interval_ns: DEFAULT_CYCLES_CHECK_INTERVAL_NS,
+        }
+    }
+}
+impl Storable for CyclesSettings {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 40, is_fixed_size: true };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.extend(self.min_balance.to_be_bytes());
+        bytes.extend(self.top_up_amount.to_be_bytes());
+        bytes.extend(self.check_interval_ns.to_be_bytes());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            min_balance: u128::from_be_bytes(bytes[0..16].try_into().unwrap()),
+            top_up_amount: u128::from_be_bytes(bytes[16..32].try_into().unwrap()),
+            check_interval_ns: u64::from_be_bytes(bytes[32..40].try_into().unwrap()),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TopUpStatus {
+    Succeeded,
+    Failed,
+}
+
+// Outcome of the last top-up request, after all of its retries.
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct TopUpRecord {
+    pub status: TopUpStatus,
+    // When the last attempt finished.
+    pub finished_at: u64,
+    // Balance when the monitor decided to top up.
+    pub balance: u128,
+    pub amount: u128,
+    pub attempts: u8,
+    // Why the last attempt failed. Empty on success.
+    pub error: String,
+}
+impl Storable for TopUpRecord {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.push(u8::from(self.status == TopUpStatus::Succeeded));
+        bytes.extend(self.finished_at.to_be_bytes());
+        bytes.extend(self.balance.to_be_bytes());
+        bytes.extend(self.amount.to_be_bytes());
+        bytes.push(self.attempts);
+        bytes.extend(self.error.as_bytes());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            status: if bytes[0] > 0 { TopUpStatus::Succeeded } else { TopUpStatus::Failed },
+            finished_at: u64::from_be_bytes(bytes[1..9].try_into().unwrap()),
+            balance: u128::from_be_bytes(bytes[9..25].try_into().unwrap()),
+            amount: u128::from_be_bytes(bytes[25..41].try_into().unwrap()),
+            attempts: bytes[41],
+            error: String::from_utf8_lossy(&bytes[42..]).into_owned(),
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::storable::Storable;
+
+// Items whose previous values are kept.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum HistoryKind {
+    LoginCell,
+    Note,
+}
+impl HistoryKind {
+    pub fn to_byte(self) -> u8 {
+        self as u8
+    }
+    pub fn from_byte(byte: u8) -> Self {
+        if byte == 0 { HistoryKind::LoginCell } else { HistoryKind::Note }
+    }
+}
+
+// Identifies a login cell or note within a vault. y is unused for notes.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct HistoryItem {
+    pub kind: HistoryKind,
+    pub x: u8,
+    pub y: u8,
+}
+
+// Identifies one prior value of an item. All revisions of an item sort together, oldest first.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HistoryKey {
+    pub principals: Vec<u8>,
+    pub kind: HistoryKind,
+    pub x: u8,
+    // Unused for notes.
+    pub y: u8,
+    pub revision: u64,
+}
+impl HistoryKey {
+    pub fn new(user_id: Principal, vault_id: Principal, item: HistoryItem, revision: u64) -> Self {
+        let mut principals = Vec::new();
+        principals.extend(user_id.as_slice());
+        principals.extend(vault_id.as_slice());
+        Self { principals, kind: item.kind, x: item.x, y: item.y, revision }
+    }
+    // Same item, different revision.
+    pub fn with_revision(&self, revision: u64) -> Self {
+        Self { revision, ..self.clone() }
+    }
+}
+impl Storable for HistoryKey {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 512, is_fixed_size: false };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.push(self.principals.len() as u8);
+        bytes.extend(self.principals.iter());
+        bytes.push(self.kind.to_byte());
+        bytes.push(self.x);
+        bytes.push(self.y);
+        bytes.extend(self.revision.to_be_bytes());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let principals_size = usize::from(bytes[0]);
+        let index = 1 + principals_size;
+        Self {
+            principals: bytes[1..index].to_vec(),
+            kind: HistoryKind::from_byte(bytes[index]),
+            x: bytes[index + 1],
+            y: bytes[index + 2],
+            revision: u64::from_be_bytes(bytes[index + 3..index + 11].try_into().unwrap()),
+        }
+    }
+}
+
+// A value an item held before it was overwritten.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Revision {
+    pub item: HistoryItem,
+    // Note label. Empty for login cells.
+    pub label: Vec<u8>,
+    // Cell data, or note body.
+    pub data: Vec<u8>,
+}
+
+pub struct HistoryEntry {
+    pub replaced_at: u64,
+    pub label: Vec<u8>,
+    pub data: Vec<u8>,
+}
+impl Storable for HistoryEntry {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.extend(self.replaced_at.to_be_bytes());
+        bytes.extend((self.label.len() as u32).to_be_bytes());
+        bytes.extend(self.label.iter());
+        bytes.extend(self.data.iter());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let replaced_at = u64::from_be_bytes(bytes[0..8].try_into().unwrap());
+        let label_size = u32::from_be_bytes(bytes[8..12].try_into().unwrap()) as usize;
+        Self {
+            replaced_at,
+            label: bytes[12..12 + label_size].to_vec(),
+            data: bytes[12 + label_size..].to_vec(),
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::storable::Storable;
+
+use crate::api::config_api::{DEFAULT_MAX_USER_STORAGE, DEFAULT_MAX_VAULTS_PER_USER, DEFAULT_MAX_VAULT_SIZE_BYTES, DEFAULT_VETKD_MAX_INPUT_BYTES, PREMIUM_MAX_VAULTS_PER_USER, PREMIUM_MAX_VAULT_SIZE_BYTES};
+
+// Service tiers. The shared canister serves the free tier, dedicated canisters the premium one.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Tier {
+    Free,
+    Premium,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TierLimits {
+    pub max_vaults_per_user: u64,
+    pub max_vault_size_bytes: u64,
+}
+impl TierLimits {
+    pub fn storage_per_user(&self) -> u64 {
+        self.max_vaults_per_user.saturating_mul(self.max_vault_size_bytes)
+    }
+    fn to_bytes(self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        bytes[0..8].copy_from_slice(&self.max_vaults_per_user.to_be_bytes());
+        bytes[8..16].copy_from_slice(&self.max_vault_size_bytes.to_be_bytes());
+        bytes
+    }
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            max_vaults_per_user: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            max_vault_size_bytes: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+        }
+    }
+}
+
+// Limits controllers can change at runtime, with an override per tier.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Limits {
+    // Storage the canister sets aside for user data, which bounds how many users it can take.
+    pub max_user_storage_bytes: u64,
+    pub vetkd_max_input_bytes: u32,
+    pub free: TierLimits,
+    pub premium: TierLimits,
+}
+impl Limits {
+    pub fn tier(&self, tier: Tier) -> TierLimits {
+        match tier {
+            Tier::Free => self.free,
+            Tier::Premium => self.premium,
+        }
+    }
+}
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_user_storage_bytes: DEFAULT_MAX_USER_STORAGE,
+            vetkd_max_input_bytes: DEFAULT_VETKD_MAX_INPUT_BYTES,
+            free: TierLimits { max_vaults_per_user: DEFAULT_MAX_VAULTS_PER_USER, max_vault_size_bytes: DEFAULT_MAX_VAULT_SIZE_BYTES },
+            premium: TierLimits { max_vaults_per_user: PREMIUM_MAX_VAULTS_PER_USER, max_vault_size_bytes: PREMIUM_MAX_VAULT_SIZE_BYTES },
+        }
+    }
+}
+impl Storable for Limits {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 44, is_fixed_size: true };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(44);
+        bytes.extend(self.max_user_storage_bytes.to_be_bytes());
+        bytes.extend(self.vetkd_max_input_bytes.to_be_bytes());
+        bytes.extend(self.free.to_bytes());
+        bytes.extend(self.premium.to_bytes());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            max_user_storage_bytes: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            vetkd_max_input_bytes: u32::from_be_bytes(bytes[8..12].try_into().unwrap()),
+            free: TierLimits::from_bytes(&bytes[12..28]),
+            premium: TierLimits::from_bytes(&bytes[28..44]),
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use candid::Principal;
+use ic_stable_structures::storable::Storable;
+
+// Identifies a grant given to a machine principal (CI runner, server, ...) over a user's vault.
+// The machine comes first so all grants held by a machine can be found together.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MachineGrantKey {
+    pub machine: Vec<u8>,
+    // Length of the user_id at the start of principals, so the grant can be traced back to its vault.
+    pub user_size: u8,
+    // Combination of user_id and vault_id, as used by the other vault keys.
+    pub principals: Vec<u8>,
+}
+impl MachineGrantKey {
+    pub fn new(machine: Principal, user_id: Principal, vault_id: Principal) -> Self {
+        let mut principals = Vec::new();
+        principals.extend(user_id.as_slice());
+        principals.extend(vault_id.as_slice());
+        Self {
+            machine: machine.as_slice().to_vec(),
+            user_size: user_id.as_slice().len() as u8,
+            principals,
+        }
+    }
+    pub fn principals_match(&self, principals: &[u8]) -> bool {
+        self.principals == principals
+    }
+    // Every grant held by the machine. Principals are at most 29 bytes each.
+    pub fn machine_range(machine: Principal) -> std::ops::RangeInclusive<Self> {
+        let machine = machine.as_slice().to_vec();
+        Self { machine: machine.clone(), user_size: 0, principals: Vec::new() }..=Self { machine, user_size: u8::MAX, principals: vec![u8::MAX; 58] }
+    }
+    pub fn user_id(&self) -> Principal {
+        Principal::from_slice(&self.principals[..usize::from(self.user_size)])
+    }
+    pub fn vault_id(&self) -> Principal {
+        Principal::from_slice(&self.principals[usize::from(self.user_size)..])
+    }
+}
+impl Storable for MachineGrantKey {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 512, is_fixed_size: false };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.push(self.machine.len() as u8);
+        bytes.extend(self.machine.iter());
+        bytes.push(self.user_size);
+        bytes.extend(self.principals.iter());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let machine_size = usize::from(bytes[0]);
+        let machine = bytes[1..1 + machine_size].to_vec();
+        let user_size = bytes[1 + machine_size];
+        let principals = bytes[2 + machine_size..].to_vec();
+        Self {
+            machine,
+            user_size,
+            principals,
+        }
+    }
+}
+
+// What a machine is allowed to read. Either the whole vault, or a set of login
+// columns (x) and secure notes (index). Grants are always read-only.
+pub struct MachineGrant {
+    // Expiry in nanoseconds since the epoch (IC time). 0 means the grant never expires.
+    pub expires_at: u64,
+    pub whole_vault: bool,
+    pub login_columns: Vec<u8>,
+    pub notes: Vec<u8>,
+}
+impl MachineGrant {
+    pub fn new(expires_at: u64, whole_vault: bool, login_columns: Vec<u8>, notes: Vec<u8>) -> Self {
+        Self { expires_at, whole_vault, login_columns, notes }
+    }
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
+    }
+}
+impl Storable for MachineGrant {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.extend(self.expires_at.to_be_bytes());
+        bytes.push(u8::from(self.whole_vault));
+        bytes.extend((self.login_columns.len() as u16).to_be_bytes());
+        bytes.extend(self.login_columns.iter());
+        bytes.extend(self.notes.iter());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let mut expires_at = [0u8; 8];
+        expires_at.copy_from_slice(&bytes[0..8]);
+        let whole_vault = bytes[8] > 0;
+        let columns_size = usize::from(u16::from_be_bytes([bytes[9], bytes[10]]));
+        let login_columns = bytes[11..11 + columns_size].to_vec();
+        let notes = bytes[11 + columns_size..].to_vec();
+        Self {
+            expires_at: u64::from_be_bytes(expires_at),
+            whole_vault,
+            login_columns,
+            notes,
+        }
+    }
+}
+
+// Indexes grants by vault, as their keys start with the machine. The grants of a vault are one
+// range of this index; the value is the grant's user_size.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VaultMachineKey {
+    pub principals: Vec<u8>,
+    pub machine: Vec<u8>,
+}
+impl VaultMachineKey {
+    pub fn of(key: &MachineGrantKey) -> Self {
+        Self { principals: key.principals.clone(), machine: key.machine.clone() }
+    }
+    // Every machine of the vault. Principals are at most 29 bytes.
+    pub fn vault_range(principals: &[u8]) -> std::ops::RangeInclusive<Self> {
+        Self { principals: principals.to_vec(), machine: Vec::new() }..=Self { principals: principals.to_vec(), machine: vec![u8::MAX; 29] }
+    }
+    pub fn grant_key(&self, user_size: u8) -> MachineGrantKey {
+        MachineGrantKey { machine: self.machine.clone(), user_size, principals: self.principals.clone() }
+    }
+}
+impl Storable for VaultMachineKey {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 512, is_fixed_size: false };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.push(self.principals.len() as u8);
+        bytes.extend(self.principals.iter());
+        bytes.extend(self.machine.iter());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let principals_size = usize::from(bytes[0]);
+        Self {
+            principals: bytes[1..1 + principals_size].to_vec(),
+            machine: bytes[1 + principals_size..].to_vec(),
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::storable::Storable;
+use sha2::{Digest, Sha256};
+
+// What a migration chunk holds. Vault sections are in the serial format of the matching sync
+// endpoint, so the importing canister applies them as it would a client update.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MigrationSection {
+    // vault_names_sync format.
+    VaultNames,
+    // The user's stored vetKD key, as is.
+    KeyMetadata,
+    // vault_spreadsheet_columns_sync format.
+    SpreadsheetColumns,
+    // vault_spreadsheet_sync format.
+    Spreadsheet,
+    // vault_login_metadata_sync format.
+    LoginMetadata,
+    // vault_login_data_sync format.
+    LoginData,
+    // vault_secrets_sync format.
+    SecureNotes,
+    // Machine grants: machine size (u8), machine, grant size (u16), grant as stored.
+    MachineGrants,
+    // vault_totp_sync format.
+    Totp,
+    // Complete attachments: id (u64), manifest size (u16), manifest as stored.
+    Attachments,
+    // Their chunks: id (u64), index (u32), size (u32), chunk.
+    AttachmentChunks,
+    // vault_cards_sync format.
+    PaymentCards,
+    // vault_identities_sync format.
+    Identities,
+    // Templates: index (u8), template size (u16), template as stored.
+    RecordTemplates,
+    // vault_custom_records_sync format. Follows the templates, seeds and attachments it refers to.
+    CustomRecords,
+    // Sheet names and order: sheet (u8), position (u8), name size (u16), name.
+    Sheets,
+    // Columns of the other sheets: sheet (u8), then an entry in vault_sheet_columns_sync format.
+    SheetColumns,
+    // Cells of the other sheets: sheet (u8), then an entry in vault_sheet_sync format.
+    SheetCells,
+    // Folders in the global sync format, each after its parent.
+    Folders,
+    // Tags in the global sync format.
+    Tags,
+    // Item labels in the global sync format. Follows the folders and tags it names.
+    ItemLabels,
+    // vault_search_tokens_sync format.
+    SearchTokens,
+}
+impl MigrationSection {
+    const ALL: [MigrationSection; 22] = [
+        MigrationSection::VaultNames, MigrationSection::KeyMetadata, MigrationSection::SpreadsheetColumns, MigrationSection::Spreadsheet,
+        MigrationSection::LoginMetadata, MigrationSection::LoginData, MigrationSection::SecureNotes, MigrationSection::MachineGrants,
+        MigrationSection::Totp, MigrationSection::Attachments, MigrationSection::AttachmentChunks, MigrationSection::PaymentCards,
+        MigrationSection::Identities, MigrationSection::RecordTemplates, MigrationSection::CustomRecords, MigrationSection::Sheets,
+        MigrationSection::SheetColumns, MigrationSection::SheetCells, MigrationSection::Folders, MigrationSection::Tags,
+        MigrationSection::ItemLabels, MigrationSection::SearchTokens,
+    ];
+
+    pub fn to_byte(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Self::ALL.get(usize::from(byte)).copied()
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct MigrationChunk {
+    pub section: MigrationSection,
+    // None for the sections that aren't tied to a vault.
+    pub vault_id: Option<Principal>,
+    pub data: Vec<u8>,
+}
+
+// Counts and a SHA-256 chained over the chunks of an export, in export order. The source keeps
+// one as it exports and the target one as it imports; they match once everything arrived.
+#[derive(CandidType, Deserialize, Clone, Default, PartialEq, Eq, Debug)]
+pub struct MigrationManifest {
+    pub vaults: u32,
+    pub items: u64,
+    pub chunks: u64,
+    pub checksum: Vec<u8>,
+}
+impl MigrationManifest {
+    // Counts a chunk holding `items` entries and chains its hash onto the checksum.
+    pub fn add(&mut self, chunk: &MigrationChunk, items: u32) {
+        let vault_id = chunk.vault_id.map(|vault_id| vault_id.as_slice().to_vec()).unwrap_or_default();
+        let mut hasher = Sha256::new();
+        hasher.update(&self.checksum);
+        hasher.update([chunk.section.to_byte(), vault_id.len() as u8]);
+        hasher.update(&vault_id);
+        hasher.update((chunk.data.len() as u32).to_be_bytes());
+        hasher.update(&chunk.data);
+        self.checksum = hasher.finalize().to_vec();
+        self.chunks += 1;
+        self.items += u64::from(items);
+        if chunk.section == MigrationSection::VaultNames {
+            self.vaults += items;
+        }
+    }
+}
+
+// Where an export resumes: a section of the user, or of one of their vaults, and the key of its
+// first entry not yet exported.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ExportCursor {
+    // Empty for the sections that aren't tied to a vault.
+    pub vault: Vec<u8>,
+    pub section: MigrationSection,
+    // Empty at the start of the section.
+    pub key: Vec<u8>,
+}
+
+// A user's export in progress, one page at a time.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MigrationExport {
+    pub started_at: u64,
+    // None once everything has been exported.
+    pub cursor: Option<ExportCursor>,
+    pub manifest: MigrationManifest,
+}
+impl MigrationExport {
+    pub fn new(now: u64) -> Self {
+        Self {
+            started_at: now,
+            cursor: Some(ExportCursor { vault: Vec::new(), section: MigrationSection::VaultNames, key: Vec::new() }),
+            manifest: MigrationManifest::default(),
+        }
+    }
+}
+impl Storable for MigrationExport {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.extend(self.started_at.to_be_bytes());
+        bytes.extend(self.manifest.vaults.to_be_bytes());
+        bytes.extend(self.manifest.items.to_be_bytes());
+        bytes.extend(self.manifest.chunks.to_be_bytes());
+        bytes.push(self.manifest.checksum.len() as u8);
+        bytes.extend(&self.manifest.checksum);
+        if let Some(cursor) = &self.cursor {
+            bytes.push(cursor.section.to_byte());
+            bytes.push(cursor.vault.len() as u8);
+            bytes.extend(&cursor.vault);
+            bytes.extend(&cursor.key);
+        }
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let checksum_end = 29 + usize::from(bytes[28]);
+        let cursor = bytes.get(checksum_end).map(|section| {
+            let vault_end = checksum_end + 2 + usize::from(bytes[checksum_end + 1]);
+            ExportCursor {
+                vault: bytes[checksum_end + 2..vault_end].to_vec(),
+                section: MigrationSection::from_byte(*section).expect("stored export section"),
+                key: bytes[vault_end..].to_vec(),
+            }
+        });
+        Self {
+            started_at: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            cursor,
+            manifest: MigrationManifest {
+                vaults: u32::from_be_bytes(bytes[8..12].try_into().unwrap()),
+                items: u64::from_be_bytes(bytes[12..20].try_into().unwrap()),
+                chunks: u64::from_be_bytes(bytes[20..28].try_into().unwrap()),
+                checksum: bytes[29..checksum_end].to_vec(),
+            },
+        }
+    }
+}
+
+// A user's consent for `target` to export their data, ahead of a move to that canister.
+pub struct MigrationGrant {
+    pub target: Principal,
+    pub authorized_at: u64,
+}
+impl Storable for MigrationGrant {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 37, is_fixed_size: false };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.extend(self.authorized_at.to_be_bytes());
+        bytes.extend(self.target.as_slice());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            authorized_at: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            target: Principal::from_slice(&bytes[8..]),
+        }
+    }
+}
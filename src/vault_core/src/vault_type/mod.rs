@@ -1,4 +1,24 @@
 pub mod vault_names;
 pub mod secure_notes;
 pub mod spreadsheet;
-pub mod logins;
\ No newline at end of file
+pub mod logins;
+pub mod machine_grants;
+pub mod audit_log;
+
+pub mod pending_deletion;
+pub mod trash;
+pub mod history;
+pub mod canister_config;
+pub mod cycles;
+pub mod capacity;
+pub mod migration;
+pub mod snapshot;
+pub mod limits;
+pub mod vault_registry;
+pub mod totp;
+pub mod attachments;
+pub mod records;
+pub mod templates;
+pub mod sheets;
+pub mod organization;
+pub mod search;
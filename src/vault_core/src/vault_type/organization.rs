@@ -0,0 +1,185 @@
+use candid::Principal;
+use ic_stable_structures::storable::Storable;
+
+use super::attachments::ItemRef;
+
+// Identifies a folder. Principals are length-prefixed so a vault's folders sort together and
+// new ids can be taken from the end of that range.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FolderKey {
+    pub principals: Vec<u8>,
+    pub id: u64,
+}
+impl FolderKey {
+    pub fn new(user_id: Principal, vault_id: Principal, id: u64) -> Self {
+        let mut principals = Vec::new();
+        principals.extend(user_id.as_slice());
+        principals.extend(vault_id.as_slice());
+        Self { principals, id }
+    }
+}
+impl Storable for FolderKey {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 512, is_fixed_size: false };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.push(self.principals.len() as u8);
+        bytes.extend(self.principals.iter());
+        bytes.extend(self.id.to_be_bytes());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let principals_size = usize::from(bytes[0]);
+        let principals = bytes[1..1 + principals_size].to_vec();
+        let id = u64::from_be_bytes(bytes[1 + principals_size..9 + principals_size].try_into().unwrap());
+        Self { principals, id }
+    }
+}
+
+// A folder: its encrypted name and the folder it sits in, None at the top.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Folder {
+    pub parent: Option<u64>,
+    pub name: Vec<u8>,
+}
+impl Storable for Folder {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(9 + self.name.len());
+        bytes.push(u8::from(self.parent.is_some()));
+        bytes.extend(self.parent.unwrap_or(0).to_be_bytes());
+        bytes.extend(self.name.iter());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let parent = (bytes[0] != 0).then(|| u64::from_be_bytes(bytes[1..9].try_into().unwrap()));
+        Self { parent, name: bytes[9..].to_vec() }
+    }
+}
+
+// Identifies a tag by the opaque id the client gave it. The label is the value.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TagKey {
+    pub principals: Vec<u8>,
+    pub id: Vec<u8>,
+}
+impl TagKey {
+    pub fn new(user_id: Principal, vault_id: Principal, id: Vec<u8>) -> Self {
+        let mut principals = Vec::new();
+        principals.extend(user_id.as_slice());
+        principals.extend(vault_id.as_slice());
+        Self { principals, id }
+    }
+}
+impl Storable for TagKey {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 512, is_fixed_size: false };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.push(self.principals.len() as u8);
+        bytes.extend(self.principals.iter());
+        bytes.extend(self.id.iter());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let principals_size = usize::from(bytes[0]);
+        Self { principals: bytes[1..1 + principals_size].to_vec(), id: bytes[1 + principals_size..].to_vec() }
+    }
+}
+
+// Identifies the folder and tags of one item. Keys sort by vault.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ItemLabelsKey {
+    pub principals: Vec<u8>,
+    pub item: ItemRef,
+}
+impl ItemLabelsKey {
+    pub fn new(user_id: Principal, vault_id: Principal, item: ItemRef) -> Self {
+        let mut principals = Vec::new();
+        principals.extend(user_id.as_slice());
+        principals.extend(vault_id.as_slice());
+        Self { principals, item }
+    }
+}
+impl Storable for ItemLabelsKey {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 512, is_fixed_size: false };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.push(self.principals.len() as u8);
+        bytes.extend(self.principals.iter());
+        bytes.extend(self.item.to_stored());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let principals_size = usize::from(bytes[0]);
+        let item = &bytes[1 + principals_size..];
+        Self {
+            principals: bytes[1..1 + principals_size].to_vec(),
+            item: ItemRef::from_stored(item),
+        }
+    }
+}
+
+// The folder an item is filed in and the ids of its tags.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct ItemLabels {
+    pub folder: Option<u64>,
+    pub tags: Vec<Vec<u8>>,
+}
+impl ItemLabels {
+    pub fn is_empty(&self) -> bool {
+        self.folder.is_none() && self.tags.is_empty()
+    }
+}
+impl Storable for ItemLabels {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.push(u8::from(self.folder.is_some()));
+        bytes.extend(self.folder.unwrap_or(0).to_be_bytes());
+        for tag in self.tags.iter() {
+            bytes.push(tag.len() as u8);
+            bytes.extend(tag.iter());
+        }
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let folder = (bytes[0] != 0).then(|| u64::from_be_bytes(bytes[1..9].try_into().unwrap()));
+        let mut tags = Vec::new();
+        let mut index = 9;
+        while index < bytes.len() {
+            let end = index + 1 + usize::from(bytes[index]);
+            tags.push(bytes[index + 1..end].to_vec());
+            index = end;
+        }
+        Self { folder, tags }
+    }
+}
@@ -0,0 +1,201 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::storable::Storable;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DeletionKind {
+    // A single vault of a user.
+    Vault,
+    // Every vault of a user.
+    User,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DeletionStatus {
+    // Waiting for the grace period to pass. Can still be cancelled.
+    Scheduled,
+    // Being carried out in batches. Can no longer be cancelled.
+    Running,
+}
+
+// Identifies a scheduled deletion. Vault deletions use the user_id + vault_id principals,
+// user purges use the user_id alone.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PendingDeletionKey {
+    pub principals: Vec<u8>,
+}
+impl PendingDeletionKey {
+    pub fn for_vault(user_id: Principal, vault_id: Principal) -> Self {
+        let mut principals = Vec::new();
+        principals.extend(user_id.as_slice());
+        principals.extend(vault_id.as_slice());
+        Self { principals }
+    }
+    pub fn for_user(user_id: Principal) -> Self {
+        Self { principals: user_id.as_slice().to_vec() }
+    }
+}
+impl Storable for PendingDeletionKey {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 512, is_fixed_size: false };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        self.principals.clone().into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.principals
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self { principals: bytes.to_vec() }
+    }
+}
+
+pub struct PendingDeletion {
+    pub kind: DeletionKind,
+    pub status: DeletionStatus,
+    pub requested_at: u64,
+    pub execute_at: u64,
+    // Number of entries removed so far, across batches.
+    pub removed: u64,
+    pub user: Vec<u8>,
+    // Empty for user purges.
+    pub vault: Vec<u8>,
+}
+impl Storable for PendingDeletion {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.push(match self.kind {
+            DeletionKind::Vault => 0,
+            DeletionKind::User => 1,
+        });
+        bytes.push(match self.status {
+            DeletionStatus::Scheduled => 0,
+            DeletionStatus::Running => 1,
+        });
+        bytes.extend(self.requested_at.to_be_bytes());
+        bytes.extend(self.execute_at.to_be_bytes());
+        bytes.extend(self.removed.to_be_bytes());
+        bytes.push(self.user.len() as u8);
+        bytes.extend(self.user.iter());
+        bytes.extend(self.vault.iter());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let kind = if bytes[0] == 0 { DeletionKind::Vault } else { DeletionKind::User };
+        let status = if bytes[1] == 0 { DeletionStatus::Scheduled } else { DeletionStatus::Running };
+        let requested_at = u64::from_be_bytes(bytes[2..10].try_into().unwrap());
+        let execute_at = u64::from_be_bytes(bytes[10..18].try_into().unwrap());
+        let removed = u64::from_be_bytes(bytes[18..26].try_into().unwrap());
+        let user_size = usize::from(bytes[26]);
+        let user = bytes[27..27 + user_size].to_vec();
+        let vault = bytes[27 + user_size..].to_vec();
+        Self {
+            kind,
+            status,
+            requested_at,
+            execute_at,
+            removed,
+            user,
+            vault,
+        }
+    }
+}
+
+// Steps of a vault deletion, in the order they run. The vault name and registry entry go last
+// so a partially deleted vault still shows up for the user. Stages added later go before
+// VaultName: a stored cursor then reads as an earlier stage, and stages are safe to repeat.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DeletionStage {
+    LoginColumns,
+    SpreadsheetColumns,
+    Spreadsheet,
+    Sheets,
+    Logins,
+    SecureNotes,
+    Totp,
+    Records,
+    CustomRecords,
+    RecordTemplates,
+    Organization,
+    SearchIndex,
+    AttachmentChunks,
+    Attachments,
+    MachineGrants,
+    Trash,
+    History,
+    VaultName,
+}
+impl DeletionStage {
+    const ALL: [DeletionStage; 18] = [
+        DeletionStage::LoginColumns,
+        DeletionStage::SpreadsheetColumns,
+        DeletionStage::Spreadsheet,
+        DeletionStage::Sheets,
+        DeletionStage::Logins,
+        DeletionStage::SecureNotes,
+        DeletionStage::Totp,
+        DeletionStage::Records,
+        DeletionStage::CustomRecords,
+        DeletionStage::RecordTemplates,
+        DeletionStage::Organization,
+        DeletionStage::SearchIndex,
+        DeletionStage::AttachmentChunks,
+        DeletionStage::Attachments,
+        DeletionStage::MachineGrants,
+        DeletionStage::Trash,
+        DeletionStage::History,
+        DeletionStage::VaultName,
+    ];
+
+    pub fn to_byte(self) -> u8 {
+        self as u8
+    }
+    pub fn from_byte(byte: u8) -> Self {
+        Self::ALL.get(usize::from(byte)).copied().unwrap_or(DeletionStage::LoginColumns)
+    }
+    // The stage after this one, None after the last.
+    pub fn next(self) -> Option<Self> {
+        Self::ALL.get(usize::from(self.to_byte()) + 1).copied()
+    }
+}
+
+// Where a running deletion resumes: the stage reached in the vault being deleted and, for
+// stages that probe cell positions, the next position to probe.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DeletionCursor {
+    pub stage: DeletionStage,
+    pub position: u32,
+}
+impl Default for DeletionCursor {
+    fn default() -> Self {
+        Self { stage: DeletionStage::LoginColumns, position: 0 }
+    }
+}
+impl Storable for DeletionCursor {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 5, is_fixed_size: true };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(5);
+        bytes.push(self.stage.to_byte());
+        bytes.extend(self.position.to_be_bytes());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            stage: DeletionStage::from_byte(bytes[0]),
+            position: u32::from_be_bytes(bytes[1..5].try_into().unwrap()),
+        }
+    }
+}
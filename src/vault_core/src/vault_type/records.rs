@@ -0,0 +1,155 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::storable::Storable;
+
+// Typed collections. Each keeps its own indexes within a vault.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum RecordKind {
+    PaymentCard,
+    Identity,
+}
+impl RecordKind {
+    pub fn to_byte(self) -> u8 {
+        self as u8
+    }
+    pub fn from_byte(byte: u8) -> Self {
+        if byte == 0 { RecordKind::PaymentCard } else { RecordKind::Identity }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CardBrand {
+    Visa,
+    Mastercard,
+    Amex,
+    Discover,
+    UnionPay,
+    Other,
+}
+impl CardBrand {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(CardBrand::Visa),
+            1 => Some(CardBrand::Mastercard),
+            2 => Some(CardBrand::Amex),
+            3 => Some(CardBrand::Discover),
+            4 => Some(CardBrand::UnionPay),
+            5 => Some(CardBrand::Other),
+            _ => None,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IdentityDocument {
+    Passport,
+    DriversLicence,
+    NationalId,
+    ResidencePermit,
+    Other,
+}
+impl IdentityDocument {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(IdentityDocument::Passport),
+            1 => Some(IdentityDocument::DriversLicence),
+            2 => Some(IdentityDocument::NationalId),
+            3 => Some(IdentityDocument::ResidencePermit),
+            4 => Some(IdentityDocument::Other),
+            _ => None,
+        }
+    }
+}
+
+// Month a card or document runs out, in the clear so expiring records can be found.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Expiry {
+    pub year: u16,
+    pub month: u8,
+}
+
+// Records sort by vault, then kind, so each collection of a vault is a single range.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RecordKey {
+    pub principals: Vec<u8>,
+    pub kind: RecordKind,
+    pub index: u8,
+}
+impl RecordKey {
+    pub fn new(user_id: Principal, vault_id: Principal, kind: RecordKind, index: u8) -> Self {
+        let mut principals = Vec::new();
+        principals.extend(user_id.as_slice());
+        principals.extend(vault_id.as_slice());
+        Self { principals, kind, index }
+    }
+}
+impl Storable for RecordKey {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 512, is_fixed_size: false };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.push(self.kind.to_byte());
+        bytes.push(self.index);
+        bytes.extend(self.principals.iter());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self { kind: RecordKind::from_byte(bytes[0]), index: bytes[1], principals: bytes[2..].to_vec() }
+    }
+}
+
+// A card or identity document. The type tag is a CardBrand or IdentityDocument by kind. Field
+// ids are the client's (number, holder, security code, ...) and their values ciphertext.
+#[derive(Clone, PartialEq, Debug)]
+pub struct TypedRecord {
+    pub type_tag: u8,
+    pub expiry: Option<Expiry>,
+    pub fields: Vec<(u8, Vec<u8>)>,
+}
+impl TypedRecord {
+    // Bytes of ciphertext held, as counted against the vault's size.
+    pub fn size(&self) -> usize {
+        self.fields.iter().map(|(_, value)| value.len()).sum()
+    }
+}
+impl Storable for TypedRecord {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(5 + 3 * self.fields.len() + self.size());
+        bytes.push(self.type_tag);
+        bytes.push(u8::from(self.expiry.is_some()));
+        let expiry = self.expiry.unwrap_or(Expiry { year: 0, month: 0 });
+        bytes.extend(expiry.year.to_be_bytes());
+        bytes.push(expiry.month);
+        for (field, value) in self.fields.iter() {
+            bytes.push(*field);
+            bytes.extend((value.len() as u16).to_be_bytes());
+            bytes.extend(value.iter());
+        }
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let mut fields = Vec::new();
+        let mut index = 5;
+        while index < bytes.len() {
+            let size = usize::from(u16::from_be_bytes([bytes[index + 1], bytes[index + 2]]));
+            fields.push((bytes[index], bytes[index + 3..index + 3 + size].to_vec()));
+            index += 3 + size;
+        }
+        Self {
+            type_tag: bytes[0],
+            expiry: (bytes[1] != 0).then(|| Expiry { year: u16::from_be_bytes([bytes[2], bytes[3]]), month: bytes[4] }),
+            fields,
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use candid::Principal;
+use ic_stable_structures::storable::Storable;
+
+use super::attachments::ItemRef;
+
+// An entry of the blind index: `item` carries `token`. Keys sort by vault, then token, so the
+// items carrying a token are one range.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SearchTokenKey {
+    pub principals: Vec<u8>,
+    pub token: Vec<u8>,
+    pub item: ItemRef,
+}
+impl SearchTokenKey {
+    pub fn new(user_id: Principal, vault_id: Principal, token: Vec<u8>, item: ItemRef) -> Self {
+        let mut principals = Vec::new();
+        principals.extend(user_id.as_slice());
+        principals.extend(vault_id.as_slice());
+        Self { principals, token, item }
+    }
+}
+impl Storable for SearchTokenKey {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 512, is_fixed_size: false };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.push(self.principals.len() as u8);
+        bytes.extend(self.principals.iter());
+        bytes.push(self.token.len() as u8);
+        bytes.extend(self.token.iter());
+        bytes.extend(self.item.to_stored());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let principals_size = usize::from(bytes[0]);
+        let token_start = 2 + principals_size;
+        let token_end = token_start + usize::from(bytes[1 + principals_size]);
+        let item = &bytes[token_end..];
+        Self {
+            principals: bytes[1..1 + principals_size].to_vec(),
+            token: bytes[token_start..token_end].to_vec(),
+            item: ItemRef::from_stored(item),
+        }
+    }
+}
+
+// Identifies the tokens of one item, so they can be taken out of the index again.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ItemTokensKey {
+    pub principals: Vec<u8>,
+    pub item: ItemRef,
+}
+impl ItemTokensKey {
+    pub fn new(user_id: Principal, vault_id: Principal, item: ItemRef) -> Self {
+        let mut principals = Vec::new();
+        principals.extend(user_id.as_slice());
+        principals.extend(vault_id.as_slice());
+        Self { principals, item }
+    }
+}
+impl Storable for ItemTokensKey {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 512, is_fixed_size: false };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.push(self.principals.len() as u8);
+        bytes.extend(self.principals.iter());
+        bytes.extend(self.item.to_stored());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let principals_size = usize::from(bytes[0]);
+        let item = &bytes[1 + principals_size..];
+        Self {
+            principals: bytes[1..1 + principals_size].to_vec(),
+            item: ItemRef::from_stored(item),
+        }
+    }
+}
+
+// The tokens an item carries, each stored behind a one-byte length.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct ItemTokens {
+    pub tokens: Vec<Vec<u8>>,
+}
+impl Storable for ItemTokens {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        for token in self.tokens.iter() {
+            bytes.push(token.len() as u8);
+            bytes.extend(token.iter());
+        }
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let mut tokens = Vec::new();
+        let mut index = 0;
+        while index < bytes.len() {
+            let end = index + 1 + usize::from(bytes[index]);
+            tokens.push(bytes[index + 1..end].to_vec());
+            index = end;
+        }
+        Self { tokens }
+    }
+}
@@ -0,0 +1,106 @@
+use candid::Principal;
+use ic_stable_structures::storable::Storable;
+
+// A principal takes at most 29 bytes, so a vault's user and vault principals together take at
+// most 58.
+const MAX_VAULT_PRINCIPALS: usize = 58;
+
+// The flexible grid of a vault holds several sheets. Sheet 0 is the grid vaults had before
+// sheets existed, and its cells and columns keep their keys. The cells and columns of any other
+// sheet are keyed by the vault's principals length-prefixed and padded to 58 bytes, then the
+// sheet. Those 60 bytes are longer than any vault's own principals, so no sheet shares its keys
+// with another sheet or vault.
+pub fn sheet_principals(user_id: Principal, vault_id: Principal, sheet: u8) -> Vec<u8> {
+    vault_sheet_principals(&[user_id.as_slice(), vault_id.as_slice()].concat(), sheet)
+}
+
+// As `sheet_principals`, from the vault's principals.
+pub fn vault_sheet_principals(principals: &[u8], sheet: u8) -> Vec<u8> {
+    if sheet == 0 {
+        return principals.to_vec();
+    }
+    let mut bytes = Vec::with_capacity(MAX_VAULT_PRINCIPALS + 2);
+    bytes.push(principals.len() as u8);
+    bytes.extend(principals);
+    bytes.resize(MAX_VAULT_PRINCIPALS + 1, 0);
+    bytes.push(sheet);
+    bytes
+}
+
+// Identifies a sheet within a vault. Keys sort by vault.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SheetKey {
+    pub principals: Vec<u8>,
+    pub sheet: u8,
+}
+impl SheetKey {
+    pub fn new(user_id: Principal, vault_id: Principal, sheet: u8) -> Self {
+        let mut principals = Vec::new();
+        principals.extend(user_id.as_slice());
+        principals.extend(vault_id.as_slice());
+        Self { principals, sheet }
+    }
+}
+impl Storable for SheetKey {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 512, is_fixed_size: false };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.push(self.sheet);
+        bytes.extend(self.principals.iter());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self { sheet: bytes[0], principals: bytes[1..].to_vec() }
+    }
+}
+
+// The encrypted name of a sheet and where it sits among the vault's sheets. Sheet 0 has no
+// record until it is renamed or another sheet is created.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct SheetRecord {
+    pub position: u8,
+    pub name: Vec<u8>,
+}
+impl Storable for SheetRecord {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(1 + self.name.len());
+        bytes.push(self.position);
+        bytes.extend(self.name.iter());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self { position: bytes[0], name: bytes[1..].to_vec() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+
+    use super::sheet_principals;
+
+    // A vault whose principal is another's followed by a sheet id must not share its sheet 0
+    // with that sheet of the other vault.
+    #[test]
+    fn sheet_keys_never_match_another_vault() {
+        let user_id = Principal::from_slice(&[1; 29]);
+        let vault_id = Principal::from_slice(&[2; 10]);
+        let longer_vault = Principal::from_slice(&[[2; 10].as_slice(), &[3]].concat());
+        assert_ne!(sheet_principals(user_id, vault_id, 3), sheet_principals(user_id, longer_vault, 0));
+        assert_ne!(sheet_principals(user_id, vault_id, 3), sheet_principals(user_id, longer_vault, 3));
+        assert!(sheet_principals(user_id, vault_id, 3).len() > sheet_principals(user_id, Principal::from_slice(&[2; 29]), 0).len());
+    }
+}
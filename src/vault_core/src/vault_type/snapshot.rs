@@ -0,0 +1,77 @@
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::storable::Storable;
+
+// What the canister is doing with a snapshot. Writes are refused while it is anything but idle.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SnapshotMode {
+    #[default]
+    Idle,
+    Exporting,
+    Restoring,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug, Default)]
+pub struct SnapshotLock {
+    pub mode: SnapshotMode,
+    pub started_at: u64,
+}
+impl Storable for SnapshotLock {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 9, is_fixed_size: true };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(9);
+        bytes.push(self.mode as u8);
+        bytes.extend(self.started_at.to_be_bytes());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let mode = match bytes[0] {
+            1 => SnapshotMode::Exporting,
+            2 => SnapshotMode::Restoring,
+            _ => SnapshotMode::Idle,
+        };
+        Self {
+            mode,
+            started_at: u64::from_be_bytes(bytes[1..9].try_into().unwrap()),
+        }
+    }
+}
+
+// Where an export picks up: the section's memory id and the last key already exported from it.
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct SnapshotCursor {
+    pub memory_id: u8,
+    pub after: Option<Vec<u8>>,
+}
+
+// Raw entries of one stable structure, as stored. Cells are a single entry with an empty key.
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct SnapshotChunk {
+    pub memory_id: u8,
+    pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+    // SHA-256 over the memory id and entries, see `_chunk_hash`.
+    pub hash: Vec<u8>,
+    // None once every section has been exported.
+    pub next: Option<SnapshotCursor>,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct SnapshotSection {
+    pub memory_id: u8,
+    pub entries: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct SnapshotManifest {
+    pub version: u32,
+    pub taken_at: u64,
+    pub sections: Vec<SnapshotSection>,
+    pub chunks: u64,
+    // SHA-256 over every chunk hash, in export order.
+    pub root_hash: Vec<u8>,
+}
@@ -1,6 +1,6 @@
 use std::u8;
 
-use candid::{Principal};
+use candid::{CandidType, Deserialize, Principal};
 use ic_stable_structures::storable::Storable;
 
 pub struct SpreadsheetKey {
@@ -202,13 +202,89 @@ impl Clone for ColumnKey {
     }
 }
 
+// What a column holds, so UIs can render links, dates and TOTP codes, and mask secrets.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ColumnKind {
+    #[default]
+    Text,
+    Secret,
+    Url,
+    Date,
+    Totp,
+}
+impl ColumnKind {
+    pub fn to_byte(self) -> u8 {
+        self as u8
+    }
+    // Kinds from newer clients read as text.
+    pub fn from_byte(byte: u8) -> Self {
+        match byte {
+            1 => ColumnKind::Secret,
+            2 => ColumnKind::Url,
+            3 => ColumnKind::Date,
+            4 => ColumnKind::Totp,
+            _ => ColumnKind::Text,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SortOrder {
+    Ascending,
+    Descending,
+}
+
+// Display settings of a column. None leaves the choice to the client.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct ColumnSettings {
+    pub width: Option<u16>,
+    pub sort: Option<SortOrder>,
+    pub required: bool,
+}
+impl ColumnSettings {
+    // Width (u16, 0 for none), sort (0 none, 1 ascending, 2 descending) and required.
+    pub const SIZE: usize = 4;
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let width = self.width.unwrap_or(0).to_be_bytes();
+        let sort = match self.sort {
+            None => 0,
+            Some(SortOrder::Ascending) => 1,
+            Some(SortOrder::Descending) => 2,
+        };
+        [width[0], width[1], sort, u8::from(self.required)]
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let width = u16::from_be_bytes([bytes[0], bytes[1]]);
+        Self {
+            width: (width > 0).then_some(width),
+            sort: match bytes[2] {
+                1 => Some(SortOrder::Ascending),
+                2 => Some(SortOrder::Descending),
+                _ => None,
+            },
+            required: bytes[3] != 0,
+        }
+    }
+}
+
+// Set in the flags byte of columns stored or sent with a kind and settings. Columns without it
+// are plain text with default settings, as stored before column kinds existed.
+pub const COLUMN_TYPED: u8 = 0x80;
+pub const COLUMN_HIDDEN: u8 = 0x01;
+
 pub struct ColumnData {
     pub hidden: bool,
-    pub name: Vec<u8>
+    pub name: Vec<u8>,
+    pub kind: ColumnKind,
+    pub settings: ColumnSettings,
+    // Read from the untyped layout, as a text column with default settings. Columns are stored
+    // typed the next time they are written, so no upgrade step rewrites them.
+    pub legacy: bool,
 }
 impl ColumnData {
-    pub fn new(hidden: bool, name: Vec<u8>) -> Self {
-        Self { hidden, name }
+    pub fn new(hidden: bool, name: Vec<u8>, kind: ColumnKind, settings: ColumnSettings) -> Self {
+        Self { hidden, name, kind, settings, legacy: false }
     }
 }
 
@@ -216,35 +292,36 @@ impl Storable for ColumnData {
     const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
     
     fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
-        let mut data : Vec<u8> = Vec::new();
-        if self.hidden {
-            data.push(0x1);
-        }
-        else {
-            data.push(0x0);
-        }
+        let mut data : Vec<u8> = Vec::with_capacity(2 + ColumnSettings::SIZE + self.name.len());
+        data.push(COLUMN_TYPED | u8::from(self.hidden));
+        data.push(self.kind.to_byte());
+        data.extend(self.settings.to_bytes());
         data.extend(self.name.iter());
         data.into()
     }
     
     fn into_bytes(self) -> Vec<u8> {
-        let mut data : Vec<u8> = Vec::new();
-        if self.hidden {
-            data.push(0x1);
-        }
-        else {
-            data.push(0x0);
-        }
-        data.extend(self.name.iter());
-        data
+        self.to_bytes().into_owned()
     }
     
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        let hidden = if bytes[0] > 0 { true } else { false };
-        let name = bytes[1..].to_vec();
+        if bytes[0] & COLUMN_TYPED == 0 {
+            let hidden = if bytes[0] > 0 { true } else { false };
+            let name = bytes[1..].to_vec();
+            return Self {
+                hidden,
+                name,
+                kind: ColumnKind::Text,
+                settings: ColumnSettings::default(),
+                legacy: true,
+            };
+        }
         Self {
-            hidden,
-            name
+            hidden: bytes[0] & COLUMN_HIDDEN != 0,
+            kind: ColumnKind::from_byte(bytes[1]),
+            settings: ColumnSettings::from_bytes(&bytes[2..2 + ColumnSettings::SIZE]),
+            name: bytes[2 + ColumnSettings::SIZE..].to_vec(),
+            legacy: false,
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,159 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::storable::Storable;
+
+// How a template field is filled in. Text, secrets and URLs are ciphertext; the other types are
+// references or dates the canister can check.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FieldType {
+    Text,
+    // Ciphertext clients keep obscured.
+    Secret,
+    Url,
+    // Year (u16), month and day, in the clear.
+    Date,
+    // Index of a TOTP seed in the same vault.
+    Totp,
+    // Id (u64) of a complete attachment in the same vault.
+    AttachmentRef,
+}
+impl FieldType {
+    fn to_byte(self) -> u8 {
+        self as u8
+    }
+    fn from_byte(byte: u8) -> Self {
+        match byte {
+            0 => FieldType::Text,
+            1 => FieldType::Secret,
+            2 => FieldType::Url,
+            3 => FieldType::Date,
+            4 => FieldType::Totp,
+            _ => FieldType::AttachmentRef,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct TemplateField {
+    pub name: Vec<u8>,
+    pub field_type: FieldType,
+    // Optional fields may be left empty.
+    pub required: bool,
+}
+
+// A client-defined record type, such as "Wi-Fi network" or "software licence".
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct RecordTemplate {
+    pub name: Vec<u8>,
+    pub fields: Vec<TemplateField>,
+}
+impl Storable for RecordTemplate {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.extend((self.name.len() as u16).to_be_bytes());
+        bytes.extend(self.name.iter());
+        for field in self.fields.iter() {
+            bytes.push(field.field_type.to_byte());
+            bytes.push(u8::from(field.required));
+            bytes.push(field.name.len() as u8);
+            bytes.extend(field.name.iter());
+        }
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let name_end = 2 + usize::from(u16::from_be_bytes([bytes[0], bytes[1]]));
+        let mut fields = Vec::new();
+        let mut index = name_end;
+        while index < bytes.len() {
+            let name_end = index + 3 + usize::from(bytes[index + 2]);
+            fields.push(TemplateField {
+                field_type: FieldType::from_byte(bytes[index]),
+                required: bytes[index + 1] != 0,
+                name: bytes[index + 3..name_end].to_vec(),
+            });
+            index = name_end;
+        }
+        Self { name: bytes[2..name_end].to_vec(), fields }
+    }
+}
+
+// Identifies a template or a custom record within a vault. Keys sort by vault.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TemplateKey {
+    pub principals: Vec<u8>,
+    pub index: u8,
+}
+impl TemplateKey {
+    pub fn new(user_id: Principal, vault_id: Principal, index: u8) -> Self {
+        let mut principals = Vec::new();
+        principals.extend(user_id.as_slice());
+        principals.extend(vault_id.as_slice());
+        Self { principals, index }
+    }
+}
+impl Storable for TemplateKey {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 512, is_fixed_size: false };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.push(self.index);
+        bytes.extend(self.principals.iter());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self { index: bytes[0], principals: bytes[1..].to_vec() }
+    }
+}
+
+// A record made from a template: one value per template field, in the template's order. Empty
+// values leave optional fields unset.
+#[derive(Clone, PartialEq, Debug)]
+pub struct CustomRecord {
+    pub template: u8,
+    pub values: Vec<Vec<u8>>,
+}
+impl CustomRecord {
+    // Bytes held, as counted against the vault's size.
+    pub fn size(&self) -> usize {
+        self.values.iter().map(|value| value.len()).sum()
+    }
+}
+impl Storable for CustomRecord {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(1 + 2 * self.values.len() + self.size());
+        bytes.push(self.template);
+        for value in self.values.iter() {
+            bytes.extend((value.len() as u16).to_be_bytes());
+            bytes.extend(value.iter());
+        }
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let mut values = Vec::new();
+        let mut index = 1;
+        while index < bytes.len() {
+            let size = usize::from(u16::from_be_bytes([bytes[index], bytes[index + 1]]));
+            values.push(bytes[index + 2..index + 2 + size].to_vec());
+            index += 2 + size;
+        }
+        Self { template: bytes[0], values }
+    }
+}
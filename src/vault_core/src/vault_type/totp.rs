@@ -0,0 +1,116 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::storable::Storable;
+
+// Hash behind the one-time passwords, as in RFC 6238.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TotpAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+impl TotpAlgorithm {
+    pub fn to_byte(self) -> u8 {
+        self as u8
+    }
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(TotpAlgorithm::Sha1),
+            1 => Some(TotpAlgorithm::Sha256),
+            2 => Some(TotpAlgorithm::Sha512),
+            _ => None,
+        }
+    }
+}
+
+// How codes are generated from the seed. Not secret, so kept in the clear for clients to show
+// a countdown without decrypting anything.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TotpParams {
+    pub algorithm: TotpAlgorithm,
+    pub digits: u8,
+    // Seconds each code is valid for.
+    pub period: u16,
+}
+
+// Seeds sort by vault, so a vault's seeds are a single range.
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TotpKey {
+    pub principals: Vec<u8>,
+    pub index: u8,
+}
+impl TotpKey {
+    pub fn new(user_id: Principal, vault_id: Principal, index: u8) -> Self {
+        let mut principals = Vec::new();
+        principals.extend(user_id.as_slice());
+        principals.extend(vault_id.as_slice());
+        Self { principals, index }
+    }
+}
+impl Storable for TotpKey {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 512, is_fixed_size: false };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.push(self.index);
+        bytes.extend(self.principals.iter());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self { index: bytes[0], principals: bytes[1..].to_vec() }
+    }
+}
+
+// An encrypted seed with its generation parameters and, optionally, the login column it is the
+// second factor for. The label (issuer, account) and the seed are ciphertext.
+#[derive(Clone, PartialEq, Debug)]
+pub struct TotpRecord {
+    pub params: TotpParams,
+    pub login_column: Option<u8>,
+    pub label: Vec<u8>,
+    pub seed: Vec<u8>,
+}
+impl TotpRecord {
+    // Bytes of ciphertext held, as counted against the vault's size.
+    pub fn size(&self) -> usize {
+        self.label.len() + self.seed.len()
+    }
+}
+impl Storable for TotpRecord {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(7 + self.label.len() + self.seed.len());
+        bytes.push(self.params.algorithm.to_byte());
+        bytes.push(self.params.digits);
+        bytes.extend(self.params.period.to_be_bytes());
+        bytes.push(u8::from(self.login_column.is_some()));
+        bytes.push(self.login_column.unwrap_or_default());
+        bytes.push(self.label.len() as u8);
+        bytes.extend(self.label.iter());
+        bytes.extend(self.seed.iter());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let label_size = usize::from(bytes[6]);
+        Self {
+            params: TotpParams {
+                algorithm: TotpAlgorithm::from_byte(bytes[0]).unwrap_or(TotpAlgorithm::Sha1),
+                digits: bytes[1],
+                period: u16::from_be_bytes([bytes[2], bytes[3]]),
+            },
+            login_column: (bytes[4] != 0).then_some(bytes[5]),
+            label: bytes[7..7 + label_size].to_vec(),
+            seed: bytes[7 + label_size..].to_vec(),
+        }
+    }
+}
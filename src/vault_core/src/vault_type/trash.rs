@@ -0,0 +1,184 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::storable::Storable;
+
+use super::{records::{RecordKind, TypedRecord}, templates::CustomRecord, totp::TotpRecord};
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TrashKind {
+    SpreadsheetCell,
+    LoginCell,
+    // A login column together with every identity that was in it.
+    LoginColumn,
+    Note,
+    // A TOTP seed. data holds the whole record as stored, label a copy of its label.
+    Totp,
+    // A payment card or identity record. data holds the whole record as stored.
+    PaymentCard,
+    Identity,
+    // A record made from a template. data holds the whole record as stored.
+    CustomRecord,
+}
+
+// Identifies a trashed item. Principals are length-prefixed so a vault's trash sorts together
+// and new ids can be taken from the end of that range.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TrashKey {
+    pub principals: Vec<u8>,
+    pub id: u64,
+}
+impl TrashKey {
+    pub fn new(user_id: Principal, vault_id: Principal, id: u64) -> Self {
+        let mut principals = Vec::new();
+        principals.extend(user_id.as_slice());
+        principals.extend(vault_id.as_slice());
+        Self { principals, id }
+    }
+    pub fn principals_match(&self, principals: &[u8]) -> bool {
+        self.principals == principals
+    }
+}
+impl Storable for TrashKey {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Bounded { max_size: 512, is_fixed_size: false };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        bytes.push(self.principals.len() as u8);
+        bytes.extend(self.principals.iter());
+        bytes.extend(self.id.to_be_bytes());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let principals_size = usize::from(bytes[0]);
+        let principals = bytes[1..1 + principals_size].to_vec();
+        let id = u64::from_be_bytes(bytes[1 + principals_size..9 + principals_size].try_into().unwrap());
+        Self { principals, id }
+    }
+}
+
+// An item removed from a vault, with enough to put it back where it was.
+#[derive(Clone, PartialEq, Debug)]
+pub struct TrashItem {
+    pub kind: TrashKind,
+    // Original coordinates. y is unused for login columns and notes.
+    pub x: u8,
+    pub y: u8,
+    // Note label, or login column label. For a cell of a sheet other than the first, the sheet.
+    pub label: Vec<u8>,
+    // Cell data, or note body.
+    pub data: Vec<u8>,
+    // Identities of a login column, keyed by y.
+    pub rows: Vec<(u8, Vec<u8>)>,
+}
+impl TrashItem {
+    pub fn cell(kind: TrashKind, x: u8, y: u8, data: Vec<u8>) -> Self {
+        Self { kind, x, y, label: Vec::new(), data, rows: Vec::new() }
+    }
+    pub fn sheet_cell(sheet: u8, x: u8, y: u8, data: Vec<u8>) -> Self {
+        let label = if sheet == 0 { Vec::new() } else { vec![sheet] };
+        Self { kind: TrashKind::SpreadsheetCell, x, y, label, data, rows: Vec::new() }
+    }
+    // The sheet a spreadsheet cell was in.
+    pub fn sheet(&self) -> u8 {
+        self.label.first().copied().unwrap_or(0)
+    }
+    pub fn login_column(x: u8, label: Vec<u8>, rows: Vec<(u8, Vec<u8>)>) -> Self {
+        Self { kind: TrashKind::LoginColumn, x, y: 0, label, data: Vec::new(), rows }
+    }
+    pub fn note(x: u8, label: Vec<u8>, note: Vec<u8>) -> Self {
+        Self { kind: TrashKind::Note, x, y: 0, label, data: note, rows: Vec::new() }
+    }
+    pub fn totp(x: u8, record: TotpRecord) -> Self {
+        Self { kind: TrashKind::Totp, x, y: 0, label: record.label.clone(), data: record.into_bytes(), rows: Vec::new() }
+    }
+    pub fn record(kind: RecordKind, x: u8, record: TypedRecord) -> Self {
+        let kind = match kind {
+            RecordKind::PaymentCard => TrashKind::PaymentCard,
+            RecordKind::Identity => TrashKind::Identity,
+        };
+        Self { kind, x, y: 0, label: Vec::new(), data: record.into_bytes(), rows: Vec::new() }
+    }
+    pub fn custom_record(x: u8, record: CustomRecord) -> Self {
+        Self { kind: TrashKind::CustomRecord, x, y: 0, label: Vec::new(), data: record.into_bytes(), rows: Vec::new() }
+    }
+}
+
+pub struct TrashEntry {
+    pub deleted_at: u64,
+    pub item: TrashItem,
+}
+impl Storable for TrashEntry {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let item = &self.item;
+        let mut bytes = Vec::new();
+        bytes.extend(self.deleted_at.to_be_bytes());
+        bytes.push(match item.kind {
+            TrashKind::SpreadsheetCell => 0,
+            TrashKind::LoginCell => 1,
+            TrashKind::LoginColumn => 2,
+            TrashKind::Note => 3,
+            TrashKind::Totp => 4,
+            TrashKind::PaymentCard => 5,
+            TrashKind::Identity => 6,
+            TrashKind::CustomRecord => 7,
+        });
+        bytes.push(item.x);
+        bytes.push(item.y);
+        bytes.extend((item.label.len() as u32).to_be_bytes());
+        bytes.extend(item.label.iter());
+        bytes.extend((item.data.len() as u32).to_be_bytes());
+        bytes.extend(item.data.iter());
+        for (y, row) in item.rows.iter() {
+            bytes.push(*y);
+            bytes.extend((row.len() as u32).to_be_bytes());
+            bytes.extend(row.iter());
+        }
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let read_size = |index: usize| u32::from_be_bytes(bytes[index..index + 4].try_into().unwrap()) as usize;
+
+        let deleted_at = u64::from_be_bytes(bytes[0..8].try_into().unwrap());
+        let kind = match bytes[8] {
+            0 => TrashKind::SpreadsheetCell,
+            1 => TrashKind::LoginCell,
+            2 => TrashKind::LoginColumn,
+            4 => TrashKind::Totp,
+            5 => TrashKind::PaymentCard,
+            6 => TrashKind::Identity,
+            7 => TrashKind::CustomRecord,
+            _ => TrashKind::Note,
+        };
+        let x = bytes[9];
+        let y = bytes[10];
+        let mut index = 11;
+        let label_size = read_size(index);
+        let label = bytes[index + 4..index + 4 + label_size].to_vec();
+        index += 4 + label_size;
+        let data_size = read_size(index);
+        let data = bytes[index + 4..index + 4 + data_size].to_vec();
+        index += 4 + data_size;
+        let mut rows = Vec::new();
+        while index < bytes.len() {
+            let row_y = bytes[index];
+            let row_size = read_size(index + 1);
+            rows.push((row_y, bytes[index + 5..index + 5 + row_size].to_vec()));
+            index += 5 + row_size;
+        }
+        Self {
+            deleted_at,
+            item: TrashItem { kind, x, y, label, data, rows },
+        }
+    }
+}
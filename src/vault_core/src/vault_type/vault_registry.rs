@@ -0,0 +1,102 @@
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::storable::Storable;
+
+// Where a vault is in its life. Follows the pending deletions rather than being set directly.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VaultStatus {
+    Active,
+    // Scheduled for deletion and read-only. Can still be cancelled.
+    PendingDeletion,
+    // Being deleted in batches.
+    Deleting,
+}
+impl VaultStatus {
+    fn to_byte(self) -> u8 {
+        match self {
+            VaultStatus::Active => 0,
+            VaultStatus::PendingDeletion => 1,
+            VaultStatus::Deleting => 2,
+        }
+    }
+    fn from_byte(byte: u8) -> Self {
+        match byte {
+            1 => VaultStatus::PendingDeletion,
+            2 => VaultStatus::Deleting,
+            _ => VaultStatus::Active,
+        }
+    }
+}
+
+// A vault known to the canister, keyed by the user_id + vault_id principals like its name.
+// Item counts and sizes cover cells, notes, TOTP seeds, cards, identities and attachments, the
+// data a vault holds; columns are left out.
+#[derive(Clone, PartialEq, Debug)]
+pub struct VaultRecord {
+    pub owner: Vec<u8>,
+    pub vault: Vec<u8>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub item_count: u64,
+    pub size_bytes: u64,
+    // Bumped by the client when it re-encrypts the vault under a new key.
+    pub key_epoch: u32,
+    pub status: VaultStatus,
+    // Encrypted client-defined metadata such as icon, colour and description. Opaque here.
+    pub metadata: Vec<u8>,
+}
+impl VaultRecord {
+    pub fn new(owner: &[u8], vault: &[u8], now: u64) -> Self {
+        Self {
+            owner: owner.to_vec(),
+            vault: vault.to_vec(),
+            created_at: now,
+            updated_at: now,
+            item_count: 0,
+            size_bytes: 0,
+            key_epoch: 0,
+            status: VaultStatus::Active,
+            metadata: Vec::new(),
+        }
+    }
+}
+impl Storable for VaultRecord {
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(39 + self.owner.len() + self.vault.len() + self.metadata.len());
+        bytes.push(self.status.to_byte());
+        bytes.extend(self.created_at.to_be_bytes());
+        bytes.extend(self.updated_at.to_be_bytes());
+        bytes.extend(self.item_count.to_be_bytes());
+        bytes.extend(self.size_bytes.to_be_bytes());
+        bytes.extend(self.key_epoch.to_be_bytes());
+        bytes.push(self.owner.len() as u8);
+        bytes.extend(self.owner.iter());
+        bytes.push(self.vault.len() as u8);
+        bytes.extend(self.vault.iter());
+        bytes.extend(self.metadata.iter());
+        bytes.into()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let owner_size = usize::from(bytes[37]);
+        let owner_end = 38 + owner_size;
+        let vault_size = usize::from(bytes[owner_end]);
+        let vault_end = owner_end + 1 + vault_size;
+        Self {
+            status: VaultStatus::from_byte(bytes[0]),
+            created_at: u64::from_be_bytes(bytes[1..9].try_into().unwrap()),
+            updated_at: u64::from_be_bytes(bytes[9..17].try_into().unwrap()),
+            item_count: u64::from_be_bytes(bytes[17..25].try_into().unwrap()),
+            size_bytes: u64::from_be_bytes(bytes[25..33].try_into().unwrap()),
+            key_epoch: u32::from_be_bytes(bytes[33..37].try_into().unwrap()),
+            owner: bytes[38..owner_end].to_vec(),
+            vault: bytes[owner_end + 1..vault_end].to_vec(),
+            metadata: bytes[vault_end..].to_vec(),
+        }
+    }
+}